@@ -0,0 +1,199 @@
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use rusqlite::Connection;
+use std::collections::{BTreeMap, HashMap};
+
+/// The last value of the key for each item having it, same as when the rows are read back
+pub(crate) const LAST_VALUES: &str = "select item_id, value from data_columns where id in \
+                           (select max(id) from data_columns where key = ?{n} group by item_id)";
+
+/// Values listed in [`ColumnProfile::sample`]
+const PROFILE_SAMPLES: usize = 5;
+
+/// Numeric stats of the values of a key, see [`TableMapDb::aggregate_key`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyStats {
+    /// values that parse as numbers
+    pub count: usize,
+    /// non-empty values that do not parse as numbers, left out of the stats
+    pub parse_failures: usize,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub sum: f64,
+    pub mean: Option<f64>,
+}
+
+impl KeyStats {
+    fn add(&mut self, value: &str) {
+        if value.is_empty() {
+            return;
+        }
+        let Some(v) = parse_number(value) else {
+            self.parse_failures += 1;
+            return;
+        };
+        self.count += 1;
+        self.sum += v;
+        self.min = Some(self.min.map_or(v, |m| m.min(v)));
+        self.max = Some(self.max.map_or(v, |m| m.max(v)));
+        self.mean = Some(self.sum / self.count as f64);
+    }
+}
+
+/// What the values of a key look like, see [`TableMapDb::profile`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnProfile {
+    pub key: String,
+    /// items having the key, even with an empty value
+    pub present_in_items: usize,
+    /// share of all the items with a non-empty value, from 0 to 1
+    pub fill_rate: f64,
+    pub distinct_values: usize,
+    /// length of the longest value, in characters
+    pub max_len: usize,
+    /// whether every non-empty value parses as a number, false if there are none
+    pub all_numeric: bool,
+    /// the first few distinct non-empty values, in the order they were inserted
+    pub sample: Vec<String>,
+}
+
+impl TableMapDb {
+    /// Count, min, max, sum and mean of the values of `key`, taking the last value of each
+    /// item, the same as it is read back. Values are parsed as `f64` in Rust, as SQLite
+    /// silently casts text to 0, the ones that do not parse, e.g. `12 EUR`, are counted in
+    /// [`KeyStats::parse_failures`]. Empty values are left out
+    pub fn aggregate_key(&self, key: &str) -> Result<KeyStats, DataToolErrors> {
+        let mut stats = KeyStats::default();
+        let q = format!(
+            "select value, null from ({})",
+            LAST_VALUES.replace("{n}", "1")
+        );
+        self.fold_values(&q, [key], |value, _| stats.add(&value))?;
+        Ok(stats)
+    }
+
+    /// Same as [`TableMapDb::aggregate_key`], for each value of `group_key`, e.g. the mean
+    /// price per category. The items without `group_key` are under `None`
+    pub fn aggregate_key_by(
+        &self,
+        value_key: &str,
+        group_key: &str,
+    ) -> Result<BTreeMap<Option<String>, KeyStats>, DataToolErrors> {
+        let mut groups: BTreeMap<Option<String>, KeyStats> = BTreeMap::new();
+        let q = format!(
+            "select v.value, g.value from ({}) v left join ({}) g on g.item_id = v.item_id",
+            LAST_VALUES.replace("{n}", "1"),
+            LAST_VALUES.replace("{n}", "2")
+        );
+        self.fold_values(&q, [value_key, group_key], |value, group| {
+            groups.entry(group).or_default().add(&value)
+        })?;
+        Ok(groups)
+    }
+
+    /// Profile of every key, in the order they were first inserted, taking the last value of
+    /// each item, the same as it is read back. Values are numbers if they parse as `f64`, the
+    /// same as for [`TableMapDb::aggregate_key`].
+    ///
+    /// The last values are copied to a temp table once, the profile is then read with a few
+    /// grouped queries, whatever the number of keys
+    pub fn profile(&self) -> Result<Vec<ColumnProfile>, DataToolErrors> {
+        let items = self.how_many_items()?;
+        self.connection.execute_batch(&self.sql(
+            "create temp table profile_values as
+                     select l.id, l.first_id, l.key, d.value from
+                     (select max(id) as id, min(id) as first_id, key from data_columns
+                      group by item_id, key) l
+                     join data_columns d on d.id = l.id;",
+        ))?;
+        let res = profile_values(&self.connection, items);
+        let dropped = self
+            .connection
+            .execute_batch("drop table temp.profile_values");
+        let profile = res?;
+        dropped?;
+        Ok(profile)
+    }
+
+    /// Hands the value and the group of each row of `q` to `f`
+    fn fold_values<F>(
+        &self,
+        q: &str,
+        params: impl rusqlite::Params,
+        mut f: F,
+    ) -> Result<(), DataToolErrors>
+    where
+        F: FnMut(String, Option<String>),
+    {
+        let mut stmt = self.connection.prepare(&self.sql(q))?;
+        let mut rows = stmt.query(params)?;
+        while let Some(r) = rows.next()? {
+            let value: Option<String> = r.get(0)?;
+            f(value.unwrap_or_default(), r.get(1)?);
+        }
+        Ok(())
+    }
+}
+
+/// Profiles the values of the temp table `profile_values`
+fn profile_values(conn: &Connection, items: usize) -> rusqlite::Result<Vec<ColumnProfile>> {
+    // values with other characters than these do not parse, the others are parsed in Rust
+    let mut stmt = conn.prepare(
+        "select key, count(*), count(nullif(value, '')), count(distinct value),
+             coalesce(max(length(value)), 0), max(value glob '*[^0-9eE.+-]*')
+         from temp.profile_values group by key order by min(first_id)",
+    )?;
+    let mut profile = stmt
+        .query_map([], |r| {
+            let filled: usize = r.get(2)?;
+            let not_numbers: bool = r.get(5)?;
+            Ok(ColumnProfile {
+                key: r.get(0)?,
+                present_in_items: r.get(1)?,
+                fill_rate: match items {
+                    0 => 0.0,
+                    _ => filled as f64 / items as f64,
+                },
+                distinct_values: r.get(3)?,
+                max_len: r.get(4)?,
+                // until a value that does not parse is found
+                all_numeric: filled > 0 && !not_numbers,
+                sample: vec![],
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let index: HashMap<String, usize> = profile
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.key.clone(), i))
+        .collect();
+    let mut stmt = conn.prepare(
+        "select key, value from temp.profile_values
+         where value != '' and value not glob '*[^0-9eE.+-]*'",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(r) = rows.next()? {
+        let column = &mut profile[index[&r.get::<_, String>(0)?]];
+        if column.all_numeric {
+            column.all_numeric = parse_number(&r.get::<_, String>(1)?).is_some();
+        }
+    }
+    let mut stmt = conn.prepare(
+        "select key, value from (
+             select key, value, row_number() over (partition by key order by min(id)) as n
+             from temp.profile_values where value != '' group by key, value)
+         where n <= ?1 order by n",
+    )?;
+    let mut rows = stmt.query([PROFILE_SAMPLES])?;
+    while let Some(r) = rows.next()? {
+        profile[index[&r.get::<_, String>(0)?]]
+            .sample
+            .push(r.get(1)?);
+    }
+    Ok(profile)
+}
+
+/// The value as a number, if it parses as a finite `f64`
+fn parse_number(value: &str) -> Option<f64> {
+    value.parse::<f64>().ok().filter(|v| v.is_finite())
+}
@@ -0,0 +1,500 @@
+use crate::errors::DataToolErrors;
+use crate::{
+    dump_copy, dump_csv, dump_csv_partitioned, dump_csv_writer, dump_db, dump_db_attach, dump_json,
+    dump_jsonl, dump_jsonl_writer, dump_sql, import_csv, import_jsonl, import_jsonl_reader,
+    import_sqlite_table, ChangeEvent, CheckpointMode, ColumnProfile, ExportCopyOptions,
+    ExportCsvOptions, ExportDbOptions, ExportJsonOptions, ExportJsonlOptions, ExportOptions,
+    ExportPartitionOptions, ExportSqlOptions, ExportSummary, ImportJsonlOptions, ImportOptions,
+    ImportSummary, KeepPolicy, KeyStats, MergePolicy, MergeSummary, SearchHit, StorageStats,
+    TableMapDb,
+};
+use indexmap::IndexMap;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+/// Handle to a [`TableMapDb`] for use from async code.
+///
+/// Every call runs on tokio's blocking thread pool, so the database work does not hold up
+/// the runtime. Clones share the same db.
+#[derive(Clone)]
+pub struct AsyncTableMapDb {
+    inner: Arc<Mutex<TableMapDb>>,
+}
+
+impl AsyncTableMapDb {
+    pub fn new(db: TableMapDb) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(db)),
+        }
+    }
+
+    /// Gives back the wrapped `TableMapDb`, if this is the last handle
+    pub fn into_inner(self) -> Result<TableMapDb, Self> {
+        Arc::try_unwrap(self.inner)
+            .map(|m| m.into_inner())
+            .map_err(|inner| Self { inner })
+    }
+
+    /// Same as [`TableMapDb::add_row`]
+    pub async fn add_row(
+        &self,
+        item_val: impl Into<String>,
+        columns: IndexMap<String, String>,
+    ) -> Result<i64, DataToolErrors> {
+        let item_val = item_val.into();
+        self.run(move |db| db.add_row(&item_val, &columns)).await
+    }
+
+    /// Same as [`TableMapDb::get_item`]
+    pub async fn get_item(
+        &self,
+        item_val: impl Into<String>,
+    ) -> Result<Option<IndexMap<String, String>>, DataToolErrors> {
+        let item_val = item_val.into();
+        self.run(move |db| db.get_item(&item_val)).await
+    }
+
+    /// Same as [`TableMapDb::insert_for`]
+    pub async fn insert_for(
+        &self,
+        item_id: i64,
+        column: impl Into<String>,
+        val: impl Into<String>,
+    ) -> Result<(), DataToolErrors> {
+        let (column, val) = (column.into(), val.into());
+        self.run(move |db| db.insert_for(item_id, &column, &val))
+            .await
+    }
+
+    /// Same as [`TableMapDb::get_value`]
+    pub async fn get_value(
+        &self,
+        item_val: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<Option<String>, DataToolErrors> {
+        let (item_val, key) = (item_val.into(), key.into());
+        self.run(move |db| db.get_value(&item_val, &key)).await
+    }
+
+    /// Same as [`TableMapDb::find_items`]
+    pub async fn find_items(
+        &self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<Vec<i64>, DataToolErrors> {
+        let (key, value) = (key.into(), value.into());
+        self.run(move |db| db.find_items(&key, &value)).await
+    }
+
+    /// Same as [`TableMapDb::how_many_items`]
+    pub async fn how_many_items(&self) -> Result<usize, DataToolErrors> {
+        self.run(|db| db.how_many_items()).await
+    }
+
+    /// Same as [`TableMapDb::how_many_items_fast`]
+    pub async fn how_many_items_fast(&self) -> Result<usize, DataToolErrors> {
+        self.run(|db| db.how_many_items_fast()).await
+    }
+
+    /// Same as [`TableMapDb::aggregate_key`]
+    pub async fn aggregate_key(&self, key: impl Into<String>) -> Result<KeyStats, DataToolErrors> {
+        let key = key.into();
+        self.run(move |db| db.aggregate_key(&key)).await
+    }
+
+    /// Same as [`TableMapDb::aggregate_key_by`]
+    pub async fn aggregate_key_by(
+        &self,
+        value_key: impl Into<String>,
+        group_key: impl Into<String>,
+    ) -> Result<BTreeMap<Option<String>, KeyStats>, DataToolErrors> {
+        let (value_key, group_key) = (value_key.into(), group_key.into());
+        self.run(move |db| db.aggregate_key_by(&value_key, &group_key))
+            .await
+    }
+
+    /// Same as [`TableMapDb::dedupe_by_key`]
+    pub async fn dedupe_by_key(
+        &self,
+        key: impl Into<String>,
+        keep: KeepPolicy,
+    ) -> Result<usize, DataToolErrors> {
+        let key = key.into();
+        self.run(move |db| db.dedupe_by_key(&key, keep)).await
+    }
+
+    /// Same as [`TableMapDb::columns`]
+    pub async fn columns(&self) -> Result<Vec<String>, DataToolErrors> {
+        self.run(|db| Ok(db.columns())).await
+    }
+
+    /// Same as [`TableMapDb::refresh_columns`]
+    pub async fn refresh_columns(&self) -> Result<(), DataToolErrors> {
+        self.run(|db| db.refresh_columns()).await
+    }
+
+    /// Same as [`TableMapDb::checkpoint`]
+    pub async fn checkpoint(&self, mode: CheckpointMode) -> Result<(), DataToolErrors> {
+        self.run(move |db| db.checkpoint(mode)).await
+    }
+
+    /// Same as [`TableMapDb::vacuum`]
+    pub async fn vacuum(&self) -> Result<(), DataToolErrors> {
+        self.run(|db| db.vacuum()).await
+    }
+
+    /// Same as [`TableMapDb::backup_to`], other calls wait until the copy is done
+    pub async fn backup_to(&self, dest: impl Into<PathBuf>) -> Result<(), DataToolErrors> {
+        let dest = dest.into();
+        self.run(move |db| db.backup_to(&dest)).await
+    }
+
+    /// Same as [`TableMapDb::items_sorted_by`]
+    pub async fn items_sorted_by(
+        &self,
+        key: impl Into<String>,
+        numeric: bool,
+        desc: bool,
+        limit: usize,
+    ) -> Result<Vec<IndexMap<String, String>>, DataToolErrors> {
+        let key = key.into();
+        self.run(move |db| db.items_sorted_by(&key, numeric, desc, limit))
+            .await
+    }
+
+    /// Same as [`TableMapDb::rows_as_records`]
+    pub async fn rows_as_records(
+        &self,
+        item_ids: Vec<i64>,
+        columns: Vec<String>,
+    ) -> Result<Vec<Vec<String>>, DataToolErrors> {
+        self.run(move |db| db.rows_as_records(&item_ids, &columns))
+            .await
+    }
+
+    /// Same as [`TableMapDb::item_vals`]
+    pub async fn item_vals(&self) -> Result<Vec<String>, DataToolErrors> {
+        self.run(|db| db.item_vals()).await
+    }
+
+    /// Same as [`TableMapDb::cells_per_item`]
+    pub async fn cells_per_item(&self) -> Result<Vec<(i64, String, usize)>, DataToolErrors> {
+        self.run(|db| db.cells_per_item()).await
+    }
+
+    /// Same as [`TableMapDb::items_since`]
+    pub async fn items_since(&self, epoch_ms: i64) -> Result<Vec<i64>, DataToolErrors> {
+        self.run(move |db| db.items_since(epoch_ms)).await
+    }
+
+    /// Same as [`TableMapDb::set_observer`]
+    pub async fn set_observer(
+        &self,
+        f: impl FnMut(ChangeEvent) + Send + 'static,
+    ) -> Result<(), DataToolErrors> {
+        self.run(move |db| {
+            db.set_observer(f);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Same as [`TableMapDb::observe_channel`]
+    pub async fn observe_channel(
+        &self,
+    ) -> Result<mpsc::UnboundedReceiver<ChangeEvent>, DataToolErrors> {
+        self.run(|db| Ok(db.observe_channel())).await
+    }
+
+    /// Same as [`TableMapDb::max_revision`]
+    pub async fn max_revision(&self) -> Result<i64, DataToolErrors> {
+        self.run(|db| db.max_revision()).await
+    }
+
+    /// Same as [`TableMapDb::items_changed_since`]
+    pub async fn items_changed_since(&self, revision: i64) -> Result<Vec<i64>, DataToolErrors> {
+        self.run(move |db| db.items_changed_since(revision)).await
+    }
+
+    /// Same as [`TableMapDb::map_names`]
+    pub async fn map_names(&self) -> Result<Vec<String>, DataToolErrors> {
+        self.run(|db| db.map_names()).await
+    }
+
+    /// Same as [`TableMapDb::storage_stats`]
+    pub async fn storage_stats(&self) -> Result<StorageStats, DataToolErrors> {
+        self.run(|db| db.storage_stats()).await
+    }
+
+    /// Same as [`TableMapDb::create_read_indexes`]
+    pub async fn create_read_indexes(&self) -> Result<(), DataToolErrors> {
+        self.run(|db| db.create_read_indexes()).await
+    }
+
+    /// Same as [`TableMapDb::optimize`]
+    pub async fn optimize(&self) -> Result<(), DataToolErrors> {
+        self.run(|db| db.optimize()).await
+    }
+
+    /// Same as [`TableMapDb::profile`]
+    pub async fn profile(&self) -> Result<Vec<ColumnProfile>, DataToolErrors> {
+        self.run(|db| db.profile()).await
+    }
+
+    /// Same as [`TableMapDb::enable_fts`], other calls wait until the values are indexed
+    pub async fn enable_fts(&self) -> Result<(), DataToolErrors> {
+        self.run(|db| db.enable_fts()).await
+    }
+
+    /// Same as [`TableMapDb::search`]
+    pub async fn search(
+        &self,
+        query: impl Into<String>,
+    ) -> Result<Vec<(i64, String)>, DataToolErrors> {
+        let query = query.into();
+        self.run(move |db| db.search(&query)).await
+    }
+
+    /// Same as [`TableMapDb::search_values`]
+    pub async fn search_values(
+        &self,
+        needle: impl Into<String>,
+        keys: Option<Vec<String>>,
+        case_sensitive: bool,
+    ) -> Result<Vec<SearchHit>, DataToolErrors> {
+        let needle = needle.into();
+        self.run(move |db| {
+            let keys = keys
+                .as_ref()
+                .map(|keys| keys.iter().map(String::as_str).collect::<Vec<_>>());
+            db.search_values(&needle, keys.as_deref(), case_sensitive)
+        })
+        .await
+    }
+
+    /// Same as [`TableMapDb::merge_from`]
+    pub async fn merge_from(
+        &self,
+        other_db_file: impl Into<PathBuf>,
+        policy: MergePolicy,
+    ) -> Result<MergeSummary, DataToolErrors> {
+        let other_db_file = other_db_file.into();
+        self.run(move |db| db.merge_from(&other_db_file, policy))
+            .await
+    }
+
+    /// Same as [`import_csv`], other calls wait until the import is done
+    pub async fn import_csv(
+        &self,
+        path: impl Into<PathBuf>,
+        options: ImportOptions,
+    ) -> Result<ImportSummary, DataToolErrors> {
+        let path = path.into();
+        self.run(move |db| import_csv(db, &path, options)).await
+    }
+
+    /// Same as [`import_jsonl`], other calls wait until the import is done
+    pub async fn import_jsonl(
+        &self,
+        path: impl Into<PathBuf>,
+        options: ImportOptions,
+        jsonl_options: ImportJsonlOptions,
+    ) -> Result<ImportSummary, DataToolErrors> {
+        let path = path.into();
+        self.run(move |db| import_jsonl(db, &path, options, jsonl_options))
+            .await
+    }
+
+    /// Same as [`import_jsonl_reader`], other calls wait until the import is done
+    pub async fn import_jsonl_reader<R: Read + Send + 'static>(
+        &self,
+        reader: R,
+        options: ImportOptions,
+        jsonl_options: ImportJsonlOptions,
+    ) -> Result<ImportSummary, DataToolErrors> {
+        self.run(move |db| import_jsonl_reader(db, reader, options, jsonl_options))
+            .await
+    }
+
+    /// Same as [`import_sqlite_table`], other calls wait until the import is done
+    pub async fn import_sqlite_table(
+        &self,
+        source: impl Into<PathBuf>,
+        table: impl Into<String>,
+        item_col: impl Into<String>,
+        options: ImportOptions,
+    ) -> Result<ImportSummary, DataToolErrors> {
+        let (source, table, item_col) = (source.into(), table.into(), item_col.into());
+        self.run(move |db| import_sqlite_table(db, &source, &table, &item_col, options))
+            .await
+    }
+
+    /// Same as [`dump_csv`], other calls wait until the export is done
+    pub async fn dump_csv(
+        &self,
+        file_name: &Path,
+        options: ExportOptions,
+        csv_options: ExportCsvOptions,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let mut db = self.inner.lock().await;
+        dump_csv(&mut db, file_name, options, csv_options).await
+    }
+
+    /// Same as [`dump_csv_writer`], other calls wait until the export is done
+    pub async fn dump_csv_writer<W: Write + Send>(
+        &self,
+        writer: W,
+        options: ExportOptions,
+        csv_options: ExportCsvOptions,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let mut db = self.inner.lock().await;
+        dump_csv_writer(&mut db, writer, options, csv_options).await
+    }
+
+    /// Same as [`dump_csv_partitioned`], other calls wait until the export is done
+    pub async fn dump_csv_partitioned(
+        &self,
+        dir: &Path,
+        options: ExportOptions,
+        csv_options: ExportCsvOptions,
+        partition_options: ExportPartitionOptions,
+    ) -> Result<BTreeMap<Option<String>, ExportSummary>, DataToolErrors> {
+        let mut db = self.inner.lock().await;
+        dump_csv_partitioned(&mut db, dir, options, csv_options, partition_options).await
+    }
+
+    /// Same as [`dump_db`], other calls wait until the export is done
+    pub async fn dump_db(
+        &self,
+        file_name: &Path,
+        options: ExportOptions,
+        db_options: ExportDbOptions,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let mut db = self.inner.lock().await;
+        dump_db(&mut db, file_name, options, db_options).await
+    }
+
+    /// Same as [`dump_db_attach`], other calls wait until the export is done
+    pub async fn dump_db_attach(
+        &self,
+        file_name: &Path,
+        options: ExportOptions,
+        db_options: ExportDbOptions,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let file_name = file_name.to_path_buf();
+        self.run(move |db| dump_db_attach(db, &file_name, options, db_options))
+            .await
+    }
+
+    /// Same as [`dump_jsonl`], other calls wait until the export is done
+    pub async fn dump_jsonl(
+        &self,
+        file_name: &Path,
+        options: ExportOptions,
+        jsonl_options: ExportJsonlOptions,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let mut db = self.inner.lock().await;
+        dump_jsonl(&mut db, file_name, options, jsonl_options).await
+    }
+
+    /// Same as [`dump_jsonl_writer`], other calls wait until the export is done
+    pub async fn dump_jsonl_writer<W: Write + Send>(
+        &self,
+        writer: W,
+        options: ExportOptions,
+        jsonl_options: ExportJsonlOptions,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let mut db = self.inner.lock().await;
+        dump_jsonl_writer(&mut db, writer, options, jsonl_options).await
+    }
+
+    /// Same as [`dump_json`], other calls wait until the export is done
+    pub async fn dump_json(
+        &self,
+        file_name: &Path,
+        options: ExportOptions,
+        json_options: ExportJsonOptions,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let mut db = self.inner.lock().await;
+        dump_json(&mut db, file_name, options, json_options).await
+    }
+
+    /// Same as [`dump_copy`], other calls wait until the export is done
+    pub async fn dump_copy(
+        &self,
+        file_name: &Path,
+        options: ExportOptions,
+        copy_options: ExportCopyOptions,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let mut db = self.inner.lock().await;
+        dump_copy(&mut db, file_name, options, copy_options).await
+    }
+
+    /// Same as [`dump_sql`], other calls wait until the export is done
+    pub async fn dump_sql(
+        &self,
+        file_name: &Path,
+        options: ExportOptions,
+        sql_options: ExportSqlOptions,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let mut db = self.inner.lock().await;
+        dump_sql(&mut db, file_name, options, sql_options).await
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, DataToolErrors>
+    where
+        F: FnOnce(&mut TableMapDb) -> Result<T, DataToolErrors> + Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || f(&mut inner.blocking_lock()))
+            .await
+            .map_err(|e| DataToolErrors::GenericError(e.to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn interleaves_inserts_and_reads() {
+        let dir = TestDir::new("async_db");
+        let db = AsyncTableMapDb::new(dir.db());
+        let tasks: Vec<_> = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    for i in 0..250 {
+                        let item_val = format!("{}-{}", t, i);
+                        let columns = IndexMap::from([("n".to_string(), i.to_string())]);
+                        db.add_row(item_val.clone(), columns).await.unwrap();
+                        let value = db.get_value(item_val.clone(), "n").await.unwrap();
+                        assert_eq!(value, Some(i.to_string()));
+                        let item = db.get_item(item_val).await.unwrap().unwrap();
+                        assert_eq!(item["n"], i.to_string());
+                    }
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+        assert_eq!(db.how_many_items().await.unwrap(), 1000);
+        assert_eq!(db.find_items("n", "249").await.unwrap().len(), 4);
+        let out = dir.path("out.csv");
+        let summary = db
+            .dump_csv(
+                &out,
+                ExportOptions::default().chunk(100),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary.rows_written, 1000);
+    }
+}
@@ -0,0 +1,100 @@
+use crate::errors::DataToolErrors;
+use crate::{open_connection, TableMapDb};
+use rusqlite::backup::{Backup, Progress, StepResult};
+use rusqlite::{Connection, OpenFlags};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Pages copied at a time, the source db is locked while they are
+const BACKUP_STEP_PAGES: i32 = 1024;
+
+impl TableMapDb {
+    /// Copies the db to `dest`, a new file, while it is in use, see
+    /// [`TableMapDb::backup_to_with_progress`]
+    pub fn backup_to(&self, dest: &Path) -> Result<(), DataToolErrors> {
+        self.backup_to_with_progress(dest, |_| {})
+    }
+
+    /// Copies the db to `dest` with SQLite's online backup, a consistent copy of what is
+    /// committed, even if rows are inserted meanwhile. `on_progress` is called after every
+    /// step of 1024 pages, the db is only locked during a step.
+    ///
+    /// Unlike the db, the copy is written with `synchronous = FULL` and a rollback journal,
+    /// a single file which survives a crash of the process, or of the machine
+    pub fn backup_to_with_progress<F>(
+        &self,
+        dest: &Path,
+        on_progress: F,
+    ) -> Result<(), DataToolErrors>
+    where
+        F: FnMut(Progress),
+    {
+        let t = Instant::now();
+        if dest.exists() {
+            return Err(DataToolErrors::FileExists(dest.to_path_buf()));
+        }
+        let res = backup(&self.connection, dest, on_progress);
+        if res.is_err() {
+            let _ = fs::remove_file(dest);
+        }
+        res?;
+        info!(
+            "Done! {:?} backed up to {:?} in {:?}",
+            self.db_file,
+            dest,
+            t.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Backs the db up to a new file in the temp dir, and returns a handle on the copy, e.g.
+    /// to export it while the ingestion goes on. The file is left in place once the handle
+    /// is dropped
+    pub fn snapshot(&self) -> Result<TableMapDb, DataToolErrors> {
+        let stem = self
+            .db_file
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let dest = env::temp_dir().join(format!(
+            "{}-snapshot-{}-{}.db",
+            stem,
+            std::process::id(),
+            nanos
+        ));
+        self.backup_to(&dest)?;
+        let connection = open_connection(&dest, OpenFlags::default())?;
+        let mut db = TableMapDb::with_connection(dest, connection);
+        db.refresh_columns()?;
+        Ok(db)
+    }
+}
+
+fn backup<F>(src: &Connection, dest: &Path, mut on_progress: F) -> rusqlite::Result<()>
+where
+    F: FnMut(Progress),
+{
+    let mut dst = Connection::open(dest)?;
+    dst.execute_batch("PRAGMA synchronous = FULL")?;
+    {
+        let backup = Backup::new(src, &mut dst)?;
+        loop {
+            match backup.step(BACKUP_STEP_PAGES)? {
+                StepResult::Done => break,
+                StepResult::More => on_progress(backup.progress()),
+                // a writer holds the lock, try again shortly
+                _ => std::thread::sleep(Duration::from_millis(50)),
+            }
+        }
+        on_progress(backup.progress());
+    }
+    // the pages copied say WAL, which would leave the copy relying on a -wal file
+    dst.execute_batch("PRAGMA journal_mode = DELETE")
+}
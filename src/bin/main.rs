@@ -1,9 +1,11 @@
+use rand::{thread_rng, Rng};
 use std::path::{Path, PathBuf};
 use std::time::Instant;
-use rand::{Rng, thread_rng};
+use table_map_db::{
+    dump_csv, dump_db, ExportCsvOptions, ExportDbOptions, ExportOptions, OverwriteMode, TableMapDb,
+};
 use tracing::{error, info};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
-use table_map_db::{dump_csv, dump_db, TableMapDb};
 
 pub fn generate_random_str(length: usize) -> String {
     let rng = rand::thread_rng();
@@ -25,13 +27,12 @@ pub fn set_tracing() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
-
 #[tokio::main]
 async fn main() {
     set_tracing().unwrap();
     let mut rng = thread_rng();
     let p = PathBuf::from("db.sqlite");
-    let mut db = TableMapDb::new(p);
+    let mut db = TableMapDb::new(p).unwrap();
     let mut keys = vec![];
     let keys_cnt = 400;
     let no_items = 1000;
@@ -39,9 +40,8 @@ async fn main() {
         keys.push(format!("C/{}", generate_random_str(5)));
     }
     for _ in 0..no_items {
-        let pr = generate_random_str(5);
-        if let Err(e) = db.next_row(&pr) {
-            error!(e);
+        if let Err(e) = db.next_row_auto() {
+            error!("{}", e);
             continue;
         }
         let mut cols = vec![];
@@ -50,17 +50,32 @@ async fn main() {
             if cols.contains(&ky) {
                 continue;
             }
-            cols.push(ky.clone());
+            cols.push(ky);
             let vl = generate_random_str(10);
             db.insert(&keys[ky], &vl).unwrap()
         }
     }
     info!("{}", db.how_many_items().unwrap());
     let instant = Instant::now();
-    dump_db(&mut db, Path::new("another_db.sqlite"), 100, vec![]).await.unwrap();
+    let options = ExportOptions::default().overwrite(OverwriteMode::Overwrite);
+    dump_db(
+        &mut db,
+        Path::new("another_db.sqlite"),
+        options.clone().chunk(100),
+        ExportDbOptions::default(),
+    )
+    .await
+    .unwrap();
     info!("sqlite: {}", instant.elapsed().as_secs());
 
     let instant = Instant::now();
-    dump_csv(&mut db, Path::new("another_db.csv"), 100, vec![]).await.unwrap();
+    dump_csv(
+        &mut db,
+        Path::new("another_db.csv"),
+        options.chunk(100),
+        ExportCsvOptions::default(),
+    )
+    .await
+    .unwrap();
     info!("csv: {}", instant.elapsed().as_secs());
-}
\ No newline at end of file
+}
@@ -0,0 +1,210 @@
+use crate::errors::DataToolErrors;
+use crate::{ChangeEvent, TableMapDb, Tables};
+use rusqlite::Connection;
+use std::time::Instant;
+use tracing::info;
+
+/// Which of the items with the same value is kept, see [`TableMapDb::dedupe_by_key`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeepPolicy {
+    /// the item inserted first
+    #[default]
+    First,
+    /// the item inserted last
+    Last,
+    /// the item with the most keys, the first inserted of them if several have as many
+    MostColumns,
+}
+
+impl TableMapDb {
+    /// Deletes the items having the same value of `key` as another item, keeping one of
+    /// them following `keep`, and returns the number of items deleted. The last value of the
+    /// key is compared, the same as it is read back. Items without the key, or with an empty
+    /// value, are left alone.
+    ///
+    /// Runs as a few SQL statements in a single transaction, the items are ranked with a
+    /// window function and their columns deleted along with them
+    pub fn dedupe_by_key(&mut self, key: &str, keep: KeepPolicy) -> Result<usize, DataToolErrors> {
+        let t = Instant::now();
+        let conn = &self.connection;
+        conn.execute_batch("BEGIN")?;
+        let res = dedupe_items(conn, &self.tables, key, keep);
+        let end = if res.is_ok() { "COMMIT" } else { "ROLLBACK" };
+        let ended = conn.execute_batch(end).map_err(DataToolErrors::from);
+        let removed = res?;
+        ended?;
+        for &id in &removed {
+            self.notify(|| ChangeEvent::ItemDeleted { id });
+        }
+        // keys only the deleted items had are gone
+        self.refresh_columns()?;
+        info!(
+            "Done! {} items with the same {:?} removed in {:?}",
+            removed.len(),
+            key,
+            t.elapsed()
+        );
+        Ok(removed.len())
+    }
+}
+
+/// Deletes the items left out, returning their ids
+fn dedupe_items(
+    conn: &Connection,
+    tables: &Tables,
+    key: &str,
+    keep: KeepPolicy,
+) -> rusqlite::Result<Vec<i64>> {
+    // the last value of the key of each item, the value is the one of the max(id) row
+    conn.execute(
+        &tables.sql(
+            "create temp table dedupe_values as
+             select item_id, max(id) as id, value from data_columns where key = ?1 group by item_id",
+        ),
+        [key],
+    )?;
+    // the number of keys of each item with the key, indexed by item
+    conn.execute_batch(
+        "create temp table dedupe_columns (item_id integer primary key, columns integer);",
+    )?;
+    let rank = match keep {
+        KeepPolicy::First => "v.item_id",
+        KeepPolicy::Last => "v.item_id desc",
+        KeepPolicy::MostColumns => {
+            conn.execute_batch(&tables.sql(
+                "insert into temp.dedupe_columns
+                     select item_id, count(distinct key) from data_columns
+                     where item_id in (select item_id from temp.dedupe_values)
+                     group by item_id;",
+            ))?;
+            "c.columns desc, v.item_id"
+        }
+    };
+    conn.execute_batch(&format!(
+        "create temp table dedupe_losers (id integer primary key);
+         insert into temp.dedupe_losers
+             select item_id from (
+                 select v.item_id, row_number() over (partition by v.value order by {}) as n
+                 from temp.dedupe_values v
+                 left join temp.dedupe_columns c on c.item_id = v.item_id
+                 where v.value != '')
+             where n > 1;",
+        rank
+    ))?;
+    conn.execute(
+        &tables
+            .sql("delete from data_columns where item_id in (select id from temp.dedupe_losers)"),
+        [],
+    )?;
+    let removed = conn
+        .prepare(&tables.sql(
+            "delete from item_data where id in (select id from temp.dedupe_losers) returning id",
+        ))?
+        .query_map([], |r| r.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+    conn.execute_batch(
+        "drop table temp.dedupe_values; drop table temp.dedupe_columns;
+         drop table temp.dedupe_losers;",
+    )?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+
+    /// A db with the items, each key inserted in order, so repeated keys have several values
+    fn db(dir: &TestDir, items: &[(&str, &[(&str, &str)])]) -> TableMapDb {
+        let mut db = dir.db();
+        for (item_val, cells) in items {
+            db.next_row(item_val).unwrap();
+            for (key, value) in cells.iter() {
+                db.insert(*key, *value).unwrap();
+            }
+        }
+        db
+    }
+
+    fn dupes(dir: &TestDir) -> TableMapDb {
+        db(
+            dir,
+            &[
+                ("a", &[("sku", "1")]),
+                ("b", &[("sku", "1"), ("name", "bolt")]),
+                ("c", &[("sku", "1"), ("name", "cog"), ("size", "2")]),
+                ("d", &[("sku", "2")]),
+            ],
+        )
+    }
+
+    #[test]
+    fn the_first_item_is_kept() {
+        let dir = TestDir::new("dedupe_first");
+        let mut db = dupes(&dir);
+        assert_eq!(db.dedupe_by_key("sku", KeepPolicy::First).unwrap(), 2);
+        assert_eq!(db.item_vals().unwrap(), ["a", "d"]);
+        // keys only the deleted items had are gone
+        assert_eq!(db.columns(), ["sku"]);
+    }
+
+    #[test]
+    fn the_last_item_is_kept() {
+        let dir = TestDir::new("dedupe_last");
+        let mut db = dupes(&dir);
+        assert_eq!(db.dedupe_by_key("sku", KeepPolicy::Last).unwrap(), 2);
+        assert_eq!(db.item_vals().unwrap(), ["c", "d"]);
+        assert_eq!(db.get_value("c", "name").unwrap().as_deref(), Some("cog"));
+    }
+
+    #[test]
+    fn the_item_with_the_most_columns_is_kept() {
+        let dir = TestDir::new("dedupe_most_columns");
+        let mut db = db(
+            &dir,
+            &[
+                ("a", &[("sku", "1")]),
+                ("b", &[("sku", "1"), ("name", "bolt")]),
+                ("c", &[("sku", "1"), ("size", "2")]),
+                ("d", &[("sku", "1")]),
+            ],
+        );
+        // b and c have as many, the first inserted is kept
+        assert_eq!(db.dedupe_by_key("sku", KeepPolicy::MostColumns).unwrap(), 3);
+        assert_eq!(db.item_vals().unwrap(), ["b"]);
+    }
+
+    #[test]
+    fn the_last_value_of_the_key_is_compared() {
+        let dir = TestDir::new("dedupe_last_value");
+        let mut db = db(
+            &dir,
+            &[
+                ("a", &[("sku", "1"), ("sku", "2")]),
+                ("b", &[("sku", "1")]),
+                ("c", &[("sku", "2")]),
+            ],
+        );
+        assert_eq!(db.dedupe_by_key("sku", KeepPolicy::First).unwrap(), 1);
+        assert_eq!(db.item_vals().unwrap(), ["a", "b"]);
+    }
+
+    #[test]
+    fn empty_values_and_items_without_the_key_are_left_alone() {
+        let dir = TestDir::new("dedupe_empty");
+        let mut db = db(
+            &dir,
+            &[
+                ("a", &[("sku", "")]),
+                ("b", &[("sku", "")]),
+                ("c", &[("name", "cog")]),
+                ("d", &[("name", "cog")]),
+                // the last value is empty
+                ("e", &[("sku", "1"), ("sku", "")]),
+                ("f", &[("sku", "1")]),
+            ],
+        );
+        assert_eq!(db.dedupe_by_key("sku", KeepPolicy::First).unwrap(), 0);
+        assert_eq!(db.how_many_items().unwrap(), 6);
+    }
+}
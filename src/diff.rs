@@ -0,0 +1,318 @@
+use crate::errors::DataToolErrors;
+use crate::{key_array, open_connection};
+use rusqlite::{Connection, OpenFlags};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Options of [`diff`]
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    ignore_keys: Vec<String>,
+    counts_only: bool,
+}
+
+impl DiffOptions {
+    /// Keys left out of the comparison, e.g. the time an item was scraped
+    pub fn ignore_keys(mut self, keys: Vec<String>) -> Self {
+        self.ignore_keys = keys;
+        self
+    }
+
+    /// Only count the keys that differ for each item, leaving [`ItemDiff::keys`] empty,
+    /// for dbs too large to hold every change in memory
+    pub fn counts_only(mut self, counts_only: bool) -> Self {
+        self.counts_only = counts_only;
+        self
+    }
+}
+
+/// The differences between two table map dbs, see [`diff`]
+#[derive(Debug, Clone, Default)]
+pub struct DiffReport {
+    /// `item_val` of the items only the second db has
+    pub added: Vec<String>,
+    /// `item_val` of the items only the first db has
+    pub removed: Vec<String>,
+    /// items of both dbs with a key that differs
+    pub changed: Vec<ItemDiff>,
+    /// items of both dbs with the same keys and values
+    pub unchanged: usize,
+    pub elapsed: Duration,
+}
+
+/// An item whose keys differ between the dbs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemDiff {
+    pub item_val: String,
+    /// number of keys that differ
+    pub changed_keys: usize,
+    /// the keys that differ, by key, empty with [`DiffOptions::counts_only`]
+    pub keys: Vec<KeyChange>,
+}
+
+/// A key of an item that differs between the dbs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    pub key: String,
+    /// value in the first db, `None` if the item does not have the key there
+    pub old: Option<String>,
+    /// value in the second db, `None` if the item does not have the key there
+    pub new: Option<String>,
+}
+
+/// Compares the table map db files `a` and `b`, matching the items by `item_val`. Values
+/// are compared as stored, so an empty value and a missing key differ, and if a key was
+/// inserted more than once for an item the last value is compared, the same as it is read
+/// back. Items without an `item_val` are left out.
+///
+/// Items are listed in the order they were inserted in `a`, or in `b` for the added ones.
+/// Both files are only read, with SQLite doing the matching
+pub fn diff(a: &Path, b: &Path, options: DiffOptions) -> Result<DiffReport, DataToolErrors> {
+    let t = Instant::now();
+    for file in [a, b] {
+        if !file.exists() {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "no database {:?}",
+                file
+            )));
+        }
+    }
+    let conn = open_connection(a, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.execute("attach database ?1 as b", [b.to_string_lossy()])?;
+    for (db, file) in [("main", a), ("b", b)] {
+        let tables: usize = conn.query_row(
+            &format!(
+                "select count(*) from {}.sqlite_master \
+                     where type = 'table' and name in ('item_data', 'data_columns')",
+                db
+            ),
+            [],
+            |r| r.get(0),
+        )?;
+        if tables != 2 {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "{:?} is not a table map db",
+                file
+            )));
+        }
+    }
+    let mut report = diff_attached(&conn, &options)?;
+    report.elapsed = t.elapsed();
+    info!(
+        "Done! {} added, {} removed, {} changed, {} unchanged in {:?}",
+        report.added.len(),
+        report.removed.len(),
+        report.changed.len(),
+        report.unchanged,
+        report.elapsed
+    );
+    Ok(report)
+}
+
+/// Diffs `main` against the db attached as `b`, in temp tables left to the connection
+fn diff_attached(conn: &Connection, options: &DiffOptions) -> rusqlite::Result<DiffReport> {
+    let mut report = DiffReport::default();
+    let item_vals = |q: &str| -> rusqlite::Result<Vec<String>> {
+        let mut stmt = conn.prepare(q)?;
+        let rows = stmt.query_map([], |r| r.get(0))?;
+        rows.collect()
+    };
+    report.added = item_vals(
+        "select item_val from b.item_data o where item_val is not null \
+         and not exists (select 1 from main.item_data m where m.item_val = o.item_val) \
+         order by id",
+    )?;
+    report.removed = item_vals(
+        "select item_val from main.item_data m where item_val is not null \
+         and not exists (select 1 from b.item_data o where o.item_val = m.item_val) \
+         order by id",
+    )?;
+    // the items of both dbs, and their last value of each key, indexed as data_columns is not
+    let ignored = key_array(&options.ignore_keys);
+    conn.execute_batch(
+        "create temp table diff_items as
+             select m.id as a_id, o.id as b_id, m.item_val from main.item_data m
+             join b.item_data o on o.item_val = m.item_val;",
+    )?;
+    for db in ["main", "b"] {
+        conn.execute(
+            &format!(
+                "create temp table diff_{db}_cells as
+                 select item_id, key, value from {db}.data_columns
+                 where id in (select max(id) from {db}.data_columns group by item_id, key)
+                 and key not in rarray(?1)",
+                db = db
+            ),
+            [&ignored],
+        )?;
+        conn.execute_batch(&format!(
+            "create index temp.diff_{db}_cells_item_key on diff_{db}_cells (item_id, key)",
+            db = db
+        ))?;
+    }
+    // the keys that differ, both ways, by item then key
+    let changes = "select i.a_id, i.item_val, a.key, a.value, b.value from temp.diff_items i
+         join temp.diff_main_cells a on a.item_id = i.a_id
+         left join temp.diff_b_cells b on b.item_id = i.b_id and b.key = a.key
+         where b.value is not a.value or b.key is null
+         union all
+         select i.a_id, i.item_val, b.key, null, b.value from temp.diff_items i
+         join temp.diff_b_cells b on b.item_id = i.b_id
+         where not exists (
+             select 1 from temp.diff_main_cells a where a.item_id = i.a_id and a.key = b.key)";
+    let q = match options.counts_only {
+        true => format!(
+            "select item_val, count(*) from ({}) group by a_id order by a_id",
+            changes
+        ),
+        false => format!("{} order by 1, 3", changes),
+    };
+    collect_changes(conn, &q, options.counts_only, &mut report.changed)?;
+    let common: usize = conn.query_row("select count(*) from temp.diff_items", [], |r| r.get(0))?;
+    report.unchanged = common - report.changed.len();
+    Ok(report)
+}
+
+fn collect_changes(
+    conn: &Connection,
+    q: &str,
+    counts_only: bool,
+    changed: &mut Vec<ItemDiff>,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(q)?;
+    let mut rows = stmt.query([])?;
+    if counts_only {
+        while let Some(r) = rows.next()? {
+            changed.push(ItemDiff {
+                item_val: r.get(0)?,
+                changed_keys: r.get(1)?,
+                keys: vec![],
+            });
+        }
+        return Ok(());
+    }
+    let mut last_id = None;
+    while let Some(r) = rows.next()? {
+        let id: i64 = r.get(0)?;
+        if last_id != Some(id) {
+            last_id = Some(id);
+            changed.push(ItemDiff {
+                item_val: r.get(1)?,
+                changed_keys: 0,
+                keys: vec![],
+            });
+        }
+        let item = changed.last_mut().expect("an item was just pushed");
+        item.keys.push(KeyChange {
+            key: r.get(2)?,
+            old: r.get(3)?,
+            new: r.get(4)?,
+        });
+        item.changed_keys += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+    use crate::TableMapDb;
+    use std::path::PathBuf;
+
+    /// A db with the items, each key inserted in order, so repeated keys have several values
+    fn db_file(dir: &TestDir, name: &str, items: &[(&str, &[(&str, &str)])]) -> PathBuf {
+        let path = dir.path(name);
+        let mut db = TableMapDb::new(path.clone()).unwrap();
+        for (item_val, cells) in items {
+            db.next_row(item_val).unwrap();
+            for (key, value) in cells.iter() {
+                db.insert(*key, *value).unwrap();
+            }
+        }
+        path
+    }
+
+    fn change(key: &str, old: Option<&str>, new: Option<&str>) -> KeyChange {
+        KeyChange {
+            key: key.to_string(),
+            old: old.map(str::to_string),
+            new: new.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn items_and_keys_that_differ_are_reported() {
+        let dir = TestDir::new("diff");
+        let a = db_file(
+            &dir,
+            "a.db",
+            &[
+                // the last price is compared
+                (
+                    "p1",
+                    &[
+                        ("name", "apple"),
+                        ("price", "0"),
+                        ("price", "1"),
+                        ("seen", "1"),
+                    ],
+                ),
+                ("p2", &[("name", "pear")]),
+                ("p3", &[("name", "plum"), ("color", "")]),
+                ("p4", &[("x", "1")]),
+                ("gone", &[("x", "2")]),
+            ],
+        );
+        let b = db_file(
+            &dir,
+            "b.db",
+            &[
+                ("new", &[("x", "3")]),
+                ("p4", &[("x", "1")]),
+                ("p3", &[("name", "plum")]),
+                ("p2", &[("name", "PEAR")]),
+                (
+                    "p1",
+                    &[
+                        ("name", "apple"),
+                        ("price", "1"),
+                        ("seen", "2"),
+                        ("color", "red"),
+                    ],
+                ),
+            ],
+        );
+        let options = DiffOptions::default().ignore_keys(vec!["seen".to_string()]);
+        let report = diff(&a, &b, options).unwrap();
+        assert_eq!(report.added, ["new"]);
+        assert_eq!(report.removed, ["gone"]);
+        assert_eq!(report.unchanged, 1);
+        // in the order of the first db, an empty value differing from a missing key
+        let item = |item_val: &str, keys: Vec<KeyChange>| ItemDiff {
+            item_val: item_val.to_string(),
+            changed_keys: keys.len(),
+            keys,
+        };
+        assert_eq!(
+            report.changed,
+            [
+                item("p1", vec![change("color", None, Some("red"))]),
+                item("p2", vec![change("name", Some("pear"), Some("PEAR"))]),
+                item("p3", vec![change("color", Some(""), None)]),
+            ]
+        );
+        let report = diff(&a, &b, DiffOptions::default().counts_only(true)).unwrap();
+        let counts: Vec<_> = report
+            .changed
+            .iter()
+            .map(|i| (i.item_val.as_str(), i.changed_keys, i.keys.len()))
+            .collect();
+        assert_eq!(counts, [("p1", 2, 0), ("p2", 1, 0), ("p3", 1, 0)]);
+        assert!(matches!(
+            diff(&a, &dir.path("missing.db"), DiffOptions::default()),
+            Err(DataToolErrors::InvalidArgument(_))
+        ));
+    }
+}
@@ -1,14 +1,75 @@
+use std::io;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Error, Debug, Clone)]
 pub enum DataToolErrors {
-
-
     #[error("Error received: {0}")]
     GenericError(String),
 
+    /// an error returned by SQLite, `code` is the extended result code when there is one,
+    /// e.g. `2067` for a unique constraint failure
+    #[error("SQLite error: {message}")]
+    Sqlite { code: Option<i32>, message: String },
+
+    #[error("I/O error: {message}")]
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
+
+    #[error("No item is set, call next_row or set_current_item first")]
+    NoCurrentItem,
+
+    #[error("Item {0} not found")]
+    ItemNotFound(i64),
+
     #[error("CSV Error: {0}")]
     CsvError(String),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("File already exists: {0:?}")]
+    FileExists(PathBuf),
+
+    #[error("Failed to write row {row}: {reason}")]
+    RowWriteFailed { row: usize, reason: String },
+
+    #[error("Failed to read line {line}: {reason}")]
+    RowReadFailed { line: u64, reason: String },
+
+    #[error("Validation failed for {key:?}: {reason}")]
+    ValidationError { key: String, reason: String },
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    /// columns asked for that are not stored keys
+    #[error("Unknown columns: {0:?}")]
+    UnknownColumns(Vec<String>),
+
+    #[error("Full-text search is not available: {0}")]
+    FtsUnavailable(String),
+
+    /// the `item_val` is already the one of another item, see
+    /// [`crate::TableMapDb::update_item_val`]
+    #[error("Item {id} already has the item_val {item_val:?}")]
+    ItemValTaken { item_val: String, id: i64 },
+
+    /// a key or value read back is not valid UTF-8, see [`crate::TextPolicy::Strict`]. `key`
+    /// has the invalid bytes replaced when it is the key itself
+    #[error("The value of item {item_id} under {key:?} is not valid UTF-8")]
+    InvalidUtf8 { item_id: i64, key: String },
+}
+
+impl DataToolErrors {
+    /// Whether SQLite failed as another connection held a lock for longer than the busy
+    /// timeout, see [`crate::TableMapDb::busy_timeout`]. Trying again later may succeed
+    pub fn is_busy(&self) -> bool {
+        matches!(self, Self::Sqlite { code: Some(code), .. }
+            if code & 0xff == rusqlite::ffi::SQLITE_BUSY)
+    }
 }
 
 impl From<csv::Error> for DataToolErrors {
@@ -17,9 +78,34 @@ impl From<csv::Error> for DataToolErrors {
     }
 }
 
-impl From<std::io::Error> for DataToolErrors {
-    fn from(value: std::io::Error) -> Self {
-        Self::GenericError(value.to_string())
+impl From<io::Error> for DataToolErrors {
+    fn from(value: io::Error) -> Self {
+        Self::Io {
+            kind: value.kind(),
+            message: value.to_string(),
+        }
     }
 }
 
+impl From<rusqlite::Error> for DataToolErrors {
+    /// A statement stopped by an [`rusqlite::InterruptHandle`] is [`DataToolErrors::Cancelled`],
+    /// the errors of the crate carried by rusqlite's are given back as they are
+    fn from(value: rusqlite::Error) -> Self {
+        if let rusqlite::Error::FromSqlConversionFailure(_, _, e) = &value {
+            if let Some(e) = e.downcast_ref::<Self>() {
+                return e.clone();
+            }
+        }
+        let code = match &value {
+            rusqlite::Error::SqliteFailure(e, _) => Some(e.extended_code),
+            _ => None,
+        };
+        if code == Some(rusqlite::ffi::SQLITE_INTERRUPT) {
+            return Self::Cancelled;
+        }
+        Self::Sqlite {
+            code,
+            message: value.to_string(),
+        }
+    }
+}
@@ -0,0 +1,4219 @@
+use crate::errors::DataToolErrors;
+use crate::{
+    existing_ids, id_array, key_array, open_connection, quote_ident, read_items, read_items_range,
+    CheckpointMode, IdPager, IterOrder, KeyOrder, OpenMode, Snapshot, TableMapDb, Tables,
+    TextPolicy, ITER_PAGE_SIZE,
+};
+use csv::QuoteStyle;
+use headers::HeaderMerge;
+use indexmap::IndexMap;
+use manifest::{commit_unhashed, Hashed, Manifest};
+use regex::Regex;
+use rusqlite::limits::Limit;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, InterruptHandle, OpenFlags};
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display};
+use std::io::Write;
+use std::iter;
+use std::mem;
+#[cfg(feature = "async")]
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io, thread};
+#[cfg(feature = "async")]
+use tokio::sync::{oneshot, watch, OwnedSemaphorePermit, Semaphore};
+#[cfg(feature = "async")]
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+#[cfg(feature = "async")]
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "async")]
+use tracing::Instrument;
+use tracing::{debug_span, error, field, info, info_span, trace, warn, Span};
+
+#[cfg(feature = "arrow")]
+mod arrow;
+#[cfg(feature = "async")]
+mod copy;
+mod diff;
+mod headers;
+mod long;
+mod lookup;
+mod manifest;
+#[cfg(feature = "parquet")]
+mod parquet;
+#[cfg(feature = "async")]
+mod partition;
+mod profile;
+mod resume;
+mod rollup;
+mod schema;
+#[cfg(feature = "async")]
+mod sql;
+#[cfg(test)]
+mod tests;
+#[cfg(feature = "xlsx")]
+mod xlsx;
+
+#[cfg(feature = "async")]
+pub use self::copy::{dump_copy, CopyFormat, ExportCopyOptions};
+pub use self::diff::dump_diff_csv;
+pub use self::headers::{CaseFold, HeaderSanitize};
+pub use self::long::ExportShape;
+pub use self::lookup::{dump_lookup, DuplicatePolicy, LookupFormat};
+#[cfg(feature = "async")]
+pub use self::partition::{dump_csv_partitioned, ExportPartitionOptions};
+pub use self::profile::dump_profile_csv;
+pub use self::rollup::{dump_rollup, Agg, RollupFormat};
+pub use self::schema::SchemaFormat;
+#[cfg(feature = "async")]
+pub use self::sql::{dump_sql, Dialect, ExportSqlOptions, SqlPreamble};
+
+#[cfg(feature = "arrow")]
+pub use self::arrow::{ExportArrowOptions, RecordBatches};
+
+#[cfg(feature = "parquet")]
+pub use self::parquet::{dump_parquet, ExportParquetOptions, ParquetCompression};
+#[cfg(feature = "xlsx")]
+pub use self::xlsx::{dump_xlsx, ExportXlsxOptions};
+
+/// Options shared by the export functions
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    order: IterOrder,
+    max_readers: Option<usize>,
+    max_concurrent: Option<usize>,
+    max_chunk_bytes: Option<usize>,
+    busy_timeout: Option<Duration>,
+    busy_retries: Option<usize>,
+    reader_mode: Option<OpenMode>,
+    /// kept negated so the default is ordered
+    unordered: bool,
+    overwrite: OverwriteMode,
+    strict: bool,
+    include_id: bool,
+    include_created_at: bool,
+    since: Option<i64>,
+    changed_since: Option<i64>,
+    exclude_columns: Vec<String>,
+    include_only: Option<Vec<String>>,
+    row_filter: Option<RowFilter>,
+    transforms: IndexMap<String, Transform>,
+    computed: IndexMap<String, ComputedFn>,
+    rename_headers: IndexMap<String, String>,
+    sanitize_headers: Option<HeaderSanitize>,
+    on_progress: Option<ProgressFn>,
+    #[cfg(feature = "async")]
+    cancel_token: Option<CancellationToken>,
+    /// set by the `_sync` exports, the chunks are read on threads of their own instead of
+    /// tokio tasks, always the case without the `async` feature
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    blocking: bool,
+    /// set by the SQLite exports, whose column names are case-insensitive, so headers only
+    /// differing in ASCII case collide, see [`ExportOptions::header_merge`]
+    fold_header_case: bool,
+    ids: Option<Vec<i64>>,
+    limit: Option<usize>,
+    offset: usize,
+    write_manifest: bool,
+    header_only: bool,
+    snapshot: bool,
+    /// items up to this one are skipped, set when resuming an export
+    after_id: Option<i64>,
+    shape: ExportShape,
+    max_columns: Option<usize>,
+    overflow_column: Option<String>,
+    key_prefix: Option<String>,
+    strip_prefix: bool,
+    checkpoint: Option<CheckpointMode>,
+    key_order: KeyOrder,
+    chunk: ChunkStrategy,
+    priority_columns: Vec<String>,
+}
+
+impl ExportOptions {
+    /// Order of the exported rows
+    pub fn order(mut self, order: IterOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Maximum number of read connections opened for the export,
+    /// defaults to the available parallelism
+    pub fn max_readers(mut self, max_readers: usize) -> Self {
+        self.max_readers = Some(max_readers.max(1));
+        self
+    }
+
+    /// Maximum number of chunks being read, or waiting to be written, at a time.
+    /// Bounds the memory used by the export, defaults to the available parallelism
+    pub fn max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = Some(max_concurrent.max(1));
+        self
+    }
+
+    /// How the items are split into chunks, read in parallel, by [`DEFAULT_CHUNK_SIZE`] items
+    /// by default. Not used by [`dump_db_attach`], which reads with a single statement
+    pub fn chunk(mut self, chunk: impl Into<ChunkStrategy>) -> Self {
+        self.chunk = chunk.into();
+        self
+    }
+
+    /// Keys exported first, in this order, the other keys following
+    pub fn priority_columns(mut self, columns: Vec<String>) -> Self {
+        self.priority_columns = columns;
+        self
+    }
+
+    /// Takes the chunks and the priority columns out of the options, for the export to
+    /// read the items by and select the columns from
+    fn take_chunk_and_priority(&mut self) -> (ChunkStrategy, Vec<String>) {
+        (self.chunk, mem::take(&mut self.priority_columns))
+    }
+
+    /// Caps the rows a worker holds to about `bytes`, estimated from the length of their
+    /// values. Once reached, the rows read so far are handed to the writer, and the worker
+    /// waits for them to be written before reading the rest of its chunk, so the export holds
+    /// about `max_concurrent * bytes` however large the chunks or the rows are. The rows are
+    /// the same, in the same order. Unset by default, a chunk is then handed over at once.
+    /// Not used by [`ExportShape::Long`], which hands over the rows by batches already
+    pub fn max_chunk_bytes(mut self, bytes: usize) -> Self {
+        self.max_chunk_bytes = Some(bytes.max(1));
+        self
+    }
+
+    /// How long the read connections of the export wait for a lock held by another
+    /// connection, defaults to the db's, see [`TableMapDb::busy_timeout`]
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    /// How many times a chunk is read again when the db stays locked past the busy timeout,
+    /// waiting longer before each try, before the export fails. Defaults to 3. Not done by
+    /// [`ExportShape::Long`] and [`dump_db_attach`], which read through the db's own
+    /// connection
+    pub fn busy_retries(mut self, retries: usize) -> Self {
+        self.busy_retries = Some(retries);
+        self
+    }
+
+    /// How the read connections of the export are opened, read-only by default, e.g.
+    /// immutable to skip the locking when exporting a db nothing writes to anymore, see
+    /// [`OpenMode`]. Not used by [`ExportShape::Long`] and [`dump_db_attach`], which read
+    /// through the db's own connection
+    pub fn reader_mode(mut self, mode: impl Into<OpenMode>) -> Self {
+        self.reader_mode = Some(mode.into());
+        self
+    }
+
+    /// Write the rows in the export order, the default. When disabled, each chunk is written as
+    /// soon as it is read, which is a bit faster, but the row order changes between exports
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.unordered = !ordered;
+        self
+    }
+
+    /// What to do if the output file exists, fails by default
+    pub fn overwrite(mut self, overwrite: OverwriteMode) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Abort the export on the first row that fails to be written. Otherwise, failed rows are
+    /// logged and counted in [`ExportSummary::rows_failed`]. Also fails the export with
+    /// [`DataToolErrors::UnknownColumns`] if some of the priority columns are neither stored
+    /// keys nor computed columns, which are otherwise exported empty with a warning
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Export the id of each item as the first column, named `_id`. Off by default
+    pub fn include_id(mut self, include_id: bool) -> Self {
+        self.include_id = include_id;
+        self
+    }
+
+    /// Export the time each item was created, in unix epoch milliseconds, as the first data
+    /// column, named `_created_at`. Empty for the items from before the db stored it. Off by
+    /// default
+    pub fn include_created_at(mut self, include_created_at: bool) -> Self {
+        self.include_created_at = include_created_at;
+        self
+    }
+
+    /// Only export the items created at or after `epoch_ms`, in unix epoch milliseconds, see
+    /// [`TableMapDb::items_since`]. Not supported by [`dump_db_attach`]
+    pub fn since(mut self, epoch_ms: i64) -> Self {
+        self.since = Some(epoch_ms);
+        self
+    }
+
+    /// Only export the items changed after `revision`, the [`ExportSummary::max_revision`]
+    /// of the previous export, to export again only what changed since. An item changes when
+    /// it is created and when any of its cells is stored, by an insert, an import or
+    /// [`TableMapDb::merge_from`]. Not supported by [`dump_db_attach`]
+    pub fn changed_since(mut self, revision: i64) -> Self {
+        self.changed_since = Some(revision);
+        self
+    }
+
+    /// Leave these columns out of the export, they are not even read. Excluding one of the
+    /// priority columns is an error
+    pub fn exclude_columns(mut self, columns: Vec<String>) -> Self {
+        self.exclude_columns = columns;
+        self
+    }
+
+    /// Export exactly these columns, in this order, even the ones without any data.
+    /// The priority columns must be part of the list
+    pub fn include_only(mut self, columns: Vec<String>) -> Self {
+        self.include_only = Some(columns);
+        self
+    }
+
+    /// Only export the rows for which the filter returns true. The filter gets all the stored
+    /// columns of the item, not just the exported ones, and runs on the export workers.
+    /// Not supported by [`dump_db_attach`]
+    pub fn row_filter(
+        mut self,
+        filter: impl Fn(&IndexMap<String, String>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.row_filter = Some(Hook(Arc::new(filter)));
+        self
+    }
+
+    /// Transform the non-empty values of the column before they are written, the stored data
+    /// is not changed. Not supported by [`dump_db_attach`]
+    pub fn transform(mut self, column: impl Into<String>, transform: Transform) -> Self {
+        self.transforms.insert(column.into(), transform);
+        self
+    }
+
+    /// Add a column computed from the stored columns of each item, it gets all of them, not
+    /// just the exported ones. Computed columns come after the stored ones, unless they are
+    /// in the priority columns, and take the place of a stored column with the same name.
+    /// They are `TEXT` in the SQLite exports, unless pinned. Not supported by [`dump_db_attach`]
+    pub fn computed_column(
+        mut self,
+        name: &str,
+        f: impl Fn(&IndexMap<String, String>) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.computed.insert(name.to_string(), Hook(Arc::new(f)));
+        self
+    }
+
+    /// Only export the keys starting with `prefix`, see [`TableMapDb::keys_with_prefix`].
+    /// Applies on top of the include and exclude lists, the priority columns must be
+    /// under the prefix, the computed ones need not. With `strip_prefix`, the prefix is
+    /// left out of the headers, unless the column is renamed
+    pub fn key_prefix(mut self, prefix: impl Into<String>, strip_prefix: bool) -> Self {
+        self.key_prefix = Some(prefix.into());
+        self.strip_prefix = strip_prefix;
+        self
+    }
+
+    /// Names to write in the header, or to give the table columns, instead of the keys.
+    /// Everything else, like the priority columns or the column types, still uses the keys
+    pub fn rename_headers(mut self, renames: IndexMap<String, String>) -> Self {
+        self.rename_headers = renames;
+        self
+    }
+
+    /// Trim and case fold the headers, after renaming them. Keys ending up with the same
+    /// header, e.g. `Price` and `price `, are merged into a single column instead of failing
+    /// the export, each row keeping the first or last non-empty value of the keys in the
+    /// column order, following `merge_duplicates`, see [`ExportSummary::merged_headers`].
+    /// The SQLite exports also merge headers only differing in ASCII case, as SQLite column
+    /// names are case-insensitive. The long shape only sanitizes the key column, its rows are
+    /// not merged
+    pub fn sanitize_headers(mut self, sanitize: HeaderSanitize) -> Self {
+        self.sanitize_headers = Some(sanitize);
+        self
+    }
+
+    /// Called with the progress of the export, after each chunk is written. The callback runs
+    /// on a task of its own and always gets the latest progress, so a slow callback skips
+    /// some of the updates instead of holding up the export.
+    /// The export returns once the callback got the final progress.
+    /// Not called by [`dump_db_attach`]
+    pub fn on_progress(mut self, f: impl Fn(ExportProgress) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(Hook(Arc::new(f)));
+        self
+    }
+
+    /// Cancelling the token stops the export, which then fails with
+    /// [`DataToolErrors::Cancelled`] and leaves no output file behind. The chunks being read
+    /// are interrupted in SQLite rather than read to the end, as is the single query of a
+    /// long export unless it is blocking. The key scan runs on the db's connection, see
+    /// [`TableMapDb::interrupt_handle`].
+    /// [`dump_db_attach`] only checks it before starting
+    #[cfg(feature = "async")]
+    pub fn cancel_token(mut self, token: CancellationToken) -> Self {
+        self.cancel_token = Some(token);
+        self
+    }
+
+    /// Once the export succeeded, write `<output>.manifest.json` next to the output file,
+    /// with the SHA-256 of the file, the same `sha256sum` gives, the rows written, the
+    /// columns, the number of items in the table map, when the export started and how long
+    /// it took. A CSV export split into files lists each of them.
+    /// The manifest is replaced when appending, and describes the whole file then.
+    ///
+    /// The file is hashed as it is written, except for the SQLite exports, which are hashed
+    /// once SQLite closed them. Only written by [`dump_csv`], [`dump_db`],
+    /// [`dump_db_attach`], [`dump_jsonl`] and [`dump_json`]
+    pub fn write_manifest(mut self, write_manifest: bool) -> Self {
+        self.write_manifest = write_manifest;
+        self
+    }
+
+    /// Dry run of the CSV exports, only the header is written and no item is read. The
+    /// summary has the columns and no rows. Off by default
+    pub fn header_only(mut self, header_only: bool) -> Self {
+        self.header_only = header_only;
+        self
+    }
+
+    /// Only export the items and cells stored when the export starts, the same as
+    /// [`TableMapDb::snapshot_rows`], so the rows do not depend on what another connection
+    /// inserts while the chunks are read. [`dump_db_attach`] reads with a single statement,
+    /// which always sees a consistent state of the db. Off by default
+    pub fn snapshot(mut self, snapshot: bool) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    /// Shape of the exported rows, a row per item by default. With [`ExportShape::Long`]
+    /// there is a row per stored cell instead, with the columns `item_id`, `item_val`, `key`
+    /// and `value`, read with a single query, without looking up the distinct keys first.
+    /// The column order and [`ExportOptions::include_id`] are ignored then, the other options
+    /// apply to the keys, e.g. the renames to the `key` column.
+    /// Only supported by [`dump_csv`], [`dump_csv_writer`], [`dump_csv_partitioned`] and
+    /// [`dump_db`]
+    pub fn shape(mut self, shape: ExportShape) -> Self {
+        self.shape = shape;
+        self
+    }
+
+    /// Order of the exported columns after the priority ones, the order the keys were first
+    /// inserted by default. Not used with [`ExportOptions::include_only`], whose order is
+    /// kept
+    pub fn key_order(mut self, key_order: KeyOrder) -> Self {
+        self.key_order = key_order;
+        self
+    }
+
+    /// Checkpoint the WAL before opening the read connections, see
+    /// [`TableMapDb::checkpoint`], so they do not go through a large WAL for every read.
+    /// Not done by default. Not done by [`dump_db_attach`], which reads through the db's
+    /// own connection
+    pub fn checkpoint(mut self, mode: CheckpointMode) -> Self {
+        self.checkpoint = Some(mode);
+        self
+    }
+
+    /// Export at most this many of the columns, the ones most items have, see
+    /// [`TableMapDb::key_counts`]. The priority and computed columns are always exported,
+    /// and count among them. The other keys are left out, listed in
+    /// [`ExportSummary::spilled_keys`], or packed into `overflow_column` if given, as a
+    /// JSON object of the item's left out keys and values, empty if it has none.
+    /// The overflow column comes last, and does not count among the columns.
+    /// Not supported by [`dump_db_attach`], ignored by [`ExportShape::Long`]
+    pub fn max_columns(mut self, max_columns: usize, overflow_column: Option<String>) -> Self {
+        self.max_columns = Some(max_columns);
+        self.overflow_column = overflow_column;
+        self
+    }
+
+    fn check_cancelled(&self) -> Result<(), DataToolErrors> {
+        #[cfg(feature = "async")]
+        if self.cancel_token.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Err(DataToolErrors::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Calls `interrupt` from a task of its own once the export is cancelled, so the
+    /// statements it interrupts stop while the export is blocked in them, until the
+    /// returned watch is dropped. `None` without a token and for the blocking exports, which
+    /// check the token themselves
+    #[cfg(feature = "async")]
+    fn on_cancel(&self, interrupt: impl FnOnce() + Send + 'static) -> Option<CancelWatch> {
+        let token = self.cancel_token.clone()?;
+        if self.blocking() {
+            return None;
+        }
+        Some(CancelWatch(tokio::spawn(async move {
+            token.cancelled().await;
+            interrupt();
+        })))
+    }
+
+    /// Resolves once the export is cancelled, never if it has no token
+    #[cfg(feature = "async")]
+    async fn cancelled(&self) {
+        match &self.cancel_token {
+            Some(token) => token.cancelled().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Export only these items, in this order instead of the export order. Ids of items that
+    /// do not exist are counted in [`ExportSummary::ids_not_found`].
+    /// Not supported by [`dump_db_attach`]
+    pub fn ids(mut self, ids: Vec<i64>) -> Self {
+        self.ids = Some(ids);
+        self
+    }
+
+    /// Export at most this many items. Not supported by [`dump_db_attach`]
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skip this many items, in the export order, before exporting any.
+    /// Not supported by [`dump_db_attach`]
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Fails for the options only [`dump_db`] and [`dump_csv`] support
+    fn check_attach(&self) -> Result<(), DataToolErrors> {
+        let unsupported = if self.row_filter.is_some() {
+            "row filters"
+        } else if !self.transforms.is_empty() {
+            "transforms"
+        } else if !self.computed.is_empty() {
+            "computed columns"
+        } else if self.ids.is_some()
+            || self.since.is_some()
+            || self.changed_since.is_some()
+            || self.limit.is_some()
+            || self.offset > 0
+        {
+            "exporting a subset of the items"
+        } else if self.max_columns.is_some() {
+            "capping the columns"
+        } else {
+            return Ok(());
+        };
+        Err(DataToolErrors::InvalidArgument(format!(
+            "dump_db_attach does not support {}, use dump_db instead",
+            unsupported
+        )))
+    }
+
+    /// Fails for a long export, which `export` does not support
+    fn check_wide(&self, export: &str) -> Result<(), DataToolErrors> {
+        match self.shape {
+            ExportShape::Wide => Ok(()),
+            ExportShape::Long => Err(DataToolErrors::InvalidArgument(format!(
+                "{} does not support the long shape, use dump_csv or dump_db instead",
+                export
+            ))),
+        }
+    }
+
+    /// The exported data columns, after applying the include and exclude lists
+    fn select_columns(
+        &self,
+        db: &TableMapDb,
+        priority_cols: Vec<String>,
+    ) -> Result<Vec<String>, DataToolErrors> {
+        if let Some(c) = priority_cols
+            .iter()
+            .find(|c| self.exclude_columns.contains(c))
+        {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "priority column {:?} is excluded from the export",
+                c
+            )));
+        }
+        if let Some(c) = priority_cols.iter().find(|c| !self.in_namespace(c)) {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "priority column {:?} does not start with the key prefix {:?}",
+                c,
+                self.key_prefix.as_deref().unwrap_or_default()
+            )));
+        }
+        let mut unknown = db.unknown_keys(&priority_cols);
+        unknown.retain(|c| !self.computed.contains_key(c));
+        if !unknown.is_empty() {
+            if self.strict {
+                return Err(DataToolErrors::UnknownColumns(unknown));
+            }
+            warn!(
+                "Priority columns {:?} are not stored keys, exported empty",
+                unknown
+            );
+        }
+        let mut columns = match &self.include_only {
+            Some(only) => {
+                if let Some(c) = priority_cols.iter().find(|c| !only.contains(c)) {
+                    return Err(DataToolErrors::InvalidArgument(format!(
+                        "priority column {:?} is not one of the included columns",
+                        c
+                    )));
+                }
+                only.clone()
+            }
+            None => {
+                let keys = match &self.key_prefix {
+                    Some(prefix) => db.keys_with_prefix(prefix)?,
+                    None => db.columns(),
+                };
+                db.order_keys(priority_cols.clone(), keys, &self.key_order)?
+            }
+        };
+        columns.retain(|c| self.in_namespace(c));
+        for name in self.computed.keys() {
+            if !columns.contains(name) {
+                columns.push(name.clone());
+            }
+        }
+        columns.retain(|c| !self.exclude_columns.contains(c));
+        let mut columns = self.cap_columns(db, &priority_cols, columns)?;
+        if self.include_created_at {
+            if columns.iter().any(|c| c == CREATED_AT_COLUMN) {
+                return Err(DataToolErrors::InvalidArgument(format!(
+                    "can not include the creation time, there is already a {:?} column",
+                    CREATED_AT_COLUMN
+                )));
+            }
+            columns.insert(0, CREATED_AT_COLUMN.to_string());
+        }
+        Ok(columns)
+    }
+
+    /// The ids to export, `ids` or all of them in the export order, left to the ones created
+    /// since [`ExportOptions::since`] and changed since [`ExportOptions::changed_since`]
+    fn filter_ids(
+        &self,
+        db: &TableMapDb,
+        ids: Option<Vec<i64>>,
+    ) -> Result<Option<Vec<i64>>, DataToolErrors> {
+        let Some(kept) = self.kept_ids(db)? else {
+            return Ok(ids);
+        };
+        let ids = match ids {
+            Some(ids) => ids,
+            None => self.order.item_ids(&db.connection, &db.tables)?,
+        };
+        Ok(Some(
+            ids.into_iter().filter(|id| kept.contains(id)).collect(),
+        ))
+    }
+
+    /// The items [`ExportOptions::since`] and [`ExportOptions::changed_since`] keep, `None`
+    /// if neither is set
+    fn kept_ids(&self, db: &TableMapDb) -> Result<Option<HashSet<i64>>, DataToolErrors> {
+        let created = match self.since {
+            Some(since) => Some(db.items_since(since)?),
+            None => None,
+        };
+        let changed = match self.changed_since {
+            Some(revision) => Some(db.items_changed_since(revision)?),
+            None => None,
+        };
+        Ok(match (created, changed) {
+            (Some(created), Some(changed)) => {
+                let changed: HashSet<i64> = changed.into_iter().collect();
+                Some(
+                    created
+                        .into_iter()
+                        .filter(|id| changed.contains(id))
+                        .collect(),
+                )
+            }
+            (kept, None) | (None, kept) => kept.map(|ids| ids.into_iter().collect()),
+        })
+    }
+
+    /// Keeps at most [`ExportOptions::max_columns`] of the columns, in the same order, and
+    /// adds the overflow column
+    fn cap_columns(
+        &self,
+        db: &TableMapDb,
+        priority_cols: &[String],
+        columns: Vec<String>,
+    ) -> Result<Vec<String>, DataToolErrors> {
+        let Some(max) = self.max_columns else {
+            return Ok(columns);
+        };
+        if let Some(name) = self
+            .overflow_column
+            .as_ref()
+            .filter(|n| columns.contains(n))
+        {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "overflow column {:?} is one of the exported columns",
+                name
+            )));
+        }
+        let always = |c: &String| priority_cols.contains(c) || self.computed.contains_key(c);
+        let mut capped = columns;
+        let slots = max.saturating_sub(capped.iter().filter(|c| always(c)).count());
+        if capped.len() > max {
+            let counts: HashMap<String, usize> = db.key_counts()?.into_iter().collect();
+            let mut rest: Vec<_> = capped.iter().filter(|c| !always(c)).collect();
+            // stable, the columns as frequent stay in the column order
+            rest.sort_by_key(|c| std::cmp::Reverse(counts.get(*c).copied().unwrap_or(0)));
+            let kept: HashSet<String> = rest.into_iter().take(slots).cloned().collect();
+            capped.retain(|c| always(c) || kept.contains(c));
+        }
+        capped.extend(self.overflow_column.clone());
+        Ok(capped)
+    }
+
+    /// Keys left out by [`ExportOptions::max_columns`] from the exported `columns`
+    fn spilled_keys(
+        &self,
+        db: &TableMapDb,
+        columns: &[String],
+    ) -> Result<Vec<String>, DataToolErrors> {
+        if self.max_columns.is_none() {
+            return Ok(vec![]);
+        }
+        let exported: HashSet<&String> = columns.iter().collect();
+        let keys = match &self.include_only {
+            Some(only) => only.clone(),
+            None => db.get_distinct_keys(vec![])?,
+        };
+        Ok(keys
+            .into_iter()
+            .filter(|k| {
+                !exported.contains(k)
+                    && self.in_namespace(k)
+                    && !self.exclude_columns.contains(k)
+                    && !self.computed.contains_key(k)
+            })
+            .collect())
+    }
+
+    /// Whether only some of the stored columns are exported
+    fn filters_columns(&self) -> bool {
+        self.include_only.is_some() || !self.exclude_columns.is_empty() || self.key_prefix.is_some()
+    }
+
+    /// Whether the key starts with [`ExportOptions::key_prefix`], if set
+    fn in_namespace(&self, key: &str) -> bool {
+        self.key_prefix
+            .as_ref()
+            .is_none_or(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    /// Name of the column in the output, renamed, or without the key prefix if stripped,
+    /// then sanitized
+    fn header<'a>(&'a self, column: &'a str) -> Cow<'a, str> {
+        let name = match (
+            self.rename_headers.get(column),
+            self.key_prefix.as_deref().filter(|_| self.strip_prefix),
+        ) {
+            (Some(name), _) => name,
+            (None, Some(prefix)) => column.strip_prefix(prefix).unwrap_or(column),
+            (None, None) => column,
+        };
+        match &self.sanitize_headers {
+            Some(s) => s.apply(name),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    /// Columns written by the export, renamed, the id column first if included. The
+    /// columns merged by [`ExportOptions::sanitize_headers`] are written once
+    fn output_columns(&self, columns: &[String]) -> Result<Vec<String>, DataToolErrors> {
+        let merge = self.header_merge(columns)?;
+        let renamed = merge.headers().map(String::from);
+        if !self.include_id {
+            return Ok(renamed.collect());
+        }
+        if merge.headers().any(|h| match self.fold_header_case {
+            true => h.eq_ignore_ascii_case(ID_COLUMN),
+            false => h == ID_COLUMN,
+        }) {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "can not include the item id, there is already a {:?} column",
+                ID_COLUMN
+            )));
+        }
+        Ok(iter::once(ID_COLUMN.to_string()).chain(renamed).collect())
+    }
+
+    fn readers(&self) -> usize {
+        self.max_readers.unwrap_or_else(available_parallelism)
+    }
+
+    fn concurrency(&self) -> usize {
+        self.max_concurrent.unwrap_or_else(available_parallelism)
+    }
+
+    fn retries(&self) -> usize {
+        self.busy_retries.unwrap_or(DEFAULT_BUSY_RETRIES)
+    }
+
+    /// Whether the chunks are read on threads instead of tokio tasks
+    #[cfg(feature = "async")]
+    fn blocking(&self) -> bool {
+        self.blocking
+    }
+}
+
+/// Task interrupting the statements of an export once cancelled, stopped on drop, see
+/// [`ExportOptions::on_cancel`]
+#[cfg(feature = "async")]
+struct CancelWatch(JoinHandle<()>);
+
+#[cfg(feature = "async")]
+impl Drop for CancelWatch {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// A callback set in the export options, shared by the export workers
+struct Hook<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Hook<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+type RowFilter = Hook<dyn Fn(&IndexMap<String, String>) -> bool + Send + Sync>;
+
+type TransformFn = Hook<dyn Fn(&str) -> String + Send + Sync>;
+
+type ComputedFn = Hook<dyn Fn(&IndexMap<String, String>) -> String + Send + Sync>;
+
+type ProgressFn = Hook<dyn Fn(ExportProgress) + Send + Sync>;
+
+type ValuePredicate = Hook<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Progress of an export, see [`ExportOptions::on_progress`]
+#[derive(Debug, Clone, Default)]
+pub struct ExportProgress {
+    pub chunks_done: usize,
+    /// only known when chunking by item count
+    pub chunks_total: Option<usize>,
+    /// rows handed to the writer so far, including the ones that failed to be written
+    pub rows_written: usize,
+    pub elapsed: Duration,
+}
+
+/// Sends the progress to the `on_progress` callback, which is called on a task of its own,
+/// or right away by the blocking exports
+struct ProgressReporter {
+    progress: ExportProgress,
+    started: Instant,
+    sink: Option<ProgressSink>,
+}
+
+enum ProgressSink {
+    #[cfg(feature = "async")]
+    Task {
+        tx: watch::Sender<ExportProgress>,
+        task: JoinHandle<()>,
+    },
+    Inline(ProgressFn),
+}
+
+impl ProgressReporter {
+    fn new(options: &ExportOptions, chunks_total: Option<usize>) -> Self {
+        let progress = ExportProgress {
+            chunks_total,
+            ..Default::default()
+        };
+        let sink = options.on_progress.clone().map(|f| {
+            #[cfg(feature = "async")]
+            if !options.blocking() {
+                return ProgressSink::spawn(f, progress.clone());
+            }
+            ProgressSink::Inline(f)
+        });
+        Self {
+            progress,
+            started: Instant::now(),
+            sink,
+        }
+    }
+
+    /// Counts `rows` written, the last ones of their chunk if `chunk_done`
+    fn chunk_written(&mut self, rows: usize, chunk_done: bool) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        self.progress.chunks_done += chunk_done as usize;
+        self.progress.rows_written += rows;
+        self.progress.elapsed = self.started.elapsed();
+        match sink {
+            // fails only if the callback failed
+            #[cfg(feature = "async")]
+            ProgressSink::Task { tx, .. } => {
+                let _ = tx.send(self.progress.clone());
+            }
+            ProgressSink::Inline(f) => (f.0)(self.progress.clone()),
+        }
+    }
+
+    /// Waits for the callback to get the final progress
+    #[cfg(feature = "async")]
+    async fn finish(self) {
+        if let Some(ProgressSink::Task { tx, task }) = self.sink {
+            drop(tx);
+            let _ = task.await;
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl ProgressSink {
+    fn spawn(f: ProgressFn, progress: ExportProgress) -> Self {
+        let (tx, mut rx) = watch::channel(progress);
+        let task = tokio::spawn(async move {
+            // returns the latest value even if the sender is already dropped
+            while rx.changed().await.is_ok() {
+                let p = rx.borrow_and_update().clone();
+                // on the blocking pool, so a slow callback does not take a runtime thread
+                let f = f.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || (f.0)(p)).await {
+                    error!("progress callback failed: {}", e);
+                    break;
+                }
+            }
+        });
+        ProgressSink::Task { tx, task }
+    }
+}
+
+/// Changes the exported values of a column, see [`ExportOptions::transform`]
+#[derive(Clone)]
+pub enum Transform {
+    /// keep at most this many characters
+    Truncate(usize),
+    /// replace the value with `***`
+    Redact,
+    /// replace all the matches of the pattern, `$1` etc. in the replacement refer to the
+    /// capture groups. The pattern is checked when the export starts
+    Regex(String, String),
+    /// any other change
+    Custom(Arc<dyn Fn(&str) -> String + Send + Sync>),
+}
+
+impl fmt::Debug for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transform::Truncate(n) => f.debug_tuple("Truncate").field(n).finish(),
+            Transform::Redact => f.write_str("Redact"),
+            Transform::Regex(p, r) => f.debug_tuple("Regex").field(p).field(r).finish(),
+            Transform::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl Transform {
+    fn compile(&self) -> Result<TransformFn, DataToolErrors> {
+        let f: Arc<dyn Fn(&str) -> String + Send + Sync> = match self.clone() {
+            Transform::Truncate(n) => Arc::new(move |v| v.chars().take(n).collect()),
+            Transform::Redact => Arc::new(|_| "***".to_string()),
+            Transform::Regex(pattern, replacement) => {
+                let re = Regex::new(&pattern).map_err(|e| {
+                    DataToolErrors::InvalidArgument(format!("invalid pattern {:?}: {}", pattern, e))
+                })?;
+                Arc::new(move |v| re.replace_all(v, replacement.as_str()).into_owned())
+            }
+            Transform::Custom(f) => f,
+        };
+        Ok(Hook(f))
+    }
+}
+
+/// Column holding the item id, see [`ExportOptions::include_id`]
+const ID_COLUMN: &str = "_id";
+
+/// Column holding the creation time of the item, see [`ExportOptions::include_created_at`]
+const CREATED_AT_COLUMN: &str = "_created_at";
+
+/// Times a chunk is read again on a locked db, see [`ExportOptions::busy_retries`]
+const DEFAULT_BUSY_RETRIES: usize = 3;
+
+/// Wait before reading a chunk again on a locked db, doubled on each try
+const BUSY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Items read for the first part of a chunk with [`ExportOptions::max_chunk_bytes`], the
+/// size of the next ones is then estimated from their rows
+const FIRST_PART_ITEMS: usize = 64;
+
+/// How often a blocking export waiting for its readers checks whether it is cancelled
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+fn available_parallelism() -> usize {
+    std::thread::available_parallelism()
+        .map(|v| v.get())
+        .unwrap_or(8)
+}
+
+/// Read-only connections reused by the export workers, at most `max_readers` are ever opened,
+/// however many chunks there are.
+struct ReaderPool {
+    db_file: PathBuf,
+    mode: OpenMode,
+    busy_timeout: Duration,
+    statement_cache_capacity: usize,
+    idle: Mutex<Vec<Connection>>,
+    /// of every connection opened, see [`ReaderPool::interrupt`]
+    interrupts: Mutex<Vec<InterruptHandle>>,
+    #[cfg(feature = "async")]
+    permits: Arc<Semaphore>,
+}
+
+impl ReaderPool {
+    fn new(
+        db_file: PathBuf,
+        mode: OpenMode,
+        max_readers: usize,
+        busy_timeout: Duration,
+        statement_cache_capacity: usize,
+    ) -> Arc<Self> {
+        // the blocking exports run a thread per reader instead
+        #[cfg(not(feature = "async"))]
+        let _ = max_readers;
+        Arc::new(Self {
+            db_file,
+            mode,
+            busy_timeout,
+            statement_cache_capacity,
+            idle: Mutex::new(vec![]),
+            interrupts: Mutex::new(vec![]),
+            #[cfg(feature = "async")]
+            permits: Arc::new(Semaphore::new(max_readers)),
+        })
+    }
+
+    /// Takes an idle connection, or opens a new one if there is none
+    fn take(&self) -> rusqlite::Result<Connection> {
+        if let Some(conn) = self.idle.lock().unwrap().pop() {
+            return Ok(conn);
+        }
+        trace!("opening reader connection");
+        let conn = open_connection(&self.db_file, self.mode)?;
+        conn.busy_timeout(self.busy_timeout)?;
+        conn.set_prepared_statement_cache_capacity(self.statement_cache_capacity);
+        if let Ok(mut interrupts) = self.interrupts.lock() {
+            interrupts.push(conn.get_interrupt_handle());
+        }
+        Ok(conn)
+    }
+
+    /// Interrupts the statements running on the connections, which then fail with
+    /// [`DataToolErrors::Cancelled`]
+    fn interrupt(&self) {
+        if let Ok(interrupts) = self.interrupts.lock() {
+            interrupts.iter().for_each(InterruptHandle::interrupt);
+        }
+    }
+
+    /// Waits for a free connection, opening a new one if none of the open ones are idle
+    #[cfg(feature = "async")]
+    async fn get(self: &Arc<Self>) -> rusqlite::Result<PooledConn> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        Ok(PooledConn {
+            conn: Some(self.take()?),
+            pool: self.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+/// Connection borrowed from the [`ReaderPool`], returned to it on drop
+#[cfg(feature = "async")]
+struct PooledConn {
+    conn: Option<Connection>,
+    pool: Arc<ReaderPool>,
+    _permit: OwnedSemaphorePermit,
+}
+
+#[cfg(feature = "async")]
+impl Deref for PooledConn {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        if let (Some(conn), Ok(mut idle)) = (self.conn.take(), self.pool.idle.lock()) {
+            idle.push(conn);
+        }
+    }
+}
+
+/// Span of an export, the counts are recorded on it once done, see [`ExportSummary::report`]
+macro_rules! export_span {
+    ($name:literal, $file_name:expr) => {
+        info_span!(
+            $name,
+            output = %$file_name.display(),
+            rows = field::Empty,
+            rows_failed = field::Empty,
+            chunks = field::Empty,
+            keys_scan_ms = field::Empty,
+            id_scan_ms = field::Empty,
+            read_ms = field::Empty,
+            read_wall_ms = field::Empty,
+            write_ms = field::Empty,
+        )
+    };
+}
+
+#[cfg(feature = "async")]
+pub async fn dump_csv(
+    db: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    csv_options: ExportCsvOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let span = export_span!("dump_csv", file_name);
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    async {
+        let files = CsvFiles::new(file_name, &mut options, &csv_options)?;
+        write_csv(db, files, chunk, column_order, options, csv_options).await
+    }
+    .instrument(span)
+    .await
+}
+
+/// Same as [`dump_csv`], without tokio, e.g. for a program that has no runtime. The chunks
+/// are read on [`ExportOptions::max_readers`] threads of their own, `1` reading them one
+/// after the other, and the progress callback is called right away. The file is the same as
+/// the one [`dump_csv`] writes, byte for byte, unless the export is unordered
+pub fn dump_csv_sync(
+    db: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    csv_options: ExportCsvOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let _span = export_span!("dump_csv_sync", file_name).entered();
+    options.blocking = true;
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    let files = CsvFiles::new(file_name, &mut options, &csv_options)?;
+    write_csv_blocking(db, files, chunk, column_order, options, csv_options)
+}
+
+/// Where the CSV of an export goes, opened by [`CsvExport`] and moved in place once the
+/// export is done
+trait CsvOutput {
+    type W: io::Write + Send;
+
+    /// The next file, the first one once the export starts
+    fn open(&mut self) -> Result<CsvFile<Self::W>, DataToolErrors>;
+
+    /// Moves the written files in place, and writes the manifest
+    fn commit(self, db: &TableMapDb, summary: &ExportSummary) -> Result<(), DataToolErrors>;
+}
+
+/// The files of [`dump_csv`] and [`dump_csv_sync`]
+enum CsvFiles {
+    /// a single file, opened before the export starts
+    Single {
+        file: Option<Box<CsvFile<Hashed<fs::File>>>>,
+        target: TempTarget,
+        manifest: Option<Manifest>,
+        /// removed once the export is done, if resumable
+        checkpoint: Option<PathBuf>,
+    },
+    /// files of at most [`ExportCsvOptions::max_rows_per_file`] rows, only moved in place
+    /// once all of them are written
+    Split {
+        file_name: PathBuf,
+        mode: OverwriteMode,
+        compression: Compression,
+        manifest: Option<Manifest>,
+        targets: Vec<TempTarget>,
+    },
+}
+
+impl CsvFiles {
+    /// The files of the export to `file_name`. Resuming an export sets where the options
+    /// start from
+    fn new(
+        file_name: &Path,
+        options: &mut ExportOptions,
+        csv_options: &ExportCsvOptions,
+    ) -> Result<Self, DataToolErrors> {
+        let compression = csv_options.compression.resolve(file_name)?;
+        if csv_options.resume {
+            return resume::resumable_files(file_name, compression, options, csv_options);
+        }
+        if csv_options.max_rows_per_file.is_some() {
+            if options.overwrite == OverwriteMode::Append {
+                return Err(DataToolErrors::InvalidArgument(
+                    "can not append to a CSV export split into files".to_string(),
+                ));
+            }
+            return Ok(Self::Split {
+                file_name: file_name.to_path_buf(),
+                mode: options.overwrite,
+                compression,
+                manifest: Manifest::new(file_name, options)?,
+                targets: vec![],
+            });
+        }
+        let mut manifest = Manifest::new(file_name, options)?;
+        let target = TempTarget::new(file_name, options.overwrite)?;
+        let sum = manifest.as_mut().map(|m| m.add_file(file_name));
+        if let Some(sum) = sum.as_ref().filter(|_| target.append) {
+            sum.update_file(target.path())?;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(target.path())?;
+        // appending to a file that already has rows, so it also has the header. Compressed
+        // exports are appended as a new gzip member or zstd frame, which readers concatenate
+        let has_header = target.append && file.metadata()?.len() > 0;
+        Ok(Self::Single {
+            file: Some(Box::new(CsvFile {
+                sink: CsvSink::new(Hashed::new(file, sum), compression)?,
+                write_header: !has_header,
+                path: None,
+                checkpoint: None,
+            })),
+            target,
+            manifest,
+            checkpoint: None,
+        })
+    }
+}
+
+impl CsvOutput for CsvFiles {
+    type W = Hashed<fs::File>;
+
+    fn open(&mut self) -> Result<CsvFile<Self::W>, DataToolErrors> {
+        match self {
+            Self::Single { file, .. } => Ok(*file.take().expect("a single file is opened")),
+            Self::Split {
+                file_name,
+                mode,
+                compression,
+                manifest,
+                targets,
+            } => {
+                let path = part_file_name(file_name, targets.len() + 1);
+                let target = TempTarget::new(&path, *mode)?;
+                let file = fs::File::create(target.path())?;
+                targets.push(target);
+                let sum = manifest.as_mut().map(|m| m.add_file(&path));
+                Ok(CsvFile {
+                    sink: CsvSink::new(Hashed::new(file, sum), *compression)?,
+                    write_header: true,
+                    path: Some(path),
+                    checkpoint: None,
+                })
+            }
+        }
+    }
+
+    fn commit(self, db: &TableMapDb, summary: &ExportSummary) -> Result<(), DataToolErrors> {
+        let manifest = match self {
+            Self::Single {
+                target,
+                manifest,
+                checkpoint,
+                ..
+            } => {
+                target.commit()?;
+                if let Some(checkpoint) = checkpoint.filter(|c| c.exists()) {
+                    fs::remove_file(checkpoint)?;
+                }
+                manifest
+            }
+            Self::Split {
+                file_name,
+                mode,
+                manifest,
+                targets,
+                ..
+            } => {
+                for target in targets {
+                    target.commit()?;
+                }
+                if mode == OverwriteMode::Overwrite {
+                    // parts left by an earlier export of more rows would look like part of
+                    // this one
+                    for part in summary.files.len() + 1.. {
+                        let path = part_file_name(&file_name, part);
+                        if !path.exists() {
+                            break;
+                        }
+                        warn!("Removing {:?}, a part of an earlier export", path);
+                        fs::remove_file(path)?;
+                    }
+                }
+                manifest
+            }
+        };
+        if let Some(manifest) = manifest {
+            manifest.write(db, summary)?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of a part of a split CSV export, the part number goes before the extension,
+/// `data.csv` is split into `data.part0001.csv`, `data.part0002.csv`, ...
+/// and `data.csv.gz` into `data.part0001.csv.gz`, ...
+fn part_file_name(file_name: &Path, part: usize) -> PathBuf {
+    let mut stem = file_name.to_path_buf();
+    let mut extensions = vec![];
+    while let Some(ext) = stem.extension().map(|e| e.to_os_string()) {
+        let compressed = ext == "gz" || ext == "zst";
+        extensions.insert(0, ext);
+        stem.set_extension("");
+        if !compressed {
+            break;
+        }
+    }
+    let mut name = stem.into_os_string();
+    name.push(format!(".part{:04}", part));
+    for ext in extensions {
+        name.push(".");
+        name.push(ext);
+    }
+    PathBuf::from(name)
+}
+
+/// The writer of [`dump_csv_writer`], which has nothing to move in place
+#[cfg(feature = "async")]
+struct CsvWriter<W: io::Write>(Option<CsvFile<W>>);
+
+#[cfg(feature = "async")]
+impl<W: io::Write + Send> CsvOutput for CsvWriter<W> {
+    type W = W;
+
+    fn open(&mut self) -> Result<CsvFile<W>, DataToolErrors> {
+        Ok(self.0.take().expect("a single file is opened"))
+    }
+
+    fn commit(self, _: &TableMapDb, _: &ExportSummary) -> Result<(), DataToolErrors> {
+        Ok(())
+    }
+}
+
+/// Same as [`dump_csv`], but writes the CSV to `writer`, which can be anything from stdout
+/// to an in-memory buffer. The header is always written. There is no file extension to pick
+/// the compression from, so the data is only compressed if a compression is set explicitly.
+/// Can not be split into files, [`ExportCsvOptions::max_rows_per_file`] is rejected.
+/// The writer is flushed once done, even if the export failed
+#[cfg(feature = "async")]
+pub async fn dump_csv_writer<W: io::Write + Send>(
+    db: &mut TableMapDb,
+    writer: W,
+    mut options: ExportOptions,
+    csv_options: ExportCsvOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    if csv_options.max_rows_per_file.is_some() {
+        return Err(DataToolErrors::InvalidArgument(
+            "can not split an export to a writer into files".to_string(),
+        ));
+    }
+    let compression = match csv_options.compression {
+        Compression::Auto => Compression::None,
+        c => c,
+    };
+    let writer = CsvWriter(Some(CsvFile {
+        sink: CsvSink::new(writer, compression)?,
+        write_header: true,
+        path: None,
+        checkpoint: None,
+    }));
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    write_csv(db, writer, chunk, column_order, options, csv_options).await
+}
+
+/// A file of a CSV export, as opened by [`CsvExport`]
+struct CsvFile<W: io::Write> {
+    sink: CsvSink<W>,
+    /// false when appending to a file that already has the header
+    write_header: bool,
+    /// listed in [`ExportSummary::files`], if set
+    path: Option<PathBuf>,
+    /// advanced once each chunk is written, if resumable
+    checkpoint: Option<resume::Checkpoint>,
+}
+
+/// Writes the CSV to the files of `output`, reading the rows with [`export_rows`]
+#[cfg(feature = "async")]
+async fn write_csv<O: CsvOutput>(
+    db: &mut TableMapDb,
+    output: O,
+    chunk: ChunkStrategy,
+    column_order: Vec<String>,
+    mut options: ExportOptions,
+    csv_options: ExportCsvOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let (mut export, columns) =
+        match CsvExport::start(db, output, chunk, column_order, &mut options, csv_options)? {
+            CsvStart::Rows(export, columns) => (export, columns),
+            CsvStart::Done(summary) => return Ok(*summary),
+        };
+    let res = export_rows(db, &options, chunk, columns, |n| export.write(&options, n)).await;
+    export.finish(db, res)
+}
+
+/// Same as [`write_csv`], reading the rows with [`export_rows_blocking`]
+fn write_csv_blocking<O: CsvOutput>(
+    db: &mut TableMapDb,
+    output: O,
+    chunk: ChunkStrategy,
+    column_order: Vec<String>,
+    mut options: ExportOptions,
+    csv_options: ExportCsvOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let (mut export, columns) =
+        match CsvExport::start(db, output, chunk, column_order, &mut options, csv_options)? {
+            CsvStart::Rows(export, columns) => (export, columns),
+            CsvStart::Done(summary) => return Ok(*summary),
+        };
+    let res = export_rows_blocking(db, &options, chunk, columns, |n| export.write(&options, n));
+    export.finish(db, res)
+}
+
+/// A started CSV export, see [`CsvExport::start`]
+enum CsvStart<O: CsvOutput> {
+    /// the rows of the columns are to be written
+    Rows(Box<CsvExport<O>>, Vec<String>),
+    /// there are no rows to write, the files are in place
+    Done(Box<ExportSummary>),
+}
+
+/// A CSV export, once its header is written, up to its last row, shared by [`write_csv`] and
+/// [`write_csv_blocking`]. A new file is opened each time
+/// [`ExportCsvOptions::max_rows_per_file`] rows are written to the current one
+struct CsvExport<O: CsvOutput> {
+    output: O,
+    csv_options: ExportCsvOptions,
+    header: Vec<String>,
+    csv_writer: csv::Writer<CsvSink<O::W>>,
+    /// of the current file
+    path: Option<PathBuf>,
+    checkpoint: Option<resume::Checkpoint>,
+    summary: ExportSummary,
+    /// rows written before the current file was opened
+    file_start: usize,
+    keys_scan: Duration,
+    started: Instant,
+}
+
+impl<O: CsvOutput> CsvExport<O> {
+    /// Opens the first file and writes the header. Done right away if there are no columns
+    /// or only the header is exported
+    fn start(
+        db: &TableMapDb,
+        mut output: O,
+        chunk: ChunkStrategy,
+        column_order: Vec<String>,
+        options: &mut ExportOptions,
+        csv_options: ExportCsvOptions,
+    ) -> Result<CsvStart<O>, DataToolErrors> {
+        let t = Instant::now();
+        chunk.validate()?;
+        csv_options.validate()?;
+        let mut keys_scan = Duration::ZERO;
+        let (columns, header) = match options.shape {
+            ExportShape::Wide => {
+                let scan = Instant::now();
+                let columns = options.select_columns(db, column_order)?;
+                keys_scan = scan.elapsed();
+                let header = if columns.is_empty() {
+                    None
+                } else {
+                    Some(options.output_columns(&columns)?)
+                };
+                (columns, header)
+            }
+            ExportShape::Long => {
+                // the item id is already one of the columns
+                options.include_id = false;
+                (vec![], Some(long::LONG_COLUMNS.map(String::from).to_vec()))
+            }
+        };
+        let mut file = output.open()?;
+        let checkpoint = file.checkpoint.take();
+        if let Some(checkpoint) = &checkpoint {
+            checkpoint.check_columns(header.as_deref().unwrap_or_default())?;
+        }
+        let (csv_writer, path) = start_csv(file, &csv_options, header.as_deref())?;
+        let Some(header) = header else {
+            warn!("No columns to export, writing no rows");
+            finish_csv(csv_writer)?;
+            let summary = ExportSummary::empty(t);
+            output.commit(db, &summary)?;
+            return Ok(CsvStart::Done(Box::new(summary)));
+        };
+        let mut summary = ExportSummary::new(header.clone());
+        if let Some(checkpoint) = &checkpoint {
+            (summary.rows_written, summary.rows_failed) = checkpoint.counts();
+        }
+        if options.header_only {
+            finish_csv(csv_writer)?;
+            summary.files.extend(path.map(|p| (p, 0)));
+            summary.elapsed = t.elapsed();
+            summary.report();
+            output.commit(db, &summary)?;
+            return Ok(CsvStart::Done(Box::new(summary)));
+        }
+        let export = Self {
+            output,
+            csv_options,
+            header,
+            csv_writer,
+            path,
+            checkpoint,
+            summary,
+            file_start: 0,
+            keys_scan,
+            started: t,
+        };
+        Ok(CsvStart::Rows(Box::new(export), columns))
+    }
+
+    /// Writes a chunk of rows
+    fn write(
+        &mut self,
+        options: &ExportOptions,
+        n: Vec<(i64, Vec<Option<String>>)>,
+    ) -> Result<(), DataToolErrors> {
+        let csv_options = &self.csv_options;
+        let last_id = n.last().map(|(id, _)| *id);
+        for (id, row) in n.iter() {
+            options.check_cancelled()?;
+            let file_rows = self.summary.rows_written - self.file_start;
+            if csv_options.max_rows_per_file == Some(file_rows) {
+                let (next, next_path) =
+                    start_csv(self.output.open()?, csv_options, Some(&self.header))?;
+                finish_csv(mem::replace(&mut self.csv_writer, next))?;
+                let done = mem::replace(&mut self.path, next_path);
+                self.summary.files.extend(done.map(|p| (p, file_rows)));
+                self.file_start = self.summary.rows_written;
+            }
+            let res = if options.include_id {
+                self.csv_writer.write_field(id.to_string())
+            } else {
+                Ok(())
+            };
+            let csv_writer = &mut self.csv_writer;
+            self.summary.record(
+                res.and_then(|_| {
+                    csv_writer.write_record(row.iter().map(|v| csv_options.cell(v.as_deref())))
+                }),
+                options.strict,
+            )?;
+        }
+        if let (Some(checkpoint), Some(last_id)) = (&mut self.checkpoint, last_id) {
+            self.csv_writer.flush()?;
+            checkpoint.advance(last_id, &self.summary)?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the last file, and moves the files in place unless reading the rows failed
+    fn finish(
+        self,
+        db: &TableMapDb,
+        res: Result<ProcStats, DataToolErrors>,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        let Self {
+            output,
+            csv_writer,
+            path,
+            mut summary,
+            file_start,
+            keys_scan,
+            started,
+            ..
+        } = self;
+        // the compressor is finished even if the export failed, so what was written is
+        // readable
+        let finished = finish_csv(csv_writer);
+        let stats = res?;
+        finished?;
+        let file_rows = summary.rows_written - file_start;
+        summary.files.extend(path.map(|p| (p, file_rows)));
+        summary.rows_skipped_by_filter = stats.skipped;
+        summary.ids_not_found = stats.ids_not_found;
+        summary.max_revision = stats.max_revision;
+        summary.chunks = stats.chunks;
+        summary.timings = ExportTimings {
+            keys_scan,
+            ..stats.timings
+        };
+        summary.spilled_keys = stats.spilled_keys;
+        summary.merged_headers = stats.merged_headers;
+        summary.elapsed = started.elapsed();
+        summary.report();
+        output.commit(db, &summary)?;
+        Ok(summary)
+    }
+}
+
+/// Writes the BOM and the header to a new file, unless it already has them
+fn start_csv<W: io::Write>(
+    file: CsvFile<W>,
+    csv_options: &ExportCsvOptions,
+    header: Option<&[String]>,
+) -> Result<(csv::Writer<CsvSink<W>>, Option<PathBuf>), DataToolErrors> {
+    let mut sink = file.sink;
+    if csv_options.excel_friendly && file.write_header {
+        sink.write_all("\u{FEFF}".as_bytes())?;
+    }
+    let mut csv_writer = csv_options.writer(sink);
+    if let Some(header) = header.filter(|_| file.write_header) {
+        csv_writer.write_record(header)?;
+    }
+    Ok((csv_writer, file.path))
+}
+
+/// Exports the items as JSON Lines, one JSON object per item, with the same columns
+/// [`dump_csv`] would export as keys. The values are strings, the item id, if included,
+/// a number
+#[cfg(feature = "async")]
+pub async fn dump_jsonl(
+    db: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    jsonl_options: ExportJsonlOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let mut manifest = Manifest::new(file_name, &options)?;
+    let target = TempTarget::new(file_name, options.overwrite)?;
+    let sum = manifest.as_mut().map(|m| m.add_file(file_name));
+    if let Some(sum) = sum.as_ref().filter(|_| target.append) {
+        sum.update_file(target.path())?;
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(target.path())?;
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    let summary = write_json(
+        db,
+        Hashed::new(file, sum),
+        chunk,
+        column_order,
+        options,
+        JsonLayout::Lines,
+        jsonl_options.sparse,
+    )
+    .await?;
+    target.commit()?;
+    if let Some(manifest) = manifest {
+        manifest.write(db, &summary)?;
+    }
+    Ok(summary)
+}
+
+/// Same as [`dump_jsonl`], but writes to `writer`.
+/// The writer is flushed once done, even if the export failed
+#[cfg(feature = "async")]
+pub async fn dump_jsonl_writer<W: io::Write + Send>(
+    db: &mut TableMapDb,
+    writer: W,
+    mut options: ExportOptions,
+    jsonl_options: ExportJsonlOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    write_json(
+        db,
+        writer,
+        chunk,
+        column_order,
+        options,
+        JsonLayout::Lines,
+        jsonl_options.sparse,
+    )
+    .await
+}
+
+/// Same as [`dump_jsonl`], but the objects are written as a single JSON array. The rows are
+/// streamed to the file as they are read, an export without any rows is `[]`.
+/// A JSON array can not be appended to, so [`OverwriteMode::Append`] is rejected
+#[cfg(feature = "async")]
+pub async fn dump_json(
+    db: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    json_options: ExportJsonOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    if options.overwrite == OverwriteMode::Append {
+        return Err(DataToolErrors::InvalidArgument(
+            "can not append to a JSON array".to_string(),
+        ));
+    }
+    let mut manifest = Manifest::new(file_name, &options)?;
+    let target = TempTarget::new(file_name, options.overwrite)?;
+    let sum = manifest.as_mut().map(|m| m.add_file(file_name));
+    let file = Hashed::new(fs::File::create(target.path())?, sum);
+    let layout = if json_options.pretty {
+        JsonLayout::PrettyArray
+    } else {
+        JsonLayout::Array
+    };
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    let summary = write_json(
+        db,
+        file,
+        chunk,
+        column_order,
+        options,
+        layout,
+        json_options.sparse,
+    )
+    .await?;
+    target.commit()?;
+    if let Some(manifest) = manifest {
+        manifest.write(db, &summary)?;
+    }
+    Ok(summary)
+}
+
+#[cfg(feature = "async")]
+async fn write_json<W: io::Write + Send>(
+    db: &mut TableMapDb,
+    writer: W,
+    chunk: ChunkStrategy,
+    column_order: Vec<String>,
+    options: ExportOptions,
+    layout: JsonLayout,
+    sparse: bool,
+) -> Result<ExportSummary, DataToolErrors> {
+    let t = Instant::now();
+    chunk.validate()?;
+    options.check_wide(match layout {
+        JsonLayout::Lines => "dump_jsonl",
+        JsonLayout::Array | JsonLayout::PrettyArray => "dump_json",
+    })?;
+    let mut writer = io::BufWriter::new(writer);
+    writer.write_all(layout.open().as_bytes())?;
+    let keys_scan = Instant::now();
+    let columns = options.select_columns(db, column_order)?;
+    let keys_scan = keys_scan.elapsed();
+    if columns.is_empty() {
+        warn!("No columns to export, writing no rows");
+        writer.write_all(layout.close(false).as_bytes())?;
+        writer.flush()?;
+        return Ok(ExportSummary::empty(t));
+    }
+    let keys = options.output_columns(&columns)?;
+    // the keys are the same for every item, escaped once
+    let json_keys = keys
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| DataToolErrors::GenericError(e.to_string()))?;
+    let mut summary = ExportSummary::new(keys);
+    let pretty = layout == JsonLayout::PrettyArray;
+    let mut line = vec![];
+    let res = proc_ids(db, &options, chunk, columns, |n| {
+        for (id, row) in n.iter() {
+            options.check_cancelled()?;
+            line.clear();
+            line.extend_from_slice(layout.before(summary.rows_written == 0).as_bytes());
+            let id = options.include_id.then_some(*id);
+            summary.record(
+                json_object(&mut line, &json_keys, id, row, sparse, pretty)
+                    .and_then(|_| writer.write_all(&line))
+                    .and_then(|_| writer.write_all(layout.after().as_bytes())),
+                options.strict,
+            )?;
+        }
+        Ok(())
+    })
+    .await;
+    let flushed = writer
+        .write_all(layout.close(summary.rows_written > 0).as_bytes())
+        .and_then(|_| writer.flush());
+    let stats = res?;
+    flushed?;
+    summary.rows_skipped_by_filter = stats.skipped;
+    summary.ids_not_found = stats.ids_not_found;
+    summary.max_revision = stats.max_revision;
+    summary.chunks = stats.chunks;
+    summary.timings = ExportTimings {
+        keys_scan,
+        ..stats.timings
+    };
+    summary.spilled_keys = stats.spilled_keys;
+    summary.merged_headers = stats.merged_headers;
+    summary.elapsed = t.elapsed();
+    summary.report();
+    Ok(summary)
+}
+
+/// How the objects of a JSON export are laid out
+#[cfg(feature = "async")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JsonLayout {
+    /// an object per line
+    Lines,
+    Array,
+    /// an indented array, with a line per key
+    PrettyArray,
+}
+
+#[cfg(feature = "async")]
+impl JsonLayout {
+    fn open(self) -> &'static str {
+        match self {
+            JsonLayout::Lines => "",
+            JsonLayout::Array | JsonLayout::PrettyArray => "[",
+        }
+    }
+
+    /// written before each object
+    fn before(self, first: bool) -> &'static str {
+        match (self, first) {
+            (JsonLayout::Lines, _) | (JsonLayout::Array, true) => "",
+            (JsonLayout::Array, false) => ",",
+            (JsonLayout::PrettyArray, true) => "\n",
+            (JsonLayout::PrettyArray, false) => ",\n",
+        }
+    }
+
+    /// written after each object
+    fn after(self) -> &'static str {
+        match self {
+            JsonLayout::Lines => "\n",
+            JsonLayout::Array | JsonLayout::PrettyArray => "",
+        }
+    }
+
+    fn close(self, any_objects: bool) -> &'static str {
+        match self {
+            JsonLayout::Lines => "",
+            JsonLayout::PrettyArray if any_objects => "\n]\n",
+            JsonLayout::Array | JsonLayout::PrettyArray => "]\n",
+        }
+    }
+}
+
+/// Writes the item as a JSON object. `keys` are already escaped, the id key first if there
+/// is an id
+#[cfg(feature = "async")]
+fn json_object(
+    line: &mut Vec<u8>,
+    keys: &[String],
+    id: Option<i64>,
+    row: &[Option<String>],
+    sparse: bool,
+    pretty: bool,
+) -> io::Result<()> {
+    let (indent, colon) = if pretty { ("\n    ", ": ") } else { ("", ":") };
+    if pretty {
+        line.extend_from_slice(b"  ");
+    }
+    line.push(b'{');
+    let mut sep = "";
+    if let Some(id) = id {
+        write!(line, "{}{}{}{}", indent, keys[0], colon, id)?;
+        sep = ",";
+    }
+    for (key, v) in keys[usize::from(id.is_some())..].iter().zip(row) {
+        let v = match v {
+            Some(v) => v.as_str(),
+            None if sparse => continue,
+            None => "",
+        };
+        write!(line, "{}{}{}{}", sep, indent, key, colon)?;
+        serde_json::to_writer(&mut *line, v)?;
+        sep = ",";
+    }
+    if pretty && !sep.is_empty() {
+        line.extend_from_slice(b"\n  ");
+    }
+    line.push(b'}');
+    Ok(())
+}
+
+/// Exports the items to a table of a SQLite db, one column per key, the table and what happens
+/// if it exists following the [`ExportDbOptions`]
+#[cfg(feature = "async")]
+pub async fn dump_db(
+    tmd: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    db_options: ExportDbOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let span = export_span!("dump_db", file_name);
+    async {
+        let chunk = options.chunk;
+        let (mut export, columns) = match DbExport::start(tmd, file_name, &mut options, db_options)?
+        {
+            DbStart::Rows(export, columns) => (export, columns),
+            DbStart::Done(summary) => return Ok(*summary),
+        };
+        let res = export_rows(tmd, &options, chunk, columns, |n| export.write(&options, n)).await;
+        export.finish(tmd, res)
+    }
+    .instrument(span)
+    .await
+}
+
+/// Same as [`dump_db`], without tokio, the chunks being read as [`dump_csv_sync`] reads them
+pub fn dump_db_sync(
+    tmd: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    db_options: ExportDbOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let _span = export_span!("dump_db_sync", file_name).entered();
+    options.blocking = true;
+    let chunk = options.chunk;
+    let (mut export, columns) = match DbExport::start(tmd, file_name, &mut options, db_options)? {
+        DbStart::Rows(export, columns) => (export, columns),
+        DbStart::Done(summary) => return Ok(*summary),
+    };
+    let res = export_rows_blocking(tmd, &options, chunk, columns, |n| export.write(&options, n));
+    export.finish(tmd, res)
+}
+
+/// A started SQLite export, see [`DbExport::start`]
+enum DbStart {
+    /// the rows of the columns are to be inserted
+    Rows(Box<DbExport>, Vec<String>),
+    /// there are no columns, so no table, the file is in place
+    Done(Box<ExportSummary>),
+}
+
+/// A SQLite export, once its table is created, up to its last row, shared by [`dump_db`] and
+/// [`dump_db_sync`]
+struct DbExport {
+    db: Connection,
+    target: TempTarget,
+    file_name: PathBuf,
+    manifest: Option<Manifest>,
+    /// of the export db, restored once done
+    journal_mode: String,
+    /// inserts a row
+    insert: String,
+    types: Vec<ColumnType>,
+    summary: ExportSummary,
+    /// rows inserted since the last commit
+    tx_rows: usize,
+    keys_scan: Duration,
+    started: Instant,
+}
+
+impl DbExport {
+    /// Opens the export db and creates the table, done right away if there are no columns
+    fn start(
+        tmd: &TableMapDb,
+        file_name: &Path,
+        options: &mut ExportOptions,
+        db_options: ExportDbOptions,
+    ) -> Result<DbStart, DataToolErrors> {
+        let t = Instant::now();
+        let priority_cols = mem::take(&mut options.priority_columns);
+        options.fold_header_case = true;
+        options.chunk.validate()?;
+        let manifest = Manifest::new(file_name, options)?;
+        let target = TempTarget::new(file_name, options.overwrite)?;
+        let db = Connection::open(target.path())?;
+        let journal_mode = tune_export_db(&db, "main")?;
+        let mut keys_scan = Duration::ZERO;
+        let (columns, types, out_columns, out_types) = match options.shape {
+            ExportShape::Wide => {
+                let scan = Instant::now();
+                let columns = options.select_columns(tmd, priority_cols)?;
+                keys_scan = scan.elapsed();
+                if columns.is_empty() {
+                    // a table needs at least one column
+                    warn!(
+                        "No columns to export, not creating the table in {:?}",
+                        file_name
+                    );
+                    restore_journal_mode(&db, "main", &journal_mode)?;
+                    drop(db);
+                    let summary = ExportSummary::empty(t);
+                    commit_unhashed(target, file_name, manifest, tmd, &summary)?;
+                    return Ok(DbStart::Done(Box::new(summary)));
+                }
+                let types = column_types(
+                    tmd,
+                    &columns,
+                    options,
+                    db_options.infer_types,
+                    &db_options.column_types,
+                )?;
+                let out_columns = options.output_columns(&columns)?;
+                let mut out_types = types.clone();
+                if options.include_id {
+                    out_types.insert(0, ColumnType::Integer);
+                }
+                (columns, types, out_columns, out_types)
+            }
+            ExportShape::Long => {
+                // the item id is already one of the columns, and the values are all text
+                options.include_id = false;
+                let types = vec![
+                    ColumnType::Integer,
+                    ColumnType::Text,
+                    ColumnType::Text,
+                    ColumnType::Text,
+                ];
+                let out_columns = long::LONG_COLUMNS.map(String::from).to_vec();
+                (vec![], types.clone(), out_columns, types)
+            }
+        };
+        create_table(&db, file_name, &out_columns, &out_types, &db_options)?;
+        let pos_vals = (0..out_columns.len())
+            .map(|v| format!("?{}", v + 1))
+            .collect::<Vec<String>>()
+            .join(",");
+        let insert = format!(
+            "insert into {} ({}) values ({})",
+            quote_ident(&db_options.table_name),
+            out_columns
+                .iter()
+                .map(|v| quote_ident(v))
+                .collect::<Vec<_>>()
+                .join(","),
+            pos_vals
+        );
+        let mut summary = ExportSummary::new(out_columns);
+        summary.column_types = out_types;
+        // rows are inserted in transactions of about DB_EXPORT_TX_ROWS rows,
+        // committed once a chunk is written
+        db.execute_batch("BEGIN")?;
+        let export = Self {
+            db,
+            target,
+            file_name: file_name.to_path_buf(),
+            manifest,
+            journal_mode,
+            insert,
+            types,
+            summary,
+            tx_rows: 0,
+            keys_scan,
+            started: t,
+        };
+        Ok(DbStart::Rows(Box::new(export), columns))
+    }
+
+    /// Inserts a chunk of rows
+    fn write(
+        &mut self,
+        options: &ExportOptions,
+        n: Vec<(i64, Vec<Option<String>>)>,
+    ) -> Result<(), DataToolErrors> {
+        let mut stmt = self.db.prepare_cached(&self.insert)?;
+        for (id, row) in n.iter() {
+            options.check_cancelled()?;
+            let id = options.include_id.then_some(Value::Integer(*id));
+            self.summary.record(
+                stmt.execute(params_from_iter(
+                    id.into_iter().chain(
+                        row.iter()
+                            .zip(self.types.iter())
+                            .map(|(v, ty)| ty.value(v.as_deref().unwrap_or_default())),
+                    ),
+                ))
+                .map(|_| ()),
+                options.strict,
+            )?;
+        }
+        drop(stmt);
+        self.tx_rows += n.len();
+        if self.tx_rows >= DB_EXPORT_TX_ROWS {
+            self.db.execute_batch("COMMIT; BEGIN")?;
+            self.tx_rows = 0;
+        }
+        Ok(())
+    }
+
+    /// Commits the last rows and moves the file in place, unless reading the rows failed
+    fn finish(
+        self,
+        tmd: &TableMapDb,
+        res: Result<ProcStats, DataToolErrors>,
+    ) -> Result<ExportSummary, DataToolErrors> {
+        // the db is closed before the target is removed, as the fields are dropped in order
+        let stats = res?;
+        let Self {
+            db,
+            target,
+            file_name,
+            manifest,
+            journal_mode,
+            mut summary,
+            keys_scan,
+            started,
+            ..
+        } = self;
+        summary.rows_skipped_by_filter = stats.skipped;
+        summary.ids_not_found = stats.ids_not_found;
+        summary.max_revision = stats.max_revision;
+        summary.chunks = stats.chunks;
+        summary.timings = ExportTimings {
+            keys_scan,
+            ..stats.timings
+        };
+        summary.spilled_keys = stats.spilled_keys;
+        summary.merged_headers = stats.merged_headers;
+        db.execute_batch("COMMIT")?;
+        restore_journal_mode(&db, "main", &journal_mode)?;
+        db.close()
+            .map_err(|(_, e)| DataToolErrors::GenericError(e.to_string()))?;
+        summary.elapsed = started.elapsed();
+        commit_unhashed(target, &file_name, manifest, tmd, &summary)?;
+        summary.report();
+        Ok(summary)
+    }
+}
+
+/// Same as [`dump_db`], but the rows are built and inserted by SQLite itself, with the export
+/// file attached to the table map's connection. No cell goes through Rust, which is a lot faster
+/// and uses constant memory however many items there are.
+///
+/// Fails if there are more columns than SQLite allows in a table, [`dump_db`] should be
+/// used then. As the rows are inserted by a single statement, the export never has failed
+/// rows, it either fails as a whole or not at all.
+pub fn dump_db_attach(
+    tmd: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    db_options: ExportDbOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let t = Instant::now();
+    let priority_cols = mem::take(&mut options.priority_columns);
+    options.fold_header_case = true;
+    options.check_attach()?;
+    options.check_wide("dump_db_attach")?;
+    options.check_cancelled()?;
+    let manifest = Manifest::new(file_name, &options)?;
+    let target = TempTarget::new(file_name, options.overwrite)?;
+    let keys_scan = Instant::now();
+    let columns = options.select_columns(tmd, priority_cols)?;
+    let keys_scan = keys_scan.elapsed();
+    tmd.ensure_read_indexes()?;
+    let max_columns = tmd.connection.limit(Limit::SQLITE_LIMIT_COLUMN) as usize;
+    let max_params = tmd.connection.limit(Limit::SQLITE_LIMIT_VARIABLE_NUMBER) as usize;
+    let out_columns = options.output_columns(&columns)?;
+    let merge = options.header_merge(&columns)?;
+    if out_columns.len() > max_columns || columns.len() + 1 > max_params {
+        return Err(DataToolErrors::InvalidArgument(format!(
+            "can not export {} columns in a single statement, SQLite allows at most {}, \
+             use dump_db instead",
+            out_columns.len(),
+            max_columns.min(max_params - 1)
+        )));
+    }
+    let db = Connection::open(target.path())?;
+    if columns.is_empty() {
+        warn!(
+            "No columns to export, not creating the table in {:?}",
+            file_name
+        );
+        drop(db);
+        let summary = ExportSummary::empty(t);
+        commit_unhashed(target, file_name, manifest, tmd, &summary)?;
+        return Ok(summary);
+    }
+    let types = column_types(
+        tmd,
+        &columns,
+        &options,
+        db_options.infer_types,
+        &db_options.column_types,
+    )?;
+    let mut out_types = types.clone();
+    if options.include_id {
+        out_types.insert(0, ColumnType::Integer);
+    }
+    create_table(&db, file_name, &out_columns, &out_types, &db_options)?;
+    db.close()
+        .map_err(|(_, e)| DataToolErrors::GenericError(e.to_string()))?;
+
+    // one column per key, if a key was inserted more than once for an item the last value
+    // wins, the same as when the rows are read back. Typed columns get NULL instead of empty
+    // cells, the column affinity takes care of converting the values
+    let key_cells: Vec<_> = columns
+        .iter()
+        .enumerate()
+        .map(
+            |(i, c)| match options.include_created_at && c == CREATED_AT_COLUMN {
+                true => "i.created_at".to_string(),
+                false => format!("max(case when d.key = ?{} then d.value end)", i + 1),
+            },
+        )
+        .collect();
+    let cells = options
+        .include_id
+        .then(|| "i.id".to_string())
+        .into_iter()
+        .chain(merge.groups().zip(&types).map(|(g, ty)| {
+            let cell = match g {
+                [i] if options.include_created_at && columns[*i] == CREATED_AT_COLUMN => {
+                    return key_cells[*i].clone();
+                }
+                [i] => key_cells[*i].clone(),
+                // the first or last non-empty value of the merged keys, as the chunk readers
+                // keep it
+                _ => {
+                    let non_empty = g.iter().map(|i| format!("nullif({}, '')", key_cells[*i]));
+                    let non_empty: Vec<_> = match merge.keeps_last() {
+                        true => non_empty.rev().collect(),
+                        false => non_empty.collect(),
+                    };
+                    let present = g.iter().map(|i| key_cells[*i].as_str());
+                    format!(
+                        "coalesce({}, {})",
+                        non_empty.join(", "),
+                        present.collect::<Vec<_>>().join(", ")
+                    )
+                }
+            };
+            match ty {
+                ColumnType::Text => format!("coalesce({}, '')", cell),
+                _ => format!("nullif({}, '')", cell),
+            }
+        }))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut params: Vec<Value> = columns.iter().cloned().map(Value::Text).collect();
+    let order_by = match &options.order {
+        IterOrder::InsertionAsc => "i.id".to_string(),
+        IterOrder::InsertionDesc => "i.id desc".to_string(),
+        IterOrder::ByItemVal => "i.item_val, i.id".to_string(),
+        IterOrder::ByKeyValue { key, numeric } => {
+            params.push(Value::Text(key.clone()));
+            let v = tmd
+                .sql(&format!(
+                    "(select s.value from data_columns s where s.item_id = i.id and s.key = ?{} \
+                     order by s.id desc limit 1)",
+                    params.len()
+                ))
+                .into_owned();
+            let sort_val = if *numeric {
+                format!("cast({} as real)", v)
+            } else {
+                v.clone()
+            };
+            format!("{} is null, {}, i.id", v, sort_val)
+        }
+    };
+    // the names of the table and columns are not rewritten for the map
+    let from = tmd.sql(
+        "from item_data i \
+         left join (select item_id, key, value from data_columns \
+             where id in (select max(id) from data_columns group by item_id, key)) d \
+         on d.item_id = i.id",
+    );
+    let q = format!(
+        "insert into export.{} ({}) select {} {} group by i.id order by {}",
+        quote_ident(&db_options.table_name),
+        out_columns
+            .iter()
+            .map(|v| quote_ident(v))
+            .collect::<Vec<_>>()
+            .join(","),
+        cells,
+        from,
+        order_by
+    );
+    let max_revision = tmd.max_revision()?;
+    let conn = &tmd.connection;
+    conn.execute(
+        "attach database ?1 as export",
+        [target.path().to_string_lossy()],
+    )?;
+    let write = Instant::now();
+    let res = tune_export_db(conn, "export").and_then(|journal_mode| {
+        let rows = conn.execute(&q, params_from_iter(params))?;
+        restore_journal_mode(conn, "export", &journal_mode)?;
+        Ok(rows)
+    });
+    // detaching even if the insert failed, so the connection is left as it was
+    let detached = conn.execute("detach database export", []);
+    let rows_written = res?;
+    detached?;
+    let summary = ExportSummary {
+        rows_written,
+        rows_failed: 0,
+        rows_skipped_by_filter: 0,
+        rows_skipped_incomplete: 0,
+        rows_skipped_duplicate: 0,
+        non_numeric_values: vec![],
+        ids_not_found: 0,
+        files: vec![],
+        columns: out_columns,
+        column_types: out_types,
+        spilled_keys: vec![],
+        merged_headers: merge.merged(&columns),
+        max_revision,
+        // the rows are copied by a single statement, not read by chunks
+        chunks: 0,
+        timings: ExportTimings {
+            keys_scan,
+            write: write.elapsed(),
+            ..Default::default()
+        },
+        elapsed: t.elapsed(),
+    };
+    commit_unhashed(target, file_name, manifest, tmd, &summary)?;
+    summary.report();
+    Ok(summary)
+}
+
+/// Sets up the `schema` of an export file for writing fast, returning the journal mode it had,
+/// to put back with [`restore_journal_mode`]. The export is written to a temporary file,
+/// nothing to protect until it is renamed
+fn tune_export_db(db: &Connection, schema: &str) -> rusqlite::Result<String> {
+    let journal_mode =
+        db.query_row(&format!("PRAGMA {}.journal_mode", schema), [], |r| r.get(0))?;
+    db.execute_batch(&format!(
+        "PRAGMA {0}.journal_mode = WAL; PRAGMA {0}.synchronous = OFF;",
+        schema
+    ))?;
+    Ok(journal_mode)
+}
+
+/// Puts back the journal mode of the export file once written, WAL is stored in the file and
+/// would leave the export relying on a -wal file next to it. Rollback journal when the file is
+/// new, WAL again when appending to a db that was using it
+fn restore_journal_mode(db: &Connection, schema: &str, journal_mode: &str) -> rusqlite::Result<()> {
+    db.execute_batch(&format!(
+        "PRAGMA {}.journal_mode = {}",
+        schema, journal_mode
+    ))
+}
+
+/// Creates the export table with the given columns, if the table already exists the
+/// [`IfTableExists`] mode decides what happens
+fn create_table(
+    db: &Connection,
+    file_name: &Path,
+    columns: &[String],
+    types: &[ColumnType],
+    db_options: &ExportDbOptions,
+) -> Result<(), DataToolErrors> {
+    let table = &db_options.table_name;
+    if table.is_empty() {
+        return Err(DataToolErrors::InvalidArgument(
+            "the table name can not be empty".to_string(),
+        ));
+    }
+    if let Some(pk) = &db_options.primary_key {
+        if !columns.contains(pk) {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "primary key {:?} is not one of the exported columns",
+                pk
+            )));
+        }
+    }
+    let existing = table_columns(db, table)?;
+    if !existing.is_empty() {
+        match db_options.if_exists {
+            IfTableExists::Error => {
+                return Err(DataToolErrors::InvalidArgument(format!(
+                    "table {:?} already exists in {:?}",
+                    table, file_name
+                )))
+            }
+            IfTableExists::Replace => {
+                db.execute(&format!("drop table {}", quote_ident(table)), [])?;
+            }
+            IfTableExists::Append => {
+                let missing: Vec<_> = existing.iter().filter(|c| !columns.contains(c)).collect();
+                let extra: Vec<_> = columns.iter().filter(|c| !existing.contains(c)).collect();
+                if !missing.is_empty() || !extra.is_empty() {
+                    return Err(DataToolErrors::InvalidArgument(format!(
+                        "can not append to table {:?} in {:?}, columns not exported: {:?}, \
+                         exported columns not in the table: {:?}",
+                        table, file_name, missing, extra
+                    )));
+                }
+                return Ok(());
+            }
+        }
+    }
+    let q = format!(
+        "create table {} ({})",
+        quote_ident(table),
+        columns
+            .iter()
+            .zip(types.iter())
+            .map(|(v, ty)| {
+                if db_options.primary_key.as_ref() == Some(v) {
+                    format!("{} {} PRIMARY KEY", quote_ident(v), ty.sql())
+                } else {
+                    format!("{} {}", quote_ident(v), ty.sql())
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    db.execute(&q, [])?;
+    Ok(())
+}
+
+/// Types of the exported columns, the pinned ones first, then the inferred ones if inference
+/// is enabled, text otherwise. Computed columns are text unless pinned. The columns merged by
+/// [`ExportOptions::sanitize_headers`] have a single type
+fn column_types(
+    db: &TableMapDb,
+    columns: &[String],
+    options: &ExportOptions,
+    infer_types: bool,
+    pinned: &HashMap<String, ColumnType>,
+) -> Result<Vec<ColumnType>, DataToolErrors> {
+    let mut inferred = HashMap::new();
+    if infer_types {
+        let to_infer: Vec<_> = columns
+            .iter()
+            .filter(|c| !pinned.contains_key(*c) && !options.computed.contains_key(*c))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut stmt = db.connection.prepare(
+            &db.sql("select key, value from data_columns where key in rarray(?1) and value != ''"),
+        )?;
+        let mut rows = stmt.query([key_array(&to_infer)])?;
+        while let Some(row) = rows.next()? {
+            let key: String = row.get(0)?;
+            let value: String = row.get(1)?;
+            let ty = inferred.entry(key).or_insert(ColumnType::Integer);
+            *ty = ty.widen(&value);
+        }
+    }
+    if options.include_created_at {
+        inferred.insert(CREATED_AT_COLUMN.to_string(), ColumnType::Integer);
+    }
+    let types: Vec<_> = columns
+        .iter()
+        .map(|c| {
+            pinned
+                .get(c)
+                .or_else(|| inferred.get(c))
+                .copied()
+                .unwrap_or_default()
+        })
+        .collect();
+    Ok(options.header_merge(columns)?.types(&types))
+}
+
+/// Type of a column in the SQLite exports
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColumnType {
+    #[default]
+    Text,
+    Integer,
+    Real,
+}
+
+impl ColumnType {
+    fn sql(&self) -> &'static str {
+        match self {
+            ColumnType::Text => "TEXT",
+            ColumnType::Integer => "INTEGER",
+            ColumnType::Real => "REAL",
+        }
+    }
+
+    /// The narrowest type holding both the values of this type and `value`
+    fn widen(self, value: &str) -> Self {
+        // integers that would not be written back the same, e.g. with leading zeros, are
+        // kept as text, as reals they would not be either
+        let int = value.parse::<i64>().map(|v| v.to_string() == value);
+        let is_real = || value.parse::<f64>().is_ok_and(|v| v.is_finite());
+        match (self, int) {
+            (_, Ok(false)) => ColumnType::Text,
+            (ColumnType::Integer, Ok(true)) => ColumnType::Integer,
+            (ColumnType::Integer | ColumnType::Real, _) if is_real() => ColumnType::Real,
+            _ => ColumnType::Text,
+        }
+    }
+
+    /// Value bound for a cell of a column of this type, empty cells of typed columns are
+    /// NULL. Cells that do not parse are bound as text
+    fn value(&self, v: &str) -> Value {
+        let parsed = match self {
+            ColumnType::Text => None,
+            _ if v.is_empty() => Some(Value::Null),
+            ColumnType::Integer => v.parse().ok().map(Value::Integer),
+            ColumnType::Real => v.parse().ok().map(Value::Real),
+        };
+        parsed.unwrap_or_else(|| Value::Text(v.to_string()))
+    }
+}
+
+/// Rows inserted by [`dump_db`] before committing
+const DB_EXPORT_TX_ROWS: usize = 10_000;
+
+/// What an export did
+#[derive(Debug, Clone, Default)]
+pub struct ExportSummary {
+    pub rows_written: usize,
+    /// rows that could not be written, always 0 for strict exports
+    pub rows_failed: usize,
+    /// rows left out by [`ExportOptions::row_filter`]
+    pub rows_skipped_by_filter: usize,
+    /// items left out by [`dump_lookup`] as they miss the lookup key or value
+    pub rows_skipped_incomplete: usize,
+    /// items left out by [`dump_lookup`] as another item has the same lookup key
+    pub rows_skipped_duplicate: usize,
+    /// values of the keys of numeric [`dump_rollup`] aggregations that are not numbers, by
+    /// key, the keys with none left out
+    pub non_numeric_values: Vec<(String, usize)>,
+    /// ids given to [`ExportOptions::ids`] without an item
+    pub ids_not_found: usize,
+    /// exported columns, in order
+    pub columns: Vec<String>,
+    /// files of an export split by [`ExportCsvOptions::max_rows_per_file`], with the rows
+    /// written to each, empty otherwise
+    pub files: Vec<(PathBuf, usize)>,
+    /// types of the exported columns, in the same order, only set by the SQLite, SQL text,
+    /// COPY and Parquet exports
+    pub column_types: Vec<ColumnType>,
+    /// keys left out by [`ExportOptions::max_columns`], in the order they were first
+    /// inserted
+    pub spilled_keys: Vec<String>,
+    /// headers [`ExportOptions::sanitize_headers`] merged keys into, with the keys, in the
+    /// column order
+    pub merged_headers: Vec<(String, Vec<String>)>,
+    /// the highest revision of the items when the export started, to pass to
+    /// [`ExportOptions::changed_since`] for the next export. Items changed while exporting
+    /// are exported again by the next one
+    pub max_revision: i64,
+    /// chunks the items were read in, by the workers or, for the long shape, handed to the
+    /// writer
+    pub chunks: usize,
+    pub timings: ExportTimings,
+    pub elapsed: Duration,
+}
+
+/// Time spent in each phase of an export, see [`ExportSummary::timings`]. The chunks are
+/// read in parallel, while the ids are scanned and the rows written, so the read time can be
+/// longer than the whole export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportTimings {
+    /// finding the exported columns, in their order
+    pub keys_scan: Duration,
+    /// finding the ids of the items of each chunk, in the export order
+    pub id_scan: Duration,
+    /// reading the cells of the chunks and preparing the rows, summed over the workers
+    pub read: Duration,
+    /// from the first chunk handed to the workers until the last one was read
+    pub read_wall: Duration,
+    /// writing the rows to the output, by a single writer, so the wall clock time
+    pub write: Duration,
+}
+
+impl ExportSummary {
+    fn new(columns: Vec<String>) -> Self {
+        Self {
+            columns,
+            ..Default::default()
+        }
+    }
+
+    fn empty(t: Instant) -> Self {
+        Self {
+            elapsed: t.elapsed(),
+            ..Default::default()
+        }
+    }
+
+    /// Rows written per chunk read, 0 if there were none
+    pub fn rows_per_chunk(&self) -> f64 {
+        match self.chunks {
+            0 => 0.0,
+            n => self.rows_written as f64 / n as f64,
+        }
+    }
+
+    /// Records the counts on the span of the export, e.g. the `dump_csv` span, and logs them
+    /// with the timings on a single line
+    fn report(&self) {
+        let ms = |d: Duration| d.as_millis() as u64;
+        let timings = &self.timings;
+        let span = Span::current();
+        span.record("rows", self.rows_written);
+        span.record("rows_failed", self.rows_failed);
+        span.record("chunks", self.chunks);
+        span.record("keys_scan_ms", ms(timings.keys_scan));
+        span.record("id_scan_ms", ms(timings.id_scan));
+        span.record("read_ms", ms(timings.read));
+        span.record("read_wall_ms", ms(timings.read_wall));
+        span.record("write_ms", ms(timings.write));
+        for (header, keys) in &self.merged_headers {
+            warn!("Keys {:?} merged into the column {:?}", keys, header);
+        }
+        info!(
+            rows = self.rows_written,
+            rows_failed = self.rows_failed,
+            rows_skipped_by_filter = self.rows_skipped_by_filter,
+            rows_skipped_incomplete = self.rows_skipped_incomplete,
+            rows_skipped_duplicate = self.rows_skipped_duplicate,
+            columns = self.columns.len(),
+            chunks = self.chunks,
+            rows_per_chunk = self.rows_per_chunk(),
+            keys_scan_ms = ms(timings.keys_scan),
+            id_scan_ms = ms(timings.id_scan),
+            read_ms = ms(timings.read),
+            read_wall_ms = ms(timings.read_wall),
+            write_ms = ms(timings.write),
+            elapsed_ms = ms(self.elapsed),
+            "export done"
+        );
+    }
+
+    /// Counts the result of writing a row, in strict mode a failure aborts the export
+    fn record<E: Display>(
+        &mut self,
+        res: Result<(), E>,
+        strict: bool,
+    ) -> Result<(), DataToolErrors> {
+        match res {
+            Ok(_) => self.rows_written += 1,
+            Err(e) => {
+                let row = self.rows_written + self.rows_failed + 1;
+                if strict {
+                    return Err(DataToolErrors::RowWriteFailed {
+                        row,
+                        reason: e.to_string(),
+                    });
+                }
+                error!("Failed to write row {}: {}", row, e);
+                self.rows_failed += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options of the CSV export, [`dump_csv`]
+#[derive(Debug, Clone)]
+pub struct ExportCsvOptions {
+    delimiter: u8,
+    quote_style: QuoteStyle,
+    terminator: LineTerminator,
+    excel_friendly: bool,
+    guard: Option<(ValuePredicate, ExcelGuard)>,
+    compression: Compression,
+    max_rows_per_file: Option<usize>,
+    missing_value: String,
+    resume: bool,
+}
+
+impl Default for ExportCsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote_style: QuoteStyle::Necessary,
+            terminator: Default::default(),
+            excel_friendly: false,
+            guard: None,
+            compression: Default::default(),
+            max_rows_per_file: None,
+            missing_value: String::new(),
+            resume: false,
+        }
+    }
+}
+
+impl ExportCsvOptions {
+    /// Field delimiter, `,` by default, `b'\t'` for TSV
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// When fields are quoted, only when necessary by default
+    pub fn quote_style(mut self, quote_style: QuoteStyle) -> Self {
+        self.quote_style = quote_style;
+        self
+    }
+
+    pub fn terminator(mut self, terminator: LineTerminator) -> Self {
+        self.terminator = terminator;
+        self
+    }
+
+    /// Write the file the way Excel opens it correctly, starting with a UTF-8 BOM and with
+    /// CRLF line endings, whatever the terminator is set to.
+    /// The BOM is not written when appending to a file with rows
+    pub fn excel_friendly(mut self, excel_friendly: bool) -> Self {
+        self.excel_friendly = excel_friendly;
+        self
+    }
+
+    /// Keep spreadsheets from converting the values matching the predicate, like numbers
+    /// with leading zeros or long ids, by writing them as text the way `guard` says.
+    /// Only the values of the items are guarded, not the header or the item id
+    pub fn guard_values(
+        mut self,
+        predicate: impl Fn(&str) -> bool + Send + Sync + 'static,
+        guard: ExcelGuard,
+    ) -> Self {
+        self.guard = Some((Hook(Arc::new(predicate)), guard));
+        self
+    }
+
+    /// Compress the file, by default depending on its extension
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Split the export into files of at most this many rows, each with the header, named
+    /// after the output file with the part number before the extension, `data.part0001.csv`,
+    /// `data.part0002.csv`, ... An export without any rows is a single file with the header.
+    /// Split exports can not be appended to, [`OverwriteMode::Append`] is rejected, and when
+    /// overwriting, the parts of an earlier export left after the last one are removed
+    pub fn max_rows_per_file(mut self, rows: usize) -> Self {
+        self.max_rows_per_file = Some(rows);
+        self
+    }
+
+    /// Written for the keys an item does not have, and the creation time of the items from
+    /// before the db stored it, `NA` or `\N` for instance. Empty by default. Written as is,
+    /// without guard. Stored values are never NULL, so an empty stored value stays empty.
+    /// Computed columns always have a value, and get the item without the placeholders
+    pub fn missing_value(mut self, missing_value: impl Into<String>) -> Self {
+        self.missing_value = missing_value.into();
+        self
+    }
+
+    /// Make the export resumable. The rows are written to `<output>.tmp`, kept if the export
+    /// fails or the process dies, and once each chunk is flushed and synced to disk, the last
+    /// item written is recorded in `<output>.checkpoint.json`. The next resumable export to
+    /// the same file truncates the partial export to the checkpoint and appends the rows of
+    /// the items after it, without the header. It fails if the exported columns changed
+    /// since, [`ExportSummary::rows_written`] counts the rows of the partial export too.
+    ///
+    /// Only for [`dump_csv`] and [`dump_csv_sync`], uncompressed, in insertion order, ordered,
+    /// without ids, offset or limit, in the wide shape and not split into files. The
+    /// overwrite mode applies to the output file, appending is not supported
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Fails for a delimiter the values could not be told apart with, or files without rows
+    fn validate(&self) -> Result<(), DataToolErrors> {
+        if self.max_rows_per_file == Some(0) {
+            return Err(DataToolErrors::InvalidArgument(
+                "a file needs at least one row".to_string(),
+            ));
+        }
+        if matches!(self.delimiter, b'"' | b'\r' | b'\n') {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "{:?} can not be used as the delimiter",
+                self.delimiter as char
+            )));
+        }
+        Ok(())
+    }
+
+    fn writer<W: io::Write>(&self, w: W) -> csv::Writer<W> {
+        csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .quote_style(self.quote_style)
+            .terminator(match self.terminator {
+                _ if self.excel_friendly => csv::Terminator::CRLF,
+                LineTerminator::Lf => csv::Terminator::Any(b'\n'),
+                LineTerminator::CrLf => csv::Terminator::CRLF,
+            })
+            .from_writer(w)
+    }
+
+    /// The cell as written, the placeholder if the item does not have it
+    fn cell<'a>(&'a self, v: Option<&'a str>) -> Cow<'a, [u8]> {
+        match v {
+            Some(v) => self.guarded(v),
+            None => Cow::Borrowed(self.missing_value.as_bytes()),
+        }
+    }
+
+    /// The value as written, guarded if it matches the predicate
+    fn guarded<'a>(&self, v: &'a str) -> Cow<'a, [u8]> {
+        match &self.guard {
+            Some((predicate, guard)) if (predicate.0)(v) => Cow::Owned(
+                match guard {
+                    ExcelGuard::TabPrefix => format!("\t{}", v),
+                    ExcelGuard::Formula => format!("=\"{}\"", v.replace('"', "\"\"")),
+                }
+                .into_bytes(),
+            ),
+            _ => Cow::Borrowed(v.as_bytes()),
+        }
+    }
+}
+
+/// How [`ExportCsvOptions::guard_values`] keeps a value from being converted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExcelGuard {
+    /// prefix the value with a tab, it stays text but the tab is part of it
+    TabPrefix,
+    /// write the value as the formula `="value"`, shown as is but a formula when edited
+    Formula,
+}
+
+/// Flushes the rows still buffered, finishes the compression and flushes the writer
+fn finish_csv<W: io::Write>(csv_writer: csv::Writer<CsvSink<W>>) -> Result<(), DataToolErrors> {
+    let sink = csv_writer.into_inner().map_err(|e| e.into_error())?;
+    sink.finish()?.flush()?;
+    Ok(())
+}
+
+/// Compression of a CSV export, see [`ExportCsvOptions::compression`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip for a `.gz` file, zstd for a `.zst` one, none otherwise. Fails if the crate was
+    /// built without the feature the extension needs
+    #[default]
+    Auto,
+    None,
+    /// gzip with the level, from 0 to 9. Needs the `gzip` feature
+    #[cfg(feature = "gzip")]
+    Gzip(u32),
+    /// zstd with the level, from 1 to 22, 0 for zstd's default. Needs the `zstd` feature
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Extension of a CSV file compressed this way, without the leading `.`
+    #[cfg(feature = "async")]
+    fn csv_extension(self) -> &'static str {
+        #[cfg(feature = "gzip")]
+        if matches!(self, Compression::Gzip(_)) {
+            return "csv.gz";
+        }
+        #[cfg(feature = "zstd")]
+        if matches!(self, Compression::Zstd(_)) {
+            return "csv.zst";
+        }
+        "csv"
+    }
+
+    /// The compression used for the file
+    fn resolve(self, file_name: &Path) -> Result<Self, DataToolErrors> {
+        if self != Compression::Auto {
+            return Ok(self);
+        }
+        let unsupported = |feature| {
+            Err(DataToolErrors::InvalidArgument(format!(
+                "can not compress {:?}, built without the {:?} feature",
+                file_name, feature
+            )))
+        };
+        match file_name.extension().and_then(|e| e.to_str()) {
+            Some("gz") if !cfg!(feature = "gzip") => unsupported("gzip"),
+            Some("zst") if !cfg!(feature = "zstd") => unsupported("zstd"),
+            #[cfg(feature = "gzip")]
+            Some("gz") => Ok(Compression::Gzip(6)),
+            #[cfg(feature = "zstd")]
+            Some("zst") => Ok(Compression::Zstd(0)),
+            _ => Ok(Compression::None),
+        }
+    }
+}
+
+/// Where a CSV export is written, through the compressor if any
+enum CsvSink<W: io::Write> {
+    Plain(W),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: io::Write> CsvSink<W> {
+    fn new(w: W, compression: Compression) -> Result<Self, DataToolErrors> {
+        Ok(match compression {
+            Compression::Auto | Compression::None => CsvSink::Plain(w),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip(level) => {
+                if level > 9 {
+                    return Err(DataToolErrors::InvalidArgument(format!(
+                        "gzip level {} is not between 0 and 9",
+                        level
+                    )));
+                }
+                CsvSink::Gzip(flate2::write::GzEncoder::new(
+                    w,
+                    flate2::Compression::new(level),
+                ))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                if !(0..=22).contains(&level) {
+                    return Err(DataToolErrors::InvalidArgument(format!(
+                        "zstd level {} is not between 0 and 22",
+                        level
+                    )));
+                }
+                CsvSink::Zstd(zstd::Encoder::new(w, level)?)
+            }
+        })
+    }
+
+    /// Writes what the compressor still holds and the end of the compressed stream
+    fn finish(self) -> io::Result<W> {
+        Ok(match self {
+            CsvSink::Plain(w) => w,
+            #[cfg(feature = "gzip")]
+            CsvSink::Gzip(w) => w.finish()?,
+            #[cfg(feature = "zstd")]
+            CsvSink::Zstd(w) => w.finish()?,
+        })
+    }
+}
+
+impl<W: io::Write> io::Write for CsvSink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CsvSink::Plain(w) => w.write(buf),
+            #[cfg(feature = "gzip")]
+            CsvSink::Gzip(w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            CsvSink::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CsvSink::Plain(w) => w.flush(),
+            #[cfg(feature = "gzip")]
+            CsvSink::Gzip(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            CsvSink::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// Options of the JSON Lines export, [`dump_jsonl`]
+#[derive(Debug, Clone, Default)]
+pub struct ExportJsonlOptions {
+    sparse: bool,
+}
+
+impl ExportJsonlOptions {
+    /// Leave out the keys an item does not have, instead of writing them as empty strings
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+}
+
+/// Options of the JSON array export, [`dump_json`]
+#[derive(Debug, Clone, Default)]
+pub struct ExportJsonOptions {
+    sparse: bool,
+    pretty: bool,
+}
+
+impl ExportJsonOptions {
+    /// Leave out the keys an item does not have, instead of writing them as empty strings
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Indent the array, with a line per key, instead of writing compact JSON
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+}
+
+/// How the rows of a CSV export end
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineTerminator {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+/// Options of the SQLite exports, [`dump_db`] and [`dump_db_attach`]
+#[derive(Debug, Clone)]
+pub struct ExportDbOptions {
+    table_name: String,
+    if_exists: IfTableExists,
+    primary_key: Option<String>,
+    infer_types: bool,
+    column_types: HashMap<String, ColumnType>,
+}
+
+impl Default for ExportDbOptions {
+    fn default() -> Self {
+        Self {
+            table_name: "products".to_string(),
+            if_exists: Default::default(),
+            primary_key: None,
+            infer_types: false,
+            column_types: HashMap::new(),
+        }
+    }
+}
+
+impl ExportDbOptions {
+    /// Name of the table the rows are exported to, `products` by default
+    pub fn table_name(mut self, table_name: impl Into<String>) -> Self {
+        self.table_name = table_name.into();
+        self
+    }
+
+    /// What to do if the table already exists, which can only happen when appending to an
+    /// existing file. Fails by default
+    pub fn if_exists(mut self, if_exists: IfTableExists) -> Self {
+        self.if_exists = if_exists;
+        self
+    }
+
+    /// Declare one of the exported columns, by its name in the table, as the primary key
+    pub fn primary_key(mut self, column: impl Into<String>) -> Self {
+        self.primary_key = Some(column.into());
+        self
+    }
+
+    /// Scan the values of each column, and declare it `INTEGER` or `REAL` if all its non-empty
+    /// values are numbers. Empty cells of these columns are exported as NULL.
+    /// Columns are `TEXT` otherwise
+    pub fn infer_types(mut self, infer_types: bool) -> Self {
+        self.infer_types = infer_types;
+        self
+    }
+
+    /// Pin the type of a column, whether types are inferred or not
+    pub fn column_type(mut self, column: impl Into<String>, column_type: ColumnType) -> Self {
+        self.column_types.insert(column.into(), column_type);
+        self
+    }
+}
+
+/// What a SQLite export does when its table already exists in the output file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IfTableExists {
+    /// fail with [`DataToolErrors::InvalidArgument`]
+    #[default]
+    Error,
+    /// drop the table and create it again
+    Replace,
+    /// add the rows to the table, which must have the same columns
+    Append,
+}
+
+/// What an export does when its output file already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwriteMode {
+    /// fail with [`DataToolErrors::FileExists`]
+    #[default]
+    Error,
+    /// delete the file and export from scratch
+    Overwrite,
+    /// add the rows to the existing file. For CSV the header is only written if the file
+    /// is empty, for SQLite [`ExportDbOptions::if_exists`] decides what happens to an
+    /// existing table
+    Append,
+}
+
+/// The export is written to a sibling temp file, which is renamed to the output file once
+/// everything is written. If the export fails, the temp file is deleted, so a half written
+/// export never shows up under the output name, and an existing output file is left untouched.
+struct TempTarget {
+    target: PathBuf,
+    tmp: PathBuf,
+    append: bool,
+    committed: bool,
+    /// kept if the export fails, see [`ExportCsvOptions::resume`]
+    keep: bool,
+}
+
+impl TempTarget {
+    /// Applies the overwrite mode, when appending the temp file starts as a copy of the
+    /// existing output file
+    fn new(file_name: &Path, mode: OverwriteMode) -> Result<Self, DataToolErrors> {
+        let mut tmp = file_name.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut target = Self {
+            target: file_name.to_path_buf(),
+            tmp: PathBuf::from(tmp),
+            append: false,
+            committed: false,
+            keep: false,
+        };
+        if target.tmp.exists() {
+            warn!("Removing leftover temp file: {:?}", target.tmp);
+            fs::remove_file(&target.tmp)?;
+        }
+        if file_name.exists() {
+            match mode {
+                OverwriteMode::Error => {
+                    return Err(DataToolErrors::FileExists(file_name.to_path_buf()))
+                }
+                OverwriteMode::Overwrite => warn!("Replacing file: {:?}", file_name),
+                OverwriteMode::Append => {
+                    fs::copy(file_name, &target.tmp)?;
+                    target.append = true;
+                }
+            }
+        }
+        Ok(target)
+    }
+
+    /// Same as [`TempTarget::new`] without appending, an existing temp file being the
+    /// partial export of a resumable export, kept as is, and kept if the export fails too
+    fn resumable(file_name: &Path, mode: OverwriteMode) -> Result<Self, DataToolErrors> {
+        if file_name.exists() {
+            match mode {
+                OverwriteMode::Error => {
+                    return Err(DataToolErrors::FileExists(file_name.to_path_buf()))
+                }
+                _ => warn!("Replacing file: {:?}", file_name),
+            }
+        }
+        let mut tmp = file_name.as_os_str().to_owned();
+        tmp.push(".tmp");
+        Ok(Self {
+            target: file_name.to_path_buf(),
+            tmp: PathBuf::from(tmp),
+            append: false,
+            committed: false,
+            keep: true,
+        })
+    }
+
+    /// where the export should be written
+    fn path(&self) -> &Path {
+        &self.tmp
+    }
+
+    /// Moves the temp file in place of the output file
+    fn commit(mut self) -> Result<(), DataToolErrors> {
+        if let Err(e) = fs::rename(&self.tmp, &self.target) {
+            if e.kind() != std::io::ErrorKind::CrossesDevices {
+                return Err(e.into());
+            }
+            // should not happen with a sibling, unless the output is a mount point of its own
+            warn!(
+                "Can not rename across filesystems, copying to {:?}",
+                self.target
+            );
+            fs::copy(&self.tmp, &self.target)?;
+            fs::remove_file(&self.tmp)?;
+        }
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempTarget {
+    fn drop(&mut self) {
+        if !self.committed && self.tmp.exists() {
+            if self.keep {
+                warn!("Export failed, keeping {:?} to resume it", self.tmp);
+                return;
+            }
+            warn!("Export failed, removing {:?}", self.tmp);
+            if let Err(e) = fs::remove_file(&self.tmp) {
+                error!("Failed to remove {:?}: {}", self.tmp, e);
+            }
+        }
+    }
+}
+
+/// Column names of the table, empty if the table does not exist
+fn table_columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare("select name from pragma_table_info(?1) order by cid")?;
+    let names = stmt.query_map([table], |r| r.get(0))?.collect();
+    names
+}
+
+/// Items in a chunk of [`ChunkStrategy::default`]
+pub const DEFAULT_CHUNK_SIZE: usize = 1000;
+
+/// How the items are split into chunks, each chunk being read by a separate task,
+/// [`DEFAULT_CHUNK_SIZE`] items by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkStrategy {
+    /// chunks of this many items
+    ByItemCount(usize),
+    /// chunks of roughly this many stored cells, useful when the number of columns varies
+    /// a lot between items. An item with more cells than this gets a chunk of its own
+    ByCellCount(usize),
+}
+
+impl ChunkStrategy {
+    fn validate(&self) -> Result<(), DataToolErrors> {
+        match self {
+            ChunkStrategy::ByItemCount(0) => Err(DataToolErrors::InvalidArgument(
+                "chunk size must be greater than zero".to_string(),
+            )),
+            ChunkStrategy::ByCellCount(0) => Err(DataToolErrors::InvalidArgument(
+                "cells per chunk must be greater than zero".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for ChunkStrategy {
+    fn default() -> Self {
+        ChunkStrategy::ByItemCount(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl From<usize> for ChunkStrategy {
+    fn from(chunk_size: usize) -> Self {
+        ChunkStrategy::ByItemCount(chunk_size)
+    }
+}
+
+/// Where the exported ids come from
+enum IdSource {
+    /// all the items, in the export order
+    Pager(IdPager),
+    /// the ids given to [`ExportOptions::ids`]
+    List(std::vec::IntoIter<i64>),
+}
+
+/// Splits the item ids, in the export order, into chunks according to the strategy
+struct Chunker {
+    order: IterOrder,
+    tables: Tables,
+    source: IdSource,
+    strategy: ChunkStrategy,
+    /// ids still to be skipped for the offset
+    skip: usize,
+    /// ids still to be exported, if limited
+    remaining: Option<usize>,
+    /// ids fetched for `ByCellCount`, with their cell counts, not yet in a chunk
+    counted: VecDeque<(i64, usize)>,
+}
+
+impl Chunker {
+    /// `ids` are the ids given to the options, all of them existing
+    fn new(
+        options: &ExportOptions,
+        strategy: ChunkStrategy,
+        ids: Option<Vec<i64>>,
+        tables: Tables,
+        snapshot: Option<Snapshot>,
+    ) -> Self {
+        let source = match ids {
+            Some(ids) => IdSource::List(ids.into_iter()),
+            None => {
+                let mut pager = IdPager::new(options.order.clone(), tables.clone());
+                // resuming an export in insertion order, after the last item written
+                if let Some(after_id) = options.after_id {
+                    pager.last = Some(vec![Value::Integer(after_id)]);
+                }
+                pager.max_id = snapshot.map(|s| s.max_item);
+                IdSource::Pager(pager)
+            }
+        };
+        Self {
+            order: options.order.clone(),
+            tables,
+            source,
+            strategy,
+            skip: options.offset,
+            remaining: options.limit,
+            counted: VecDeque::new(),
+        }
+    }
+
+    /// next `limit` ids, after the offset and within the limit
+    fn next_page(&mut self, conn: &Connection, limit: usize) -> rusqlite::Result<Vec<i64>> {
+        while self.skip > 0 {
+            let skipped = self.source_page(conn, self.skip.min(ITER_PAGE_SIZE))?.len();
+            if skipped == 0 {
+                return Ok(vec![]);
+            }
+            self.skip -= skipped;
+        }
+        let limit = self.remaining.map_or(limit, |r| r.min(limit));
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+        let page = self.source_page(conn, limit)?;
+        if let Some(r) = &mut self.remaining {
+            *r -= page.len();
+        }
+        Ok(page)
+    }
+
+    fn source_page(&mut self, conn: &Connection, limit: usize) -> rusqlite::Result<Vec<i64>> {
+        match &mut self.source {
+            IdSource::Pager(pager) => pager.next_page(conn, limit),
+            IdSource::List(ids) => Ok(ids.take(limit).collect()),
+        }
+    }
+
+    /// The items of a chunk, a range of ids if possible
+    fn chunk_ids(&self, ids: Vec<i64>) -> ChunkIds {
+        match self.source {
+            IdSource::Pager(_) => ChunkIds::from_page(&self.order, ids),
+            IdSource::List(_) => ChunkIds::List(ids),
+        }
+    }
+
+    /// ids of the next chunk, empty when there are none left
+    fn next_chunk(&mut self, conn: &Connection) -> rusqlite::Result<Vec<i64>> {
+        let max_cells = match self.strategy {
+            ChunkStrategy::ByItemCount(n) => return self.next_page(conn, n),
+            ChunkStrategy::ByCellCount(n) => n,
+        };
+        let mut ids = vec![];
+        let mut cells = 0;
+        loop {
+            if self.counted.is_empty() {
+                let page = self.next_page(conn, ITER_PAGE_SIZE)?;
+                if page.is_empty() {
+                    break;
+                }
+                let counts = cell_counts(conn, &self.tables, &page)?;
+                // an item without any cell still makes a row
+                self.counted.extend(
+                    page.into_iter()
+                        .map(|id| (id, counts.get(&id).copied().unwrap_or(0).max(1))),
+                );
+            }
+            let (id, count) = self.counted[0];
+            if !ids.is_empty() && cells + count > max_cells {
+                break;
+            }
+            ids.push(id);
+            cells += count;
+            self.counted.pop_front();
+        }
+        Ok(ids)
+    }
+}
+
+/// Rows read by an export worker, each with the id of its item. All the rows of the chunk,
+/// or a part of them with [`ExportOptions::max_chunk_bytes`]
+struct ChunkRows {
+    /// index of the chunk
+    index: usize,
+    /// the id and the cells of each item, `None` for the keys the item does not have
+    rows: Vec<(i64, Vec<Option<String>>)>,
+    /// rows left out by the row filter
+    skipped: usize,
+    /// time taken reading and preparing the rows
+    read: Duration,
+    /// the last rows of the chunk
+    last: bool,
+    /// tells the worker once the rows are written, set on the parts before the last one
+    written: Option<PartWritten>,
+}
+
+/// Tells the worker that a part of its chunk is written, so it reads the next one
+enum PartWritten {
+    #[cfg(feature = "async")]
+    Task(oneshot::Sender<()>),
+    Thread(mpsc::Sender<()>),
+}
+
+impl PartWritten {
+    fn done(self) {
+        // fails only if the worker stopped
+        match self {
+            #[cfg(feature = "async")]
+            PartWritten::Task(tx) => {
+                let _ = tx.send(());
+            }
+            PartWritten::Thread(tx) => {
+                let _ = tx.send(());
+            }
+        }
+    }
+}
+
+/// How far an export worker read its chunk, see [`ExportOptions::max_chunk_bytes`]
+#[derive(Default)]
+struct ChunkCursor {
+    /// ids of the chunk in its order, found on reading the first part
+    ids: Option<Vec<i64>>,
+    /// items read
+    done: usize,
+    /// estimated bytes of a row, from the ones read so far
+    row_bytes: Option<usize>,
+    /// time taken reading the parts
+    read: Duration,
+}
+
+/// Items read by a single export worker
+#[derive(Debug)]
+enum ChunkIds {
+    /// every item with `lo <= id <= hi`, in descending order if `desc` is set
+    Range { lo: i64, hi: i64, desc: bool },
+    /// exactly these items, in this order
+    List(Vec<i64>),
+}
+
+impl ChunkIds {
+    /// `ids` must be non-empty and sorted in `order`
+    fn from_page(order: &IterOrder, ids: Vec<i64>) -> Self {
+        let (first, last) = (ids[0], ids[ids.len() - 1]);
+        match order {
+            IterOrder::InsertionAsc => ChunkIds::Range {
+                lo: first,
+                hi: last,
+                desc: false,
+            },
+            IterOrder::InsertionDesc => ChunkIds::Range {
+                lo: last,
+                hi: first,
+                desc: true,
+            },
+            _ => ChunkIds::List(ids),
+        }
+    }
+}
+
+/// Hands the rows to `write_rows` as [`proc_ids`] does, or as [`long::long_rows`] does for a
+/// long export
+#[cfg(feature = "async")]
+async fn export_rows<F>(
+    db: &TableMapDb,
+    options: &ExportOptions,
+    chunk: ChunkStrategy,
+    columns: Vec<String>,
+    write_rows: F,
+) -> Result<ProcStats, DataToolErrors>
+where
+    F: FnMut(Vec<(i64, Vec<Option<String>>)>) -> Result<(), DataToolErrors>,
+{
+    match options.shape {
+        ExportShape::Wide => proc_ids(db, options, chunk, columns, write_rows).await,
+        ExportShape::Long => long::long_rows(db, options, write_rows).await,
+    }
+}
+
+/// Same as [`export_rows`], for the `_sync` exports
+fn export_rows_blocking<F>(
+    db: &TableMapDb,
+    options: &ExportOptions,
+    chunk: ChunkStrategy,
+    columns: Vec<String>,
+    write_rows: F,
+) -> Result<ProcStats, DataToolErrors>
+where
+    F: FnMut(Vec<(i64, Vec<Option<String>>)>) -> Result<(), DataToolErrors>,
+{
+    match options.shape {
+        ExportShape::Wide => proc_ids_blocking(db, options, chunk, columns, write_rows),
+        ExportShape::Long => long::long_rows_blocking(db, options, write_rows),
+    }
+}
+
+/// What the chunks of an export are read with, see [`read_setup`]
+struct ReadSetup {
+    stats: ProcStats,
+    reader: Arc<ChunkReader>,
+    chunker: Chunker,
+    /// number of chunks, if known
+    chunks_total: Option<usize>,
+    /// interrupts the readers once cancelled
+    #[cfg(feature = "async")]
+    _cancel_watch: Option<CancelWatch>,
+}
+
+/// Finds the ids to export and prepares reading the chunks, the same for [`proc_ids`] and
+/// [`proc_ids_blocking`]
+fn read_setup(
+    db: &TableMapDb,
+    options: &ExportOptions,
+    chunk: ChunkStrategy,
+    columns: Vec<String>,
+) -> Result<ReadSetup, DataToolErrors> {
+    db.ensure_read_indexes()?;
+    if let Some(mode) = options.checkpoint {
+        db.checkpoint(mode)?;
+    }
+    let merge = options.header_merge(&columns)?;
+    let mut stats = ProcStats {
+        spilled_keys: options.spilled_keys(db, &columns)?,
+        merged_headers: merge.merged(&columns),
+        max_revision: db.max_revision()?,
+        ..Default::default()
+    };
+    let id_scan = Instant::now();
+    let snapshot = match options.snapshot {
+        true => Some(db.snapshot_bounds()?),
+        false => None,
+    };
+    let in_range = |id: &i64| {
+        snapshot.is_none_or(|s| *id <= s.max_item) && options.after_id.is_none_or(|a| *id > a)
+    };
+    let ids = match &options.ids {
+        Some(ids) => {
+            let mut found = existing_ids(&db.connection, &db.tables, ids)?;
+            // the items stored since the export started are not found either
+            found.retain(in_range);
+            stats.ids_not_found = ids.iter().filter(|id| !found.contains(id)).count();
+            // a repeated id is exported once, in its first place
+            Some(
+                ids.iter()
+                    .copied()
+                    .filter(|id| found.remove(id))
+                    .collect::<Vec<_>>(),
+            )
+        }
+        None => None,
+    };
+    let mut ids = options.filter_ids(db, ids)?;
+    if let Some(ids) = &mut ids {
+        ids.retain(in_range);
+    }
+    stats.timings.id_scan += id_scan.elapsed();
+    let reader = Arc::new(ChunkReader {
+        pool: ReaderPool::new(
+            db.db_file(),
+            options
+                .reader_mode
+                .unwrap_or(OpenFlags::SQLITE_OPEN_READ_ONLY.into()),
+            options.readers(),
+            options.busy_timeout.unwrap_or(db.busy_timeout),
+            db.statement_cache_capacity,
+        ),
+        busy_retries: options.retries(),
+        max_bytes: options.max_chunk_bytes,
+        text_policy: db.text_policy,
+        tables: db.tables.clone(),
+        // the row filter and computed columns get all the columns of the item, the overflow
+        // column all the left out ones
+        only_columns: options.filters_columns()
+            && options.row_filter.is_none()
+            && options.computed.is_empty()
+            && options.overflow_column.is_none(),
+        overflow: options
+            .overflow_column
+            .as_ref()
+            .and_then(|name| columns.iter().position(|c| c == name))
+            .map(|i| (i, stats.spilled_keys.iter().cloned().collect())),
+        computed: columns
+            .iter()
+            .map(|c| options.computed.get(c).cloned())
+            .collect(),
+        transforms: columns
+            .iter()
+            .map(|c| options.transforms.get(c).map(|t| t.compile()).transpose())
+            .collect::<Result<_, _>>()?,
+        created_at: options
+            .include_created_at
+            .then(|| columns.iter().position(|c| c == CREATED_AT_COLUMN))
+            .flatten(),
+        merge: merge.merges().then_some(merge),
+        columns,
+        row_filter: options.row_filter.clone(),
+        max_cell: snapshot.map(|s| s.max_cell),
+    });
+    #[cfg(feature = "async")]
+    let cancel_watch = {
+        let pool = reader.pool.clone();
+        options.on_cancel(move || pool.interrupt())
+    };
+    let chunks_total = match chunk {
+        ChunkStrategy::ByItemCount(n) => {
+            let items = match (&ids, snapshot) {
+                (Some(ids), _) => ids.len(),
+                (None, Some(s)) => db.items_up_to(s.max_item)?,
+                (None, None) => db.how_many_items()?,
+            };
+            let items = match (&ids, options.after_id) {
+                (None, Some(after_id)) => items.saturating_sub(db.items_up_to(after_id)?),
+                _ => items,
+            };
+            let items = items.saturating_sub(options.offset);
+            Some(options.limit.map_or(items, |l| l.min(items)).div_ceil(n))
+        }
+        ChunkStrategy::ByCellCount(_) => None,
+    };
+    Ok(ReadSetup {
+        stats,
+        reader,
+        chunker: Chunker::new(options, chunk, ids, db.tables.clone(), snapshot),
+        chunks_total,
+        #[cfg(feature = "async")]
+        _cancel_watch: cancel_watch,
+    })
+}
+
+/// Finds the ids of the next chunk, with the span it is read in, `None` once there are no
+/// more
+fn next_chunk(
+    db: &TableMapDb,
+    setup: &mut ReadSetup,
+    spawned: usize,
+) -> Result<Option<(ChunkIds, Span)>, DataToolErrors> {
+    let id_scan = Instant::now();
+    let ids = setup.chunker.next_chunk(&db.connection)?;
+    setup.stats.timings.id_scan += id_scan.elapsed();
+    if ids.is_empty() {
+        return Ok(None);
+    }
+    match setup.chunks_total {
+        Some(nn) => trace!("processing ... {} of {}", spawned + 1, nn),
+        None => trace!("processing ... {}", spawned + 1),
+    }
+    // the read time is recorded once it is read
+    let span = debug_span!(
+        "export_chunk",
+        chunk_index = spawned,
+        items = ids.len(),
+        read_ms = field::Empty
+    );
+    Ok(Some((setup.chunker.chunk_ids(ids), span)))
+}
+
+/// Hands the rows of the chunks to `write_rows`, in the export order unless the export is
+/// unordered, and keeps the counts
+struct ChunkWriter<'a, F> {
+    options: &'a ExportOptions,
+    write_rows: F,
+    progress: ProgressReporter,
+    /// parts of the chunks read before the ones before them
+    pending: BTreeMap<usize, Vec<ChunkRows>>,
+    next_chunk: usize,
+    /// when the chunks started to be read
+    reading: Instant,
+}
+
+impl<'a, F> ChunkWriter<'a, F>
+where
+    F: FnMut(Vec<(i64, Vec<Option<String>>)>) -> Result<(), DataToolErrors>,
+{
+    fn new(options: &'a ExportOptions, write_rows: F, chunks_total: Option<usize>) -> Self {
+        Self {
+            options,
+            write_rows,
+            progress: ProgressReporter::new(options, chunks_total),
+            pending: BTreeMap::new(),
+            next_chunk: 0,
+            reading: Instant::now(),
+        }
+    }
+
+    fn chunk_read(&mut self, n: ChunkRows, stats: &mut ProcStats) -> Result<(), DataToolErrors> {
+        stats.skipped += n.skipped;
+        stats.timings.read += n.read;
+        stats.timings.read_wall = self.reading.elapsed();
+        if self.options.unordered {
+            return self.write(n, stats);
+        }
+        if n.index != self.next_chunk {
+            self.pending.entry(n.index).or_default().push(n);
+            return Ok(());
+        }
+        let mut done = n.last;
+        self.write(n, stats)?;
+        // the parts of the next chunks read meanwhile
+        while done {
+            self.next_chunk += 1;
+            let Some(parts) = self.pending.remove(&self.next_chunk) else {
+                break;
+            };
+            done = parts.last().is_some_and(|p| p.last);
+            for part in parts {
+                self.write(part, stats)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, part: ChunkRows, stats: &mut ProcStats) -> Result<(), DataToolErrors> {
+        let n = part.rows.len();
+        let write = Instant::now();
+        (self.write_rows)(part.rows)?;
+        stats.timings.write += write.elapsed();
+        self.progress.chunk_written(n, part.last);
+        if let Some(written) = part.written {
+            written.done();
+        }
+        Ok(())
+    }
+
+    /// Chunks read, waiting for the ones before them to be written. The ones with only some
+    /// parts read are still being read
+    fn waiting(&self) -> usize {
+        self.pending
+            .values()
+            .filter(|parts| parts.last().is_some_and(|p| p.last))
+            .count()
+    }
+}
+
+/// Pages through the ids in the export order and reads every chunk in a separate task.
+/// At most `max_concurrent` chunks are read or waiting to be written at a time,
+/// the next chunk is only spawned once one of them is written.
+/// The rows are handed to `write_rows` in the export order, regardless of which chunk
+/// finishes first, unless the export is unordered.
+#[cfg(feature = "async")]
+async fn proc_ids<F>(
+    db: &TableMapDb,
+    options: &ExportOptions,
+    chunk: ChunkStrategy,
+    columns: Vec<String>,
+    write_rows: F,
+) -> Result<ProcStats, DataToolErrors>
+where
+    F: FnMut(Vec<(i64, Vec<Option<String>>)>) -> Result<(), DataToolErrors>,
+{
+    let mut setup = read_setup(db, options, chunk, columns)?;
+    let max_concurrent = options.concurrency();
+    let mut writer = ChunkWriter::new(options, write_rows, setup.chunks_total);
+    let mut cols = JoinSet::new();
+    // the parts of the chunks before their last one, see `ExportOptions::max_chunk_bytes`
+    let (parts_tx, mut parts_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut spawned = 0;
+    let mut ids_left = true;
+    loop {
+        while ids_left && cols.len() + writer.waiting() < max_concurrent {
+            options.check_cancelled()?;
+            let Some((ids, span)) = next_chunk(db, &mut setup, spawned)? else {
+                ids_left = false;
+                break;
+            };
+            let read = setup.reader.clone().read(ids, spawned, parts_tx.clone());
+            cols.spawn(read.instrument(span));
+            spawned += 1;
+        }
+        // returning drops the join set, aborting the chunks still being read
+        let c = tokio::select! {
+            c = cols.join_next() => c,
+            Some(part) = parts_rx.recv() => {
+                writer.chunk_read(part, &mut setup.stats)?;
+                continue;
+            }
+            _ = options.cancelled() => return Err(DataToolErrors::Cancelled),
+        };
+        let Some(c) = c else {
+            break;
+        };
+        writer.chunk_read(c.map_err(worker_error)??, &mut setup.stats)?;
+    }
+    let mut stats = setup.stats;
+    stats.chunks = spawned;
+    writer.progress.finish().await;
+    Ok(stats)
+}
+
+/// Same as [`proc_ids`], reading the chunks on `max_readers` threads of their own instead of
+/// tokio tasks, for the `_sync` exports. The rows are the same, written in the same order
+fn proc_ids_blocking<F>(
+    db: &TableMapDb,
+    options: &ExportOptions,
+    chunk: ChunkStrategy,
+    columns: Vec<String>,
+    write_rows: F,
+) -> Result<ProcStats, DataToolErrors>
+where
+    F: FnMut(Vec<(i64, Vec<Option<String>>)>) -> Result<(), DataToolErrors>,
+{
+    let mut setup = read_setup(db, options, chunk, columns)?;
+    let max_concurrent = options.concurrency();
+    let reader = setup.reader.clone();
+    let (chunk_tx, chunk_rx) = mpsc::channel::<(usize, (ChunkIds, Span))>();
+    let chunk_rx = Mutex::new(chunk_rx);
+    let (done_tx, done_rx) = mpsc::channel();
+    let spawned = thread::scope(|scope| {
+        for _ in 0..options.readers() {
+            let done_tx = done_tx.clone();
+            let (reader, chunk_rx) = (&reader, &chunk_rx);
+            scope.spawn(move || {
+                // opened on the first chunk, and returned to the pool once done
+                let mut conn = None;
+                loop {
+                    let next = chunk_rx.lock().map(|rx| rx.recv());
+                    let Ok(Ok((cc, (ids, span)))) = next else {
+                        break;
+                    };
+                    // the chunks sent before the export was cancelled are left unread
+                    if options.check_cancelled().is_err() {
+                        break;
+                    }
+                    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let conn = match &mut conn {
+                            Some(conn) => conn,
+                            None => {
+                                conn.insert(reader.pool.take().map_err(|e| chunk_error(e, cc))?)
+                            }
+                        };
+                        span.in_scope(|| reader.read_blocking(conn, &ids, cc, &done_tx))
+                    }));
+                    if done_tx
+                        .send(res.unwrap_or_else(|p| Err(panic_error(p))))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                if let (Some(conn), Ok(mut idle)) = (conn, reader.pool.idle.lock()) {
+                    idle.push(conn);
+                }
+            });
+        }
+        drop(done_tx);
+        // returning drops the senders, so the readers stop once done with their chunk, and
+        // the parts waiting to be written, so the readers waiting on them stop too
+        let chunk_tx = chunk_tx;
+        let mut writer = ChunkWriter::new(options, write_rows, setup.chunks_total);
+        let mut spawned = 0;
+        let mut reading = 0;
+        let mut ids_left = true;
+        loop {
+            while ids_left && reading + writer.waiting() < max_concurrent {
+                options.check_cancelled()?;
+                let Some(chunk) = next_chunk(db, &mut setup, spawned)? else {
+                    ids_left = false;
+                    break;
+                };
+                // fails only once every reader stopped, which the receive below reports
+                let _ = chunk_tx.send((spawned, chunk));
+                spawned += 1;
+                reading += 1;
+            }
+            if reading == 0 {
+                break;
+            }
+            let n = loop {
+                match done_rx.recv_timeout(CANCEL_POLL) {
+                    Ok(n) => break n,
+                    // the chunks being read are interrupted, the readers then stop
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Err(e) = options.check_cancelled() {
+                            reader.pool.interrupt();
+                            return Err(e);
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Err(DataToolErrors::GenericError(
+                            "Export workers stopped".to_string(),
+                        ))
+                    }
+                }
+            };
+            let n = n?;
+            if n.last {
+                reading -= 1;
+            }
+            options.check_cancelled()?;
+            writer.chunk_read(n, &mut setup.stats)?;
+        }
+        Ok::<_, DataToolErrors>(spawned)
+    })?;
+    let mut stats = setup.stats;
+    stats.chunks = spawned;
+    Ok(stats)
+}
+
+/// Counts kept by [`proc_ids`] for the summary
+#[derive(Default)]
+struct ProcStats {
+    /// rows left out by the row filter
+    skipped: usize,
+    ids_not_found: usize,
+    /// keys left out by [`ExportOptions::max_columns`]
+    spilled_keys: Vec<String>,
+    /// see [`ExportSummary::merged_headers`]
+    merged_headers: Vec<(String, Vec<String>)>,
+    /// see [`ExportSummary::max_revision`]
+    max_revision: i64,
+    chunks: usize,
+    timings: ExportTimings,
+}
+
+/// An SQLite error reading the chunk, saying which one
+fn chunk_error(e: rusqlite::Error, cc: usize) -> DataToolErrors {
+    match DataToolErrors::from(e) {
+        DataToolErrors::Sqlite { code, message } => DataToolErrors::Sqlite {
+            code,
+            message: format!("Failed to read chunk {}: {}", cc, message),
+        },
+        e => e,
+    }
+}
+
+/// Error returned when an export worker failed, e.g. if a computed column panicked
+#[cfg(feature = "async")]
+fn worker_error(e: JoinError) -> DataToolErrors {
+    if !e.is_panic() {
+        return DataToolErrors::GenericError(format!("Export worker failed: {}", e));
+    }
+    panic_error(e.into_panic())
+}
+
+/// Error returned when an export worker panicked
+fn panic_error(panic: Box<dyn Any + Send>) -> DataToolErrors {
+    let msg = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+    DataToolErrors::GenericError(format!("Export worker panicked: {}", msg))
+}
+
+/// What the export workers need to read the chunks, shared by all of them
+struct ChunkReader {
+    pool: Arc<ReaderPool>,
+    tables: Tables,
+    columns: Vec<String>,
+    /// do not read the columns that are not exported
+    only_columns: bool,
+    row_filter: Option<RowFilter>,
+    /// function computing each of the columns, for the computed ones
+    computed: Vec<Option<ComputedFn>>,
+    /// transform of each of the columns
+    transforms: Vec<Option<TransformFn>>,
+    /// index of the overflow column and the keys packed into it
+    overflow: Option<(usize, HashSet<String>)>,
+    /// index of the creation time column, if included
+    created_at: Option<usize>,
+    /// the columns merged into one, see [`ExportOptions::sanitize_headers`]
+    merge: Option<HeaderMerge>,
+    /// see [`ExportOptions::busy_retries`]
+    busy_retries: usize,
+    /// see [`ExportOptions::max_chunk_bytes`]
+    max_bytes: Option<usize>,
+    /// see [`TableMapDb::text_policy`]
+    text_policy: TextPolicy,
+    /// the last cell read, see [`ExportOptions::snapshot`]
+    max_cell: Option<i64>,
+}
+
+impl ChunkReader {
+    /// Reads the rows of the chunk, returned in the chunk's order. The parts before the last
+    /// one are sent to `parts`, waiting for each to be written before reading the next. A part
+    /// is read again if the db is locked
+    #[cfg(feature = "async")]
+    async fn read(
+        self: Arc<Self>,
+        chunk: ChunkIds,
+        cc: usize,
+        parts: tokio::sync::mpsc::UnboundedSender<ChunkRows>,
+    ) -> Result<ChunkRows, DataToolErrors> {
+        let mut cursor = ChunkCursor::default();
+        let mut tries = 0;
+        loop {
+            // the connection is given back before waiting, the chunks before may need it
+            let mut part = loop {
+                let conn = self.pool.get().await.map_err(|e| chunk_error(e, cc))?;
+                match self.read_part(&conn, &chunk, cc, &mut cursor) {
+                    Err(e) if self.retry(&e, &mut tries, cc) => {
+                        drop(conn);
+                        tokio::time::sleep(self.retry_wait(tries)).await;
+                    }
+                    res => break res?,
+                }
+            };
+            if part.last {
+                return Ok(part);
+            }
+            let (tx, rx) = oneshot::channel();
+            part.written = Some(PartWritten::Task(tx));
+            if parts.send(part).is_err() || rx.await.is_err() {
+                return Err(writer_stopped());
+            }
+        }
+    }
+
+    /// Same as [`ChunkReader::read`], on the current thread, the parts are sent to `parts`
+    fn read_blocking(
+        &self,
+        conn: &Connection,
+        chunk: &ChunkIds,
+        cc: usize,
+        parts: &mpsc::Sender<Result<ChunkRows, DataToolErrors>>,
+    ) -> Result<ChunkRows, DataToolErrors> {
+        let mut cursor = ChunkCursor::default();
+        let mut tries = 0;
+        loop {
+            let mut part = loop {
+                match self.read_part(conn, chunk, cc, &mut cursor) {
+                    Err(e) if self.retry(&e, &mut tries, cc) => {
+                        thread::sleep(self.retry_wait(tries));
+                    }
+                    res => break res?,
+                }
+            };
+            if part.last {
+                return Ok(part);
+            }
+            let (tx, rx) = mpsc::channel();
+            part.written = Some(PartWritten::Thread(tx));
+            if parts.send(Ok(part)).is_err() || rx.recv().is_err() {
+                return Err(writer_stopped());
+            }
+        }
+    }
+
+    /// Whether the chunk is to be read again after `e`, counting the try
+    fn retry(&self, e: &DataToolErrors, tries: &mut usize, cc: usize) -> bool {
+        if !e.is_busy() || *tries >= self.busy_retries {
+            return false;
+        }
+        *tries += 1;
+        warn!(
+            "Chunk {} could not be read, reading it again in {:?} ({} of {}): {}",
+            cc,
+            self.retry_wait(*tries),
+            tries,
+            self.busy_retries,
+            e
+        );
+        true
+    }
+
+    /// Wait before the given try, doubled on each one
+    fn retry_wait(&self, tries: usize) -> Duration {
+        BUSY_BACKOFF * 2u32.pow(tries.saturating_sub(1).min(10) as u32)
+    }
+
+    /// Reads the next part of the chunk, all of it without [`ExportOptions::max_chunk_bytes`].
+    /// The cursor only moves past the part once it is read
+    fn read_part(
+        &self,
+        conn: &Connection,
+        chunk: &ChunkIds,
+        cc: usize,
+        cursor: &mut ChunkCursor,
+    ) -> Result<ChunkRows, DataToolErrors> {
+        let map_err = |e| chunk_error(e, cc);
+        let t = Instant::now();
+        if cursor.ids.is_none() {
+            cursor.ids = Some(match chunk {
+                &ChunkIds::Range { lo, hi, desc } => {
+                    // items without any data are only in item_data
+                    let mut ids = item_ids_range(conn, &self.tables, lo, hi).map_err(map_err)?;
+                    if desc {
+                        ids.reverse();
+                    }
+                    ids
+                }
+                ChunkIds::List(ids) => ids.clone(),
+            });
+        }
+        let ids = cursor.ids.as_deref().unwrap_or_default();
+        let mut res_vec = vec![];
+        let mut skipped = 0;
+        let mut bytes = 0;
+        let mut done = cursor.done;
+        let mut row_bytes = cursor.row_bytes;
+        while done < ids.len() {
+            let left = ids.len() - done;
+            // as many items as are estimated to fit under the cap
+            let n = match (self.max_bytes, row_bytes) {
+                (None, _) => left,
+                (Some(_), None) => left.min(FIRST_PART_ITEMS),
+                (Some(max), Some(row)) => (max.saturating_sub(bytes) / row).clamp(1, left),
+            };
+            let part = &ids[done..done + n];
+            for (id, row) in self.read_rows(conn, chunk, part).map_err(map_err)? {
+                match row {
+                    Some(row) => {
+                        bytes += estimated_bytes(&row);
+                        res_vec.push((id, row));
+                    }
+                    None => skipped += 1,
+                }
+            }
+            done += n;
+            let Some(max) = self.max_bytes else {
+                continue;
+            };
+            row_bytes = Some(bytes.div_ceil(done - cursor.done).max(1));
+            if bytes >= max {
+                break;
+            }
+        }
+        let read = t.elapsed();
+        let last = done == ids.len();
+        cursor.done = done;
+        cursor.row_bytes = row_bytes;
+        cursor.read += read;
+        trace!("done processing: {}, {:2}", cc, cursor.read.as_secs_f32());
+        Span::current().record("read_ms", cursor.read.as_millis() as u64);
+        Ok(ChunkRows {
+            index: cc,
+            rows: res_vec,
+            skipped,
+            read,
+            last,
+            written: None,
+        })
+    }
+
+    /// Rows of the items `ids`, a part of the chunk in its order, `None` for the ones left
+    /// out by the row filter
+    fn read_rows(
+        &self,
+        conn: &Connection,
+        chunk: &ChunkIds,
+        ids: &[i64],
+    ) -> rusqlite::Result<Vec<(i64, Option<Row>)>> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let keys = self.only_columns.then_some(&self.columns[..]);
+        let mut im_dd = match chunk {
+            // the items of a range are the ones between the first and the last of the part
+            ChunkIds::Range { .. } => {
+                let (first, last) = (ids[0], ids[ids.len() - 1]);
+                read_items_range(
+                    conn,
+                    &self.tables,
+                    first.min(last),
+                    first.max(last),
+                    keys,
+                    self.max_cell,
+                    self.text_policy,
+                )?
+            }
+            ChunkIds::List(_) => read_items(
+                conn,
+                &self.tables,
+                ids,
+                keys,
+                self.max_cell,
+                self.text_policy,
+            )?,
+        };
+        let created_at = match self.created_at {
+            Some(_) => created_at_of(conn, &self.tables, ids)?,
+            None => HashMap::new(),
+        };
+        let mut rows = Vec::with_capacity(ids.len());
+        for id in ids.iter() {
+            let im = im_dd.swap_remove(id).unwrap_or_default();
+            if let Some(filter) = &self.row_filter {
+                if !(filter.0)(&im) {
+                    rows.push((*id, None));
+                    continue;
+                }
+            }
+            let mut prep_cols: Vec<_> = self
+                .columns
+                .iter()
+                .zip(self.computed.iter())
+                .zip(self.transforms.iter())
+                .map(|((k, c), t)| {
+                    let v = match c {
+                        Some(c) => Some((c.0)(&im)),
+                        None => im.get(k).cloned(),
+                    };
+                    match (v, t) {
+                        (Some(v), Some(t)) if !v.is_empty() => Some((t.0)(&v)),
+                        (v, _) => v,
+                    }
+                })
+                .collect();
+            if let Some((i, spilled)) = &self.overflow {
+                let cells: serde_json::Map<_, _> = im
+                    .iter()
+                    .filter(|(k, _)| spilled.contains(*k))
+                    .map(|(k, v)| (k.clone(), v.as_str().into()))
+                    .collect();
+                prep_cols[*i] =
+                    (!cells.is_empty()).then(|| serde_json::Value::Object(cells).to_string());
+            }
+            if let Some(i) = self.created_at {
+                prep_cols[i] = created_at.get(id).map(i64::to_string);
+            }
+            if let Some(merge) = &self.merge {
+                prep_cols = merge.row(prep_cols);
+            }
+            rows.push((*id, Some(prep_cols)));
+        }
+        Ok(rows)
+    }
+}
+
+/// Cells of an exported row, `None` for the keys the item does not have
+type Row = Vec<Option<String>>;
+
+/// Estimated bytes held by a row, see [`ExportOptions::max_chunk_bytes`]
+fn estimated_bytes(row: &[Option<String>]) -> usize {
+    mem::size_of::<(i64, Vec<Option<String>>)>()
+        + row
+            .iter()
+            .map(|v| mem::size_of::<Option<String>>() + v.as_ref().map_or(0, String::len))
+            .sum::<usize>()
+}
+
+/// Error of a worker whose part was dropped without being written, the export failed
+fn writer_stopped() -> DataToolErrors {
+    DataToolErrors::GenericError("Export writer stopped".to_string())
+}
+
+/// Ids of the items between `lo` and `hi`, inclusive, ascending
+fn item_ids_range(
+    conn: &Connection,
+    tables: &Tables,
+    lo: i64,
+    hi: i64,
+) -> rusqlite::Result<Vec<i64>> {
+    let mut stmt = conn.prepare_cached(
+        &tables.sql("select id from item_data where id between ?1 and ?2 order by id"),
+    )?;
+    let ids = stmt.query_map([lo, hi], |r| r.get(0))?.collect();
+    ids
+}
+
+/// Creation time of each of the items, items without any are left out
+fn created_at_of(
+    conn: &Connection,
+    tables: &Tables,
+    ids: &[i64],
+) -> rusqlite::Result<HashMap<i64, i64>> {
+    let mut stmt = conn.prepare_cached(&tables.sql(
+        "select id, created_at from item_data where id in rarray(?1) and created_at is not null",
+    ))?;
+    let created_at = stmt
+        .query_map([id_array(ids)], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect();
+    created_at
+}
+
+/// Number of stored cells of each of the items, items without any are left out
+fn cell_counts(
+    conn: &Connection,
+    tables: &Tables,
+    ids: &[i64],
+) -> rusqlite::Result<HashMap<i64, usize>> {
+    let mut stmt = conn.prepare_cached(&tables.sql(
+        "select item_id, count(*) from data_columns where item_id in rarray(?1) group by item_id",
+    ))?;
+    let counts = stmt
+        .query_map([id_array(ids)], |r| {
+            Ok((r.get(0)?, r.get::<_, i64>(1)? as usize))
+        })?
+        .collect();
+    counts
+}
@@ -0,0 +1,295 @@
+//! Arrow record batches of the items, needs the `arrow` feature
+
+use super::{column_types, proc_ids, ColumnType, ExportOptions, ExportSummary};
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use arrow_array::builder::{Float64Builder, Int64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use futures_core::Stream;
+use rusqlite::types::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+impl TableMapDb {
+    /// Reads the items as Arrow record batches, a batch per chunk, with the columns
+    /// [`dump_csv`](super::dump_csv) would export. Columns are strings, unless typed by the
+    /// options, the item id, if included, a 64 bit integer. Keys an item does not have are
+    /// null. All the batches have the same schema, chunks without any row are skipped.
+    ///
+    /// The chunks are only read while the stream is polled. Rows with a value that does not
+    /// parse as the type of its column are failed rows, left out of the batch, or ending the
+    /// stream with an error if the export is strict
+    pub fn to_record_batches(
+        &mut self,
+        mut options: ExportOptions,
+        arrow_options: ExportArrowOptions,
+    ) -> RecordBatches<'_> {
+        let (chunk, column_order) = options.take_chunk_and_priority();
+        let batches = Rc::new(RefCell::new(VecDeque::new()));
+        let queue = batches.clone();
+        let driver = async move {
+            chunk.validate()?;
+            options.check_wide("to_record_batches")?;
+            let Some(layout) = BatchLayout::new(
+                self,
+                column_order,
+                &options,
+                arrow_options.infer_types,
+                &arrow_options.column_types,
+            )?
+            else {
+                return Ok(());
+            };
+            // only used to count failed rows, the same way the exports do
+            let mut summary = ExportSummary::default();
+            proc_ids(self, &options, chunk, layout.columns.clone(), |n| {
+                let batch = layout.batch(&n, &options, &mut summary)?;
+                if batch.num_rows() > 0 {
+                    queue.borrow_mut().push_back(Ok(batch));
+                }
+                Ok(())
+            })
+            .await?;
+            Ok(())
+        };
+        RecordBatches {
+            driver: Some(Box::pin(driver)),
+            batches,
+        }
+    }
+}
+
+type BatchDriver<'a> = Pin<Box<dyn Future<Output = Result<(), DataToolErrors>> + 'a>>;
+
+/// Stream of the record batches of [`TableMapDb::to_record_batches`]
+pub struct RecordBatches<'a> {
+    /// reads the chunks, `None` once done
+    driver: Option<BatchDriver<'a>>,
+    batches: Rc<RefCell<VecDeque<Result<RecordBatch, DataToolErrors>>>>,
+}
+
+impl Stream for RecordBatches<'_> {
+    type Item = Result<RecordBatch, DataToolErrors>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // more chunks are only read once the batches already read are taken
+        if this.batches.borrow().is_empty() {
+            if let Some(driver) = &mut this.driver {
+                if let Poll::Ready(res) = driver.as_mut().poll(cx) {
+                    this.driver = None;
+                    if let Err(e) = res {
+                        this.batches.borrow_mut().push_back(Err(e));
+                    }
+                }
+            }
+        }
+        match this.batches.borrow_mut().pop_front() {
+            Some(batch) => Poll::Ready(Some(batch)),
+            None if this.driver.is_none() => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Options of [`TableMapDb::to_record_batches`]
+#[derive(Debug, Clone, Default)]
+pub struct ExportArrowOptions {
+    infer_types: bool,
+    column_types: HashMap<String, ColumnType>,
+}
+
+impl ExportArrowOptions {
+    /// Type the columns by their values, the same as [`ExportDbOptions::infer_types`]
+    ///
+    /// [`ExportDbOptions::infer_types`]: super::ExportDbOptions::infer_types
+    pub fn infer_types(mut self, infer_types: bool) -> Self {
+        self.infer_types = infer_types;
+        self
+    }
+
+    /// Pin the type of a column, whether types are inferred or not
+    pub fn column_type(mut self, column: impl Into<String>, column_type: ColumnType) -> Self {
+        self.column_types.insert(column.into(), column_type);
+        self
+    }
+}
+
+/// The exported columns and the schema of their batches
+pub(super) struct BatchLayout {
+    /// the exported data columns
+    pub columns: Vec<String>,
+    types: Vec<ColumnType>,
+    /// names of the data columns in the batches, without the id column
+    headers: Vec<String>,
+    pub schema: SchemaRef,
+}
+
+impl BatchLayout {
+    /// `None` if there are no columns to export
+    pub fn new(
+        db: &TableMapDb,
+        column_order: Vec<String>,
+        options: &ExportOptions,
+        infer_types: bool,
+        pinned: &HashMap<String, ColumnType>,
+    ) -> Result<Option<Self>, DataToolErrors> {
+        let columns = options.select_columns(db, column_order)?;
+        let out_columns = options.output_columns(&columns)?;
+        if out_columns.is_empty() {
+            return Ok(None);
+        }
+        let types = column_types(db, &columns, options, infer_types, pinned)?;
+        let fields = out_columns
+            .iter()
+            .zip(
+                options
+                    .include_id
+                    .then_some(ColumnType::Integer)
+                    .iter()
+                    .chain(&types),
+            )
+            .map(|(c, ty)| Field::new(c, ty.arrow(), true))
+            .collect::<Vec<_>>();
+        Ok(Some(Self {
+            headers: out_columns[options.include_id as usize..].to_vec(),
+            columns,
+            types,
+            schema: Arc::new(Schema::new(fields)),
+        }))
+    }
+
+    /// Types of the columns of the batches
+    #[cfg(feature = "parquet")]
+    pub fn out_types(&self, options: &ExportOptions) -> Vec<ColumnType> {
+        options
+            .include_id
+            .then_some(ColumnType::Integer)
+            .into_iter()
+            .chain(self.types.iter().copied())
+            .collect()
+    }
+
+    /// The batch of the rows of a chunk, failed rows are recorded in the summary
+    pub fn batch(
+        &self,
+        rows: &[(i64, Vec<Option<String>>)],
+        options: &ExportOptions,
+        summary: &mut ExportSummary,
+    ) -> Result<RecordBatch, DataToolErrors> {
+        let mut batch = BatchBuilder::new(&self.types, options.include_id, rows.len());
+        for (id, row) in rows.iter() {
+            options.check_cancelled()?;
+            // a row is checked first, so a failed row leaves nothing in the batch
+            let res = check_row(row, &self.types, &self.headers);
+            if res.is_ok() {
+                batch.append(*id, row);
+            }
+            summary.record(res, options.strict)?;
+        }
+        RecordBatch::try_new(self.schema.clone(), batch.finish())
+            .map_err(|e| DataToolErrors::GenericError(e.to_string()))
+    }
+}
+
+impl ColumnType {
+    fn arrow(&self) -> DataType {
+        match self {
+            ColumnType::Text => DataType::Utf8,
+            ColumnType::Integer => DataType::Int64,
+            ColumnType::Real => DataType::Float64,
+        }
+    }
+}
+
+/// Fails if a value of a typed column does not parse
+fn check_row(
+    row: &[Option<String>],
+    types: &[ColumnType],
+    columns: &[String],
+) -> Result<(), String> {
+    for ((v, ty), c) in row.iter().zip(types).zip(columns) {
+        if let Some(v) = v {
+            if *ty != ColumnType::Text && matches!(ty.value(v), Value::Text(_)) {
+                return Err(format!(
+                    "{:?} is not a valid {} for column {:?}",
+                    v,
+                    ty.sql(),
+                    c
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds the columns of a record batch, row by row
+struct BatchBuilder {
+    id: Option<Int64Builder>,
+    columns: Vec<ColumnBuilder>,
+}
+
+enum ColumnBuilder {
+    Text(StringBuilder),
+    Integer(Int64Builder),
+    Real(Float64Builder),
+}
+
+impl BatchBuilder {
+    fn new(types: &[ColumnType], include_id: bool, rows: usize) -> Self {
+        Self {
+            id: include_id.then(|| Int64Builder::with_capacity(rows)),
+            columns: types
+                .iter()
+                .map(|ty| match ty {
+                    // the size of the values is not known, the builder grows as needed
+                    ColumnType::Text => ColumnBuilder::Text(StringBuilder::with_capacity(rows, 0)),
+                    ColumnType::Integer => {
+                        ColumnBuilder::Integer(Int64Builder::with_capacity(rows))
+                    }
+                    ColumnType::Real => ColumnBuilder::Real(Float64Builder::with_capacity(rows)),
+                })
+                .collect(),
+        }
+    }
+
+    /// Adds a row, already checked by [`check_row`]
+    fn append(&mut self, id: i64, row: &[Option<String>]) {
+        if let Some(b) = &mut self.id {
+            b.append_value(id);
+        }
+        for (b, v) in self.columns.iter_mut().zip(row) {
+            match b {
+                ColumnBuilder::Text(b) => b.append_option(v.as_deref()),
+                ColumnBuilder::Integer(b) => {
+                    match v.as_deref().map(|v| ColumnType::Integer.value(v)) {
+                        Some(Value::Integer(i)) => b.append_value(i),
+                        _ => b.append_null(),
+                    }
+                }
+                ColumnBuilder::Real(b) => match v.as_deref().map(|v| ColumnType::Real.value(v)) {
+                    Some(Value::Real(f)) => b.append_value(f),
+                    _ => b.append_null(),
+                },
+            }
+        }
+    }
+
+    fn finish(self) -> Vec<ArrayRef> {
+        self.id
+            .map(|mut b| Arc::new(b.finish()) as ArrayRef)
+            .into_iter()
+            .chain(self.columns.into_iter().map(|b| match b {
+                ColumnBuilder::Text(mut b) => Arc::new(b.finish()) as ArrayRef,
+                ColumnBuilder::Integer(mut b) => Arc::new(b.finish()) as ArrayRef,
+                ColumnBuilder::Real(mut b) => Arc::new(b.finish()) as ArrayRef,
+            }))
+            .collect()
+    }
+}
@@ -0,0 +1,299 @@
+//! PostgreSQL `COPY` export, a data file and the schema to load it into
+
+use super::sql::{number, Dialect};
+use super::{
+    column_types, proc_ids, ColumnType, ExportOptions, ExportSummary, ExportTimings, OverwriteMode,
+    TempTarget,
+};
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::warn;
+
+/// Exports the items as a data file for PostgreSQL's `COPY FROM`, with the columns
+/// [`dump_csv`](super::dump_csv) would export, and writes the `CREATE TABLE` of the table to
+/// load it into to a schema file, see [`ExportCopyOptions::schema_file`]. The schema file
+/// ends with the `\copy` command loading the data file, as a comment.
+///
+/// Unlike the CSV export, keys an item does not have are `NULL`, while empty values are
+/// empty strings, as PostgreSQL tells them apart. Empty values of typed columns are `NULL`.
+/// Rows with a value that does not parse as the type of its column, or with a NUL
+/// character, which PostgreSQL strings can not hold, are failed rows. When appending to a
+/// file that already has rows, the header is not written again and the schema file is
+/// replaced
+pub async fn dump_copy(
+    db: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    copy_options: ExportCopyOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let t = Instant::now();
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    chunk.validate()?;
+    options.check_wide("dump_copy")?;
+    if copy_options.table_name.is_empty() {
+        return Err(DataToolErrors::InvalidArgument(
+            "the table name can not be empty".to_string(),
+        ));
+    }
+    let schema_file = copy_options
+        .schema_file
+        .clone()
+        .unwrap_or_else(|| file_name.with_extension("schema.sql"));
+    if schema_file == file_name {
+        return Err(DataToolErrors::InvalidArgument(format!(
+            "the schema can not be written to the data file {:?}",
+            file_name
+        )));
+    }
+    let schema_mode = match options.overwrite {
+        OverwriteMode::Append => OverwriteMode::Overwrite,
+        mode => mode,
+    };
+    let schema_target = TempTarget::new(&schema_file, schema_mode)?;
+    let target = TempTarget::new(file_name, options.overwrite)?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(target.path())?;
+    // appending to a file that already has rows, so it also has the header
+    let has_header = target.append && file.metadata()?.len() > 0;
+    let mut writer = io::BufWriter::new(file);
+    let keys_scan = Instant::now();
+    let columns = options.select_columns(db, column_order)?;
+    let keys_scan = keys_scan.elapsed();
+    if columns.is_empty() {
+        // a table needs at least one column
+        warn!("No columns to export, writing no rows and no schema");
+        writer.flush()?;
+        drop(writer);
+        target.commit()?;
+        return Ok(ExportSummary::empty(t));
+    }
+    let types = column_types(
+        db,
+        &columns,
+        &options,
+        copy_options.infer_types,
+        &copy_options.column_types,
+    )?;
+    let out_columns = options.output_columns(&columns)?;
+    let mut out_types = types.clone();
+    if options.include_id {
+        out_types.insert(0, ColumnType::Integer);
+    }
+    let format = copy_options.format;
+    let mut line = String::new();
+    if format == CopyFormat::Csv && !has_header {
+        for (i, c) in out_columns.iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            format.string(&mut line, c);
+        }
+        line.push('\n');
+        writer.write_all(line.as_bytes())?;
+    }
+    let mut summary = ExportSummary::new(out_columns);
+    summary.column_types = out_types;
+    let stats = proc_ids(db, &options, chunk, columns, |n| {
+        for (id, row) in n.iter() {
+            options.check_cancelled()?;
+            line.clear();
+            let id = options.include_id.then_some(*id);
+            let res = format.row(&mut line, id, row, &types);
+            if res.is_ok() {
+                writer.write_all(line.as_bytes())?;
+            }
+            summary.record(res, options.strict)?;
+        }
+        Ok(())
+    })
+    .await?;
+    summary.rows_skipped_by_filter = stats.skipped;
+    summary.ids_not_found = stats.ids_not_found;
+    summary.max_revision = stats.max_revision;
+    summary.chunks = stats.chunks;
+    summary.timings = ExportTimings {
+        keys_scan,
+        ..stats.timings
+    };
+    summary.spilled_keys = stats.spilled_keys;
+    summary.merged_headers = stats.merged_headers;
+    writer.flush()?;
+    drop(writer);
+    let schema = copy_options.schema(file_name, &summary.columns, &summary.column_types);
+    fs::write(schema_target.path(), schema)?;
+    target.commit()?;
+    schema_target.commit()?;
+    summary.elapsed = t.elapsed();
+    summary.report();
+    Ok(summary)
+}
+
+/// Options of the PostgreSQL `COPY` export, [`dump_copy`]
+#[derive(Debug, Clone)]
+pub struct ExportCopyOptions {
+    format: CopyFormat,
+    table_name: String,
+    schema_file: Option<PathBuf>,
+    infer_types: bool,
+    column_types: HashMap<String, ColumnType>,
+}
+
+impl Default for ExportCopyOptions {
+    fn default() -> Self {
+        Self {
+            format: Default::default(),
+            table_name: "products".to_string(),
+            schema_file: None,
+            infer_types: false,
+            column_types: HashMap::new(),
+        }
+    }
+}
+
+impl ExportCopyOptions {
+    /// Format of the data file, CSV by default
+    pub fn format(mut self, format: CopyFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Name of the table in the schema, `products` by default
+    pub fn table_name(mut self, table_name: impl Into<String>) -> Self {
+        self.table_name = table_name.into();
+        self
+    }
+
+    /// Where the schema is written, by default next to the data file, with the extension
+    /// replaced by `schema.sql`. It follows the overwrite mode of the export, but is
+    /// replaced when appending
+    pub fn schema_file(mut self, schema_file: impl Into<PathBuf>) -> Self {
+        self.schema_file = Some(schema_file.into());
+        self
+    }
+
+    /// Type the columns by their values, the same as [`ExportDbOptions::infer_types`]
+    ///
+    /// [`ExportDbOptions::infer_types`]: super::ExportDbOptions::infer_types
+    pub fn infer_types(mut self, infer_types: bool) -> Self {
+        self.infer_types = infer_types;
+        self
+    }
+
+    /// Pin the type of a column, whether types are inferred or not
+    pub fn column_type(mut self, column: impl Into<String>, column_type: ColumnType) -> Self {
+        self.column_types.insert(column.into(), column_type);
+        self
+    }
+
+    /// The `CREATE TABLE`, and the command loading `file_name` into it
+    fn schema(&self, file_name: &Path, columns: &[String], types: &[ColumnType]) -> String {
+        let dialect = Dialect::Postgres;
+        let table = dialect.ident(&self.table_name);
+        let file = file_name
+            .file_name()
+            .unwrap_or(file_name.as_os_str())
+            .to_string_lossy()
+            .replace('\'', "''");
+        let column_list = columns
+            .iter()
+            .map(|c| dialect.ident(c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "{}\n-- \\copy {} ({}) FROM '{}' WITH ({})\n",
+            dialect.create_table(&table, columns, types, false),
+            table,
+            column_list,
+            file,
+            self.format.copy_options()
+        )
+    }
+}
+
+/// Format of a `COPY` export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CopyFormat {
+    /// `FORMAT csv` with a header, `NULL` is an unquoted empty field, empty strings are `""`
+    #[default]
+    Csv,
+    /// `FORMAT text`, tab separated without a header, `NULL` is `\N`
+    Text,
+}
+
+impl CopyFormat {
+    fn copy_options(self) -> &'static str {
+        match self {
+            CopyFormat::Csv => "FORMAT csv, HEADER true",
+            CopyFormat::Text => "FORMAT text",
+        }
+    }
+
+    /// Writes the row as a line of the data file
+    fn row(
+        self,
+        out: &mut String,
+        id: Option<i64>,
+        row: &[Option<String>],
+        types: &[ColumnType],
+    ) -> Result<(), String> {
+        if row.iter().flatten().any(|v| v.contains('\0')) {
+            return Err("a value has a NUL character, which PostgreSQL can not hold".to_string());
+        }
+        let sep = match self {
+            CopyFormat::Csv => ',',
+            CopyFormat::Text => '\t',
+        };
+        if let Some(id) = id {
+            out.push_str(&id.to_string());
+        }
+        for (i, (v, ty)) in row.iter().zip(types).enumerate() {
+            if i > 0 || id.is_some() {
+                out.push(sep);
+            }
+            match (v.as_deref(), ty) {
+                (Some(v), ColumnType::Text) => self.string(out, v),
+                (Some(v), ty) if !v.is_empty() => out.push_str(&number(v, *ty)?),
+                _ => self.null(out),
+            }
+        }
+        out.push('\n');
+        Ok(())
+    }
+
+    fn null(self, out: &mut String) {
+        match self {
+            CopyFormat::Csv => {}
+            CopyFormat::Text => out.push_str("\\N"),
+        }
+    }
+
+    fn string(self, out: &mut String, v: &str) {
+        match self {
+            // quoted if it could be read as NULL, or as the end of the data
+            CopyFormat::Csv if v.is_empty() || v == "\\." || v.contains([',', '"', '\n', '\r']) => {
+                out.push('"');
+                out.push_str(&v.replace('"', "\"\""));
+                out.push('"');
+            }
+            CopyFormat::Csv => out.push_str(v),
+            CopyFormat::Text => {
+                for c in v.chars() {
+                    match c {
+                        '\\' => out.push_str("\\\\"),
+                        '\t' => out.push_str("\\t"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        c => out.push(c),
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! CSV of a [`DiffReport`], in long format
+
+use super::{OverwriteMode, TempTarget};
+use crate::diff::DiffReport;
+use crate::errors::DataToolErrors;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::info;
+
+/// Writes the report as a CSV file with a row per change, with the columns `item`, `key`,
+/// `old`, `new` and `change_type`. Added and removed items have a row of their own, with an
+/// empty key and `item_added` or `item_removed` as the change. The keys of the changed
+/// items are `key_added`, `key_removed` or `value_changed`, a missing value being empty.
+/// A report of [`counts_only`](crate::DiffOptions::counts_only) has a `value_changed` row
+/// with an empty key per changed item.
+///
+/// Returns the number of rows written. When appending to a file that already has rows, the
+/// header is not written again
+pub fn dump_diff_csv(
+    report: &DiffReport,
+    file_name: &Path,
+    overwrite: OverwriteMode,
+) -> Result<usize, DataToolErrors> {
+    let target = TempTarget::new(file_name, overwrite)?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(target.path())?;
+    // appending to a file that already has rows, so it also has the header
+    let has_header = target.append && file.metadata()?.len() > 0;
+    let mut writer = csv::Writer::from_writer(io::BufWriter::new(file));
+    if !has_header {
+        writer.write_record(["item", "key", "old", "new", "change_type"])?;
+    }
+    let mut rows = 0;
+    for (items, change) in [
+        (&report.added, "item_added"),
+        (&report.removed, "item_removed"),
+    ] {
+        for item in items {
+            writer.write_record([item.as_str(), "", "", "", change])?;
+            rows += 1;
+        }
+    }
+    for item in &report.changed {
+        if item.keys.is_empty() {
+            writer.write_record([item.item_val.as_str(), "", "", "", "value_changed"])?;
+            rows += 1;
+        }
+        for key in &item.keys {
+            let change = match (&key.old, &key.new) {
+                (None, _) => "key_added",
+                (_, None) => "key_removed",
+                _ => "value_changed",
+            };
+            writer.write_record([
+                item.item_val.as_str(),
+                &key.key,
+                key.old.as_deref().unwrap_or_default(),
+                key.new.as_deref().unwrap_or_default(),
+                change,
+            ])?;
+            rows += 1;
+        }
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.flush()?;
+    target.commit()?;
+    info!("Done! {} rows written to {:?}", rows, file_name);
+    Ok(rows)
+}
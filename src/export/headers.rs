@@ -0,0 +1,178 @@
+//! Sanitized headers, see [`ExportOptions::sanitize_headers`]
+
+use super::{ColumnType, DuplicatePolicy, ExportOptions, Row};
+use crate::errors::DataToolErrors;
+use indexmap::IndexMap;
+use std::borrow::Cow;
+
+/// How [`ExportOptions::sanitize_headers`] normalizes the headers, after renaming them and
+/// stripping the key prefix
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeaderSanitize {
+    /// remove the leading and trailing whitespace
+    pub trim: bool,
+    pub case_fold: CaseFold,
+    /// which value is kept when keys end up with the same header
+    pub merge_duplicates: DuplicatePolicy,
+}
+
+/// Case of the sanitized headers, see [`HeaderSanitize`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaseFold {
+    /// as the keys are
+    #[default]
+    Keep,
+    Lower,
+    Upper,
+}
+
+impl HeaderSanitize {
+    pub(super) fn apply<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let name = match self.trim {
+            true => name.trim(),
+            false => name,
+        };
+        match self.case_fold {
+            CaseFold::Keep => Cow::Borrowed(name),
+            CaseFold::Lower => Cow::Owned(name.to_lowercase()),
+            CaseFold::Upper => Cow::Owned(name.to_uppercase()),
+        }
+    }
+}
+
+/// The data columns of an export, with the exported columns read into each, see
+/// [`ExportOptions::header_merge`]
+#[derive(Debug, Clone)]
+pub(super) struct HeaderMerge {
+    /// the header of each data column and the indices of its exported columns, in order
+    groups: Vec<(String, Vec<usize>)>,
+    policy: DuplicatePolicy,
+}
+
+impl HeaderMerge {
+    /// Whether a data column has more than one exported column
+    pub(super) fn merges(&self) -> bool {
+        self.groups.iter().any(|(_, g)| g.len() > 1)
+    }
+
+    /// Whether the last non-empty value of the merged columns is kept, the first otherwise
+    pub(super) fn keeps_last(&self) -> bool {
+        self.policy == DuplicatePolicy::Last
+    }
+
+    pub(super) fn headers(&self) -> impl Iterator<Item = &str> {
+        self.groups.iter().map(|(h, _)| h.as_str())
+    }
+
+    /// Indices of the exported columns read into each data column
+    pub(super) fn groups(&self) -> impl Iterator<Item = &[usize]> {
+        self.groups.iter().map(|(_, g)| g.as_slice())
+    }
+
+    /// Each header with more than one of the `columns`, see [`ExportSummary::merged_headers`]
+    ///
+    /// [`ExportSummary::merged_headers`]: super::ExportSummary::merged_headers
+    pub(super) fn merged(&self, columns: &[String]) -> Vec<(String, Vec<String>)> {
+        self.groups
+            .iter()
+            .filter(|(_, g)| g.len() > 1)
+            .map(|(h, g)| (h.clone(), g.iter().map(|i| columns[*i].clone()).collect()))
+            .collect()
+    }
+
+    /// The row of the data columns, the first or last non-empty value of each, following
+    /// the policy, or an empty one if the item has any of the columns
+    pub(super) fn row(&self, mut row: Row) -> Row {
+        self.groups
+            .iter()
+            .map(|(_, g)| match g[..] {
+                [i] => row[i].take(),
+                _ => {
+                    let non_empty = |i: &&usize| row[**i].as_ref().is_some_and(|v| !v.is_empty());
+                    let kept = match self.policy {
+                        DuplicatePolicy::Last => g.iter().rev().find(non_empty),
+                        _ => g.iter().find(non_empty),
+                    };
+                    match kept {
+                        Some(i) => row[*i].take(),
+                        None => g.iter().find_map(|i| row[*i].take()),
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Types of the data columns, the common one of the merged columns, real if they are
+    /// all numbers, text otherwise
+    pub(super) fn types(&self, types: &[ColumnType]) -> Vec<ColumnType> {
+        self.groups
+            .iter()
+            .map(|(_, g)| {
+                g.iter()
+                    .map(|i| types[*i])
+                    .reduce(|a, b| match (a, b) {
+                        _ if a == b => a,
+                        (ColumnType::Text, _) | (_, ColumnType::Text) => ColumnType::Text,
+                        _ => ColumnType::Real,
+                    })
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+impl ExportOptions {
+    /// The data columns of the exported `columns`, by header. Columns with the same header
+    /// fail the export, unless [`ExportOptions::sanitize_headers`] merges them. For a SQLite
+    /// export, headers only differing in ASCII case are the same, the first one being kept
+    pub(super) fn header_merge(&self, columns: &[String]) -> Result<HeaderMerge, DataToolErrors> {
+        // the header of each group, by the name it is matched by
+        let mut groups: IndexMap<Cow<str>, (Cow<str>, Vec<usize>)> = IndexMap::new();
+        for (i, c) in columns.iter().enumerate() {
+            let header = self.header(c);
+            let name = match self.fold_header_case {
+                true => Cow::Owned(header.to_ascii_lowercase()),
+                false => header.clone(),
+            };
+            groups.entry(name).or_insert((header, vec![])).1.push(i);
+        }
+        let collisions: Vec<_> = groups.values().filter(|(_, g)| g.len() > 1).collect();
+        let ignoring_case = match self.fold_header_case {
+            true => ", ignoring case",
+            false => "",
+        };
+        let describe = || {
+            collisions
+                .iter()
+                .map(|(name, g)| {
+                    let keys: Vec<_> = g.iter().map(|i| &columns[*i]).collect();
+                    format!("{:?} are all named {:?}{}", keys, name, ignoring_case)
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let policy = match self.sanitize_headers {
+            Some(s) => s.merge_duplicates,
+            None if collisions.is_empty() => DuplicatePolicy::default(),
+            None => {
+                return Err(DataToolErrors::InvalidArgument(format!(
+                    "renamed columns collide: {}",
+                    describe()
+                )))
+            }
+        };
+        if let (DuplicatePolicy::Error, Some((name, _))) = (policy, collisions.first()) {
+            return Err(DataToolErrors::ValidationError {
+                key: name.to_string(),
+                reason: format!("the headers collide: {}", describe()),
+            });
+        }
+        Ok(HeaderMerge {
+            groups: groups
+                .into_values()
+                .map(|(h, g)| (h.into_owned(), g))
+                .collect(),
+            policy,
+        })
+    }
+}
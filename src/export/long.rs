@@ -0,0 +1,348 @@
+//! Long shape of the exports, a row per stored cell, see [`ExportShape::Long`]
+
+use super::{ExportOptions, ProcStats, ProgressReporter, TransformFn, CREATED_AT_COLUMN};
+use crate::errors::DataToolErrors;
+use crate::{existing_ids, key_array, read_text, IterOrder, TableMapDb};
+use indexmap::IndexMap;
+use rusqlite::params_from_iter;
+use rusqlite::types::{ToSql, ValueRef};
+use std::collections::HashMap;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Columns of a long export
+pub(super) const LONG_COLUMNS: [&str; 4] = ["item_id", "item_val", "key", "value"];
+
+/// Rows handed to the writer at a time, each batch counts as a chunk for the progress
+const LONG_BATCH_ROWS: usize = 10_000;
+
+/// Shape of the exported rows, see [`ExportOptions::shape`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportShape {
+    /// A row per item, with a column per key
+    #[default]
+    Wide,
+    /// A row per stored cell, with the columns `item_id`, `item_val`, `key` and `value`
+    Long,
+}
+
+/// Reads the cells of the items to export with a single query, in the export order, and
+/// hands them to `write_rows` as rows of [`LONG_COLUMNS`], by batches
+#[cfg(feature = "async")]
+pub(super) async fn long_rows<F>(
+    db: &TableMapDb,
+    options: &ExportOptions,
+    mut write_rows: F,
+) -> Result<ProcStats, DataToolErrors>
+where
+    F: FnMut(Vec<(i64, Vec<Option<String>>)>) -> Result<(), DataToolErrors>,
+{
+    prepare_long(db, options)?;
+    let mut progress = ProgressReporter::new(options, None);
+    // the query may run for a while before its first row, when the token is not checked
+    let _cancel_watch = {
+        let interrupt = db.connection.get_interrupt_handle();
+        options.on_cancel(move || interrupt.interrupt())
+    };
+    let stats = read_long(db, options, &mut progress, &mut write_rows)?;
+    progress.finish().await;
+    Ok(stats)
+}
+
+/// Same as [`long_rows`], for the blocking exports, the progress callback is called right away
+pub(super) fn long_rows_blocking<F>(
+    db: &TableMapDb,
+    options: &ExportOptions,
+    mut write_rows: F,
+) -> Result<ProcStats, DataToolErrors>
+where
+    F: FnMut(Vec<(i64, Vec<Option<String>>)>) -> Result<(), DataToolErrors>,
+{
+    prepare_long(db, options)?;
+    let mut progress = ProgressReporter::new(options, None);
+    read_long(db, options, &mut progress, &mut write_rows)
+}
+
+/// Sets up the db for the query of [`read_long`]
+fn prepare_long(db: &TableMapDb, options: &ExportOptions) -> Result<(), DataToolErrors> {
+    db.ensure_read_indexes()?;
+    if let Some(mode) = options.checkpoint {
+        db.checkpoint(mode)?;
+    }
+    Ok(())
+}
+
+fn read_long<F>(
+    db: &TableMapDb,
+    options: &ExportOptions,
+    progress: &mut ProgressReporter,
+    write_rows: &mut F,
+) -> Result<ProcStats, DataToolErrors>
+where
+    F: FnMut(Vec<(i64, Vec<Option<String>>)>) -> Result<(), DataToolErrors>,
+{
+    let mut stats = ProcStats {
+        max_revision: db.max_revision()?,
+        ..Default::default()
+    };
+    let id_scan = Instant::now();
+    let snapshot = match options.snapshot {
+        true => Some(db.snapshot_bounds()?),
+        false => None,
+    };
+    let mut params: Vec<Box<dyn ToSql>> = vec![];
+    // the items to export, with their position in the export order
+    let items = match &options.ids {
+        Some(ids) => {
+            let mut found = existing_ids(&db.connection, &db.tables, ids)?;
+            if let Some(s) = snapshot {
+                found.retain(|id| *id <= s.max_item);
+            }
+            stats.ids_not_found = ids.iter().filter(|id| !found.contains(id)).count();
+            if let Some(kept) = options.kept_ids(db)? {
+                found.retain(|id| kept.contains(id));
+            }
+            // a repeated id is exported once, in its first place
+            let ids = ids
+                .iter()
+                .filter(|id| found.remove(id))
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>();
+            params.push(Box::new(format!("[{}]", ids.join(","))));
+            "select j.value as id, j.key + 1 as pos from json_each(?1) j".to_string()
+        }
+        None => {
+            let (join, order_by) = match &options.order {
+                IterOrder::InsertionAsc => ("", "i.id".to_string()),
+                IterOrder::InsertionDesc => ("", "i.id desc".to_string()),
+                IterOrder::ByItemVal => ("", "i.item_val, i.id".to_string()),
+                IterOrder::ByKeyValue { key, numeric } => {
+                    params.push(Box::new(key.clone()));
+                    let sort_val = if *numeric { "cast(s.v as real)" } else { "s.v" };
+                    // if the key was inserted more than once for an item, the last value
+                    // wins, same as when the row is read back
+                    (
+                        "left join (select item_id, value as v from data_columns where id in \
+                         (select max(id) from data_columns where key = ?1 group by item_id)) s \
+                         on s.item_id = i.id",
+                        format!("s.v is null, {}, i.id", sort_val),
+                    )
+                }
+            };
+            let mut kept = vec![];
+            if let Some(since) = options.since {
+                params.push(Box::new(since));
+                kept.push(format!("i.created_at >= ?{}", params.len()));
+            }
+            if let Some(revision) = options.changed_since {
+                params.push(Box::new(revision));
+                kept.push(format!("i.revision > ?{}", params.len()));
+            }
+            if let Some(s) = snapshot {
+                params.push(Box::new(s.max_item));
+                kept.push(format!("i.id <= ?{}", params.len()));
+            }
+            let filter = match kept.is_empty() {
+                true => String::new(),
+                false => format!(" where {}", kept.join(" and ")),
+            };
+            format!(
+                "select i.id, row_number() over (order by {}) as pos from item_data i {}{}",
+                order_by, join, filter
+            )
+        }
+    };
+    params.push(Box::new(options.offset as i64));
+    let mut range = format!("o.pos > ?{}", params.len());
+    if let Some(limit) = options.limit {
+        params.push(Box::new((options.offset + limit) as i64));
+        range.push_str(&format!(" and o.pos <= ?{}", params.len()));
+    }
+    // the row filter and computed columns get all the keys of the item
+    let all_keys = options.row_filter.is_some() || !options.computed.is_empty();
+    let mut key_filter = String::new();
+    if !all_keys {
+        let (op, keys) = match &options.include_only {
+            Some(only) => ("in", only.clone()),
+            None => ("not in", options.exclude_columns.clone()),
+        };
+        params.push(Box::new(key_array(&keys)));
+        key_filter = format!(" and d.key {} rarray(?{})", op, params.len());
+        if let Some(prefix) = &options.key_prefix {
+            params.push(Box::new(prefix.clone()));
+            key_filter.push_str(&format!(
+                " and substr(d.key, 1, length(?{n})) = ?{n}",
+                n = params.len()
+            ));
+        }
+    }
+    if let Some(s) = snapshot {
+        params.push(Box::new(s.max_cell));
+        key_filter.push_str(&format!(" and d.id <= ?{}", params.len()));
+    }
+    // items without any cell still get their computed columns
+    let q = format!(
+        "with o as materialized ({}) \
+         select o.id, i.item_val, d.key, d.value, i.created_at from o join item_data i on i.id = o.id \
+         left join data_columns d on d.item_id = o.id{} \
+         where {} order by o.pos, d.id",
+        items, key_filter, range
+    );
+    let transforms = options
+        .transforms
+        .iter()
+        .map(|(k, t)| Ok((k.as_str(), t.compile()?)))
+        .collect::<Result<HashMap<_, _>, DataToolErrors>>()?;
+    // the ids are scanned by the same query as the cells, only finding the given ids is
+    // timed apart
+    stats.timings.id_scan = id_scan.elapsed();
+    let read = Instant::now();
+    let mut writer = LongWriter {
+        options,
+        transforms,
+        batch: vec![],
+        progress,
+        write_rows,
+        batches: 0,
+        write: Duration::ZERO,
+    };
+    let mut stmt = db.connection.prepare(&db.sql(&q))?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+    // the item being read, its item_val, creation time and cells
+    let mut current = None;
+    let mut item_val = None;
+    let mut created_at = None;
+    let mut cells = vec![];
+    while let Some(r) = rows.next()? {
+        let id: i64 = r.get(0)?;
+        if current != Some(id) {
+            if let Some(done) = current.replace(id) {
+                stats.skipped += writer.item(done, item_val, created_at, mem::take(&mut cells))?;
+            }
+            item_val = r.get(1)?;
+            created_at = r.get(4)?;
+        }
+        // items without any cell have a row without a key
+        if r.get_ref(2)? != ValueRef::Null {
+            let key = read_text(r, 2, id, None, db.text_policy)?;
+            let value = match r.get_ref(3)? {
+                ValueRef::Null => String::new(),
+                _ => read_text(r, 3, id, Some(&key), db.text_policy)?,
+            };
+            cells.push((key, value));
+        }
+    }
+    if let Some(done) = current {
+        stats.skipped += writer.item(done, item_val, created_at, cells)?;
+    }
+    writer.flush()?;
+    stats.chunks = writer.batches;
+    stats.timings.write = writer.write;
+    stats.timings.read = read.elapsed().saturating_sub(writer.write);
+    // read by a single query, so the wall clock time too
+    stats.timings.read_wall = stats.timings.read;
+    Ok(stats)
+}
+
+/// Turns the cells of the items into rows of a long export
+struct LongWriter<'a, F> {
+    options: &'a ExportOptions,
+    transforms: HashMap<&'a str, TransformFn>,
+    batch: Vec<(i64, Vec<Option<String>>)>,
+    progress: &'a mut ProgressReporter,
+    write_rows: &'a mut F,
+    /// batches handed to `write_rows` and the time taken writing them
+    batches: usize,
+    write: Duration,
+}
+
+impl<F> LongWriter<'_, F>
+where
+    F: FnMut(Vec<(i64, Vec<Option<String>>)>) -> Result<(), DataToolErrors>,
+{
+    /// Adds the rows of an item, returns 1 if the row filter left it out
+    fn item(
+        &mut self,
+        id: i64,
+        item_val: Option<String>,
+        created_at: Option<i64>,
+        cells: Vec<(String, String)>,
+    ) -> Result<usize, DataToolErrors> {
+        let options = self.options;
+        options.check_cancelled()?;
+        let mut computed = vec![];
+        if options.row_filter.is_some() || !options.computed.is_empty() {
+            // the last value wins, same as when the row is read back
+            let im: IndexMap<String, String> = cells.iter().cloned().collect();
+            if let Some(filter) = &options.row_filter {
+                if !(filter.0)(&im) {
+                    return Ok(1);
+                }
+            }
+            computed = options
+                .computed
+                .iter()
+                .map(|(k, f)| (k.clone(), (f.0)(&im)))
+                .collect();
+        }
+        let exported = |key: &str| {
+            let listed = match &options.include_only {
+                Some(only) => only.iter().any(|c| c == key),
+                None => !options.exclude_columns.iter().any(|c| c == key),
+            };
+            listed && (options.in_namespace(key) || options.computed.contains_key(key))
+        };
+        // the creation time first, as in the wide exports, missing if the item has none
+        if options.include_created_at {
+            self.batch.push((
+                id,
+                vec![
+                    Some(id.to_string()),
+                    item_val.clone(),
+                    Some(CREATED_AT_COLUMN.to_string()),
+                    created_at.map(|t| t.to_string()),
+                ],
+            ));
+        }
+        // computed columns take the place of the stored ones with the same name
+        let stored = cells
+            .into_iter()
+            .filter(|(k, _)| !options.computed.contains_key(k));
+        for (key, value) in stored.chain(computed) {
+            if !exported(&key) {
+                continue;
+            }
+            let value = match self.transforms.get(key.as_str()) {
+                Some(t) if !value.is_empty() => (t.0)(&value),
+                _ => value,
+            };
+            let key = options.header(&key).into_owned();
+            self.batch.push((
+                id,
+                vec![
+                    Some(id.to_string()),
+                    item_val.clone(),
+                    Some(key),
+                    Some(value),
+                ],
+            ));
+        }
+        if self.batch.len() >= LONG_BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(0)
+    }
+
+    fn flush(&mut self) -> Result<(), DataToolErrors> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let rows = self.batch.len();
+        let t = Instant::now();
+        (self.write_rows)(std::mem::take(&mut self.batch))?;
+        self.write += t.elapsed();
+        self.batches += 1;
+        self.progress.chunk_written(rows, true);
+        Ok(())
+    }
+}
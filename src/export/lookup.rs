@@ -0,0 +1,226 @@
+//! Lookup table of two keys of the items, see [`dump_lookup`]
+
+use super::{restore_journal_mode, tune_export_db, ExportSummary, OverwriteMode, TempTarget};
+use crate::errors::DataToolErrors;
+use crate::{quote_ident, TableMapDb};
+use indexmap::map::Entry;
+use indexmap::IndexMap;
+use rusqlite::Connection;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// Output of [`dump_lookup`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupFormat {
+    /// A CSV file with the two columns, named after the keys
+    Csv,
+    /// A SQLite db with the two columns in `table`, created if missing. With `unique` the
+    /// lookup key is the primary key of the table, so it is indexed and rows appended with a
+    /// key the table already has follow the [`DuplicatePolicy`] too
+    Sqlite { table: String, unique: bool },
+}
+
+/// Which item wins when several have the same lookup key, see [`dump_lookup`], or which
+/// value when several keys have the same header, see [`HeaderSanitize`]
+///
+/// [`HeaderSanitize`]: super::HeaderSanitize
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// the item inserted first, or the first non-empty value in the column order
+    #[default]
+    First,
+    /// the item inserted last, in the place of the first one, or the last non-empty value
+    Last,
+    /// the export fails with [`DataToolErrors::ValidationError`]
+    Error,
+}
+
+/// Exports the items as a lookup table from the value of `key_col` to the value of
+/// `value_col`, one row per item in insertion order, e.g. `sku -> price`, without reading
+/// the other keys. The last value of each key is used, the same as it is read back.
+///
+/// Items without either key, or with an empty value, are counted in
+/// [`ExportSummary::rows_skipped_incomplete`], the ones left out as another item has the
+/// same lookup key in [`ExportSummary::rows_skipped_duplicate`]. When appending to a CSV file
+/// that already has rows, the header is not written again
+pub fn dump_lookup(
+    db: &TableMapDb,
+    file_name: &Path,
+    key_col: &str,
+    value_col: &str,
+    format: LookupFormat,
+    duplicates: DuplicatePolicy,
+    overwrite: OverwriteMode,
+) -> Result<ExportSummary, DataToolErrors> {
+    let t = Instant::now();
+    if key_col == value_col {
+        return Err(DataToolErrors::InvalidArgument(format!(
+            "the lookup key and value are both {:?}",
+            key_col
+        )));
+    }
+    let unknown = db.unknown_keys(&[key_col.to_string(), value_col.to_string()]);
+    if !unknown.is_empty() {
+        return Err(DataToolErrors::UnknownColumns(unknown));
+    }
+    let read = Instant::now();
+    let mut summary = ExportSummary::new(vec![key_col.to_string(), value_col.to_string()]);
+    let lookup = read_lookup(db, key_col, value_col, duplicates, &mut summary)?;
+    let read = read.elapsed();
+    let write = Instant::now();
+    let target = TempTarget::new(file_name, overwrite)?;
+    summary.rows_written = match &format {
+        LookupFormat::Csv => write_csv(target.path(), target.append, &summary.columns, &lookup)?,
+        LookupFormat::Sqlite { table, unique } => write_sqlite(
+            target.path(),
+            table,
+            *unique,
+            &summary.columns,
+            duplicates,
+            &lookup,
+        )?,
+    };
+    target.commit()?;
+    summary.timings.read = read;
+    summary.timings.read_wall = read;
+    summary.timings.write = write.elapsed();
+    summary.elapsed = t.elapsed();
+    summary.report();
+    Ok(summary)
+}
+
+/// The lookup key and value of the items, in insertion order, following `duplicates`
+fn read_lookup(
+    db: &TableMapDb,
+    key_col: &str,
+    value_col: &str,
+    duplicates: DuplicatePolicy,
+    summary: &mut ExportSummary,
+) -> Result<IndexMap<String, (i64, String)>, DataToolErrors> {
+    db.ensure_read_indexes()?;
+    summary.max_revision = db.max_revision()?;
+    // the last value of each key, the value of the max(id) row
+    let mut stmt = db.connection.prepare(&db.sql(
+        "with k as (select item_id, value from data_columns where id in
+             (select max(id) from data_columns where key = ?1 group by item_id)),
+         v as (select item_id, value from data_columns where id in
+             (select max(id) from data_columns where key = ?2 group by item_id))
+         select i.id, k.value, v.value from item_data i
+         left join k on k.item_id = i.id left join v on v.item_id = i.id
+         order by i.id",
+    ))?;
+    let mut rows = stmt.query([key_col, value_col])?;
+    let mut lookup = IndexMap::new();
+    while let Some(r) = rows.next()? {
+        let id: i64 = r.get(0)?;
+        let key: Option<String> = r.get(1)?;
+        let value: Option<String> = r.get(2)?;
+        let (Some(key), Some(value)) = (key, value) else {
+            summary.rows_skipped_incomplete += 1;
+            continue;
+        };
+        if key.is_empty() || value.is_empty() {
+            summary.rows_skipped_incomplete += 1;
+            continue;
+        }
+        match lookup.entry(key) {
+            Entry::Vacant(e) => {
+                e.insert((id, value));
+            }
+            Entry::Occupied(mut e) => {
+                summary.rows_skipped_duplicate += 1;
+                match duplicates {
+                    DuplicatePolicy::First => {}
+                    DuplicatePolicy::Last => {
+                        e.insert((id, value));
+                    }
+                    DuplicatePolicy::Error => {
+                        return Err(DataToolErrors::ValidationError {
+                            key: key_col.to_string(),
+                            reason: format!(
+                                "items {} and {} have the same value {:?}",
+                                e.get().0,
+                                id,
+                                e.key()
+                            ),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(lookup)
+}
+
+/// Writes the rows to the CSV file, returns the number written
+fn write_csv(
+    path: &Path,
+    append: bool,
+    columns: &[String],
+    lookup: &IndexMap<String, (i64, String)>,
+) -> Result<usize, DataToolErrors> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    // appending to a file that already has rows, so it also has the header
+    let has_header = append && file.metadata()?.len() > 0;
+    let mut writer = csv::Writer::from_writer(io::BufWriter::new(file));
+    if !has_header {
+        writer.write_record(columns)?;
+    }
+    for (key, (_, value)) in lookup {
+        writer.write_record([key, value])?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.flush()?;
+    Ok(lookup.len())
+}
+
+/// Writes the rows to the table of the SQLite db, returns the number written
+fn write_sqlite(
+    path: &Path,
+    table: &str,
+    unique: bool,
+    columns: &[String],
+    duplicates: DuplicatePolicy,
+    lookup: &IndexMap<String, (i64, String)>,
+) -> Result<usize, DataToolErrors> {
+    let db = Connection::open(path)?;
+    let journal_mode = tune_export_db(&db, "main")?;
+    let (key, value) = (quote_ident(&columns[0]), quote_ident(&columns[1]));
+    let key_def = match unique {
+        true => format!("{} text primary key", key),
+        false => format!("{} text", key),
+    };
+    db.execute_batch(&format!(
+        "create table if not exists {} ({}, {} text)",
+        quote_ident(table),
+        key_def,
+        value
+    ))?;
+    // only a unique table can have the key already, when appending
+    let conflict = match duplicates {
+        DuplicatePolicy::First => " or ignore",
+        DuplicatePolicy::Last => " or replace",
+        DuplicatePolicy::Error => "",
+    };
+    let q = format!(
+        "insert{} into {} ({}, {}) values (?1, ?2)",
+        conflict,
+        quote_ident(table),
+        key,
+        value
+    );
+    db.execute_batch("BEGIN")?;
+    let mut stmt = db.prepare(&q)?;
+    let mut rows = 0;
+    for (key, (_, value)) in lookup {
+        rows += stmt.execute([key, value])?;
+    }
+    drop(stmt);
+    db.execute_batch("COMMIT")?;
+    restore_journal_mode(&db, "main", &journal_mode)?;
+    Ok(rows)
+}
@@ -0,0 +1,185 @@
+//! Manifest of an export, see [`ExportOptions::write_manifest`]
+
+use super::{ExportOptions, ExportSummary, OverwriteMode, TempTarget};
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// SHA-256 of the bytes written through a [`Hashed`] writer, shared with the export so it
+/// can be read once the writer is gone
+#[derive(Clone, Default)]
+pub(super) struct Sha256Sum(Arc<Mutex<Sha256>>);
+
+impl Sha256Sum {
+    fn lock(&self) -> MutexGuard<'_, Sha256> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hashes the content of a file, for the bytes not written by the export: what a file
+    /// appended to already had, or a whole SQLite file, which SQLite writes in place
+    pub(super) fn update_file(&self, path: &Path) -> io::Result<()> {
+        let mut file = fs::File::open(path)?;
+        io::copy(&mut file, &mut *self.lock())?;
+        Ok(())
+    }
+
+    fn hex(&self) -> String {
+        self.lock()
+            .clone()
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Passes the bytes written to `inner` through the hash, if any
+pub(super) struct Hashed<W> {
+    inner: W,
+    sum: Option<Sha256Sum>,
+}
+
+impl<W> Hashed<W> {
+    pub(super) fn new(inner: W, sum: Option<Sha256Sum>) -> Self {
+        Self { inner, sum }
+    }
+}
+
+impl<W: Write> Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(sum) = &self.sum {
+            sum.lock().update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// `<output>.manifest.json`, listing the files of the export with their SHA-256
+pub(super) struct Manifest {
+    target: TempTarget,
+    created_at: SystemTime,
+    files: Vec<(PathBuf, Sha256Sum)>,
+}
+
+impl Manifest {
+    /// The manifest of the export to `file_name`, `None` unless
+    /// [`ExportOptions::write_manifest`] is set. Fails right away if the manifest exists
+    /// and the export would not overwrite it
+    pub(super) fn new(
+        file_name: &Path,
+        options: &ExportOptions,
+    ) -> Result<Option<Self>, DataToolErrors> {
+        if !options.write_manifest {
+            return Ok(None);
+        }
+        let mut path = file_name.as_os_str().to_owned();
+        path.push(".manifest.json");
+        // it describes the whole file, so it is replaced when appending
+        let mode = match options.overwrite {
+            OverwriteMode::Append => OverwriteMode::Overwrite,
+            mode => mode,
+        };
+        Ok(Some(Self {
+            target: TempTarget::new(Path::new(&path), mode)?,
+            created_at: SystemTime::now(),
+            files: vec![],
+        }))
+    }
+
+    /// Adds a file of the export, the returned sum is to hash all its bytes
+    pub(super) fn add_file(&mut self, path: &Path) -> Sha256Sum {
+        let sum = Sha256Sum::default();
+        self.files.push((path.to_path_buf(), sum.clone()));
+        sum
+    }
+
+    /// Writes the manifest, once the files of the export are in place
+    pub(super) fn write(
+        self,
+        db: &TableMapDb,
+        summary: &ExportSummary,
+    ) -> Result<(), DataToolErrors> {
+        let files = self
+            .files
+            .iter()
+            .map(|(path, sum)| {
+                let rows = summary
+                    .files
+                    .iter()
+                    .find(|(p, _)| p == path)
+                    .map_or(summary.rows_written, |(_, rows)| *rows);
+                json!({
+                    "name": path.file_name().unwrap_or_default().to_string_lossy(),
+                    "rows": rows,
+                    "sha256": sum.hex(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let manifest = json!({
+            "files": files,
+            "rows": summary.rows_written,
+            "rows_failed": summary.rows_failed,
+            "columns": summary.columns,
+            "source_items": db.how_many_items()?,
+            "created_at": rfc3339(self.created_at),
+            "elapsed_ms": summary.elapsed.as_millis() as u64,
+        });
+        let json = serde_json::to_vec_pretty(&manifest)
+            .map_err(|e| DataToolErrors::GenericError(e.to_string()))?;
+        fs::write(self.target.path(), json)?;
+        self.target.commit()
+    }
+}
+
+/// Moves a file the export did not write through a [`Hashed`] writer in place, hashing it
+/// for the manifest first, then writes the manifest, if any
+pub(super) fn commit_unhashed(
+    target: TempTarget,
+    file_name: &Path,
+    manifest: Option<Manifest>,
+    db: &TableMapDb,
+    summary: &ExportSummary,
+) -> Result<(), DataToolErrors> {
+    let Some(mut manifest) = manifest else {
+        return target.commit();
+    };
+    manifest.add_file(file_name).update_file(target.path())?;
+    target.commit()?;
+    manifest.write(db, summary)
+}
+
+/// UTC date and time, to the second, e.g. `2024-05-17T08:03:59Z`
+fn rfc3339(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, time) = ((secs / 86_400) as i64, secs % 86_400);
+    // the date from the days since the epoch, Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
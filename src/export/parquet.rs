@@ -0,0 +1,168 @@
+//! Parquet export, needs the `parquet` feature
+
+use super::arrow::BatchLayout;
+use super::{
+    proc_ids, ColumnType, ExportOptions, ExportSummary, ExportTimings, OverwriteMode, TempTarget,
+};
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use tracing::warn;
+
+/// Exports the items to a Parquet file, the columns are the same [`dump_csv`](super::dump_csv)
+/// would export. Columns are strings, unless typed by the options, the item id, if
+/// included, a 64 bit integer. Keys an item does not have are null.
+///
+/// Each chunk is written as a record batch, so the memory used grows with the chunk size,
+/// not with the number of items. Rows with a value that does not parse as the type of its
+/// column are failed rows. Parquet files can not be appended to, so
+/// [`OverwriteMode::Append`] is rejected
+pub async fn dump_parquet(
+    db: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    parquet_options: ExportParquetOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let t = Instant::now();
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    chunk.validate()?;
+    options.check_wide("dump_parquet")?;
+    if options.overwrite == OverwriteMode::Append {
+        return Err(DataToolErrors::InvalidArgument(
+            "can not append to a Parquet file".to_string(),
+        ));
+    }
+    let map_err = |e: parquet::errors::ParquetError| DataToolErrors::GenericError(e.to_string());
+    let props = WriterProperties::builder()
+        .set_compression(parquet_options.compression.parquet()?)
+        .set_max_row_group_size(parquet_options.max_row_group_rows)
+        .build();
+    let target = TempTarget::new(file_name, options.overwrite)?;
+    let keys_scan = Instant::now();
+    let Some(layout) = BatchLayout::new(
+        db,
+        column_order,
+        &options,
+        parquet_options.infer_types,
+        &parquet_options.column_types,
+    )?
+    else {
+        // a Parquet file needs at least one column
+        warn!("No columns to export, not creating {:?}", file_name);
+        return Ok(ExportSummary::empty(t));
+    };
+    let keys_scan = keys_scan.elapsed();
+    let file = fs::File::create(target.path())?;
+    let mut writer =
+        ArrowWriter::try_new(file, layout.schema.clone(), Some(props)).map_err(map_err)?;
+    let mut summary = ExportSummary::new(
+        layout
+            .schema
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect(),
+    );
+    summary.column_types = layout.out_types(&options);
+    let stats = proc_ids(db, &options, chunk, layout.columns.clone(), |n| {
+        let batch = layout.batch(&n, &options, &mut summary)?;
+        writer.write(&batch).map_err(map_err)
+    })
+    .await?;
+    summary.rows_skipped_by_filter = stats.skipped;
+    summary.ids_not_found = stats.ids_not_found;
+    summary.max_revision = stats.max_revision;
+    summary.chunks = stats.chunks;
+    summary.timings = ExportTimings {
+        keys_scan,
+        ..stats.timings
+    };
+    summary.spilled_keys = stats.spilled_keys;
+    summary.merged_headers = stats.merged_headers;
+    writer.close().map_err(map_err)?;
+    target.commit()?;
+    summary.elapsed = t.elapsed();
+    summary.report();
+    Ok(summary)
+}
+
+/// Options of the Parquet export, [`dump_parquet`]
+#[derive(Debug, Clone)]
+pub struct ExportParquetOptions {
+    compression: ParquetCompression,
+    max_row_group_rows: usize,
+    infer_types: bool,
+    column_types: HashMap<String, ColumnType>,
+}
+
+impl Default for ExportParquetOptions {
+    fn default() -> Self {
+        Self {
+            compression: Default::default(),
+            max_row_group_rows: 64 * 1024,
+            infer_types: false,
+            column_types: HashMap::new(),
+        }
+    }
+}
+
+impl ExportParquetOptions {
+    pub fn compression(mut self, compression: ParquetCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Most rows in a row group, 65536 by default. The rows of a row group are held in
+    /// memory, encoded, until the group is complete
+    pub fn max_row_group_rows(mut self, rows: usize) -> Self {
+        self.max_row_group_rows = rows;
+        self
+    }
+
+    /// Type the columns by their values, the same as [`ExportDbOptions::infer_types`]
+    ///
+    /// [`ExportDbOptions::infer_types`]: super::ExportDbOptions::infer_types
+    pub fn infer_types(mut self, infer_types: bool) -> Self {
+        self.infer_types = infer_types;
+        self
+    }
+
+    /// Pin the type of a column, whether types are inferred or not
+    pub fn column_type(mut self, column: impl Into<String>, column_type: ColumnType) -> Self {
+        self.column_types.insert(column.into(), column_type);
+        self
+    }
+}
+
+/// Compression of the pages of a Parquet export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParquetCompression {
+    None,
+    #[default]
+    Snappy,
+    /// zstd with the level, from 1 to 22
+    Zstd(i32),
+}
+
+impl ParquetCompression {
+    fn parquet(self) -> Result<Compression, DataToolErrors> {
+        Ok(match self {
+            ParquetCompression::None => Compression::UNCOMPRESSED,
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd(level) => {
+                Compression::ZSTD(ZstdLevel::try_new(level).map_err(|_| {
+                    DataToolErrors::InvalidArgument(format!(
+                        "zstd level {} is not between 1 and 22",
+                        level
+                    ))
+                })?)
+            }
+        })
+    }
+}
@@ -0,0 +1,151 @@
+//! CSV export split into a file per value of a key
+
+use super::{dump_csv, ExportCsvOptions, ExportOptions, ExportSummary};
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use tracing::info;
+
+/// Longest file name made from a partition value, without the hash and the extension
+const MAX_NAME_LEN: usize = 100;
+
+/// Exports the items to a CSV file per value of [`ExportPartitionOptions::new`]'s key, in
+/// `dir`, which is created if missing. Items without the key, or with an empty value, go to
+/// the file named by [`ExportPartitionOptions::unpartitioned_name`]. All the files have the
+/// columns [`dump_csv`] would export, and each is written by it, so the overwrite mode
+/// applies to every file, and [`ExportOptions::limit`] and [`ExportOptions::offset`] to the
+/// items of each partition.
+///
+/// Returns the summary of each file by partition value, `None` for the unpartitioned items.
+/// If the export of a partition fails, the files already written are kept.
+///
+/// File names are made from the values, with the characters that are not safe in a file
+/// name percent-encoded, e.g. `a/b` is written to `a%2Fb.csv`. Long values are cut and end
+/// with a hash of the value, as do values whose name differs only in case from another one
+pub async fn dump_csv_partitioned(
+    db: &mut TableMapDb,
+    dir: &Path,
+    options: ExportOptions,
+    csv_options: ExportCsvOptions,
+    partition_options: ExportPartitionOptions,
+) -> Result<BTreeMap<Option<String>, ExportSummary>, DataToolErrors> {
+    let t = Instant::now();
+    options.chunk.validate()?;
+    partition_options.validate()?;
+    let ids = match &options.ids {
+        Some(ids) => ids.clone(),
+        None => options.order.item_ids(&db.connection, &db.tables)?,
+    };
+    let values = partition_values(db, &partition_options.key)?;
+    let mut partitions: BTreeMap<Option<&str>, Vec<i64>> = BTreeMap::new();
+    for id in ids {
+        let value = values.get(&id).map(String::as_str);
+        partitions.entry(value).or_default().push(id);
+    }
+    fs::create_dir_all(dir)?;
+    let extension = csv_options.compression.csv_extension();
+    // names already used, lowercased as some filesystems ignore the case
+    let mut used = HashSet::new();
+    let mut summaries = BTreeMap::new();
+    for (value, ids) in partitions {
+        let mut name = match value {
+            Some(value) => partition_file_name(value),
+            None => partition_options.unpartitioned_name.clone(),
+        };
+        if !used.insert(name.to_lowercase()) {
+            name = format!("{}~{:016x}", name, fnv1a(value.unwrap_or_default()));
+            used.insert(name.to_lowercase());
+        }
+        let file_name = dir.join(format!("{}.{}", name, extension));
+        let summary = dump_csv(
+            db,
+            &file_name,
+            options.clone().ids(ids),
+            csv_options.clone(),
+        )
+        .await?;
+        summaries.insert(value.map(str::to_string), summary);
+    }
+    info!("Done! {} partitions in {:?}", summaries.len(), t.elapsed());
+    Ok(summaries)
+}
+
+/// Options of the partitioned CSV export, [`dump_csv_partitioned`]
+#[derive(Debug, Clone)]
+pub struct ExportPartitionOptions {
+    key: String,
+    unpartitioned_name: String,
+}
+
+impl ExportPartitionOptions {
+    /// Partition the items by the value of `key`
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            unpartitioned_name: "_unpartitioned".to_string(),
+        }
+    }
+
+    /// Name of the file of the items without the key, `_unpartitioned` by default.
+    /// The extension is added to it
+    pub fn unpartitioned_name(mut self, name: impl Into<String>) -> Self {
+        self.unpartitioned_name = name.into();
+        self
+    }
+
+    fn validate(&self) -> Result<(), DataToolErrors> {
+        if self.key.is_empty() {
+            return Err(DataToolErrors::InvalidArgument(
+                "the partition key can not be empty".to_string(),
+            ));
+        }
+        let name = &self.unpartitioned_name;
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "{:?} is not a file name",
+                name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Non-empty values of `key`, by item id
+fn partition_values(db: &TableMapDb, key: &str) -> rusqlite::Result<HashMap<i64, String>> {
+    let mut stmt = db.connection.prepare(
+        &db.sql("select item_id, value from data_columns where key = ?1 and value != ''"),
+    )?;
+    let values = stmt
+        .query_map([key], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect();
+    values
+}
+
+/// File name for a partition value. Bytes other than ASCII letters, digits, `-`, `_` and `.`
+/// are percent-encoded, as is a leading `.` or `_`, so the name is never hidden, `.` or
+/// `..`, nor the default name of the unpartitioned file
+fn partition_file_name(value: &str) -> String {
+    let mut name = String::new();
+    for (i, b) in value.bytes().enumerate() {
+        match b {
+            b'.' | b'_' if i == 0 => name.push_str(&format!("%{:02X}", b)),
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => name.push(b as char),
+            _ => name.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    if name.len() > MAX_NAME_LEN {
+        name.truncate(MAX_NAME_LEN);
+        name.push_str(&format!("~{:016x}", fnv1a(value)));
+    }
+    name
+}
+
+/// 64 bit FNV-1a, a hash that stays the same across builds, unlike the std hasher
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,57 @@
+//! CSV of a [`TableMapDb::profile`](crate::TableMapDb::profile)
+
+use super::{OverwriteMode, TempTarget};
+use crate::aggregate::ColumnProfile;
+use crate::errors::DataToolErrors;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::info;
+
+/// Writes the profile as a CSV file with a row per key, with the columns of
+/// [`ColumnProfile`], the sample being a JSON array of the values.
+///
+/// Returns the number of rows written. When appending to a file that already has rows, the
+/// header is not written again
+pub fn dump_profile_csv(
+    profile: &[ColumnProfile],
+    file_name: &Path,
+    overwrite: OverwriteMode,
+) -> Result<usize, DataToolErrors> {
+    let target = TempTarget::new(file_name, overwrite)?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(target.path())?;
+    // appending to a file that already has rows, so it also has the header
+    let has_header = target.append && file.metadata()?.len() > 0;
+    let mut writer = csv::Writer::from_writer(io::BufWriter::new(file));
+    if !has_header {
+        writer.write_record([
+            "key",
+            "present_in_items",
+            "fill_rate",
+            "distinct_values",
+            "max_len",
+            "all_numeric",
+            "sample",
+        ])?;
+    }
+    for column in profile {
+        let sample = serde_json::to_string(&column.sample)
+            .map_err(|e| DataToolErrors::GenericError(e.to_string()))?;
+        writer.write_record([
+            column.key.clone(),
+            column.present_in_items.to_string(),
+            column.fill_rate.to_string(),
+            column.distinct_values.to_string(),
+            column.max_len.to_string(),
+            column.all_numeric.to_string(),
+            sample,
+        ])?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.flush()?;
+    target.commit()?;
+    info!("Done! {} rows written to {:?}", profile.len(), file_name);
+    Ok(profile.len())
+}
@@ -0,0 +1,204 @@
+//! Resumable CSV exports, see [`ExportCsvOptions::resume`]
+
+use super::manifest::{Hashed, Manifest};
+use super::{
+    Compression, CsvFile, CsvFiles, CsvSink, ExportCsvOptions, ExportOptions, ExportShape,
+    ExportSummary, OverwriteMode, TempTarget,
+};
+use crate::errors::DataToolErrors;
+use crate::IterOrder;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// `<output>.checkpoint.json`, next to the partial export `<output>.tmp`, recording the last
+/// item whose row is on disk. Only advanced once the rows before it are flushed and synced
+pub(super) struct Checkpoint {
+    path: PathBuf,
+    /// the partial export
+    data: PathBuf,
+    /// the handle the partial export is written with, synced before the checkpoint is
+    /// advanced
+    file: Option<fs::File>,
+    /// header of the partial export, `None` when starting from scratch
+    columns: Option<Vec<String>>,
+    last_id: Option<i64>,
+    rows: usize,
+    rows_failed: usize,
+    /// length of the partial export when the checkpoint was written
+    bytes: u64,
+}
+
+impl Checkpoint {
+    /// The checkpoint of the partial export `data` of `file_name`, a fresh one if there is
+    /// no partial export to resume
+    fn load(file_name: &Path, data: &Path) -> Result<Self, DataToolErrors> {
+        let mut path = file_name.as_os_str().to_owned();
+        path.push(".checkpoint.json");
+        let mut checkpoint = Self {
+            path: PathBuf::from(path),
+            data: data.to_path_buf(),
+            file: None,
+            columns: None,
+            last_id: None,
+            rows: 0,
+            rows_failed: 0,
+            bytes: 0,
+        };
+        if !checkpoint.path.exists() {
+            return Ok(checkpoint);
+        }
+        if !data.exists() {
+            warn!(
+                "Removing {:?}, the partial export it is for is gone",
+                checkpoint.path
+            );
+            fs::remove_file(&checkpoint.path)?;
+            return Ok(checkpoint);
+        }
+        let invalid = |reason: &str| {
+            DataToolErrors::InvalidArgument(format!(
+                "can not resume from {:?}: {}, remove it to export from scratch",
+                checkpoint.path, reason
+            ))
+        };
+        let saved: Value = serde_json::from_slice(&fs::read(&checkpoint.path)?)
+            .map_err(|e| invalid(&e.to_string()))?;
+        let count = |key: &str| {
+            saved[key]
+                .as_u64()
+                .ok_or_else(|| invalid(&format!("{} is missing", key)))
+        };
+        let columns = saved["columns"]
+            .as_array()
+            .and_then(|c| c.iter().map(|c| c.as_str().map(String::from)).collect())
+            .ok_or_else(|| invalid("columns are missing"))?;
+        let last_id = saved["last_id"]
+            .as_i64()
+            .ok_or_else(|| invalid("last_id is missing"))?;
+        let (rows, rows_failed, bytes) = (count("rows")?, count("rows_failed")?, count("bytes")?);
+        if fs::metadata(data)?.len() < bytes {
+            return Err(invalid(&format!(
+                "{:?} is shorter than when it was written",
+                data
+            )));
+        }
+        checkpoint.columns = Some(columns);
+        checkpoint.last_id = Some(last_id);
+        checkpoint.rows = rows as usize;
+        checkpoint.rows_failed = rows_failed as usize;
+        checkpoint.bytes = bytes;
+        Ok(checkpoint)
+    }
+
+    /// Fails if the partial export has other columns than `header`
+    pub(super) fn check_columns(&self, header: &[String]) -> Result<(), DataToolErrors> {
+        match &self.columns {
+            Some(columns) if columns != header => Err(DataToolErrors::InvalidArgument(format!(
+                "can not resume the partial export {:?}, its columns changed from {:?} to {:?}, \
+                 remove {:?} to export from scratch",
+                self.data, columns, header, self.path
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rows written and failed by the partial export
+    pub(super) fn counts(&self) -> (usize, usize) {
+        (self.rows, self.rows_failed)
+    }
+
+    /// Records that the rows up to the item `last_id` are written, once they are on disk
+    pub(super) fn advance(
+        &mut self,
+        last_id: i64,
+        summary: &ExportSummary,
+    ) -> Result<(), DataToolErrors> {
+        let file = self
+            .file
+            .as_ref()
+            .expect("the partial export is opened before its rows are written");
+        file.sync_data()?;
+        self.bytes = file.metadata()?.len();
+        self.last_id = Some(last_id);
+        let saved = json!({
+            "columns": summary.columns,
+            "last_id": last_id,
+            "rows": summary.rows_written,
+            "rows_failed": summary.rows_failed,
+            "bytes": self.bytes,
+        });
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(saved.to_string().as_bytes())?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// The file of [`dump_csv`](super::dump_csv) with [`ExportCsvOptions::resume`] set. When
+/// resuming, the options start after the last item of the checkpoint
+pub(super) fn resumable_files(
+    file_name: &Path,
+    compression: Compression,
+    options: &mut ExportOptions,
+    csv_options: &ExportCsvOptions,
+) -> Result<CsvFiles, DataToolErrors> {
+    let unsupported = [
+        (compression != Compression::None, "compressed"),
+        (csv_options.max_rows_per_file.is_some(), "split into files"),
+        (options.overwrite == OverwriteMode::Append, "appended"),
+        (options.order != IterOrder::InsertionAsc, "in another order"),
+        (options.unordered, "unordered"),
+        (options.ids.is_some(), "of given ids"),
+        (
+            options.offset > 0 || options.limit.is_some(),
+            "with an offset or limit",
+        ),
+        (options.shape != ExportShape::Wide, "in the long shape"),
+    ];
+    if let Some((_, what)) = unsupported.iter().find(|(found, _)| *found) {
+        return Err(DataToolErrors::InvalidArgument(format!(
+            "an export {} can not be resumed",
+            what
+        )));
+    }
+    let mut manifest = Manifest::new(file_name, options)?;
+    let target = TempTarget::resumable(file_name, options.overwrite)?;
+    let mut checkpoint = Checkpoint::load(file_name, target.path())?;
+    let sum = manifest.as_mut().map(|m| m.add_file(file_name));
+    let file = match checkpoint.last_id {
+        Some(last_id) => {
+            info!(
+                "Resuming the export to {:?} after item {}, {} rows written",
+                file_name, last_id, checkpoint.rows
+            );
+            // the rows written after the checkpoint are written again
+            let file = fs::OpenOptions::new().append(true).open(target.path())?;
+            file.set_len(checkpoint.bytes)?;
+            if let Some(sum) = &sum {
+                sum.update_file(target.path())?;
+            }
+            options.after_id = Some(last_id);
+            file
+        }
+        None => fs::File::create(target.path())?,
+    };
+    checkpoint.file = Some(file.try_clone()?);
+    let checkpoint_path = checkpoint.path.clone();
+    Ok(CsvFiles::Single {
+        file: Some(Box::new(CsvFile {
+            sink: CsvSink::new(Hashed::new(file, sum), compression)?,
+            write_header: checkpoint.last_id.is_none(),
+            path: None,
+            checkpoint: Some(checkpoint),
+        })),
+        target,
+        manifest,
+        checkpoint: Some(checkpoint_path),
+    })
+}
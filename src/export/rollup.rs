@@ -0,0 +1,296 @@
+//! Aggregates of the items by the value of a key, see [`dump_rollup`]
+
+use super::{restore_journal_mode, tune_export_db, ExportSummary, OverwriteMode, TempTarget};
+use crate::aggregate::LAST_VALUES;
+use crate::errors::DataToolErrors;
+use crate::{quote_ident, TableMapDb};
+use indexmap::IndexSet;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+use tracing::warn;
+
+/// An aggregated column of [`dump_rollup`], named as it is displayed, e.g. `sum(price)`.
+/// The numeric ones cast the values to numbers in SQLite, leaving out the empty ones and
+/// counting the others that are not numbers in [`ExportSummary::non_numeric_values`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Agg {
+    /// items in the group
+    Count,
+    Sum(String),
+    Avg(String),
+    Min(String),
+    Max(String),
+    /// distinct non-empty values of the key in the group
+    CountDistinct(String),
+}
+
+impl Agg {
+    fn key(&self) -> Option<&str> {
+        match self {
+            Agg::Count => None,
+            Agg::Sum(k) | Agg::Avg(k) | Agg::Min(k) | Agg::Max(k) | Agg::CountDistinct(k) => {
+                Some(k)
+            }
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Agg::Sum(_) | Agg::Avg(_) | Agg::Min(_) | Agg::Max(_))
+    }
+
+    /// The SQL of the aggregate, over the values in `v`
+    fn sql(&self, v: &str) -> String {
+        let number = format!("case when {} then cast({v}.value as real) end", numeric(v));
+        match self {
+            Agg::Count => "count(*)".to_string(),
+            Agg::Sum(_) => format!("sum({})", number),
+            Agg::Avg(_) => format!("avg({})", number),
+            Agg::Min(_) => format!("min({})", number),
+            Agg::Max(_) => format!("max({})", number),
+            Agg::CountDistinct(_) => format!("count(distinct nullif({v}.value, ''))"),
+        }
+    }
+
+    /// SQLite type of the column
+    fn column_type(&self) -> &'static str {
+        match self {
+            Agg::Count | Agg::CountDistinct(_) => "integer",
+            _ => "real",
+        }
+    }
+}
+
+impl fmt::Display for Agg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Agg::Count => write!(f, "count"),
+            Agg::Sum(k) => write!(f, "sum({})", k),
+            Agg::Avg(k) => write!(f, "avg({})", k),
+            Agg::Min(k) => write!(f, "min({})", k),
+            Agg::Max(k) => write!(f, "max({})", k),
+            Agg::CountDistinct(k) => write!(f, "count_distinct({})", k),
+        }
+    }
+}
+
+/// Output of [`dump_rollup`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RollupFormat {
+    Csv,
+    /// A SQLite db with the rows in `table`, created if missing
+    Sqlite {
+        table: String,
+    },
+}
+
+/// Whether the value in `v` is a number: only digits, signs, exponents and dots, with at
+/// least a digit. SQLite casts any text to a number, `12 EUR` to 12 and `n/a` to 0
+fn numeric(v: &str) -> String {
+    format!("({v}.value not glob '*[^0-9eE.+-]*' and {v}.value glob '*[0-9]*')")
+}
+
+/// Exports a row per value of `group_key`, with the `aggs` of the items having it, e.g. the
+/// count and mean price per brand, sorted by the value. The items without `group_key` are in
+/// a group of their own, with an empty value, first. The last value of each key is used, the
+/// same as it is read back.
+///
+/// Computed by a single grouped query joining the values of the keys, no row of an item is
+/// read into Rust. The values of numeric aggregations that are not numbers are left out and
+/// counted by key in [`ExportSummary::non_numeric_values`]. When appending to a CSV file
+/// that already has rows, the header is not written again
+pub fn dump_rollup(
+    db: &TableMapDb,
+    file_name: &Path,
+    group_key: &str,
+    aggs: Vec<Agg>,
+    format: RollupFormat,
+    overwrite: OverwriteMode,
+) -> Result<ExportSummary, DataToolErrors> {
+    let t = Instant::now();
+    if let Some(agg) = aggs
+        .iter()
+        .enumerate()
+        .find_map(|(i, a)| aggs[..i].contains(a).then_some(a))
+    {
+        return Err(DataToolErrors::InvalidArgument(format!(
+            "{} is aggregated twice",
+            agg
+        )));
+    }
+    // the keys the aggregations read, each joined once
+    let keys: IndexSet<&str> = aggs.iter().filter_map(Agg::key).collect();
+    let mut all_keys = vec![group_key.to_string()];
+    all_keys.extend(keys.iter().map(|k| k.to_string()));
+    let unknown = db.unknown_keys(&all_keys);
+    if !unknown.is_empty() {
+        return Err(DataToolErrors::UnknownColumns(unknown));
+    }
+    let columns = [group_key.to_string()]
+        .into_iter()
+        .chain(aggs.iter().map(Agg::to_string))
+        .collect::<Vec<_>>();
+    let mut summary = ExportSummary::new(columns);
+    db.ensure_read_indexes()?;
+    summary.max_revision = db.max_revision()?;
+    let numeric_keys: IndexSet<&str> = aggs
+        .iter()
+        .filter(|a| a.is_numeric())
+        .filter_map(Agg::key)
+        .collect();
+    let value_of = |key: &str| format!("v{}", keys.get_index_of(key).unwrap_or_default());
+    let mut select = vec!["coalesce(g.value, '')".to_string()];
+    select.extend(
+        aggs.iter()
+            .map(|a| a.sql(&a.key().map_or(String::new(), value_of))),
+    );
+    // the values that are not numbers, by key
+    select.extend(numeric_keys.iter().map(|k| {
+        let v = value_of(k);
+        format!(
+            "count(nullif({v}.value, '')) - count(case when {} then 1 end)",
+            numeric(&v)
+        )
+    }));
+    let mut joins = format!(
+        " left join ({}) g on g.item_id = i.id",
+        LAST_VALUES.replace("{n}", "1")
+    );
+    for (n, _) in keys.iter().enumerate() {
+        joins.push_str(&format!(
+            " left join ({}) v{n} on v{n}.item_id = i.id",
+            LAST_VALUES.replace("{n}", &(n + 2).to_string())
+        ));
+    }
+    let q = format!(
+        "select {} from item_data i{} group by 1 order by 1",
+        select.join(", "),
+        joins
+    );
+    let read = Instant::now();
+    let mut stmt = db.connection.prepare(&db.sql(&q))?;
+    let mut rows = stmt.query(params_from_iter(all_keys.iter()))?;
+    let mut groups = vec![];
+    let mut non_numeric = vec![0; numeric_keys.len()];
+    while let Some(r) = rows.next()? {
+        let row = (0..=aggs.len())
+            .map(|i| r.get::<_, Value>(i))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (i, n) in non_numeric.iter_mut().enumerate() {
+            *n += r.get::<_, usize>(aggs.len() + 1 + i)?;
+        }
+        groups.push(row);
+    }
+    drop(rows);
+    drop(stmt);
+    summary.timings.read = read.elapsed();
+    summary.timings.read_wall = summary.timings.read;
+    summary.non_numeric_values = numeric_keys
+        .iter()
+        .zip(non_numeric)
+        .filter(|(_, n)| *n > 0)
+        .map(|(k, n)| (k.to_string(), n))
+        .collect();
+    for (key, n) in &summary.non_numeric_values {
+        warn!("{} values of {:?} are not numbers, left out", n, key);
+    }
+    let write = Instant::now();
+    let target = TempTarget::new(file_name, overwrite)?;
+    summary.rows_written = match &format {
+        RollupFormat::Csv => write_csv(target.path(), target.append, &summary.columns, &groups)?,
+        RollupFormat::Sqlite { table } => {
+            write_sqlite(target.path(), table, &summary.columns, &aggs, &groups)?
+        }
+    };
+    target.commit()?;
+    summary.timings.write = write.elapsed();
+    summary.elapsed = t.elapsed();
+    summary.report();
+    Ok(summary)
+}
+
+/// The value as written to a CSV file, numbers without a trailing `.0`
+fn csv_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(v) => v.to_string(),
+        Value::Real(v) => v.to_string(),
+        Value::Text(v) => v.clone(),
+        Value::Blob(v) => String::from_utf8_lossy(v).into_owned(),
+    }
+}
+
+/// Writes the rows to the CSV file, returns the number written
+fn write_csv(
+    path: &Path,
+    append: bool,
+    columns: &[String],
+    groups: &[Vec<Value>],
+) -> Result<usize, DataToolErrors> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    // appending to a file that already has rows, so it also has the header
+    let has_header = append && file.metadata()?.len() > 0;
+    let mut writer = csv::Writer::from_writer(io::BufWriter::new(file));
+    if !has_header {
+        writer.write_record(columns)?;
+    }
+    for row in groups {
+        writer.write_record(row.iter().map(csv_value))?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.flush()?;
+    Ok(groups.len())
+}
+
+/// Writes the rows to the table of the SQLite db, returns the number written
+fn write_sqlite(
+    path: &Path,
+    table: &str,
+    columns: &[String],
+    aggs: &[Agg],
+    groups: &[Vec<Value>],
+) -> Result<usize, DataToolErrors> {
+    let db = Connection::open(path)?;
+    let journal_mode = tune_export_db(&db, "main")?;
+    let types = ["text"]
+        .into_iter()
+        .chain(aggs.iter().map(Agg::column_type));
+    let defs = columns
+        .iter()
+        .zip(types)
+        .map(|(c, ty)| format!("{} {}", quote_ident(c), ty))
+        .collect::<Vec<_>>();
+    db.execute_batch(&format!(
+        "create table if not exists {} ({})",
+        quote_ident(table),
+        defs.join(", ")
+    ))?;
+    let q = format!(
+        "insert into {} ({}) values ({})",
+        quote_ident(table),
+        columns
+            .iter()
+            .map(|c| quote_ident(c))
+            .collect::<Vec<_>>()
+            .join(", "),
+        (1..=columns.len())
+            .map(|n| format!("?{}", n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    db.execute_batch("BEGIN")?;
+    let mut stmt = db.prepare(&q)?;
+    for row in groups {
+        stmt.execute(params_from_iter(row))?;
+    }
+    drop(stmt);
+    db.execute_batch("COMMIT")?;
+    restore_journal_mode(&db, "main", &journal_mode)?;
+    Ok(groups.len())
+}
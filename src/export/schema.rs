@@ -0,0 +1,149 @@
+//! Schema of an export, the columns it would write, see [`TableMapDb::dump_schema`]
+
+use super::{column_types, ColumnType, ExportDbOptions, ExportOptions, TempTarget, ID_COLUMN};
+use crate::errors::DataToolErrors;
+use crate::{quote_ident, TableMapDb};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::info;
+
+/// Format of [`TableMapDb::dump_schema`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SchemaFormat {
+    /// An array of objects with the `name`, `key`, `type` and `fill_rate` of each column
+    #[default]
+    Json,
+    /// A row per column, with the same fields as the JSON objects
+    Csv,
+    /// The `create table` statement [`dump_db`](super::dump_db) would run with types inferred
+    Sql,
+}
+
+/// A column of the schema
+struct SchemaColumn {
+    /// name in the output
+    name: String,
+    /// the stored key or computed column it is read from, the first one if
+    /// [`ExportOptions::sanitize_headers`] merged several, `None` for the item id
+    key: Option<String>,
+    column_type: ColumnType,
+    /// share of the items having the key, `None` for the columns that are not stored keys
+    /// or merge several
+    fill_rate: Option<f64>,
+}
+
+impl TableMapDb {
+    /// Writes the columns [`dump_csv`](super::dump_csv) would export with the same
+    /// `options`, in the same order and renamed, with the type inferred
+    /// from the stored values and the share of the items having the key.
+    ///
+    /// The fill rate is over all the items, whatever the filters of the options. Computed
+    /// columns are typed as text and have no fill rate, nor do the id, creation time and
+    /// overflow columns. The file is replaced when appending
+    pub fn dump_schema(
+        &mut self,
+        path: &Path,
+        format: SchemaFormat,
+        options: &ExportOptions,
+    ) -> Result<(), DataToolErrors> {
+        options.check_wide("dump_schema")?;
+        let columns = options.select_columns(self, options.priority_columns.clone())?;
+        let out_columns = options.output_columns(&columns)?;
+        let merge = options.header_merge(&columns)?;
+        let types = column_types(self, &columns, options, true, &HashMap::new())?;
+        let items = self.how_many_items()?;
+        let counts: HashMap<String, usize> = self.key_counts()?.into_iter().collect();
+        let ids = options.include_id.then(|| SchemaColumn {
+            name: ID_COLUMN.to_string(),
+            key: None,
+            column_type: ColumnType::Integer,
+            fill_rate: None,
+        });
+        let schema = ids
+            .into_iter()
+            .chain(
+                out_columns
+                    .into_iter()
+                    .skip(options.include_id as usize)
+                    .zip(merge.groups().zip(types))
+                    .map(|(name, (g, column_type))| {
+                        let key = columns[g[0]].clone();
+                        let fill_rate = counts
+                            .get(&key)
+                            .filter(|_| g.len() == 1 && !options.computed.contains_key(&key))
+                            .map(|n| match items {
+                                0 => 0.0,
+                                _ => *n as f64 / items as f64,
+                            });
+                        SchemaColumn {
+                            name,
+                            key: Some(key),
+                            column_type,
+                            fill_rate,
+                        }
+                    }),
+            )
+            .collect::<Vec<_>>();
+        let target = TempTarget::new(path, options.overwrite)?;
+        let mut out = io::BufWriter::new(fs::File::create(target.path())?);
+        match format {
+            SchemaFormat::Json => write_json(&mut out, &schema)?,
+            SchemaFormat::Csv => write_csv(&mut out, &schema)?,
+            SchemaFormat::Sql => write_sql(&mut out, &schema)?,
+        }
+        out.flush()?;
+        drop(out);
+        target.commit()?;
+        info!("Schema of {} columns written to {:?}", schema.len(), path);
+        Ok(())
+    }
+}
+
+fn write_json(out: &mut impl Write, schema: &[SchemaColumn]) -> Result<(), DataToolErrors> {
+    let columns = schema
+        .iter()
+        .map(|c| {
+            serde_json::json!({
+                "name": c.name,
+                "key": c.key,
+                "type": c.column_type.sql(),
+                "fill_rate": c.fill_rate,
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_writer_pretty(&mut *out, &columns)
+        .map_err(|e| DataToolErrors::GenericError(e.to_string()))?;
+    writeln!(out)?;
+    Ok(())
+}
+
+fn write_csv(out: &mut impl Write, schema: &[SchemaColumn]) -> Result<(), DataToolErrors> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["name", "key", "type", "fill_rate"])?;
+    for c in schema {
+        writer.write_record([
+            c.name.clone(),
+            c.key.clone().unwrap_or_default(),
+            c.column_type.sql().to_string(),
+            c.fill_rate.map(|r| r.to_string()).unwrap_or_default(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_sql(out: &mut impl Write, schema: &[SchemaColumn]) -> Result<(), DataToolErrors> {
+    let columns = schema
+        .iter()
+        .map(|c| format!("    {} {}", quote_ident(&c.name), c.column_type.sql()))
+        .collect::<Vec<_>>();
+    writeln!(
+        out,
+        "create table {} (\n{}\n);",
+        quote_ident(&ExportDbOptions::default().table_name),
+        columns.join(",\n")
+    )?;
+    Ok(())
+}
@@ -0,0 +1,378 @@
+//! SQL text export, `CREATE TABLE` and `INSERT` statements to load into other databases
+
+use super::{
+    column_types, proc_ids, ColumnType, ExportOptions, ExportSummary, ExportTimings, TempTarget,
+};
+use crate::errors::DataToolErrors;
+use crate::{quote_ident, TableMapDb};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+use tracing::warn;
+
+/// Exports the items as a `.sql` file, to be loaded with the command line client of the
+/// database, with the columns [`dump_csv`](super::dump_csv) would export. The file creates
+/// the table, as set by [`ExportSqlOptions::preamble`], then inserts the rows, with an
+/// `INSERT` statement holding all the rows of a chunk, or at most
+/// [`ExportSqlOptions::max_insert_rows`].
+///
+/// Columns are `TEXT`, unless typed by the options, the item id, if included, an integer.
+/// Rows with a value that does not parse as the type of its column, or that can not be
+/// written as a string of the dialect, are failed rows. When appending to a file that
+/// already has statements, only the rows are added
+pub async fn dump_sql(
+    db: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    sql_options: ExportSqlOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let t = Instant::now();
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    chunk.validate()?;
+    options.check_wide("dump_sql")?;
+    sql_options.validate()?;
+    let target = TempTarget::new(file_name, options.overwrite)?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(target.path())?;
+    // appending to a file that already has rows, so it also creates the table
+    let has_table = target.append && file.metadata()?.len() > 0;
+    let mut writer = io::BufWriter::new(file);
+    let keys_scan = Instant::now();
+    let columns = options.select_columns(db, column_order)?;
+    let keys_scan = keys_scan.elapsed();
+    if columns.is_empty() {
+        // a table needs at least one column
+        warn!("No columns to export, writing no statements");
+        writer.flush()?;
+        drop(writer);
+        target.commit()?;
+        return Ok(ExportSummary::empty(t));
+    }
+    let types = column_types(
+        db,
+        &columns,
+        &options,
+        sql_options.infer_types,
+        &sql_options.column_types,
+    )?;
+    let out_columns = options.output_columns(&columns)?;
+    let mut out_types = types.clone();
+    if options.include_id {
+        out_types.insert(0, ColumnType::Integer);
+    }
+    let dialect = sql_options.dialect;
+    let table = dialect.ident(&sql_options.table_name);
+    if !has_table {
+        let preamble = sql_options.preamble_sql(&table, &out_columns, &out_types);
+        writer.write_all(preamble.as_bytes())?;
+    }
+    let insert = format!(
+        "INSERT INTO {} ({}) VALUES\n",
+        table,
+        out_columns
+            .iter()
+            .map(|c| dialect.ident(c))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let mut summary = ExportSummary::new(out_columns);
+    summary.column_types = out_types;
+    let max_rows = sql_options.max_insert_rows.unwrap_or(usize::MAX);
+    let mut tuple = String::new();
+    // rows in the current statement
+    let mut rows = 0;
+    let stats = proc_ids(db, &options, chunk, columns, |n| {
+        for (id, row) in n.iter() {
+            options.check_cancelled()?;
+            tuple.clear();
+            let id = options.include_id.then_some(*id);
+            let res = dialect.tuple(&mut tuple, id, row, &types);
+            if res.is_ok() {
+                writer.write_all(if rows == 0 { insert.as_bytes() } else { b",\n" })?;
+                writer.write_all(tuple.as_bytes())?;
+                rows += 1;
+                if rows == max_rows {
+                    writer.write_all(b";\n")?;
+                    rows = 0;
+                }
+            }
+            summary.record(res, options.strict)?;
+        }
+        // each chunk ends its statement
+        if rows > 0 {
+            writer.write_all(b";\n")?;
+            rows = 0;
+        }
+        Ok(())
+    })
+    .await?;
+    summary.rows_skipped_by_filter = stats.skipped;
+    summary.ids_not_found = stats.ids_not_found;
+    summary.max_revision = stats.max_revision;
+    summary.chunks = stats.chunks;
+    summary.timings = ExportTimings {
+        keys_scan,
+        ..stats.timings
+    };
+    summary.spilled_keys = stats.spilled_keys;
+    summary.merged_headers = stats.merged_headers;
+    writer.flush()?;
+    drop(writer);
+    target.commit()?;
+    summary.elapsed = t.elapsed();
+    summary.report();
+    Ok(summary)
+}
+
+/// Options of the SQL text export, [`dump_sql`]
+#[derive(Debug, Clone)]
+pub struct ExportSqlOptions {
+    dialect: Dialect,
+    table_name: String,
+    preamble: SqlPreamble,
+    max_insert_rows: Option<usize>,
+    infer_types: bool,
+    column_types: HashMap<String, ColumnType>,
+}
+
+impl Default for ExportSqlOptions {
+    fn default() -> Self {
+        Self {
+            dialect: Default::default(),
+            table_name: "products".to_string(),
+            preamble: Default::default(),
+            max_insert_rows: None,
+            infer_types: false,
+            column_types: HashMap::new(),
+        }
+    }
+}
+
+impl ExportSqlOptions {
+    /// Database the statements are written for, SQLite by default
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Name of the table the rows are inserted into, `products` by default
+    pub fn table_name(mut self, table_name: impl Into<String>) -> Self {
+        self.table_name = table_name.into();
+        self
+    }
+
+    /// Statements written before the rows, [`SqlPreamble::Create`] by default
+    pub fn preamble(mut self, preamble: SqlPreamble) -> Self {
+        self.preamble = preamble;
+        self
+    }
+
+    /// Most rows in an `INSERT` statement. By default all the rows of a chunk are inserted
+    /// by a single statement, which can be more than the database accepts for large chunks
+    pub fn max_insert_rows(mut self, rows: usize) -> Self {
+        self.max_insert_rows = Some(rows);
+        self
+    }
+
+    /// Type the columns by their values, the same as [`ExportDbOptions::infer_types`].
+    /// Values of typed columns are written as numbers, empty ones as `NULL`
+    ///
+    /// [`ExportDbOptions::infer_types`]: super::ExportDbOptions::infer_types
+    pub fn infer_types(mut self, infer_types: bool) -> Self {
+        self.infer_types = infer_types;
+        self
+    }
+
+    /// Pin the type of a column, whether types are inferred or not
+    pub fn column_type(mut self, column: impl Into<String>, column_type: ColumnType) -> Self {
+        self.column_types.insert(column.into(), column_type);
+        self
+    }
+
+    fn validate(&self) -> Result<(), DataToolErrors> {
+        if self.table_name.is_empty() {
+            return Err(DataToolErrors::InvalidArgument(
+                "the table name can not be empty".to_string(),
+            ));
+        }
+        if self.max_insert_rows == Some(0) {
+            return Err(DataToolErrors::InvalidArgument(
+                "an INSERT statement needs at least one row".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Statements creating the table, `table` is already quoted
+    fn preamble_sql(&self, table: &str, columns: &[String], types: &[ColumnType]) -> String {
+        let create = |if_not_exists| {
+            self.dialect
+                .create_table(table, columns, types, if_not_exists)
+        };
+        match self.preamble {
+            SqlPreamble::Create => create(false),
+            SqlPreamble::DropAndCreate => {
+                format!("DROP TABLE IF EXISTS {};\n{}", table, create(false))
+            }
+            SqlPreamble::Truncate => {
+                // SQLite has no TRUNCATE, a DELETE without a WHERE is optimized the same way
+                let truncate = match self.dialect {
+                    Dialect::Sqlite => "DELETE FROM",
+                    Dialect::Postgres | Dialect::Mysql => "TRUNCATE TABLE",
+                };
+                format!("{}{} {};\n", create(true), truncate, table)
+            }
+        }
+    }
+}
+
+/// Database a SQL text export is written for, deciding how identifiers and strings are quoted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    Sqlite,
+    /// strings are written for `standard_conforming_strings`, on by default since
+    /// PostgreSQL 9.1, so backslashes are not escaped
+    Postgres,
+    /// strings are escaped with backslashes, which does not load with the
+    /// `NO_BACKSLASH_ESCAPES` SQL mode
+    Mysql,
+}
+
+impl Dialect {
+    pub(super) fn ident(self, name: &str) -> String {
+        match self {
+            Dialect::Sqlite | Dialect::Postgres => quote_ident(name),
+            Dialect::Mysql => format!("`{}`", name.replace('`', "``")),
+        }
+    }
+
+    /// `CREATE TABLE` statement, `table` is already quoted
+    pub(super) fn create_table(
+        self,
+        table: &str,
+        columns: &[String],
+        types: &[ColumnType],
+        if_not_exists: bool,
+    ) -> String {
+        let definitions = columns
+            .iter()
+            .zip(types)
+            .map(|(c, ty)| format!("  {} {}", self.ident(c), self.sql_type(*ty)))
+            .collect::<Vec<_>>()
+            .join(",\n");
+        let if_not_exists = if if_not_exists { "IF NOT EXISTS " } else { "" };
+        format!(
+            "CREATE TABLE {}{} (\n{}\n);\n",
+            if_not_exists, table, definitions
+        )
+    }
+
+    fn sql_type(self, ty: ColumnType) -> &'static str {
+        match (self, ty) {
+            (_, ColumnType::Text) => "TEXT",
+            (Dialect::Sqlite, ColumnType::Integer) => "INTEGER",
+            (Dialect::Sqlite, ColumnType::Real) => "REAL",
+            (Dialect::Postgres | Dialect::Mysql, ColumnType::Integer) => "BIGINT",
+            (Dialect::Postgres, ColumnType::Real) => "DOUBLE PRECISION",
+            (Dialect::Mysql, ColumnType::Real) => "DOUBLE",
+        }
+    }
+
+    /// Writes the row as a parenthesized list of values
+    fn tuple(
+        self,
+        out: &mut String,
+        id: Option<i64>,
+        row: &[Option<String>],
+        types: &[ColumnType],
+    ) -> Result<(), String> {
+        out.push('(');
+        if let Some(id) = id {
+            out.push_str(&id.to_string());
+        }
+        for (i, (v, ty)) in row.iter().zip(types).enumerate() {
+            if i > 0 || id.is_some() {
+                out.push_str(", ");
+            }
+            self.value(out, v.as_deref().unwrap_or_default(), *ty)?;
+        }
+        out.push(')');
+        Ok(())
+    }
+
+    /// Writes a cell as a literal, empty cells of typed columns are `NULL`
+    fn value(self, out: &mut String, v: &str, ty: ColumnType) -> Result<(), String> {
+        match ty {
+            ColumnType::Text => self.string(out, v)?,
+            _ if v.is_empty() => out.push_str("NULL"),
+            _ => out.push_str(&number(v, ty)?),
+        }
+        Ok(())
+    }
+
+    fn string(self, out: &mut String, v: &str) -> Result<(), String> {
+        out.push('\'');
+        match self {
+            Dialect::Sqlite | Dialect::Postgres => {
+                // would end the string early in SQLite, and is rejected by PostgreSQL
+                if v.contains('\0') {
+                    return Err(format!(
+                        "a value has a NUL character, which {:?} strings can not hold",
+                        self
+                    ));
+                }
+                out.push_str(&v.replace('\'', "''"));
+            }
+            Dialect::Mysql => {
+                for c in v.chars() {
+                    match c {
+                        '\'' => out.push_str("\\'"),
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\0' => out.push_str("\\0"),
+                        // ends the input on Windows
+                        '\x1a' => out.push_str("\\Z"),
+                        c => out.push(c),
+                    }
+                }
+            }
+        }
+        out.push('\'');
+        Ok(())
+    }
+}
+
+/// A cell of a typed column, as written in SQL
+pub(super) fn number(v: &str, ty: ColumnType) -> Result<String, String> {
+    match ty {
+        ColumnType::Text => Ok(v.to_string()),
+        ColumnType::Integer => v
+            .parse::<i64>()
+            .map(|v| v.to_string())
+            .map_err(|_| format!("{:?} is not an integer", v)),
+        ColumnType::Real => v
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .map(|v| v.to_string())
+            .ok_or_else(|| format!("{:?} is not a finite number", v)),
+    }
+}
+
+/// Statements a SQL text export writes before the rows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SqlPreamble {
+    /// `CREATE TABLE`, loading fails if the table already exists
+    #[default]
+    Create,
+    /// `DROP TABLE IF EXISTS`, then `CREATE TABLE`
+    DropAndCreate,
+    /// `CREATE TABLE IF NOT EXISTS`, then empty the table with `TRUNCATE`, `DELETE` for SQLite
+    Truncate,
+}
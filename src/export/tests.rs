@@ -0,0 +1,1576 @@
+use super::*;
+use crate::tests::TestDir;
+
+/// Items with some keys each, the same fixture for every export test
+fn fixture(db: &mut TableMapDb) {
+    db.add_row("a", [("name", "apple"), ("price", "1")])
+        .unwrap();
+    db.add_row("b", [("price", "2"), ("color", "blue")])
+        .unwrap();
+    db.add_row("c", [("name", "cherry")]).unwrap();
+    db.add_row("d", [("color", "red"), ("name", "date"), ("price", "4")])
+        .unwrap();
+}
+
+/// The CSV of [`fixture`] with the default options
+const FIXTURE_CSV: &str = "name,price,color\napple,1,\n,2,blue\ncherry,,\ndate,4,red\n";
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn csv_export_is_the_same_for_any_chunk_size() {
+    let dir = TestDir::new("csv_chunks");
+    let mut db = dir.db();
+    fixture(&mut db);
+    for chunk in [1, 2, 3, 100] {
+        let out = dir.path(&format!("{}.csv", chunk));
+        let options = ExportOptions::default();
+        dump_csv(&mut db, &out, options.chunk(chunk), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), FIXTURE_CSV);
+    }
+    // an id list instead of a range
+    let out = dir.path("desc.csv");
+    let options = ExportOptions::default().order(IterOrder::InsertionDesc);
+    dump_csv(&mut db, &out, options.chunk(3), Default::default())
+        .await
+        .unwrap();
+    let mut lines: Vec<_> = FIXTURE_CSV.lines().collect();
+    lines[1..].reverse();
+    assert_eq!(fs::read_to_string(&out).unwrap(), lines.join("\n") + "\n");
+}
+
+#[test]
+fn sync_csv_export_is_the_same_for_any_chunk_size() {
+    let dir = TestDir::new("csv_chunks_sync");
+    let mut db = dir.db();
+    fixture(&mut db);
+    for chunk in [1, 2, 3, 100] {
+        let out = dir.path(&format!("{}.csv", chunk));
+        let options = ExportOptions::default();
+        dump_csv_sync(&mut db, &out, options.chunk(chunk), Default::default()).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), FIXTURE_CSV);
+    }
+}
+
+#[test]
+fn existing_files_are_refused_replaced_or_appended_to() {
+    let dir = TestDir::new("overwrite_modes");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let out = dir.path("out.csv");
+    fs::write(&out, "old\n").unwrap();
+    let res = dump_csv_sync(
+        &mut db,
+        &out,
+        ExportOptions::default().chunk(2),
+        Default::default(),
+    );
+    assert!(
+        matches!(&res, Err(DataToolErrors::FileExists(f)) if f == &out),
+        "{:?}",
+        res
+    );
+    assert_eq!(fs::read_to_string(&out).unwrap(), "old\n");
+    let options = ExportOptions::default().overwrite(OverwriteMode::Overwrite);
+    dump_csv_sync(&mut db, &out, options.chunk(2), Default::default()).unwrap();
+    assert_eq!(fs::read_to_string(&out).unwrap(), FIXTURE_CSV);
+    // the rows are added after the existing ones, without a second header
+    let options = ExportOptions::default().overwrite(OverwriteMode::Append);
+    let summary = dump_csv_sync(&mut db, &out, options.chunk(2), Default::default()).unwrap();
+    assert_eq!(summary.rows_written, 4);
+    let rows = FIXTURE_CSV.split_once('\n').unwrap().1;
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        FIXTURE_CSV.to_string() + rows
+    );
+    // an empty file gets one
+    let empty = dir.path("empty.csv");
+    fs::write(&empty, "").unwrap();
+    let options = ExportOptions::default().overwrite(OverwriteMode::Append);
+    dump_csv_sync(&mut db, &empty, options.chunk(2), Default::default()).unwrap();
+    assert_eq!(fs::read_to_string(&empty).unwrap(), FIXTURE_CSV);
+    assert!(!dir.path("out.csv.tmp").exists() && !dir.path("empty.csv.tmp").exists());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn failed_exports_leave_the_existing_file_as_it_was() {
+    let dir = TestDir::new("failed_overwrite");
+    let mut db = dir.db();
+    fixture(&mut db);
+    for (name, mode) in [
+        ("overwrite", OverwriteMode::Overwrite),
+        ("append", OverwriteMode::Append),
+    ] {
+        for (csv, cancel) in [(true, true), (true, false), (false, true)] {
+            let out = dir.path(&format!("{}.{}", name, if csv { "csv" } else { "db" }));
+            match csv {
+                true => fs::write(&out, "old\n").unwrap(),
+                false if !out.exists() => Connection::open(&out)
+                    .unwrap()
+                    .execute_batch("create table other (a); insert into other values (1)")
+                    .unwrap(),
+                false => {}
+            }
+            let before = fs::read(&out).unwrap();
+            let token = CancellationToken::new();
+            let cancelling = token.clone();
+            // cancelled once the export is under way, or failed by a strict option
+            let options = ExportOptions::default()
+                .overwrite(mode)
+                .cancel_token(token)
+                .row_filter(move |row| {
+                    if cancel && row.get("name").is_some_and(|n| n == "cherry") {
+                        cancelling.cancel();
+                    }
+                    true
+                })
+                .strict(!cancel);
+            let res = if csv {
+                let priority = match cancel {
+                    true => vec![],
+                    false => vec!["nope".to_string()],
+                };
+                dump_csv_sync(
+                    &mut db,
+                    &out,
+                    options.chunk(1).priority_columns(priority),
+                    Default::default(),
+                )
+            } else {
+                dump_db_sync(&mut db, &out, options.chunk(1), Default::default())
+            };
+            match cancel {
+                true => assert!(matches!(res, Err(DataToolErrors::Cancelled)), "{:?}", res),
+                false => assert!(res.is_err()),
+            }
+            assert_eq!(fs::read(&out).unwrap(), before);
+        }
+    }
+    let mut left: Vec<_> = fs::read_dir(dir.path(""))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|f| !f.starts_with("test.db"))
+        .collect();
+    left.sort();
+    assert_eq!(
+        left,
+        ["append.csv", "append.db", "overwrite.csv", "overwrite.db"]
+    );
+}
+
+#[test]
+fn summaries_count_the_failed_rows_unless_strict() {
+    let dir = TestDir::new("summary_strict");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let out = dir.path("out.csv");
+    let t = Instant::now();
+    let summary = dump_csv_sync(
+        &mut db,
+        &out,
+        ExportOptions::default().chunk(2),
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!((summary.rows_written, summary.rows_failed), (4, 0));
+    assert_eq!(summary.columns, ["name", "price", "color"]);
+    assert!(summary.elapsed <= t.elapsed());
+    // every item has the same key, so only the first row can be inserted
+    let options = ExportOptions::default().computed_column("source", |_| "shop".to_string());
+    let db_options = ExportDbOptions::default().primary_key("source");
+    let out = dir.path("out.db");
+    let summary = dump_db_sync(&mut db, &out, options.clone(), db_options.clone()).unwrap();
+    assert_eq!((summary.rows_written, summary.rows_failed), (1, 3));
+    assert_eq!(summary.columns, ["name", "price", "color", "source"]);
+    assert_eq!(db_column(&out, "name"), [Some("apple".to_string())]);
+    let strict = dir.path("strict.db");
+    let res = dump_db_sync(&mut db, &strict, options.strict(true), db_options);
+    assert!(
+        matches!(&res, Err(DataToolErrors::RowWriteFailed { row: 2, .. })),
+        "{:?}",
+        res
+    );
+    assert!(!strict.exists());
+}
+
+#[test]
+fn zero_chunk_size_is_rejected() {
+    let dir = TestDir::new("zero_chunk");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let out = dir.path("out.csv");
+    let result = dump_csv_sync(
+        &mut db,
+        &out,
+        ExportOptions::default().chunk(0),
+        Default::default(),
+    );
+    assert!(matches!(result, Err(DataToolErrors::InvalidArgument(_))));
+    let out = dir.path("out.db");
+    let options = ExportOptions::default().chunk(ChunkStrategy::ByCellCount(0));
+    let result = dump_db_sync(&mut db, &out, options, Default::default());
+    assert!(matches!(result, Err(DataToolErrors::InvalidArgument(_))));
+    assert!(!dir.path("out.csv").exists() && !out.exists());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn zero_chunk_size_is_rejected_by_async_exports() {
+    let dir = TestDir::new("zero_chunk_async");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let out = dir.path("out.csv");
+    let result = dump_csv(
+        &mut db,
+        &out,
+        ExportOptions::default().chunk(0),
+        Default::default(),
+    )
+    .await;
+    assert!(matches!(result, Err(DataToolErrors::InvalidArgument(_))));
+    let out = dir.path("out.db");
+    let options = ExportOptions::default().chunk(0);
+    let result = dump_db(&mut db, &out, options, Default::default()).await;
+    assert!(matches!(result, Err(DataToolErrors::InvalidArgument(_))));
+}
+
+#[test]
+fn empty_db_is_exported() {
+    let dir = TestDir::new("empty");
+    let mut db = dir.db();
+    let out = dir.path("out.csv");
+    let summary = dump_csv_sync(
+        &mut db,
+        &out,
+        ExportOptions::default().chunk(10),
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(summary.rows_written, 0);
+    assert_eq!(fs::read_to_string(&out).unwrap(), "");
+    let out = dir.path("out.db");
+    let summary = dump_db_sync(&mut db, &out, Default::default(), Default::default()).unwrap();
+    assert_eq!(summary.rows_written, 0);
+    assert!(summary.columns.is_empty());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread")]
+async fn exports_of_the_same_data_are_identical() {
+    let dir = TestDir::new("deterministic");
+    let mut db = dir.db();
+    for i in 0..500 {
+        // chunks with more cells take longer to read
+        let cells = (0..i % 20).map(|k| (format!("k{}", k), k.to_string()));
+        let cells = iter::once(("n".to_string(), i.to_string())).chain(cells);
+        db.add_row(&format!("item{}", i), cells).unwrap();
+    }
+    let mut exports = vec![];
+    for run in 0..2 {
+        let out = dir.path(&format!("{}.csv", run));
+        let options = ExportOptions::default().max_concurrent(8);
+        dump_csv(&mut db, &out, options.chunk(7), Default::default())
+            .await
+            .unwrap();
+        exports.push(fs::read(&out).unwrap());
+    }
+    assert_eq!(exports[0], exports[1]);
+    let ids: Vec<usize> = csv::Reader::from_reader(&exports[0][..])
+        .records()
+        .map(|r| r.unwrap()[0].parse().unwrap())
+        .collect();
+    assert_eq!(ids, (0..500).collect::<Vec<_>>());
+}
+
+/// Keys that break SQL spliced together without escaping
+const HOSTILE_KEYS: [&str; 4] = [
+    "C/size \"large\"",
+    "a; drop table products; --",
+    "multi\nline",
+    "it's",
+];
+
+#[test]
+fn hostile_keys_are_exported() {
+    let dir = TestDir::new("hostile_keys");
+    let mut db = dir.db();
+    for i in 0..3 {
+        let cells = HOSTILE_KEYS.map(|k| (k, format!("{} {}", k, i)));
+        db.add_row(&i.to_string(), cells).unwrap();
+    }
+    let out = dir.path("out.db");
+    let db_options = ExportDbOptions::default().table_name("a \"table\"; --");
+    let options = ExportOptions::default().chunk(2);
+    dump_db_sync(&mut db, &out, options, db_options).unwrap();
+    let conn = Connection::open(&out).unwrap();
+    assert_eq!(
+        table_columns(&conn, "a \"table\"; --").unwrap(),
+        HOSTILE_KEYS
+    );
+    for key in HOSTILE_KEYS {
+        let q = format!(
+            "select {} from \"a \"\"table\"\"; --\" order by rowid",
+            quote_ident(key)
+        );
+        let mut stmt = conn.prepare(&q).unwrap();
+        let values: Vec<String> = stmt
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|v| v.unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            (0..3).map(|i| format!("{} {}", key, i)).collect::<Vec<_>>()
+        );
+    }
+
+    let out = dir.path("out.csv");
+    dump_csv_sync(
+        &mut db,
+        &out,
+        ExportOptions::default().chunk(2),
+        Default::default(),
+    )
+    .unwrap();
+    let mut reader = csv::Reader::from_path(&out).unwrap();
+    assert_eq!(reader.headers().unwrap(), &HOSTILE_KEYS[..]);
+    assert_eq!(reader.records().count(), 3);
+
+    // and the keys as a column order
+    let order = vec![HOSTILE_KEYS[2].to_string(), HOSTILE_KEYS[0].to_string()];
+    let out = dir.path("ordered.csv");
+    dump_csv_sync(
+        &mut db,
+        &out,
+        ExportOptions::default().chunk(2).priority_columns(order),
+        Default::default(),
+    )
+    .unwrap();
+    let mut reader = csv::Reader::from_path(&out).unwrap();
+    let headers: Vec<_> = reader.headers().unwrap().iter().collect();
+    assert_eq!(headers[..2], [HOSTILE_KEYS[2], HOSTILE_KEYS[0]]);
+}
+
+/// The values of `column` in the table of a SQLite export, in rowid order
+fn db_column(file: &Path, column: &str) -> Vec<Option<String>> {
+    let conn = Connection::open(file).unwrap();
+    let q = format!(
+        "select {} from products order by rowid",
+        quote_ident(column)
+    );
+    let mut stmt = conn.prepare(&q).unwrap();
+    let values = stmt
+        .query_map([], |r| r.get(0))
+        .unwrap()
+        .map(|v| v.unwrap())
+        .collect();
+    values
+}
+
+#[test]
+fn headers_only_differing_in_case_collide_in_sqlite_exports() {
+    let dir = TestDir::new("header_case");
+    let mut db = dir.db();
+    db.add_row("a", [("Price", "1"), ("price", "10")]).unwrap();
+    db.add_row("b", [("price", "20")]).unwrap();
+    db.add_row("c", [("Price", "3")]).unwrap();
+    // CSV headers are case-sensitive
+    let out = dir.path("out.csv");
+    dump_csv_sync(
+        &mut db,
+        &out,
+        ExportOptions::default().chunk(2),
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "Price,price\n1,10\n,20\n3,\n"
+    );
+    let collide = |res: Result<ExportSummary, DataToolErrors>| matches!(res, Err(DataToolErrors::InvalidArgument(m)) if m.contains("renamed columns collide"));
+    let out = dir.path("out.db");
+    let res = dump_db_sync(&mut db, &out, Default::default(), Default::default());
+    assert!(collide(res));
+    let res = dump_db_attach(&mut db, &out, Default::default(), Default::default());
+    assert!(collide(res));
+    assert!(!out.exists());
+
+    let sanitize = |merge_duplicates| {
+        ExportOptions::default().sanitize_headers(HeaderSanitize {
+            merge_duplicates,
+            ..Default::default()
+        })
+    };
+    for (i, (policy, expected)) in [
+        (DuplicatePolicy::First, ["1", "20", "3"]),
+        (DuplicatePolicy::Last, ["10", "20", "3"]),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let expected = expected.map(|v| Some(v.to_string()));
+        let out = dir.path(&format!("sync{}.db", i));
+        let summary = dump_db_sync(&mut db, &out, sanitize(policy), Default::default()).unwrap();
+        assert_eq!(summary.columns, ["Price"]);
+        assert_eq!(
+            summary.merged_headers,
+            [(
+                "Price".to_string(),
+                vec!["Price".to_string(), "price".to_string()]
+            )]
+        );
+        assert_eq!(db_column(&out, "Price"), expected);
+        let out = dir.path(&format!("attach{}.db", i));
+        dump_db_attach(&mut db, &out, sanitize(policy), Default::default()).unwrap();
+        assert_eq!(db_column(&out, "Price"), expected);
+    }
+    let out = dir.path("error.db");
+    let res = dump_db_sync(
+        &mut db,
+        &out,
+        sanitize(DuplicatePolicy::Error),
+        Default::default(),
+    );
+    assert!(
+        matches!(&res, Err(DataToolErrors::ValidationError { key, .. }) if key == "Price"),
+        "{:?}",
+        res.map(|s| s.columns)
+    );
+    // and the item id column
+    let id_dir = TestDir::new("header_case_id");
+    let mut db = id_dir.db();
+    db.add_row("a", [("_ID", "x")]).unwrap();
+    let options = ExportOptions::default().include_id(true);
+    let res = dump_db_sync(&mut db, &dir.path("id.db"), options, Default::default());
+    assert!(
+        matches!(&res, Err(DataToolErrors::InvalidArgument(_))),
+        "{:?}",
+        res.map(|s| s.columns)
+    );
+}
+
+/// The journal mode stored in a SQLite export, and whether a -wal file was left next to it
+fn journal_mode(file: &Path) -> (String, bool) {
+    let mut wal = file.as_os_str().to_owned();
+    wal.push("-wal");
+    let wal = Path::new(&wal).exists();
+    let conn = Connection::open(file).unwrap();
+    let mode = conn
+        .query_row("PRAGMA journal_mode", [], |r| r.get(0))
+        .unwrap();
+    (mode, wal)
+}
+
+#[test]
+fn sqlite_exports_are_left_in_rollback_mode() {
+    let dir = TestDir::new("journal_mode");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let delete = ("delete".to_string(), false);
+    let out = dir.path("sync.db");
+    let options = ExportOptions::default().chunk(2);
+    dump_db_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!(journal_mode(&out), delete);
+    let out = dir.path("attach.db");
+    dump_db_attach(&mut db, &out, Default::default(), Default::default()).unwrap();
+    assert_eq!(journal_mode(&out), delete);
+    let out = dir.path("lookup.db");
+    let format = LookupFormat::Sqlite {
+        table: "lookup".to_string(),
+        unique: true,
+    };
+    let duplicates = DuplicatePolicy::First;
+    dump_lookup(
+        &db,
+        &out,
+        "name",
+        "price",
+        format,
+        duplicates,
+        OverwriteMode::Error,
+    )
+    .unwrap();
+    assert_eq!(journal_mode(&out), delete);
+    let out = dir.path("rollup.db");
+    let format = RollupFormat::Sqlite {
+        table: "rollup".to_string(),
+    };
+    dump_rollup(
+        &db,
+        &out,
+        "color",
+        vec![Agg::Count],
+        format,
+        OverwriteMode::Error,
+    )
+    .unwrap();
+    assert_eq!(journal_mode(&out), delete);
+}
+
+#[test]
+fn appending_to_a_wal_db_keeps_it_in_wal_mode() {
+    let dir = TestDir::new("journal_mode_append");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let out = dir.path("out.db");
+    Connection::open(&out)
+        .unwrap()
+        .execute_batch("PRAGMA journal_mode = WAL; create table other (a)")
+        .unwrap();
+    let options = ExportOptions::default()
+        .overwrite(OverwriteMode::Append)
+        .chunk(2);
+    dump_db_sync(&mut db, &out, options.clone(), Default::default()).unwrap();
+    assert_eq!(journal_mode(&out).0, "wal");
+    let db_options = ExportDbOptions::default().table_name("attached");
+    dump_db_attach(&mut db, &out, options, db_options).unwrap();
+    assert_eq!(journal_mode(&out).0, "wal");
+}
+
+#[test]
+fn exports_read_the_chunks_and_priority_columns_from_the_options() {
+    let dir = TestDir::new("options_chunk_priority");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let priority = vec!["color".to_string(), "price".to_string()];
+    let options = ExportOptions::default()
+        .chunk(ChunkStrategy::ByCellCount(2))
+        .priority_columns(priority);
+    let out = dir.path("sync.db");
+    let summary = dump_db_sync(&mut db, &out, options.clone(), Default::default()).unwrap();
+    assert_eq!(summary.columns, ["color", "price", "name"]);
+    assert_eq!(summary.rows_written, 4);
+    let chunks = summary.chunks;
+    assert!(chunks > 1);
+    let out = dir.path("attach.db");
+    let summary = dump_db_attach(&mut db, &out, options.clone(), Default::default()).unwrap();
+    assert_eq!(summary.columns, ["color", "price", "name"]);
+    assert_eq!(summary.rows_written, 4);
+    let out = dir.path("out.csv");
+    let summary = dump_csv_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!(summary.chunks, chunks);
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "color,price,name\n,1,apple\nblue,2,\n,,cherry\nred,4,date\n"
+    );
+}
+
+#[test]
+fn unknown_priority_columns_are_exported_empty_unless_strict() {
+    let dir = TestDir::new("unknown_priority_export");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let priority = vec!["nope".to_string(), "price".to_string(), "nope".to_string()];
+    let out = dir.path("out.csv");
+    dump_csv_sync(
+        &mut db,
+        &out,
+        ExportOptions::default()
+            .chunk(2)
+            .priority_columns(priority.clone()),
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "nope,price,name,color\n,1,apple,\n,2,,blue\n,,cherry,\n,4,date,red\n"
+    );
+    let strict = dir.path("strict.csv");
+    let options = ExportOptions::default().strict(true);
+    let res = dump_csv_sync(
+        &mut db,
+        &strict,
+        options.chunk(2).priority_columns(priority),
+        Default::default(),
+    );
+    assert!(
+        matches!(&res, Err(DataToolErrors::UnknownColumns(c)) if c == &["nope"]),
+        "{:?}",
+        res
+    );
+    assert!(!strict.exists());
+}
+
+#[test]
+fn column_types_widen_from_integer_to_real_to_text() {
+    let widen = |values: &[&str]| values.iter().fold(ColumnType::Integer, |ty, v| ty.widen(v));
+    assert_eq!(widen(&["1", "-2", "30"]), ColumnType::Integer);
+    assert_eq!(widen(&["1", "2.5", "3"]), ColumnType::Real);
+    assert_eq!(widen(&["1e3"]), ColumnType::Real);
+    assert_eq!(widen(&["1", "2.5", "x", "4"]), ColumnType::Text);
+    assert_eq!(widen(&["NaN"]), ColumnType::Text);
+    // the zeros would be lost
+    assert_eq!(widen(&["1", "007"]), ColumnType::Text);
+    assert_eq!(widen(&["1.5", "007"]), ColumnType::Text);
+    // never narrowed back
+    assert_eq!(ColumnType::Text.widen("1"), ColumnType::Text);
+    assert_eq!(ColumnType::Real.widen("1"), ColumnType::Real);
+
+    let dir = TestDir::new("widen");
+    let mut db = dir.db();
+    fixture(&mut db);
+    db.add_row("e", [("price", "4.5"), ("color", "7")]).unwrap();
+    let out = dir.path("out.db");
+    let db_options = ExportDbOptions::default().infer_types(true);
+    let summary = dump_db_sync(&mut db, &out, Default::default(), db_options).unwrap();
+    assert_eq!(
+        summary.column_types,
+        [ColumnType::Text, ColumnType::Real, ColumnType::Text]
+    );
+    let conn = Connection::open(&out).unwrap();
+    let types: Vec<String> = conn
+        .prepare("select typeof(price) from products order by rowid")
+        .unwrap()
+        .query_map([], |r| r.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(types, ["real", "real", "null", "real", "real"]);
+}
+
+#[test]
+fn columns_are_excluded_or_included_in_order() {
+    let dir = TestDir::new("include_exclude");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let out = dir.path("excluded.csv");
+    let options = ExportOptions::default().exclude_columns(vec!["price".to_string()]);
+    dump_csv_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "name,color\napple,\n,blue\ncherry,\ndate,red\n"
+    );
+    // in the given order, even the column without any data
+    let only = vec![
+        "color".to_string(),
+        "missing".to_string(),
+        "name".to_string(),
+    ];
+    let out = dir.path("included.csv");
+    let options = ExportOptions::default().include_only(only.clone());
+    dump_csv_sync(&mut db, &out, options.clone(), Default::default()).unwrap();
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "color,missing,name\n,,apple\nblue,,\n,,cherry\nred,,date\n"
+    );
+    let out = dir.path("included.db");
+    let summary = dump_db_sync(&mut db, &out, options.clone(), Default::default()).unwrap();
+    assert_eq!(summary.columns, only);
+    assert_eq!(db_column(&out, "missing"), vec![Some(String::new()); 4]);
+    // contradicting the priority columns
+    let price = vec!["price".to_string()];
+    for options in [
+        options.priority_columns(price.clone()),
+        ExportOptions::default()
+            .exclude_columns(price.clone())
+            .priority_columns(price.clone()),
+    ] {
+        let out = dir.path("contradiction.csv");
+        let res = dump_csv_sync(&mut db, &out, options, Default::default());
+        assert!(
+            matches!(res, Err(DataToolErrors::InvalidArgument(_))),
+            "{:?}",
+            res
+        );
+        assert!(!out.exists());
+    }
+}
+
+#[test]
+fn row_filters_drop_rows() {
+    let dir = TestDir::new("row_filter");
+    let mut db = dir.db();
+    fixture(&mut db);
+    // the filter sees the columns left out of the export too
+    let options = ExportOptions::default()
+        .chunk(1)
+        .exclude_columns(vec!["price".to_string()])
+        .row_filter(|row| row.get("price").is_some_and(|p| p != "2"));
+    let out = dir.path("out.csv");
+    let summary = dump_csv_sync(&mut db, &out, options.clone(), Default::default()).unwrap();
+    assert_eq!(
+        (summary.rows_written, summary.rows_skipped_by_filter),
+        (2, 2)
+    );
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "name,color\napple,\ndate,red\n"
+    );
+    let out = dir.path("out.db");
+    let summary = dump_db_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!(
+        (summary.rows_written, summary.rows_skipped_by_filter),
+        (2, 2)
+    );
+    assert_eq!(
+        db_column(&out, "name"),
+        [Some("apple".to_string()), Some("date".to_string())]
+    );
+}
+
+#[test]
+fn transforms_change_the_exported_values_only() {
+    let dir = TestDir::new("transforms");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let upper = Transform::Custom(Arc::new(|v: &str| v.to_uppercase()));
+    let options = ExportOptions::default()
+        .transform("name", Transform::Truncate(3))
+        .transform("color", Transform::Redact)
+        .transform("price", Transform::Regex("^(\\d+)$".into(), "$1.00".into()));
+    let out = dir.path("out.csv");
+    dump_csv_sync(&mut db, &out, options.clone(), Default::default()).unwrap();
+    // empty values are left empty
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "name,price,color\napp,1.00,\n,2.00,***\nche,,\ndat,4.00,***\n"
+    );
+    let out = dir.path("out.db");
+    let options = options.transform("name", upper);
+    dump_db_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!(
+        db_column(&out, "name"),
+        ["APPLE", "", "CHERRY", "DATE"].map(|v| Some(v.to_string()))
+    );
+    assert_eq!(
+        db.get_value("a", "name").unwrap(),
+        Some("apple".to_string())
+    );
+    let out = dir.path("bad.csv");
+    let options =
+        ExportOptions::default().transform("name", Transform::Regex("(".into(), "".into()));
+    let res = dump_csv_sync(&mut db, &out, options, Default::default());
+    assert!(
+        matches!(res, Err(DataToolErrors::InvalidArgument(_))),
+        "{:?}",
+        res
+    );
+    assert!(!out.exists());
+}
+
+#[test]
+fn computed_columns_are_exported() {
+    let dir = TestDir::new("computed");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let options = ExportOptions::default()
+        .computed_column("source", |_| "shop".to_string())
+        .computed_column("double", |row| match row.get("price") {
+            Some(p) => (p.parse::<i64>().unwrap() * 2).to_string(),
+            None => String::new(),
+        })
+        // in place of the stored column
+        .computed_column("color", |row| {
+            row.get("color").cloned().unwrap_or("none".to_string())
+        });
+    let out = dir.path("out.csv");
+    let summary = dump_csv_sync(&mut db, &out, options.clone(), Default::default()).unwrap();
+    assert_eq!(
+        summary.columns,
+        ["name", "price", "color", "source", "double"]
+    );
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "name,price,color,source,double\n\
+         apple,1,none,shop,2\n\
+         ,2,blue,shop,4\n\
+         cherry,,none,shop,\n\
+         date,4,red,shop,8\n"
+    );
+    // placed by the priority columns, and text in the SQLite exports
+    let options = options.priority_columns(vec!["double".to_string()]);
+    let out = dir.path("out.db");
+    let db_options = ExportDbOptions::default().infer_types(true);
+    let summary = dump_db_sync(&mut db, &out, options, db_options).unwrap();
+    assert_eq!(
+        summary.columns,
+        ["double", "name", "price", "color", "source"]
+    );
+    assert_eq!(
+        summary.column_types,
+        [
+            ColumnType::Text,
+            ColumnType::Text,
+            ColumnType::Integer,
+            ColumnType::Text,
+            ColumnType::Text
+        ]
+    );
+    assert_eq!(
+        db_column(&out, "double"),
+        ["2", "4", "", "8"].map(|v| Some(v.to_string()))
+    );
+    // a panic fails the export
+    let options = ExportOptions::default().computed_column("boom", |_| panic!("boom"));
+    let out = dir.path("panic.csv");
+    let res = dump_csv_sync(&mut db, &out, options, Default::default());
+    assert!(res.is_err());
+    assert!(!out.exists());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn panicking_computed_columns_fail_async_exports() {
+    let dir = TestDir::new("computed_panic");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let options = ExportOptions::default()
+        .chunk(1)
+        .computed_column("boom", |_| panic!("boom"));
+    let out = dir.path("panic.csv");
+    let res = dump_csv(&mut db, &out, options, Default::default()).await;
+    assert!(res.is_err());
+    assert!(!out.exists());
+}
+
+#[test]
+fn subsets_of_the_items_are_exported() {
+    let dir = TestDir::new("subsets");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let rows: Vec<_> = FIXTURE_CSV.lines().collect();
+    let csv = |lines: &[usize]| {
+        let mut csv = rows[0].to_string() + "\n";
+        for i in lines {
+            csv = csv + rows[*i] + "\n";
+        }
+        csv
+    };
+    // in the given order, the ids without an item counted
+    let options = ExportOptions::default().chunk(2).ids(vec![3, 99, 1]);
+    let out = dir.path("ids.csv");
+    let summary = dump_csv_sync(&mut db, &out, options.clone(), Default::default()).unwrap();
+    assert_eq!((summary.rows_written, summary.ids_not_found), (2, 1));
+    assert_eq!(fs::read_to_string(&out).unwrap(), csv(&[3, 1]));
+    let out = dir.path("ids.db");
+    let summary = dump_db_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!((summary.rows_written, summary.ids_not_found), (2, 1));
+    assert_eq!(
+        db_column(&out, "name"),
+        [Some("cherry".to_string()), Some("apple".to_string())]
+    );
+    // a window of the export order
+    for (offset, limit, lines) in [(1, 2, vec![2, 3]), (3, 5, vec![4]), (5, 1, vec![])] {
+        let options = ExportOptions::default()
+            .chunk(1)
+            .offset(offset)
+            .limit(limit);
+        let out = dir.path(&format!("window{}.csv", offset));
+        let summary = dump_csv_sync(&mut db, &out, options, Default::default()).unwrap();
+        assert_eq!(summary.rows_written, lines.len());
+        assert_eq!(fs::read_to_string(&out).unwrap(), csv(&lines));
+    }
+    let options = ExportOptions::default()
+        .order(IterOrder::InsertionDesc)
+        .offset(1)
+        .limit(2);
+    let out = dir.path("desc.db");
+    dump_db_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!(
+        db_column(&out, "name"),
+        [Some("cherry".to_string()), Some(String::new())]
+    );
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn items_are_exported_as_json_lines() {
+    let dir = TestDir::new("jsonl");
+    let mut db = dir.db();
+    fixture(&mut db);
+    db.add_row("e", [("name", "say \"hi\"\nbye")]).unwrap();
+    let out = dir.path("out.jsonl");
+    let options = ExportOptions::default().chunk(2).include_id(true);
+    let summary = dump_jsonl(&mut db, &out, options.clone(), Default::default())
+        .await
+        .unwrap();
+    assert_eq!(summary.rows_written, 5);
+    let text = fs::read_to_string(&out).unwrap();
+    assert_eq!(
+        text.lines().next().unwrap(),
+        r#"{"_id":1,"name":"apple","price":"1","color":""}"#
+    );
+    let objects: Vec<serde_json::Value> = text
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(objects.len(), 5);
+    assert_eq!(objects[4]["name"], "say \"hi\"\nbye");
+    // the keys an item does not have are left out
+    let mut sparse = vec![];
+    let jsonl_options = ExportJsonlOptions::default().sparse(true);
+    dump_jsonl_writer(&mut db, &mut sparse, options, jsonl_options)
+        .await
+        .unwrap();
+    let sparse = String::from_utf8(sparse).unwrap();
+    assert_eq!(
+        sparse.lines().nth(1).unwrap(),
+        r#"{"_id":2,"price":"2","color":"blue"}"#
+    );
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn items_are_exported_as_a_json_array() {
+    let dir = TestDir::new("json");
+    let mut db = dir.db();
+    for pretty in [false, true] {
+        let out = dir.path(&format!("empty{}.json", pretty));
+        let json_options = ExportJsonOptions::default().pretty(pretty);
+        dump_json(&mut db, &out, Default::default(), json_options)
+            .await
+            .unwrap();
+        let empty: serde_json::Value = serde_json::from_slice(&fs::read(&out).unwrap()).unwrap();
+        assert_eq!(empty, serde_json::json!([]));
+    }
+    fixture(&mut db);
+    let out = dir.path("out.json");
+    let options = ExportOptions::default().chunk(3);
+    dump_json(&mut db, &out, options.clone(), Default::default())
+        .await
+        .unwrap();
+    let compact = fs::read_to_string(&out).unwrap();
+    assert_eq!(
+        compact,
+        concat!(
+            r#"[{"name":"apple","price":"1","color":""},"#,
+            r#"{"name":"","price":"2","color":"blue"},"#,
+            r#"{"name":"cherry","price":"","color":""},"#,
+            r#"{"name":"date","price":"4","color":"red"}]"#,
+            "\n"
+        )
+    );
+    let out = dir.path("pretty.json");
+    let json_options = ExportJsonOptions::default().pretty(true);
+    dump_json(&mut db, &out, options, json_options)
+        .await
+        .unwrap();
+    let pretty = fs::read_to_string(&out).unwrap();
+    assert!(
+        pretty.starts_with("[\n  {\n    \"name\": \"apple\","),
+        "{}",
+        pretty
+    );
+    let parse = |s: &str| serde_json::from_str::<serde_json::Value>(s).unwrap();
+    assert_eq!(parse(&pretty), parse(&compact));
+    // a JSON array can not be appended to
+    let options = ExportOptions::default().overwrite(OverwriteMode::Append);
+    let res = dump_json(&mut db, &out, options, Default::default()).await;
+    assert!(matches!(res, Err(DataToolErrors::InvalidArgument(_))));
+    // nor is a truncated array left behind
+    let out = dir.path("cancelled.json");
+    let token = CancellationToken::new();
+    let cancelling = token.clone();
+    let options = ExportOptions::default()
+        .chunk(1)
+        .cancel_token(token)
+        .row_filter(move |row| {
+            if row.get("name").is_some_and(|n| n == "cherry") {
+                cancelling.cancel();
+            }
+            true
+        });
+    let res = dump_json(&mut db, &out, options, Default::default()).await;
+    assert!(matches!(res, Err(DataToolErrors::Cancelled)), "{:?}", res);
+    assert!(!out.exists() && !dir.path("cancelled.json.tmp").exists());
+}
+
+/// Items with keys and values that need quoting or escaping, an empty value, and missing keys
+#[cfg(feature = "async")]
+fn hostile_fixture(db: &mut TableMapDb) {
+    db.add_row(
+        "1",
+        [("it's", "O'Brien \\ back"), ("a \"b\"", ""), ("n", "5")],
+    )
+    .unwrap();
+    db.add_row("2", [("it's", "tab\there\nnew")]).unwrap();
+    db.add_row("3", [("a \"b\"", "\\."), ("n", "-2")]).unwrap();
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn sql_exports_quote_the_identifiers_and_strings() {
+    let dir = TestDir::new("sql_golden");
+    let mut db = dir.db();
+    hostile_fixture(&mut db);
+    let create = |int: &str| {
+        format!(
+            "CREATE TABLE \"my \"\"table\"\"\" (\n  \"_id\" {int},\n  \"it's\" TEXT,\n  \
+             \"a \"\"b\"\"\" TEXT,\n  \"n\" {int}\n);\n"
+        )
+    };
+    let insert = "INSERT INTO \"my \"\"table\"\"\" (\"_id\", \"it's\", \"a \"\"b\"\"\", \"n\") \
+                  VALUES\n\
+                  (1, 'O''Brien \\ back', '', 5),\n\
+                  (2, 'tab\there\nnew', '', NULL),\n\
+                  (3, '', '\\.', -2);\n";
+    let mysql = "CREATE TABLE `my \"table\"` (\n  `_id` BIGINT,\n  `it's` TEXT,\n  \
+                 `a \"b\"` TEXT,\n  `n` BIGINT\n);\n\
+                 INSERT INTO `my \"table\"` (`_id`, `it's`, `a \"b\"`, `n`) VALUES\n\
+                 (1, 'O\\'Brien \\\\ back', '', 5),\n\
+                 (2, 'tab\there\\nnew', '', NULL),\n\
+                 (3, '', '\\\\.', -2);\n";
+    for (dialect, expected) in [
+        (Dialect::Sqlite, create("INTEGER") + insert),
+        (Dialect::Postgres, create("BIGINT") + insert),
+        (Dialect::Mysql, mysql.to_string()),
+    ] {
+        let out = dir.path(&format!("{:?}.sql", dialect));
+        let options = ExportOptions::default().include_id(true);
+        let sql_options = ExportSqlOptions::default()
+            .dialect(dialect)
+            .infer_types(true)
+            .table_name("my \"table\"");
+        dump_sql(&mut db, &out, options, sql_options).await.unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), expected, "{:?}", dialect);
+    }
+    // loads back as exported, the missing integer as NULL
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(&fs::read_to_string(dir.path("Sqlite.sql")).unwrap())
+        .unwrap();
+    let rows: Vec<(String, String, Option<i64>)> = conn
+        .prepare("select \"it's\", \"a \"\"b\"\"\", n from \"my \"\"table\"\"\" order by _id")
+        .unwrap()
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(
+        rows,
+        [
+            ("O'Brien \\ back".to_string(), String::new(), Some(5)),
+            ("tab\there\nnew".to_string(), String::new(), None),
+            (String::new(), "\\.".to_string(), Some(-2)),
+        ]
+    );
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn copy_exports_escape_the_values_and_tell_null_from_empty() {
+    let dir = TestDir::new("copy_golden");
+    let mut db = dir.db();
+    hostile_fixture(&mut db);
+    // NULL is an unquoted empty field in CSV, `\N` in text, empty strings are `""` or nothing
+    let csv = "it's,\"a \"\"b\"\"\",n\n\
+               O'Brien \\ back,\"\",5\n\
+               \"tab\there\nnew\",,\n\
+               ,\"\\.\",-2\n";
+    let text = "O'Brien \\\\ back\t\t5\n\
+                tab\\there\\nnew\t\\N\t\\N\n\
+                \\N\t\\\\.\t-2\n";
+    for (format, expected, with) in [
+        (CopyFormat::Csv, csv, "FORMAT csv, HEADER true"),
+        (CopyFormat::Text, text, "FORMAT text"),
+    ] {
+        let out = dir.path(&format!("{:?}.copy", format));
+        let copy_options = ExportCopyOptions::default()
+            .format(format)
+            .infer_types(true);
+        dump_copy(&mut db, &out, Default::default(), copy_options)
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), expected, "{:?}", format);
+        let schema = fs::read_to_string(out.with_extension("schema.sql")).unwrap();
+        assert_eq!(
+            schema,
+            format!(
+                "CREATE TABLE \"products\" (\n  \"it's\" TEXT,\n  \"a \"\"b\"\"\" TEXT,\n  \
+                 \"n\" BIGINT\n);\n\n\
+                 -- \\copy \"products\" (\"it's\", \"a \"\"b\"\"\", \"n\") FROM '{:?}.copy' \
+                 WITH ({})\n",
+                format, with
+            )
+        );
+    }
+    // a NUL can not be loaded
+    db.add_row("4", [("it's", "nul\0")]).unwrap();
+    let out = dir.path("nul.copy");
+    let summary = dump_copy(&mut db, &out, Default::default(), Default::default())
+        .await
+        .unwrap();
+    assert_eq!((summary.rows_written, summary.rows_failed), (3, 1));
+}
+
+#[test]
+fn csv_exports_are_split_into_files() {
+    let dir = TestDir::new("split");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let out = dir.path("data.csv");
+    let csv_options = ExportCsvOptions::default().max_rows_per_file(3);
+    let summary = dump_csv_sync(&mut db, &out, Default::default(), csv_options).unwrap();
+    let parts = [dir.path("data.part0001.csv"), dir.path("data.part0002.csv")];
+    assert_eq!(
+        summary.files,
+        [(parts[0].clone(), 3), (parts[1].clone(), 1)]
+    );
+    assert_eq!(summary.rows_written, 4);
+    let (header, rows) = FIXTURE_CSV.split_once('\n').unwrap();
+    let rows: Vec<_> = rows.lines().collect();
+    assert_eq!(
+        fs::read_to_string(&parts[0]).unwrap(),
+        format!("{}\n{}\n", header, rows[..3].join("\n"))
+    );
+    assert_eq!(
+        fs::read_to_string(&parts[1]).unwrap(),
+        format!("{}\n{}\n", header, rows[3])
+    );
+    assert!(!out.exists());
+    // the parts left by the earlier export are removed when overwriting
+    let options = ExportOptions::default().overwrite(OverwriteMode::Overwrite);
+    let csv_options = ExportCsvOptions::default().max_rows_per_file(4);
+    let summary = dump_csv_sync(&mut db, &out, options, csv_options).unwrap();
+    assert_eq!(summary.files, [(parts[0].clone(), 4)]);
+    assert_eq!(fs::read_to_string(&parts[0]).unwrap(), FIXTURE_CSV);
+    assert!(!parts[1].exists());
+    let options = ExportOptions::default().overwrite(OverwriteMode::Append);
+    let csv_options = ExportCsvOptions::default().max_rows_per_file(4);
+    let res = dump_csv_sync(&mut db, &out, options, csv_options);
+    assert!(matches!(res, Err(DataToolErrors::InvalidArgument(_))));
+}
+
+/// The manifest written next to `file`, and the SHA-256 of each of the files it lists,
+/// computed from the files themselves
+fn read_manifest(file: &Path) -> (serde_json::Value, Vec<(String, String)>) {
+    use sha2::{Digest, Sha256};
+    let mut path = file.as_os_str().to_owned();
+    path.push(".manifest.json");
+    let manifest: serde_json::Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+    let sums = manifest["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| {
+            let name = f["name"].as_str().unwrap();
+            let bytes = fs::read(file.with_file_name(name)).unwrap();
+            let sum: String = Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            assert_eq!(f["sha256"], sum, "{}", name);
+            (name.to_string(), sum)
+        })
+        .collect();
+    (manifest, sums)
+}
+
+#[test]
+fn manifests_have_the_sha256_of_the_files() {
+    let dir = TestDir::new("manifest");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let options = ExportOptions::default().write_manifest(true);
+    let out = dir.path("out.csv");
+    dump_csv_sync(&mut db, &out, options.clone(), Default::default()).unwrap();
+    let (manifest, sums) = read_manifest(&out);
+    assert_eq!(manifest["rows"], 4);
+    assert_eq!(manifest["source_items"], 4);
+    assert_eq!(
+        manifest["columns"],
+        serde_json::json!(["name", "price", "color"])
+    );
+    assert_eq!(sums.len(), 1);
+    // the whole file, not just the rows appended
+    let append = options.clone().overwrite(OverwriteMode::Append);
+    dump_csv_sync(&mut db, &out, append, Default::default()).unwrap();
+    let (_, appended) = read_manifest(&out);
+    assert_ne!(appended, sums);
+    let out = dir.path("split.csv");
+    let csv_options = ExportCsvOptions::default().max_rows_per_file(3);
+    dump_csv_sync(&mut db, &out, options.clone(), csv_options).unwrap();
+    let (manifest, sums) = read_manifest(&out);
+    let names: Vec<_> = sums.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["split.part0001.csv", "split.part0002.csv"]);
+    assert_eq!(manifest["files"][1]["rows"], 1);
+    let out = dir.path("out.db");
+    dump_db_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!(read_manifest(&out).1.len(), 1);
+}
+
+#[test]
+fn diff_reports_are_exported_with_a_row_per_change() {
+    use crate::diff::{DiffReport, ItemDiff, KeyChange};
+    let dir = TestDir::new("diff_csv");
+    let change = |key: &str, old: Option<&str>, new: Option<&str>| KeyChange {
+        key: key.to_string(),
+        old: old.map(str::to_string),
+        new: new.map(str::to_string),
+    };
+    let report = DiffReport {
+        added: vec!["new".to_string()],
+        removed: vec!["gone".to_string()],
+        changed: vec![
+            ItemDiff {
+                item_val: "p1".to_string(),
+                changed_keys: 3,
+                keys: vec![
+                    change("color", None, Some("red")),
+                    change("name", Some("pear"), Some("pear, ripe")),
+                    change("size", Some(""), None),
+                ],
+            },
+            // counts only
+            ItemDiff {
+                item_val: "p2".to_string(),
+                changed_keys: 2,
+                keys: vec![],
+            },
+        ],
+        ..Default::default()
+    };
+    let out = dir.path("diff.csv");
+    let rows = dump_diff_csv(&report, &out, OverwriteMode::Error).unwrap();
+    assert_eq!(rows, 6);
+    let csv = "item,key,old,new,change_type\n\
+               new,,,,item_added\n\
+               gone,,,,item_removed\n\
+               p1,color,,red,key_added\n\
+               p1,name,pear,\"pear, ripe\",value_changed\n\
+               p1,size,,,key_removed\n\
+               p2,,,,value_changed\n";
+    assert_eq!(fs::read_to_string(&out).unwrap(), csv);
+    dump_diff_csv(&report, &out, OverwriteMode::Append).unwrap();
+    let rows = csv.split_once('\n').unwrap().1;
+    assert_eq!(fs::read_to_string(&out).unwrap(), csv.to_string() + rows);
+    let res = dump_diff_csv(&report, &out, OverwriteMode::Error);
+    assert!(matches!(res, Err(DataToolErrors::FileExists(_))));
+}
+
+#[test]
+fn only_the_items_changed_since_a_revision_are_exported() {
+    use crate::import::{import_csv, ImportOptions};
+    use crate::MergePolicy;
+    let dir = TestDir::new("changed_since");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let options = || ExportOptions::default().include_only(vec!["name".to_string()]);
+    let out = dir.path("all.csv");
+    let summary = dump_csv_sync(&mut db, &out, options(), Default::default()).unwrap();
+    let prev = summary.max_revision;
+    assert_eq!(prev, db.max_revision().unwrap());
+    // a cell added to b
+    db.set_current_item_by_val("b").unwrap();
+    db.insert("name", "banana").unwrap();
+    // e imported
+    let csv = dir.path("import.csv");
+    fs::write(&csv, "sku,name\ne,elder\n").unwrap();
+    let options_import = ImportOptions::default().item_val_column("sku");
+    import_csv(&mut db, &csv, options_import).unwrap();
+    // c given a key and f added by a merge, a keeping its own name is not changed
+    let other = dir.path("other.db");
+    let mut other_db = TableMapDb::new(other.clone()).unwrap();
+    other_db.add_row("a", [("name", "avocado")]).unwrap();
+    other_db.add_row("c", [("size", "big")]).unwrap();
+    other_db.add_row("f", [("name", "fig")]).unwrap();
+    drop(other_db);
+    db.merge_from(&other, MergePolicy::PreferSelf).unwrap();
+    let out = dir.path("changed.csv");
+    let options = options().changed_since(prev);
+    let summary = dump_csv_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!(summary.rows_written, 4);
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "name\nbanana\ncherry\nelder\nfig\n"
+    );
+    assert!(summary.max_revision > prev);
+    assert_eq!(summary.max_revision, db.max_revision().unwrap());
+    // nothing changed since
+    let out = dir.path("unchanged.csv");
+    let options = ExportOptions::default().changed_since(summary.max_revision);
+    let again = dump_csv_sync(&mut db, &out, options, Default::default()).unwrap();
+    assert_eq!(again.rows_written, 0);
+    assert_eq!(again.max_revision, summary.max_revision);
+}
+
+#[cfg(feature = "async")]
+/// Exports [`fixture`] resumably to `out`, cancelled once two chunks of one item are
+/// written, leaving the partial export and its checkpoint
+fn interrupted_export(db: &mut TableMapDb, out: &Path) {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    let options = ExportOptions::default()
+        .chunk(1)
+        .cancel_token(token)
+        .on_progress(move |p| {
+            if p.chunks_done == 2 {
+                cancel.cancel();
+            }
+        });
+    let csv_options = ExportCsvOptions::default().resume(true);
+    let res = dump_csv_sync(db, out, options, csv_options);
+    assert!(matches!(res, Err(DataToolErrors::Cancelled)), "{:?}", res);
+    assert!(!out.exists());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn interrupted_exports_are_resumed() {
+    let dir = TestDir::new("resume");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let out = dir.path("out.csv");
+    let tmp = dir.path("out.csv.tmp");
+    let checkpoint = dir.path("out.csv.checkpoint.json");
+    interrupted_export(&mut db, &out);
+    assert_eq!(
+        fs::read_to_string(&tmp).unwrap(),
+        "name,price,color\napple,1,\n,2,blue\n"
+    );
+    assert!(checkpoint.exists());
+    // a row written after the checkpoint, before the process died
+    let mut partial = fs::OpenOptions::new().append(true).open(&tmp).unwrap();
+    partial.write_all(b"cherry,,\nda").unwrap();
+    drop(partial);
+    let csv_options = ExportCsvOptions::default().resume(true);
+    let options = ExportOptions::default().chunk(1);
+    let summary = dump_csv_sync(&mut db, &out, options, csv_options).unwrap();
+    assert_eq!(summary.rows_written, 4);
+    let csv = fs::read_to_string(&out).unwrap();
+    assert_eq!(csv, FIXTURE_CSV);
+    assert_eq!(csv.matches("name,price,color").count(), 1);
+    assert!(!tmp.exists() && !checkpoint.exists());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn exports_with_other_columns_are_not_resumed() {
+    let dir = TestDir::new("resume_columns");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let out = dir.path("out.csv");
+    interrupted_export(&mut db, &out);
+    let partial = fs::read(dir.path("out.csv.tmp")).unwrap();
+    db.add_row("e", [("size", "big")]).unwrap();
+    let csv_options = ExportCsvOptions::default().resume(true);
+    let res = dump_csv_sync(&mut db, &out, Default::default(), csv_options);
+    assert!(
+        matches!(res, Err(DataToolErrors::InvalidArgument(_))),
+        "{:?}",
+        res
+    );
+    assert!(!out.exists());
+    // left for the export with the same columns to resume
+    assert_eq!(fs::read(dir.path("out.csv.tmp")).unwrap(), partial);
+    assert!(dir.path("out.csv.checkpoint.json").exists());
+}
+
+/// Options reading one item per chunk, each taking `delay`, so an export of the [`slow_fixture`]
+/// takes a while
+#[cfg(feature = "async")]
+fn slow_chunks(delay: Duration) -> ExportOptions {
+    ExportOptions::default()
+        .max_concurrent(2)
+        .chunk(1)
+        .row_filter(move |_| {
+            std::thread::sleep(delay);
+            true
+        })
+}
+
+/// Enough items for an export with [`slow_chunks`] to run for seconds
+#[cfg(feature = "async")]
+fn slow_fixture(db: &mut TableMapDb) {
+    for i in 0..200 {
+        db.add_row(&i.to_string(), [("k", i.to_string())]).unwrap();
+    }
+}
+
+// the chunks are read on the runtime's workers, the cancelling task needs one of its own
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn cancelled_exports_stop_promptly_and_leave_no_file() {
+    let dir = TestDir::new("cancel");
+    let mut db = dir.db();
+    slow_fixture(&mut db);
+    for (i, csv) in [true, false].into_iter().enumerate() {
+        let out = dir.path(&format!("out{}", i));
+        let token = CancellationToken::new();
+        let options = slow_chunks(Duration::from_millis(50)).cancel_token(token.clone());
+        let cancel = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            token.cancel();
+        });
+        let t = Instant::now();
+        let result = match csv {
+            true => dump_csv(&mut db, &out, options.chunk(1), Default::default()).await,
+            false => dump_db(&mut db, &out, options, Default::default()).await,
+        };
+        let elapsed = t.elapsed();
+        cancel.await.unwrap();
+        assert!(
+            matches!(result, Err(DataToolErrors::Cancelled)),
+            "{:?}",
+            result
+        );
+        assert!(elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+        assert!(elapsed >= Duration::from_millis(200));
+        // nor the temp file
+        let left: Vec<_> = fs::read_dir(dir.path(""))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .filter(|f| f.to_string_lossy().starts_with("out"))
+            .collect();
+        assert!(left.is_empty(), "{:?}", left);
+    }
+}
+
+#[test]
+fn values_with_delimiters_round_trip() {
+    let dir = TestDir::new("delimiters");
+    let mut db = dir.db();
+    let values = [
+        "tab\there",
+        "comma, here",
+        "semi;colon",
+        "\"quoted\" and 'single'",
+        "new\nline",
+        "crlf\r\nline",
+        " padded ",
+        "",
+    ];
+    for (i, v) in values.iter().enumerate() {
+        db.add_row(&i.to_string(), [("key\twith, \"all\"", *v), ("n", "x")])
+            .unwrap();
+    }
+    let cases = [
+        (b'\t', QuoteStyle::Necessary, LineTerminator::Lf),
+        (b',', QuoteStyle::Necessary, LineTerminator::CrLf),
+        (b';', QuoteStyle::Always, LineTerminator::Lf),
+        (b'|', QuoteStyle::NonNumeric, LineTerminator::CrLf),
+    ];
+    for (i, (delimiter, quote_style, terminator)) in cases.into_iter().enumerate() {
+        let out = dir.path(&format!("{}.csv", i));
+        let csv_options = ExportCsvOptions::default()
+            .delimiter(delimiter)
+            .quote_style(quote_style)
+            .terminator(terminator);
+        dump_csv_sync(
+            &mut db,
+            &out,
+            ExportOptions::default().chunk(3),
+            csv_options,
+        )
+        .unwrap();
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .from_path(&out)
+            .unwrap();
+        assert_eq!(reader.headers().unwrap(), vec!["key\twith, \"all\"", "n"]);
+        let read: Vec<_> = reader
+            .records()
+            .map(|r| r.unwrap()[0].to_string())
+            .collect();
+        assert_eq!(read, values, "delimiter {:?}", delimiter as char);
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn csv_is_exported_to_a_writer() {
+    let dir = TestDir::new("csv_writer");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let mut buf = Vec::new();
+    let summary = dump_csv_writer(
+        &mut db,
+        &mut buf,
+        ExportOptions::default().chunk(2),
+        Default::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(summary.rows_written, 4);
+    assert_eq!(String::from_utf8(buf).unwrap(), FIXTURE_CSV);
+
+    let mut buf = Vec::new();
+    let order = vec!["price".to_string()];
+    dump_csv_writer(
+        &mut db,
+        &mut buf,
+        ExportOptions::default().chunk(2).priority_columns(order),
+        Default::default(),
+    )
+    .await
+    .unwrap();
+    let mut reader = csv::Reader::from_reader(&buf[..]);
+    assert_eq!(reader.headers().unwrap(), vec!["price", "name", "color"]);
+    assert_eq!(reader.records().count(), 4);
+}
+
+/// The columns of a Parquet file, each cell formatted as text, `None` for nulls
+#[cfg(feature = "parquet")]
+fn read_parquet(file: &Path) -> Vec<(String, Vec<Option<String>>)> {
+    use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, Int64Type};
+    use arrow_array::Array;
+    use arrow_schema::DataType;
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(fs::File::open(file).unwrap())
+        .unwrap()
+        .build()
+        .unwrap();
+    let mut columns: Vec<(String, Vec<Option<String>>)> = vec![];
+    for batch in reader {
+        let batch = batch.unwrap();
+        if columns.is_empty() {
+            columns = batch
+                .schema()
+                .fields()
+                .iter()
+                .map(|f| (f.name().clone(), vec![]))
+                .collect();
+        }
+        for (i, array) in batch.columns().iter().enumerate() {
+            for row in 0..array.len() {
+                let cell = (!array.is_null(row)).then(|| match array.data_type() {
+                    DataType::Utf8 => array.as_string::<i32>().value(row).to_string(),
+                    DataType::Int64 => array.as_primitive::<Int64Type>().value(row).to_string(),
+                    DataType::Float64 => array.as_primitive::<Float64Type>().value(row).to_string(),
+                    ty => panic!("unexpected type {:?}", ty),
+                });
+                columns[i].1.push(cell);
+            }
+        }
+    }
+    columns
+}
+
+#[cfg(feature = "parquet")]
+#[tokio::test]
+async fn parquet_export_round_trips() {
+    let dir = TestDir::new("parquet");
+    let mut db = dir.db();
+    fixture(&mut db);
+    let s = |v: &str| Some(v.to_string());
+    for infer_types in [false, true] {
+        let out = dir.path(&format!("{}.parquet", infer_types));
+        let parquet_options = ExportParquetOptions::default()
+            .infer_types(infer_types)
+            .max_row_group_rows(3);
+        let summary = dump_parquet(
+            &mut db,
+            &out,
+            ExportOptions::default().chunk(2),
+            parquet_options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.rows_written, 4);
+        let types = match infer_types {
+            true => [ColumnType::Text, ColumnType::Integer, ColumnType::Text],
+            false => [ColumnType::Text; 3],
+        };
+        assert_eq!(summary.column_types, types);
+        let columns = read_parquet(&out);
+        let names: Vec<_> = columns.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["name", "price", "color"]);
+        // the keys an item does not have are nulls
+        assert_eq!(columns[0].1, [s("apple"), None, s("cherry"), s("date")]);
+        assert_eq!(columns[1].1, [s("1"), s("2"), None, s("4")]);
+        assert_eq!(columns[2].1, [None, s("blue"), None, s("red")]);
+    }
+}
@@ -0,0 +1,243 @@
+//! Excel export, needs the `xlsx` feature
+
+use super::{proc_ids, ExportOptions, ExportSummary, ExportTimings, OverwriteMode, TempTarget};
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use rust_xlsxwriter::{Workbook, Worksheet, XlsxError};
+use std::path::Path;
+use std::time::Instant;
+
+/// Rows of a worksheet, the header included
+const SHEET_ROWS: u32 = 1_048_576;
+/// Columns of a worksheet
+const SHEET_COLUMNS: usize = 16_384;
+/// Characters of a cell
+const CELL_CHARS: usize = 32_767;
+
+/// Exports the items to an Excel workbook, with the rows [`dump_csv`](super::dump_csv)
+/// would write. The values are strings, empty cells are left blank, the item id, if included,
+/// a number. A worksheet holds at most 1048575 rows besides the header, more rows fail the
+/// export, unless [`ExportXlsxOptions::split_sheets`] is set. Rows with a value longer than
+/// a cell holds are failed rows.
+///
+/// The worksheets are written in constant memory mode, so only the current row is held in
+/// memory. Workbooks can not be appended to, so [`OverwriteMode::Append`] is rejected
+pub async fn dump_xlsx(
+    db: &mut TableMapDb,
+    file_name: &Path,
+    mut options: ExportOptions,
+    xlsx_options: ExportXlsxOptions,
+) -> Result<ExportSummary, DataToolErrors> {
+    let t = Instant::now();
+    let (chunk, column_order) = options.take_chunk_and_priority();
+    chunk.validate()?;
+    options.check_wide("dump_xlsx")?;
+    if options.overwrite == OverwriteMode::Append {
+        return Err(DataToolErrors::InvalidArgument(
+            "can not append to an Excel workbook".to_string(),
+        ));
+    }
+    let target = TempTarget::new(file_name, options.overwrite)?;
+    let keys_scan = Instant::now();
+    let columns = options.select_columns(db, column_order)?;
+    let keys_scan = keys_scan.elapsed();
+    let header = options.output_columns(&columns)?;
+    if header.len() > SHEET_COLUMNS {
+        return Err(DataToolErrors::InvalidArgument(format!(
+            "can not export {} columns, a worksheet has at most {}",
+            header.len(),
+            SHEET_COLUMNS
+        )));
+    }
+    let mut summary = ExportSummary::new(header.clone());
+    // fails for a sheet name Excel does not allow
+    let mut sheets = Sheets::new(&header, &xlsx_options)
+        .map_err(|e| DataToolErrors::InvalidArgument(e.to_string()))?;
+    let stats = proc_ids(db, &options, chunk, columns, |n| {
+        for (id, row) in n.iter() {
+            options.check_cancelled()?;
+            let id = options.include_id.then_some(*id);
+            let res = match row
+                .iter()
+                .flatten()
+                .find(|v| v.chars().count() > CELL_CHARS)
+            {
+                Some(v) => Err(format!(
+                    "a value of {} characters is longer than a cell holds",
+                    v.chars().count()
+                )),
+                None => sheets.write_row(id, row)?.map_err(|e| e.to_string()),
+            };
+            summary.record(res, options.strict)?;
+        }
+        Ok(())
+    })
+    .await?;
+    summary.rows_skipped_by_filter = stats.skipped;
+    summary.ids_not_found = stats.ids_not_found;
+    summary.max_revision = stats.max_revision;
+    summary.chunks = stats.chunks;
+    summary.timings = ExportTimings {
+        keys_scan,
+        ..stats.timings
+    };
+    summary.spilled_keys = stats.spilled_keys;
+    summary.merged_headers = stats.merged_headers;
+    sheets.finish().map_err(map_err)?;
+    sheets.workbook.save(target.path()).map_err(map_err)?;
+    target.commit()?;
+    summary.elapsed = t.elapsed();
+    summary.report();
+    Ok(summary)
+}
+
+fn map_err(e: XlsxError) -> DataToolErrors {
+    DataToolErrors::GenericError(e.to_string())
+}
+
+/// Options of the Excel export, [`dump_xlsx`]
+#[derive(Debug, Clone)]
+pub struct ExportXlsxOptions {
+    sheet_name: String,
+    freeze_header: bool,
+    autofilter: bool,
+    split_sheets: bool,
+}
+
+impl Default for ExportXlsxOptions {
+    fn default() -> Self {
+        Self {
+            sheet_name: "data".to_string(),
+            freeze_header: true,
+            autofilter: false,
+            split_sheets: false,
+        }
+    }
+}
+
+impl ExportXlsxOptions {
+    /// Name of the worksheet, `data` by default
+    pub fn sheet_name(mut self, name: impl Into<String>) -> Self {
+        self.sheet_name = name.into();
+        self
+    }
+
+    /// Keep the header row in view when scrolling, on by default
+    pub fn freeze_header(mut self, freeze_header: bool) -> Self {
+        self.freeze_header = freeze_header;
+        self
+    }
+
+    /// Add filter buttons to the header row
+    pub fn autofilter(mut self, autofilter: bool) -> Self {
+        self.autofilter = autofilter;
+        self
+    }
+
+    /// Continue on a new worksheet once a worksheet is full, instead of failing the export.
+    /// The worksheets are then named `<sheet name>_1`, `<sheet name>_2`, ...
+    pub fn split_sheets(mut self, split_sheets: bool) -> Self {
+        self.split_sheets = split_sheets;
+        self
+    }
+}
+
+/// The worksheets of the export, rows are written to the last one
+struct Sheets<'a> {
+    workbook: Workbook,
+    header: &'a [String],
+    options: &'a ExportXlsxOptions,
+    /// next row of the last worksheet
+    row: u32,
+}
+
+impl<'a> Sheets<'a> {
+    fn new(header: &'a [String], options: &'a ExportXlsxOptions) -> Result<Self, XlsxError> {
+        let mut sheets = Self {
+            workbook: Workbook::new(),
+            header,
+            options,
+            row: 0,
+        };
+        sheets.add_sheet(&options.sheet_name)?;
+        Ok(sheets)
+    }
+
+    fn sheet(&mut self) -> &mut Worksheet {
+        // there is always at least one
+        let last = self.workbook.worksheets_mut().len() - 1;
+        &mut self.workbook.worksheets_mut()[last]
+    }
+
+    fn add_sheet(&mut self, name: &str) -> Result<(), XlsxError> {
+        if !self.workbook.worksheets_mut().is_empty() {
+            self.finish()?;
+        }
+        let sheet = self.workbook.add_worksheet_with_constant_memory();
+        sheet.set_name(name)?;
+        if self.options.freeze_header {
+            sheet.set_freeze_panes(1, 0)?;
+        }
+        for (i, c) in self.header.iter().enumerate() {
+            sheet.write_string(0, i as u16, c)?;
+        }
+        self.row = 1;
+        Ok(())
+    }
+
+    /// Writes a row to the last worksheet, or to a new one if it is full. Fails the export
+    /// if all worksheets are full, the row if it can not be written
+    fn write_row(
+        &mut self,
+        id: Option<i64>,
+        row: &[Option<String>],
+    ) -> Result<Result<(), XlsxError>, DataToolErrors> {
+        if self.row == SHEET_ROWS {
+            if !self.options.split_sheets {
+                return Err(DataToolErrors::InvalidArgument(format!(
+                    "more than {} rows, the most a worksheet holds, \
+                     split_sheets continues on a new worksheet",
+                    SHEET_ROWS - 1
+                )));
+            }
+            let n = self.workbook.worksheets_mut().len();
+            let base = &self.options.sheet_name;
+            let res = if n == 1 {
+                self.sheet().set_name(format!("{}_1", base)).map(|_| ())
+            } else {
+                Ok(())
+            };
+            res.and_then(|_| self.add_sheet(&format!("{}_{}", base, n + 1)))
+                .map_err(map_err)?;
+        }
+        let r = self.row;
+        let sheet = self.sheet();
+        let mut cells = Ok(());
+        if let Some(id) = id {
+            cells = sheet.write_number(r, 0, id as f64).map(|_| ());
+        }
+        let offset = usize::from(id.is_some());
+        for (i, v) in row.iter().enumerate() {
+            match v.as_deref() {
+                Some(v) if !v.is_empty() && cells.is_ok() => {
+                    cells = sheet.write_string(r, (i + offset) as u16, v).map(|_| ());
+                }
+                _ => {}
+            }
+        }
+        if cells.is_ok() {
+            self.row += 1;
+        }
+        Ok(cells)
+    }
+
+    /// Adds the autofilter to the last worksheet, once all its rows are written
+    fn finish(&mut self) -> Result<(), XlsxError> {
+        if self.options.autofilter && !self.header.is_empty() {
+            let (rows, columns) = (self.row, self.header.len());
+            self.sheet()
+                .autofilter(0, 0, rows - 1, columns as u16 - 1)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use crate::errors::DataToolErrors;
+use crate::{key_array, TableMapDb, Tables};
+use rusqlite::{Connection, ErrorCode, OptionalExtension};
+use tracing::{info, warn};
+
+/// Full-text index of the stored values, its rows are the ids of `data_columns`
+const FTS_TABLE: &str = r#"
+BEGIN;
+create virtual table data_fts using fts5(value, content = 'data_columns', content_rowid = 'id');
+
+create trigger data_fts_insert after insert on data_columns begin
+    insert into data_fts (rowid, value) values (new.id, new.value);
+end;
+
+create trigger data_fts_delete after delete on data_columns begin
+    insert into data_fts (data_fts, rowid, value) values ('delete', old.id, old.value);
+end;
+
+create trigger data_fts_update after update on data_columns begin
+    insert into data_fts (data_fts, rowid, value) values ('delete', old.id, old.value);
+    insert into data_fts (rowid, value) values (new.id, new.value);
+end;
+
+insert into data_fts (data_fts) values ('rebuild');
+COMMIT;
+"#;
+
+/// A stored value found by [`TableMapDb::search_values`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit {
+    pub item_id: i64,
+    pub item_val: Option<String>,
+    pub key: String,
+    pub value: String,
+}
+
+impl TableMapDb {
+    /// Indexes the stored values for [`TableMapDb::search`], with an FTS5 table kept in sync
+    /// by triggers, so the values inserted afterwards, however they are, are indexed too.
+    /// The values already stored are indexed in a single transaction. Does nothing if the
+    /// index exists. Fails with [`DataToolErrors::FtsUnavailable`] if SQLite was built
+    /// without FTS5
+    pub fn enable_fts(&mut self) -> Result<(), DataToolErrors> {
+        if fts_enabled(&self.connection, &self.tables)? {
+            return Ok(());
+        }
+        let fts5: bool = self.connection.query_row(
+            "select sqlite_compileoption_used('ENABLE_FTS5')",
+            [],
+            |r| r.get(0),
+        )?;
+        if !fts5 {
+            return Err(DataToolErrors::FtsUnavailable(
+                "SQLite was built without FTS5".to_string(),
+            ));
+        }
+        if let Err(e) = self.connection.execute_batch(&self.sql(FTS_TABLE)) {
+            let _ = self.connection.execute_batch("ROLLBACK");
+            return Err(e.into());
+        }
+        info!("full-text index of {:?} is ready", self.db_file);
+        Ok(())
+    }
+
+    /// Items with a value matching `query`, with the key of the value, by item then in the
+    /// order the values were inserted. A key matching more than once for an item is listed
+    /// once, values since replaced by a later insert of the key still match.
+    ///
+    /// With the index of [`TableMapDb::enable_fts`], `query` is an FTS5 query, e.g. `recall`,
+    /// `"product recall"` or `recal*`, matching whole words regardless of the case.
+    /// Without it, every value is scanned for `query` as is, anywhere in the value and
+    /// regardless of the ASCII case, with a warning
+    pub fn search(&self, query: &str) -> Result<Vec<(i64, String)>, DataToolErrors> {
+        let fts = fts_enabled(&self.connection, &self.tables)?;
+        let (q, param) = if fts {
+            (
+                "select d.item_id, d.key from data_fts f join data_columns d on d.id = f.rowid \
+                 where data_fts match ?1 group by d.item_id, d.key order by d.item_id, min(d.id)",
+                query.to_string(),
+            )
+        } else {
+            warn!("no full-text index, scanning all the values, see enable_fts");
+            (
+                "select item_id, key from data_columns where value like ?1 escape '\\' \
+                 group by item_id, key order by item_id, min(id)",
+                format!("%{}%", escape_like(query)),
+            )
+        };
+        let mut stmt = self.connection.prepare_cached(&self.sql(q))?;
+        stmt.query_map([param], |r| Ok((r.get(0)?, r.get(1)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| match e {
+                // a query FTS5 can not parse
+                rusqlite::Error::SqliteFailure(f, Some(m))
+                    if fts && f.code == ErrorCode::Unknown =>
+                {
+                    DataToolErrors::InvalidArgument(format!("search query {:?}: {}", query, m))
+                }
+                e => e.into(),
+            })
+    }
+
+    /// Stored values containing `needle`, in the order they were inserted, only looking in
+    /// `keys` if given. The needle is matched as is, wildcards included, regardless of the
+    /// ASCII case unless `case_sensitive`. Every matching value is listed, even one replaced
+    /// by a later insert of its key.
+    ///
+    /// Scans the values, restricting the keys only saves comparing the others, see
+    /// [`TableMapDb::search`] for an indexed search
+    pub fn search_values(
+        &self,
+        needle: &str,
+        keys: Option<&[&str]>,
+        case_sensitive: bool,
+    ) -> Result<Vec<SearchHit>, DataToolErrors> {
+        let (matches, needle) = match case_sensitive {
+            true => ("instr(d.value, ?1) > 0", needle.to_string()),
+            false => (
+                "d.value like ?1 escape '\\'",
+                format!("%{}%", escape_like(needle)),
+            ),
+        };
+        let key_filter = match keys {
+            Some(_) => " and d.key in rarray(?2)",
+            None => "",
+        };
+        let keys =
+            keys.map(|keys| key_array(&keys.iter().map(|k| k.to_string()).collect::<Vec<_>>()));
+        let q = format!(
+            "select d.item_id, i.item_val, d.key, d.value from data_columns d \
+             join item_data i on i.id = d.item_id where {}{} order by d.id",
+            matches, key_filter
+        );
+        let mut stmt = self.connection.prepare_cached(&self.sql(&q))?;
+        let params = rusqlite::params_from_iter(
+            [&needle as &dyn rusqlite::ToSql]
+                .into_iter()
+                .chain(keys.as_ref().map(|k| k as &dyn rusqlite::ToSql)),
+        );
+        let hits = stmt
+            .query_map(params, |r| {
+                Ok(SearchHit {
+                    item_id: r.get(0)?,
+                    item_val: r.get(1)?,
+                    key: r.get(2)?,
+                    value: r.get(3)?,
+                })
+            })
+            .and_then(|rows| rows.collect())
+            .map_err(DataToolErrors::from);
+        hits
+    }
+}
+
+fn fts_enabled(conn: &Connection, tables: &Tables) -> rusqlite::Result<bool> {
+    let found = conn
+        .query_row(
+            &tables.sql("select 1 from sqlite_master where type = 'table' and name = 'data_fts'"),
+            [],
+            |_| Ok(()),
+        )
+        .optional()?;
+    Ok(found.is_some())
+}
+
+/// Escapes the wildcards of a LIKE pattern
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
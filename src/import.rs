@@ -0,0 +1,624 @@
+use crate::errors::DataToolErrors;
+use crate::{auto_item_val, now_ms, quote_ident, ChangeEvent, TableMapDb, NEW_ITEM};
+use indexmap::IndexMap;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OpenFlags};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Options of the imports, [`import_csv`], [`import_jsonl`] and [`import_sqlite_table`]
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    item_val_column: Option<String>,
+    skip_empty: bool,
+    rename_headers: IndexMap<String, String>,
+    delimiter: u8,
+    batch_rows: usize,
+    strict: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            item_val_column: None,
+            skip_empty: true,
+            rename_headers: IndexMap::new(),
+            delimiter: b',',
+            batch_rows: 1000,
+            strict: false,
+        }
+    }
+}
+
+impl ImportOptions {
+    /// The column holding the `item_val` of each row, by its header in a CSV file, or its
+    /// flattened key in a JSON line. The column is stored as well, like the others. Without
+    /// it, each row gets a random UUID, as [`TableMapDb::next_row_auto`] gives, so importing
+    /// a file twice stores its rows twice
+    pub fn item_val_column(mut self, column: impl Into<String>) -> Self {
+        self.item_val_column = Some(column.into());
+        self
+    }
+
+    /// Do not store the empty cells, on by default. The exports write the same for a key an
+    /// item does not have and an empty value
+    pub fn skip_empty(mut self, skip_empty: bool) -> Self {
+        self.skip_empty = skip_empty;
+        self
+    }
+
+    /// Store the columns under other keys, the map going from the header in a CSV file, or
+    /// the flattened key in a JSON line, to the key. Other columns keep their name
+    pub fn rename_headers(mut self, renames: IndexMap<String, String>) -> Self {
+        self.rename_headers = renames;
+        self
+    }
+
+    /// Field delimiter of a CSV file, `,` by default, `b'\t'` for TSV
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Rows stored per transaction, 1000 by default
+    pub fn batch_rows(mut self, rows: usize) -> Self {
+        self.batch_rows = rows;
+        self
+    }
+
+    /// Stop at the first malformed row, failing with [`DataToolErrors::RowReadFailed`],
+    /// instead of listing it in [`ImportSummary::malformed_rows`]. The rows before it are
+    /// kept
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+}
+
+/// Options of the JSONL import, [`import_jsonl`]
+#[derive(Debug, Clone)]
+pub struct ImportJsonlOptions {
+    key_separator: String,
+    arrays: JsonArrays,
+}
+
+impl Default for ImportJsonlOptions {
+    fn default() -> Self {
+        Self {
+            key_separator: ".".to_string(),
+            arrays: Default::default(),
+        }
+    }
+}
+
+impl ImportJsonlOptions {
+    /// Joins the keys of nested objects, `.` by default, `{"address": {"city": ..}}` is
+    /// stored as `address.city`
+    pub fn key_separator(mut self, separator: impl Into<String>) -> Self {
+        self.key_separator = separator.into();
+        self
+    }
+
+    /// What to do with arrays, skipped by default
+    pub fn arrays(mut self, arrays: JsonArrays) -> Self {
+        self.arrays = arrays;
+        self
+    }
+}
+
+/// Arrays in the lines of a JSONL import
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JsonArrays {
+    /// The key of the array is not stored
+    #[default]
+    Skip,
+    /// The line is a malformed row
+    Reject,
+}
+
+/// What an import did
+#[derive(Debug, Clone, Default)]
+pub struct ImportSummary {
+    pub rows_imported: usize,
+    pub cells_stored: usize,
+    /// rows whose `item_val` is already taken, by an existing item or an earlier row
+    pub rows_skipped_duplicate: usize,
+    /// rows that could not be read, or without an `item_val`, in the order of the file
+    pub malformed_rows: Vec<MalformedRow>,
+    pub elapsed: Duration,
+}
+
+/// A row an import left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedRow {
+    /// line of the file the row starts on, or position of the row in a SQLite table,
+    /// counting from 1
+    pub line: u64,
+    pub reason: String,
+}
+
+/// Stores the rows of a CSV file with a header as items, each column under the header as
+/// the key. Rows are stored in batched transactions, the way the writer task does, see
+/// [`TableMapDb::spawn_writer`]. A UTF-8 BOM before the header, as written by Excel, is
+/// ignored.
+///
+/// Rows with another number of fields than the header, that are not valid UTF-8, or
+/// without an `item_val` are listed in [`ImportSummary::malformed_rows`], and do not stop
+/// the import, unless it is [`strict`](ImportOptions::strict). If reading the file fails,
+/// the rows stored so far are kept
+pub fn import_csv(
+    db: &mut TableMapDb,
+    path: &Path,
+    options: ImportOptions,
+) -> Result<ImportSummary, DataToolErrors> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .from_reader(fs::File::open(path)?);
+    let mut keys = Vec::new();
+    for (i, header) in reader.headers()?.iter().enumerate() {
+        let header = match i {
+            0 => header.trim_start_matches('\u{FEFF}'),
+            _ => header,
+        };
+        let key = options
+            .rename_headers
+            .get(header)
+            .map_or(header, String::as_str);
+        if key.is_empty() {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "column {} has no name",
+                i + 1
+            )));
+        }
+        if keys.iter().any(|(_, k)| k == key) {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "more than one column is stored as {:?}",
+                key
+            )));
+        }
+        keys.push((header.to_string(), key.to_string()));
+    }
+    let item_val_index = match &options.item_val_column {
+        Some(column) => Some(keys.iter().position(|(h, _)| h == column).ok_or_else(|| {
+            DataToolErrors::InvalidArgument(format!("no column {:?} in {:?}", column, path))
+        })?),
+        None => None,
+    };
+    run_import(db, &options, |loader| {
+        let mut record = csv::StringRecord::new();
+        loop {
+            match reader.read_record(&mut record) {
+                Ok(false) => return Ok(()),
+                Ok(true) => {}
+                Err(e) if matches!(e.kind(), csv::ErrorKind::Io(_)) => return Err(e.into()),
+                Err(e) => {
+                    let line = e.position().map_or(0, |p| p.line());
+                    loader.malformed(line, e.to_string())?;
+                    continue;
+                }
+            }
+            let line = record.position().map_or(0, |p| p.line());
+            let item_val = match item_val_index {
+                Some(i) if record[i].is_empty() => {
+                    let reason = format!("no value in the item_val column {:?}", keys[i].0);
+                    loader.malformed(line, reason)?;
+                    continue;
+                }
+                Some(i) => record[i].to_string(),
+                None => auto_item_val(),
+            };
+            let cells = keys.iter().map(|(_, k)| k.as_str()).zip(record.iter());
+            loader.store(line, item_val, cells)?;
+        }
+    })
+}
+
+/// Stores the lines of a JSONL file as items, see [`import_jsonl_reader`]
+pub fn import_jsonl(
+    db: &mut TableMapDb,
+    path: &Path,
+    options: ImportOptions,
+    jsonl_options: ImportJsonlOptions,
+) -> Result<ImportSummary, DataToolErrors> {
+    import_jsonl_reader(db, fs::File::open(path)?, options, jsonl_options)
+}
+
+/// Stores each line of a JSONL stream, a JSON object, as an item. Nested objects are
+/// flattened, their keys joined by [`ImportJsonlOptions::key_separator`], numbers and
+/// booleans are stored as their JSON text, `null` as an empty value. Rows are stored in
+/// batched transactions, the same as [`import_csv`], and blank lines are ignored.
+///
+/// Lines that are not a JSON object, have an array that is rejected, a key twice once
+/// flattened, or no `item_val` are listed in [`ImportSummary::malformed_rows`], and do not
+/// stop the import, unless it is [`strict`](ImportOptions::strict). If reading fails, the
+/// rows stored so far are kept
+pub fn import_jsonl_reader<R: Read>(
+    db: &mut TableMapDb,
+    reader: R,
+    options: ImportOptions,
+    jsonl_options: ImportJsonlOptions,
+) -> Result<ImportSummary, DataToolErrors> {
+    let mut reader = io::BufReader::new(reader);
+    run_import(db, &options, |loader| {
+        let mut buf = Vec::new();
+        let mut line = 0;
+        loop {
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                return Ok(());
+            }
+            line += 1;
+            let text = match line {
+                1 => buf.strip_prefix("\u{FEFF}".as_bytes()).unwrap_or(&buf),
+                _ => &buf,
+            };
+            if text.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let cells = match json_cells(text, &options, &jsonl_options) {
+                Ok(cells) => cells,
+                Err(reason) => {
+                    loader.malformed(line, reason)?;
+                    continue;
+                }
+            };
+            let item_val = match &options.item_val_column {
+                Some(column) => match cells.iter().find(|(k, _)| k == column) {
+                    Some((_, v)) if !v.is_empty() => v.clone(),
+                    _ => {
+                        let reason = format!("no value in the item_val column {:?}", column);
+                        loader.malformed(line, reason)?;
+                        continue;
+                    }
+                },
+                None => auto_item_val(),
+            };
+            let cells = cells.iter().map(|(k, v)| {
+                let key = options.rename_headers.get(k).unwrap_or(k);
+                (key.as_str(), v.as_str())
+            });
+            loader.store(line, item_val, cells)?;
+        }
+    })
+}
+
+/// Stores the rows of a table of another SQLite database as items, each column under its
+/// name as the key, the item named by the value of `item_col`. Meant for wide tables, such
+/// as the ones written by [`dump_db`](crate::dump_db). Rows are stored in batched
+/// transactions, the same as [`import_csv`], and [`ImportOptions::item_val_column`] is not
+/// used.
+///
+/// `NULL` is an empty value, so it is not stored unless [`ImportOptions::skip_empty`] is
+/// off. Numbers are stored as the SQL exports write them, e.g. `2.5`, and `3` for the
+/// `REAL` 3.0, and a `BLOB` as its text. Rows without an `item_val`, or with
+/// text or a `BLOB` that is not valid UTF-8, are listed in [`ImportSummary::malformed_rows`]
+/// by their position in the table, and do not stop the import, unless it is
+/// [`strict`](ImportOptions::strict)
+pub fn import_sqlite_table(
+    db: &mut TableMapDb,
+    source: &Path,
+    table: &str,
+    item_col: &str,
+    options: ImportOptions,
+) -> Result<ImportSummary, DataToolErrors> {
+    if !source.exists() {
+        return Err(DataToolErrors::InvalidArgument(format!(
+            "no database {:?}",
+            source
+        )));
+    }
+    let conn = Connection::open_with_flags(source, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let columns = conn
+        .prepare("select name from pragma_table_info(?1)")
+        .and_then(|mut stmt| {
+            stmt.query_map([table], |r| r.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        })?;
+    if columns.is_empty() {
+        return Err(DataToolErrors::InvalidArgument(format!(
+            "no table {:?} in {:?}",
+            table, source
+        )));
+    }
+    // SQLite column names ignore the case
+    let item_val_index = columns
+        .iter()
+        .position(|c| c.eq_ignore_ascii_case(item_col))
+        .ok_or_else(|| {
+            DataToolErrors::InvalidArgument(format!("no column {:?} in {:?}", item_col, table))
+        })?;
+    let mut keys: Vec<&str> = vec![];
+    for column in &columns {
+        let key = options.rename_headers.get(column).unwrap_or(column);
+        if key.is_empty() {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "column {:?} is renamed to an empty key",
+                column
+            )));
+        }
+        if keys.contains(&key.as_str()) {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "more than one column is stored as {:?}",
+                key
+            )));
+        }
+        keys.push(key);
+    }
+    let column_list = columns
+        .iter()
+        .map(|c| quote_ident(c))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut stmt = conn.prepare(&format!(
+        "select {} from {}",
+        column_list,
+        quote_ident(table)
+    ))?;
+    let mut rows = stmt.query([])?;
+    run_import(db, &options, |loader| {
+        let mut position = 0;
+        let mut values = Vec::with_capacity(columns.len());
+        while let Some(row) = rows.next()? {
+            position += 1;
+            values.clear();
+            for (i, column) in columns.iter().enumerate() {
+                match sqlite_text(row.get_ref(i)?) {
+                    Ok(v) => values.push(v),
+                    Err(e) => {
+                        let reason = format!("{:?} {}", column, e);
+                        loader.malformed(position, reason)?;
+                        break;
+                    }
+                }
+            }
+            if values.len() < columns.len() {
+                continue;
+            }
+            if values[item_val_index].is_empty() {
+                let reason = format!("no value in the item_val column {:?}", item_col);
+                loader.malformed(position, reason)?;
+                continue;
+            }
+            let item_val = values[item_val_index].clone();
+            let cells = keys.iter().copied().zip(values.iter().map(String::as_str));
+            loader.store(position, item_val, cells)?;
+        }
+        Ok(())
+    })
+}
+
+/// A value of a SQLite table as the text stored, empty for `NULL`
+fn sqlite_text(value: ValueRef) -> Result<String, String> {
+    match value {
+        ValueRef::Null => Ok(String::new()),
+        ValueRef::Integer(i) => Ok(i.to_string()),
+        ValueRef::Real(f) => Ok(f.to_string()),
+        ValueRef::Text(b) | ValueRef::Blob(b) => {
+            String::from_utf8(b.to_vec()).map_err(|_| "is not valid UTF-8".to_string())
+        }
+    }
+}
+
+/// The flattened keys of a JSON line, before renaming, with their value
+fn json_cells(
+    text: &[u8],
+    options: &ImportOptions,
+    jsonl_options: &ImportJsonlOptions,
+) -> Result<Vec<(String, String)>, String> {
+    let object = match serde_json::from_slice(text).map_err(|e| e.to_string())? {
+        Value::Object(object) => object,
+        _ => return Err("not a JSON object".to_string()),
+    };
+    let mut cells = vec![];
+    flatten(None, object, jsonl_options, &mut cells)?;
+    let mut keys = HashSet::new();
+    for (k, _) in &cells {
+        let key = options.rename_headers.get(k).unwrap_or(k);
+        if key.is_empty() {
+            return Err("a key is empty".to_string());
+        }
+        if !keys.insert(key) {
+            return Err(format!("more than one value is stored as {:?}", key));
+        }
+    }
+    Ok(cells)
+}
+
+fn flatten(
+    prefix: Option<&str>,
+    object: Map<String, Value>,
+    jsonl_options: &ImportJsonlOptions,
+    cells: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    for (k, v) in object {
+        let key = match prefix {
+            Some(prefix) => format!("{}{}{}", prefix, jsonl_options.key_separator, k),
+            None => k,
+        };
+        match v {
+            Value::Object(object) => flatten(Some(&key), object, jsonl_options, cells)?,
+            Value::Array(_) if jsonl_options.arrays == JsonArrays::Reject => {
+                return Err(format!("{:?} is an array", key));
+            }
+            Value::Array(_) => {}
+            Value::Null => cells.push((key, String::new())),
+            Value::String(s) => cells.push((key, s)),
+            v => cells.push((key, v.to_string())),
+        }
+    }
+    Ok(())
+}
+
+/// Runs an import in transactions of [`ImportOptions::batch_rows`] rows. The rows stored
+/// before a failure are committed
+fn run_import(
+    db: &mut TableMapDb,
+    options: &ImportOptions,
+    read: impl FnOnce(&mut Loader) -> Result<(), DataToolErrors>,
+) -> Result<ImportSummary, DataToolErrors> {
+    let t = Instant::now();
+    db.connection.execute_batch("BEGIN")?;
+    let mut loader = Loader {
+        db,
+        options,
+        summary: ImportSummary::default(),
+        seen: HashSet::new(),
+        tx_rows: 0,
+    };
+    let res = read(&mut loader);
+    let committed = loader
+        .db
+        .connection
+        .execute_batch("COMMIT")
+        .map_err(DataToolErrors::from);
+    res.and(committed)?;
+    let mut summary = loader.summary;
+    summary.elapsed = t.elapsed();
+    info!("Done! {:?}", summary);
+    Ok(summary)
+}
+
+/// Stores the rows of an import, counting them
+struct Loader<'a> {
+    db: &'a mut TableMapDb,
+    options: &'a ImportOptions,
+    summary: ImportSummary,
+    /// the item_vals of the rows stored, so repeated rows are told apart from existing items
+    seen: HashSet<String>,
+    tx_rows: usize,
+}
+
+impl Loader<'_> {
+    fn malformed(&mut self, line: u64, reason: String) -> Result<(), DataToolErrors> {
+        if self.options.strict {
+            return Err(DataToolErrors::RowReadFailed { line, reason });
+        }
+        warn!("Skipping malformed row on line {}: {}", line, reason);
+        self.summary
+            .malformed_rows
+            .push(MalformedRow { line, reason });
+        Ok(())
+    }
+
+    /// Stores a row as a new item, unless an item is already named `item_val`
+    fn store<'c>(
+        &mut self,
+        line: u64,
+        item_val: String,
+        cells: impl Iterator<Item = (&'c str, &'c str)>,
+    ) -> Result<(), DataToolErrors> {
+        // the row is checked before anything is stored
+        let mut prepared = vec![];
+        for (key, value) in cells {
+            match self.db.prepare_cell(key, value) {
+                Ok(Some(value)) if !(value.is_empty() && self.options.skip_empty) => {
+                    prepared.push((key, value))
+                }
+                Ok(_) => {}
+                Err(e) if self.options.strict => return Err(e),
+                Err(e) => return self.malformed(line, e.to_string()),
+            }
+        }
+        let conn = &self.db.connection;
+        let new_item = NEW_ITEM.replacen("insert", "insert or ignore", 1);
+        let inserted = conn
+            .prepare_cached(&self.db.sql(&new_item))
+            .and_then(|mut stmt| stmt.execute(params![&item_val, now_ms()]))?;
+        if inserted == 0 {
+            match self.seen.contains(&item_val) {
+                true => warn!("Skipping row on line {}, {:?} is repeated", line, item_val),
+                false => warn!(
+                    "Skipping row on line {}, {:?} already exists",
+                    line, item_val
+                ),
+            }
+            self.summary.rows_skipped_duplicate += 1;
+            return Ok(());
+        }
+        let id = conn.last_insert_rowid();
+        self.db.notify(|| ChangeEvent::ItemCreated {
+            id,
+            item_val: item_val.clone(),
+        });
+        self.db.store_cells_for(id, &prepared)?;
+        self.summary.cells_stored += prepared.len();
+        self.seen.insert(item_val);
+        self.summary.rows_imported += 1;
+        self.tx_rows += 1;
+        if self.tx_rows >= self.options.batch_rows.max(1) {
+            self.db.connection.execute_batch("COMMIT; BEGIN")?;
+            self.tx_rows = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+
+    #[test]
+    fn csv_rows_without_an_item_val_column_get_unique_ones() {
+        let dir = TestDir::new("import_csv");
+        let mut db = dir.db();
+        let file = dir.path("in.csv");
+        fs::write(&file, "name,price\napple,1\nbanana,2\napple,1\n").unwrap();
+        for _ in 0..2 {
+            let summary = import_csv(&mut db, &file, ImportOptions::default()).unwrap();
+            assert_eq!(summary.rows_imported, 3);
+            assert_eq!(summary.rows_skipped_duplicate, 0);
+        }
+        let item_vals = db.item_vals().unwrap();
+        assert_eq!(item_vals.len(), 6);
+        assert_eq!(item_vals.iter().collect::<HashSet<_>>().len(), 6);
+        assert!(item_vals
+            .iter()
+            .all(|v| v.len() == 36 && v.as_bytes()[14] == b'4'));
+        assert_eq!(db.find_items("name", "apple").unwrap().len(), 4);
+    }
+
+    #[test]
+    fn json_lines_without_an_item_val_column_get_unique_ones() {
+        let dir = TestDir::new("import_jsonl");
+        let mut db = dir.db();
+        let lines = "{\"name\": \"apple\"}\n\n{\"name\": \"banana\"}\n";
+        for _ in 0..2 {
+            let summary = import_jsonl_reader(
+                &mut db,
+                lines.as_bytes(),
+                ImportOptions::default(),
+                ImportJsonlOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(summary.rows_imported, 2);
+        }
+        let item_vals = db.item_vals().unwrap();
+        assert_eq!(item_vals.iter().collect::<HashSet<_>>().len(), 4);
+        assert!(!item_vals.iter().any(|v| v == "1" || v == "3"));
+        assert_eq!(db.find_items("name", "banana").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn imported_items_are_new_items_of_the_map() {
+        let dir = TestDir::new("import_map");
+        let mut db = dir.db();
+        let file = dir.path("in.csv");
+        fs::write(&file, "sku,name\na,apple\nb,banana\na,again\n").unwrap();
+        let options = ImportOptions::default().item_val_column("sku");
+        let mut map = db.named_map("products").unwrap();
+        let summary = import_csv(&mut map, &file, options.clone()).unwrap();
+        assert_eq!(summary.rows_imported, 2);
+        assert_eq!(summary.rows_skipped_duplicate, 1);
+        assert_eq!(map.get_value("a", "name").unwrap().unwrap(), "apple");
+        let summary = import_csv(&mut map, &file, options).unwrap();
+        assert_eq!(summary.rows_skipped_duplicate, 3);
+        drop(map);
+        assert_eq!(db.how_many_items().unwrap(), 0);
+    }
+}
@@ -1,21 +1,86 @@
-use indexmap::IndexMap;
-use rusqlite::config::DbConfig;
-use rusqlite::{params_from_iter, Connection, OpenFlags};
-use std::collections::HashSet;
+use crate::errors::DataToolErrors;
+use indexmap::{IndexMap, IndexSet};
+use rusqlite::types::{ToSql, Type, Value, ValueRef};
+use rusqlite::{params, params_from_iter, Connection, InterruptHandle, OptionalExtension};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::slice::Chunks;
-use tokio::task::{JoinHandle, JoinSet};
-use tokio::time::Instant;
-use tracing::{error, info, trace, warn};
-use crate::errors::DataToolErrors;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
 
+pub mod aggregate;
+#[cfg(feature = "async-db")]
+pub mod async_db;
+pub mod backup;
+pub mod dedupe;
+pub mod diff;
 pub mod errors;
+pub mod export;
+pub mod fts;
+pub mod import;
+pub mod maintenance;
+pub mod map;
+pub mod merge;
+pub mod normalize;
+pub mod observe;
+pub mod shared;
+#[cfg(test)]
+mod tests;
+pub mod tx;
+mod validate;
+#[cfg(feature = "async")]
+pub mod writer;
+
+pub use aggregate::{ColumnProfile, KeyStats};
+pub use csv::QuoteStyle;
+pub use dedupe::KeepPolicy;
+pub use diff::{diff, DiffOptions, DiffReport, ItemDiff, KeyChange};
+#[cfg(feature = "async")]
+pub use export::{
+    dump_copy, dump_csv, dump_csv_partitioned, dump_csv_writer, dump_db, dump_json, dump_jsonl,
+    dump_jsonl_writer, dump_sql, CopyFormat, Dialect, ExportCopyOptions, ExportPartitionOptions,
+    ExportSqlOptions, SqlPreamble,
+};
+pub use export::{
+    dump_csv_sync, dump_db_attach, dump_db_sync, dump_diff_csv, dump_lookup, dump_profile_csv,
+    dump_rollup, Agg, CaseFold, ChunkStrategy, ColumnType, Compression, DuplicatePolicy,
+    ExcelGuard, ExportCsvOptions, ExportDbOptions, ExportJsonOptions, ExportJsonlOptions,
+    ExportOptions, ExportProgress, ExportShape, ExportSummary, ExportTimings, HeaderSanitize,
+    IfTableExists, LineTerminator, LookupFormat, OverwriteMode, RollupFormat, SchemaFormat,
+    Transform, DEFAULT_CHUNK_SIZE,
+};
+#[cfg(feature = "parquet")]
+pub use export::{dump_parquet, ExportParquetOptions, ParquetCompression};
+#[cfg(feature = "xlsx")]
+pub use export::{dump_xlsx, ExportXlsxOptions};
+#[cfg(feature = "arrow")]
+pub use export::{ExportArrowOptions, RecordBatches};
+pub use fts::SearchHit;
+pub use import::{
+    import_csv, import_jsonl, import_jsonl_reader, import_sqlite_table, ImportJsonlOptions,
+    ImportOptions, ImportSummary, JsonArrays, MalformedRow,
+};
+pub use maintenance::{CheckpointMode, IndexPolicy, StorageStats};
+pub use map::MapHandle;
+use map::{MapState, Tables};
+pub use merge::{MergePolicy, MergeSummary};
+pub use normalize::Normalizer;
+pub use observe::ChangeEvent;
+use observe::Observer;
+pub use rusqlite::OpenFlags;
+#[cfg(feature = "async")]
+pub use tokio_util::sync::CancellationToken;
+pub use tx::TableMapTx;
 
 const KEY_TABLE: &str = r#"
 PRAGMA temp_store = MEMORY; PRAGMA journal_mode = WAL; PRAGMA synchronous = OFF;
+"#;
 
-BEGIN;
+/// The tables of a map, see [`Tables`]
+const MAP_TABLES: &str = r#"
 create table if not exists item_data
 (
     id       integer not null
@@ -23,7 +88,9 @@ create table if not exists item_data
             primary key autoincrement,
     item_val TEXT
         constraint item_data_pk
-            unique
+            unique,
+    created_at integer default (cast(strftime('%s', 'now') as integer) * 1000),
+    revision integer not null default 0
 );
 
 create table if not exists data_columns
@@ -38,14 +105,26 @@ create table if not exists data_columns
             references item_data
             on update cascade on delete cascade
 );
-
-delete from item_data;
-COMMIT;
 "#;
 
+/// Indexes of the tables of a map, created once the tables of an earlier version have the
+/// columns they index, see [`migrate`]
+const MAP_INDEXES: &str = "
+create index if not exists item_data_revision on item_data (revision);
+";
+
+/// A new item, with the current time and the next revision
+const NEW_ITEM: &str = "insert into item_data (item_val, created_at, revision) values \
+                        (?1, ?2, (select coalesce(max(revision), 0) + 1 from item_data))";
+
+/// The item's revision made the highest, run whenever its cells change
+const BUMP_REVISION: &str = "update item_data set revision = \
+                             (select max(revision) from item_data) + 1 where id = ?1";
+
 #[derive(Debug)]
 struct ColumnDef(String);
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct ItemData {
     id: i64,
@@ -53,7 +132,7 @@ pub struct ItemData {
 }
 
 /// Used as temporary key value storage.
-/// `item_data` -> Stores item (id, item_val)
+/// `item_data` -> Stores item (id, item_val, created_at, revision)
 /// `data_columns` -> Data belonging to item, stored as key, value
 ///
 /// ## Caution
@@ -65,153 +144,1576 @@ pub struct ItemData {
 pub struct TableMapDb {
     db_file: PathBuf,
     pub connection: Connection,
-    columns: HashSet<String>,
+    /// every stored key, in the order they were first inserted, see [`TableMapDb::columns`]
+    columns: IndexSet<String>,
     current_id: Option<i64>,
-    current_row_iter: Option<Vec<i64>>,
+    current_row_iter: Option<RowCursor>,
+    iter_order: IterOrder,
+    normalizers: Vec<Normalizer>,
+    validation: validate::Validation,
+    index_policy: IndexPolicy,
+    /// the map the handle works on, the default one unless it is a [`MapHandle`]
+    tables: Tables,
+    /// the state of the maps the handle is not working on, see [`TableMapDb::named_map`]
+    maps: HashMap<String, MapState>,
+    observer: Option<Observer>,
+    /// see [`TableMapDb::busy_timeout`]
+    busy_timeout: Duration,
+    /// see [`TableMapDb::statement_cache_capacity`]
+    statement_cache_capacity: usize,
+    /// see [`TableMapDb::text_policy`]
+    text_policy: TextPolicy,
+}
+
+/// Order of the keys, after the priority columns, returned by
+/// [`TableMapDb::get_distinct_keys_by`] and exported, see [`ExportOptions::key_order`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum KeyOrder {
+    /// In the order they were first inserted, the same from one run to the next
+    #[default]
+    FirstSeen,
+    Alphabetical,
+    /// The keys most items have first, keys as frequent in the order they were first
+    /// inserted
+    ByFrequencyDesc,
+    /// The listed keys first, in this order, the others after them in the order they were
+    /// first inserted
+    Custom(Vec<String>),
+}
+
+/// How the keys and values read back that are not valid UTF-8 are handled, see
+/// [`TableMapDb::text_policy`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextPolicy {
+    /// reading them fails with [`DataToolErrors::InvalidUtf8`]
+    #[default]
+    Strict,
+    /// the invalid bytes are replaced with `U+FFFD`
+    Lossy,
+}
+
+/// Order in which items are yielded by the row iterators and written by the exports.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum IterOrder {
+    /// Oldest item first
+    #[default]
+    InsertionAsc,
+    /// Newest item first
+    InsertionDesc,
+    /// Alphabetically by `item_val`
+    ByItemVal,
+    /// By the value stored under `key`, cast to a number if `numeric` is set.
+    /// Items missing the key are placed last, in insertion order.
+    ByKeyValue { key: String, numeric: bool },
+}
+
+impl IterOrder {
+    /// query selecting the item ids in this order, along with its parameters
+    fn ids_query(&self, tables: &Tables) -> (String, Vec<Value>) {
+        let (q, params) = self.default_ids_query();
+        (tables.sql(&q).into_owned(), params)
+    }
+
+    /// same as [`IterOrder::ids_query`], for the default map
+    fn default_ids_query(&self) -> (String, Vec<Value>) {
+        match self {
+            IterOrder::InsertionAsc => ("select id from item_data order by id".to_string(), vec![]),
+            IterOrder::InsertionDesc => (
+                "select id from item_data order by id desc".to_string(),
+                vec![],
+            ),
+            IterOrder::ByItemVal => (
+                "select id from item_data order by item_val, id".to_string(),
+                vec![],
+            ),
+            IterOrder::ByKeyValue { key, numeric } => {
+                let sort_val = if *numeric { "cast(v as real)" } else { "v" };
+                // if the key was inserted more than once for an item, the last value wins,
+                // same as when the row is read back
+                let q = format!(
+                    "select id from (select i.id as id, \
+                        (select d.value from data_columns d where d.item_id = i.id and d.key = ?1 \
+                         order by d.id desc limit 1) as v from item_data i) \
+                     order by v is null, {}, id",
+                    sort_val
+                );
+                (q, vec![Value::Text(key.clone())])
+            }
+        }
+    }
+
+    /// all item ids, sorted in this order
+    fn item_ids(&self, conn: &Connection, tables: &Tables) -> rusqlite::Result<Vec<i64>> {
+        let (q, params) = self.ids_query(tables);
+        query_ids(conn, &q, params_from_iter(params))
+    }
+
+    /// ids of `limit` items starting at `offset`, sorted in this order
+    fn item_ids_page(
+        &self,
+        conn: &Connection,
+        tables: &Tables,
+        offset: usize,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<i64>> {
+        let (q, mut params) = self.ids_query(tables);
+        let q = format!(
+            "{} limit ?{} offset ?{}",
+            q,
+            params.len() + 1,
+            params.len() + 2
+        );
+        params.push(Value::Integer(limit as i64));
+        params.push(Value::Integer(offset as i64));
+        query_ids(conn, &q, params_from_iter(params))
+    }
+}
+
+fn query_ids<P: rusqlite::Params>(
+    conn: &Connection,
+    q: &str,
+    params: P,
+) -> rusqlite::Result<Vec<i64>> {
+    let mut stmt = conn.prepare_cached(q)?;
+    let ids = stmt.query_map(params, |r| r.get(0))?.collect();
+    ids
+}
+
+/// Reads the stored columns of all the given items with a single query, grouped by item id.
+/// Items without any stored column are not included. If `keys` are given, only those columns
+/// are read, if `max_cell` is, only the cells stored up to it, see [`Snapshot`].
+fn read_items(
+    conn: &Connection,
+    tables: &Tables,
+    ids: &[i64],
+    keys: Option<&[String]>,
+    max_cell: Option<i64>,
+    policy: TextPolicy,
+) -> rusqlite::Result<IndexMap<i64, IndexMap<String, String>>> {
+    let ids = id_array(ids);
+    let keys = keys.map(key_array);
+    let mut params: Vec<&dyn ToSql> = vec![&ids];
+    let mut filter = String::new();
+    if let Some(keys) = &keys {
+        params.push(keys);
+        filter.push_str(&format!(" and key in rarray(?{})", params.len()));
+    }
+    if let Some(max_cell) = &max_cell {
+        params.push(max_cell);
+        filter.push_str(&format!(" and id <= ?{}", params.len()));
+    }
+    let mut inner_stmt = conn.prepare_cached(&tables.sql(&format!(
+        "select item_id, key, value from data_columns where item_id in rarray(?1){} \
+         order by item_id",
+        filter
+    )))?;
+    group_items(&mut inner_stmt, params_from_iter(params), policy)
+}
+
+/// Which of the ids have an item
+fn existing_ids(conn: &Connection, tables: &Tables, ids: &[i64]) -> rusqlite::Result<HashSet<i64>> {
+    let mut stmt =
+        conn.prepare_cached(&tables.sql("select id from item_data where id in rarray(?1)"))?;
+    let found = stmt.query_map([id_array(ids)], |r| r.get(0))?.collect();
+    found
+}
+
+/// Binds a list of ids to a single parameter, to be used with `rarray`
+fn id_array(ids: &[i64]) -> Rc<Vec<Value>> {
+    Rc::new(ids.iter().copied().map(Value::from).collect())
+}
+
+/// Same as [`id_array`], for a list of keys
+fn key_array(keys: &[String]) -> Rc<Vec<Value>> {
+    Rc::new(keys.iter().cloned().map(Value::from).collect())
+}
+
+/// How long a connection waits for a lock held by another one before failing with
+/// `database is locked`, see [`TableMapDb::busy_timeout`]
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Prepared statements each connection keeps, rusqlite's default, see
+/// [`TableMapDb::statement_cache_capacity`]
+const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 16;
+
+/// How a connection to the db file is opened, see [`TableMapDb::open_existing_with`],
+/// [`TableMapDb::read_only_conn_with`] and [`ExportOptions::reader_mode`]. Made from the
+/// [`OpenFlags`] alone with `into()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenMode {
+    flags: OpenFlags,
+    immutable: bool,
+}
+
+impl OpenMode {
+    /// Opened with `flags`, e.g. `SQLITE_OPEN_READ_ONLY | SQLITE_OPEN_NOFOLLOW`
+    pub fn new(flags: OpenFlags) -> Self {
+        Self {
+            flags,
+            immutable: false,
+        }
+    }
+
+    /// Opens the db file as a `file:` URI with `immutable=1`. SQLite then takes no lock and
+    /// does not check for changes made by other connections, which speeds up reading a db
+    /// nothing writes to anymore, e.g. a sealed snapshot or a file on a read-only mount.
+    /// Reading a db written to meanwhile may return wrong rows or fail as corrupt, and
+    /// writing through the connection fails
+    pub fn immutable(mut self, immutable: bool) -> Self {
+        self.immutable = immutable;
+        self
+    }
+}
+
+impl From<OpenFlags> for OpenMode {
+    fn from(flags: OpenFlags) -> Self {
+        Self::new(flags)
+    }
+}
+
+/// `db_file` as a `file:` URI opening it immutable, with the characters a URI path can not
+/// have escaped
+fn immutable_uri(db_file: &Path) -> String {
+    let mut uri = String::from("file:");
+    for c in db_file.to_string_lossy().chars() {
+        match c {
+            '%' => uri.push_str("%25"),
+            '?' => uri.push_str("%3f"),
+            '#' => uri.push_str("%23"),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?immutable=1");
+    uri
+}
+
+/// Opens a connection to the database, with the `rarray` table function loaded, waiting
+/// [`DEFAULT_BUSY_TIMEOUT`] for the locks
+fn open_connection(db_file: &Path, mode: impl Into<OpenMode>) -> rusqlite::Result<Connection> {
+    let mode = mode.into();
+    let conn = match mode.immutable {
+        true => Connection::open_with_flags(
+            immutable_uri(db_file),
+            mode.flags | OpenFlags::SQLITE_OPEN_URI,
+        )?,
+        false => Connection::open_with_flags(db_file, mode.flags)?,
+    };
+    conn.busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+    rusqlite::vtab::array::load_module(&conn)?;
+    Ok(conn)
+}
+
+/// Unix epoch milliseconds, the time stored in `created_at`
+pub(crate) fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// Adds the columns `item_data` got since to the tables of a db from an earlier version,
+/// then creates the indexes. The items already stored get a `NULL` `created_at`, SQLite
+/// does not allow adding a column with the current time as default, and revision 0
+fn migrate(conn: &Connection, tables: &Tables) -> rusqlite::Result<()> {
+    let added = [
+        ("created_at", "created_at integer"),
+        ("revision", "revision integer not null default 0"),
+    ];
+    for (name, def) in added {
+        let found: bool = conn.query_row(
+            &tables.sql("select count(*) > 0 from pragma_table_info('item_data') where name = ?1"),
+            [name],
+            |r| r.get(0),
+        )?;
+        if !found {
+            conn.execute_batch(&tables.sql(&format!("alter table item_data add column {}", def)))?;
+            info!("{} added to {}", name, tables.sql("item_data"));
+        }
+    }
+    conn.execute_batch(&tables.sql(MAP_INDEXES))
+}
+
+/// Cells stored by a single insert statement, see [`insert_cells`]
+const CELLS_PER_INSERT: usize = 100;
+
+/// Stores the cells of the item, [`CELLS_PER_INSERT`] of them per statement, the tail with
+/// a statement of its own. The cells get increasing ids in their order, the same as with
+/// a statement per cell
+fn insert_cells(
+    conn: &Connection,
+    tables: &Tables,
+    item_id: i64,
+    cells: &[(&str, Cow<str>)],
+) -> rusqlite::Result<()> {
+    for batch in cells.chunks(CELLS_PER_INSERT) {
+        let q = format!(
+            "insert into data_columns (key, value, item_id) values {}",
+            vec!["(?, ?, ?)"; batch.len()].join(", ")
+        );
+        let mut params: Vec<&dyn ToSql> = Vec::with_capacity(batch.len() * 3);
+        for (k, v) in batch {
+            params.extend([k as &dyn ToSql, v as &dyn ToSql, &item_id]);
+        }
+        conn.prepare_cached(&tables.sql(&q))?
+            .execute(params_from_iter(params))?;
+    }
+    conn.prepare_cached(&tables.sql(BUMP_REVISION))?
+        .execute([item_id])?;
+    Ok(())
+}
+
+/// A random UUID v4, in its hyphenated lowercase form, see [`TableMapDb::next_row_auto`]
+fn auto_item_val() -> String {
+    // the version and variant bits set, as RFC 9562 has them
+    let bits = rand::random::<u128>() & !(0xf << 76) & !(0x3 << 62) | (0x4 << 76) | (0x2 << 62);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Quotes a column name to be spliced into SQL
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Same as [`read_items`], for all the items with `lo <= id <= hi`
+fn read_items_range(
+    conn: &Connection,
+    tables: &Tables,
+    lo: i64,
+    hi: i64,
+    keys: Option<&[String]>,
+    max_cell: Option<i64>,
+    policy: TextPolicy,
+) -> rusqlite::Result<IndexMap<i64, IndexMap<String, String>>> {
+    let keys = keys.map(key_array);
+    let mut params: Vec<&dyn ToSql> = vec![&lo, &hi];
+    let mut filter = String::new();
+    if let Some(keys) = &keys {
+        params.push(keys);
+        filter.push_str(&format!(" and key in rarray(?{})", params.len()));
+    }
+    if let Some(max_cell) = &max_cell {
+        params.push(max_cell);
+        filter.push_str(&format!(" and id <= ?{}", params.len()));
+    }
+    let mut inner_stmt = conn.prepare_cached(&tables.sql(&format!(
+        "select item_id, key, value from data_columns where item_id between ?1 and ?2{} \
+         order by item_id",
+        filter
+    )))?;
+    group_items(&mut inner_stmt, params_from_iter(params), policy)
+}
+
+/// Groups the `(item_id, key, value)` rows returned by the statement by item id
+fn group_items<P: rusqlite::Params>(
+    inner_stmt: &mut rusqlite::Statement,
+    params: P,
+    policy: TextPolicy,
+) -> rusqlite::Result<IndexMap<i64, IndexMap<String, String>>> {
+    let mut im_dd: IndexMap<i64, IndexMap<String, String>> = IndexMap::new();
+    // the first error stops the grouping, instead of leaving out the cell
+    inner_stmt
+        .query_map(params, |row| {
+            let item_id: i64 = row.get(0)?;
+            let key = read_text(row, 1, item_id, None, policy)?;
+            let val = read_text(row, 2, item_id, Some(&key), policy)?;
+            im_dd
+                .entry(item_id)
+                .and_modify(|v| {
+                    v.insert(key.clone(), val.clone());
+                })
+                .or_insert_with(|| {
+                    let mut im = IndexMap::new();
+                    im.insert(key, val);
+                    im
+                });
+            Ok(())
+        })?
+        .collect::<rusqlite::Result<()>>()?;
+    Ok(im_dd)
+}
+
+/// The text in column `i` of the row, the key of a cell of the item `item_id` or, if `key`
+/// is given, its value, read following `policy`. Read from blobs too, e.g. copied from
+/// another db
+pub(crate) fn read_text(
+    row: &rusqlite::Row,
+    i: usize,
+    item_id: i64,
+    key: Option<&str>,
+    policy: TextPolicy,
+) -> rusqlite::Result<String> {
+    let bytes = match row.get_ref(i)? {
+        ValueRef::Text(bytes) | ValueRef::Blob(bytes) => bytes,
+        _ => return row.get(i),
+    };
+    match (std::str::from_utf8(bytes), policy) {
+        (Ok(text), _) => Ok(text.to_string()),
+        (Err(_), TextPolicy::Lossy) => Ok(String::from_utf8_lossy(bytes).into_owned()),
+        (Err(_), TextPolicy::Strict) => {
+            let key = key.map_or_else(|| String::from_utf8_lossy(bytes).into_owned(), String::from);
+            Err(rusqlite::Error::FromSqlConversionFailure(
+                i,
+                Type::Text,
+                Box::new(DataToolErrors::InvalidUtf8 { item_id, key }),
+            ))
+        }
+    }
+}
+
+/// Number of ids fetched at a time by the row iterators
+const ITER_PAGE_SIZE: usize = 1000;
+
+/// Walks through the item ids in the given order a page at a time, using keyset pagination,
+/// so only one page of ids is held in memory.
+/// Sorting by a data column has to sort the whole join anyway, so for
+/// [`IterOrder::ByKeyValue`] all the ids are fetched with the first page.
+/// The items without an `item_val` sort first by [`IterOrder::ByItemVal`], as SQLite sorts
+/// NULLs, and are paged by id, a row value comparison with a NULL being NULL
+#[derive(Debug)]
+struct IdPager {
+    order: IterOrder,
+    tables: Tables,
+    /// sort key of the last returned id, `None` before the first page
+    last: Option<Vec<Value>>,
+    sorted: Option<std::vec::IntoIter<i64>>,
+    /// the last item paged, the ones stored after a [`Snapshot`] are skipped
+    max_id: Option<i64>,
+    /// set once a page is the last one
+    done: bool,
+}
+
+impl IdPager {
+    fn new(order: IterOrder, tables: Tables) -> Self {
+        Self {
+            order,
+            tables,
+            last: None,
+            sorted: None,
+            max_id: None,
+            done: false,
+        }
+    }
+
+    /// next `limit` ids, up to `max_id` if set, empty when there are none left
+    fn next_page(&mut self, conn: &Connection, limit: usize) -> rusqlite::Result<Vec<i64>> {
+        let Some(max_id) = self.max_id else {
+            return self.fetch_page(conn, limit);
+        };
+        // the items stored since are skipped, a page may only have them
+        loop {
+            let mut page = self.fetch_page(conn, limit)?;
+            let fetched = page.len();
+            page.retain(|id| *id <= max_id);
+            if !page.is_empty() || fetched == 0 {
+                return Ok(page);
+            }
+        }
+    }
+
+    /// next `limit` ids of all the items, empty when there are none left
+    fn fetch_page(&mut self, conn: &Connection, limit: usize) -> rusqlite::Result<Vec<i64>> {
+        let (q, params) = match (&self.order, self.last.clone()) {
+            (IterOrder::ByKeyValue { .. }, _) => {
+                if self.sorted.is_none() {
+                    self.sorted = Some(self.order.item_ids(conn, &self.tables)?.into_iter());
+                }
+                let page: Vec<i64> = self.sorted.as_mut().unwrap().take(limit).collect();
+                self.done = page.len() < limit;
+                return Ok(page);
+            }
+            (IterOrder::InsertionAsc, None) => {
+                ("select id, id from item_data order by id limit ?1", vec![])
+            }
+            (IterOrder::InsertionAsc, Some(last)) => (
+                "select id, id from item_data where id > ?1 order by id limit ?2",
+                last,
+            ),
+            (IterOrder::InsertionDesc, None) => (
+                "select id, id from item_data order by id desc limit ?1",
+                vec![],
+            ),
+            (IterOrder::InsertionDesc, Some(last)) => (
+                "select id, id from item_data where id < ?1 order by id desc limit ?2",
+                last,
+            ),
+            (IterOrder::ByItemVal, None) => (
+                "select id, item_val from item_data order by item_val, id limit ?1",
+                vec![],
+            ),
+            (IterOrder::ByItemVal, Some(mut last)) if last[0] == Value::Null => {
+                last.remove(0);
+                (
+                    "select id, item_val from item_data where item_val is null and id > ?1 \
+                     order by id limit ?2",
+                    last,
+                )
+            }
+            (IterOrder::ByItemVal, Some(last)) => (
+                "select id, item_val from item_data where (item_val, id) > (?1, ?2) \
+                 order by item_val, id limit ?3",
+                last,
+            ),
+        };
+        let mut rows = self.query_page(conn, q, params, limit)?;
+        // past the last item without an item_val, the others follow
+        let nulls_left = matches!(&self.last, Some(last) if last[0] == Value::Null);
+        if self.order == IterOrder::ByItemVal && nulls_left && rows.len() < limit {
+            rows.extend(self.query_page(
+                conn,
+                "select id, item_val from item_data where item_val is not null \
+                 order by item_val, id limit ?1",
+                vec![],
+                limit - rows.len(),
+            )?);
+        }
+        self.done = rows.len() < limit;
+        match rows.last() {
+            Some((id, item_val)) if self.order == IterOrder::ByItemVal => {
+                self.last = Some(vec![item_val.clone(), Value::Integer(*id)]);
+            }
+            Some((id, _)) => self.last = Some(vec![Value::Integer(*id)]),
+            None => {}
+        }
+        Ok(rows.into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// The ids and sort keys returned by `q`, limited to `limit`, bound as the last parameter
+    fn query_page(
+        &self,
+        conn: &Connection,
+        q: &str,
+        mut params: Vec<Value>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<(i64, Value)>> {
+        params.push(Value::Integer(limit as i64));
+        let mut stmt = conn.prepare_cached(&self.tables.sql(q))?;
+        let rows = stmt
+            .query_map(params_from_iter(params), |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect();
+        rows
+    }
+}
+
+/// Iteration state shared by the row iterators, the items stored once it started are not
+/// iterated over
+#[derive(Debug)]
+struct RowCursor {
+    pager: IdPager,
+    ids: VecDeque<i64>,
+    /// rows left to yield, counted when the iteration started
+    remaining: usize,
+    policy: TextPolicy,
+    /// set once a page of ids could not be read, nothing more is read after it
+    failed: bool,
+}
+
+impl RowCursor {
+    fn new(db: &TableMapDb) -> rusqlite::Result<Self> {
+        let mut pager = IdPager::new(db.iter_order.clone(), db.tables.clone());
+        // a single statement, so the items counted are the ones paged
+        let (max_id, remaining) = db
+            .connection
+            .prepare_cached(&db.sql("select coalesce(max(id), 0), count(*) from item_data"))?
+            .query_row([], |r| Ok((r.get(0)?, r.get::<_, i64>(1)?)))?;
+        pager.max_id = Some(max_id);
+        Ok(Self {
+            pager,
+            ids: VecDeque::new(),
+            remaining: remaining as usize,
+            policy: db.text_policy,
+            failed: false,
+        })
+    }
+
+    /// An error if the row can not be read, e.g. a value is not valid UTF-8 with
+    /// [`TextPolicy::Strict`], the next call reading the row after it
+    fn next_row(
+        &mut self,
+        conn: &Connection,
+    ) -> Option<Result<IndexMap<String, String>, DataToolErrors>> {
+        if self.ids.is_empty() && !self.failed {
+            match self.pager.next_page(conn, ITER_PAGE_SIZE) {
+                Ok(ids) => {
+                    // the last page, the count is corrected for the items deleted since
+                    // the iteration started
+                    if self.pager.done {
+                        self.remaining = ids.len();
+                    }
+                    self.ids = ids.into();
+                }
+                Err(e) => {
+                    self.failed = true;
+                    self.remaining = 0;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        let n = self.ids.pop_front()?;
+        self.remaining = self.remaining.saturating_sub(1);
+        Some(read_row(conn, &self.pager.tables, n, self.policy).map_err(DataToolErrors::from))
+    }
+}
+
+/// Reads all the stored columns of an item, `id` being the first one
+fn read_row(
+    conn: &Connection,
+    tables: &Tables,
+    id: i64,
+    policy: TextPolicy,
+) -> rusqlite::Result<IndexMap<String, String>> {
+    let mut inner_stmt =
+        conn.prepare_cached(&tables.sql("select key, value from data_columns where item_id = ?1"))?;
+    let rows = inner_stmt.query_map([id], |r| {
+        let key = read_text(r, 0, id, None, policy)?;
+        let value = read_text(r, 1, id, Some(&key), policy)?;
+        Ok(KeyValPair { key, value })
+    })?;
+    let mut im = IndexMap::new();
+    im.insert("id".to_string(), id.to_string());
+    for row in rows {
+        let r = row?;
+        im.insert(r.key, r.value);
+    }
+    Ok(im)
+}
+
+/// if the key was inserted more than once for the item, the last value is returned
+fn get_value(
+    conn: &Connection,
+    tables: &Tables,
+    item_val: &str,
+    key: &str,
+    policy: TextPolicy,
+) -> rusqlite::Result<Option<String>> {
+    let mut stmt = conn.prepare_cached(&tables.sql(
+        "select d.item_id, d.value from data_columns d join item_data i on i.id = d.item_id \
+         where i.item_val = ?1 and d.key = ?2 order by d.id desc limit 1",
+    ))?;
+    match stmt.query_row([item_val, key], |r| {
+        read_text(r, 1, r.get(0)?, Some(key), policy)
+    }) {
+        Ok(v) => Ok(Some(v)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn find_items(
+    conn: &Connection,
+    tables: &Tables,
+    key: &str,
+    value: &str,
+) -> rusqlite::Result<Vec<i64>> {
+    query_ids(
+        conn,
+        &tables.sql(
+            "select distinct item_id from data_columns where key = ?1 and value = ?2 \
+             order by item_id",
+        ),
+        [key, value],
+    )
+}
+
+/// Highest item id, the number of items if none was deleted, see
+/// [`TableMapDb::how_many_items_fast`]
+fn max_item_id(conn: &Connection, tables: &Tables) -> rusqlite::Result<usize> {
+    let max: i64 = conn
+        .prepare_cached(&tables.sql("select coalesce(max(id), 0) from item_data"))?
+        .query_row([], |r| r.get(0))?;
+    Ok(max as usize)
+}
+
+fn count_items(conn: &Connection, tables: &Tables) -> rusqlite::Result<usize> {
+    let count: i64 = conn
+        .prepare_cached(&tables.sql("select count(*) from item_data"))?
+        .query_row([], |r| r.get(0))?;
+    Ok(count as usize)
 }
 
 impl TableMapDb {
     /// Tries to open the database file, the setting being used might corrupt the database
     /// so remove the file, IF the database seems corrupt. This will also create the required
     /// tables if they do not exist.
-    /// If the tables exist, it will clear the data
-    pub fn new(db_file: PathBuf) -> Self {
+    /// If the tables exist, it will clear the data.
+    /// Fails if the file can not be removed, or the db created
+    pub fn new(db_file: PathBuf) -> Result<Self, DataToolErrors> {
         if db_file.exists() {
             warn!("Removing db file: {:?}", db_file);
-            fs::remove_file(&db_file).unwrap();
-        }
-        let mut connection = Connection::open(&db_file).unwrap();
-        if let Err(e) = connection.execute_batch(KEY_TABLE) {
-            panic!("{:?} {}", db_file, e);
+            fs::remove_file(&db_file)?;
         }
+        let connection = open_connection(&db_file, OpenFlags::default())?;
+        connection.execute_batch(&format!("{}{}{}", KEY_TABLE, MAP_TABLES, MAP_INDEXES))?;
         info!("all good, db is ready");
+        Ok(Self::with_connection(db_file, connection))
+    }
+
+    /// Opens a db written by an earlier run, keeping its items, e.g. to export them again or
+    /// add more. The tables are created if missing, and the ones of an earlier version get
+    /// the columns added since, e.g. `created_at`, without a time for the items they have
+    pub fn open_existing(db_file: PathBuf) -> Result<Self, DataToolErrors> {
+        Self::open_existing_with(db_file, OpenFlags::default())
+    }
+
+    /// Same as [`TableMapDb::open_existing`], opening the connection as `mode` says, e.g.
+    /// read-only and immutable to export a sealed snapshot, see [`OpenMode`]. A read-only
+    /// db must have the tables of this version already
+    pub fn open_existing_with(
+        db_file: PathBuf,
+        mode: impl Into<OpenMode>,
+    ) -> Result<Self, DataToolErrors> {
+        if !db_file.exists() {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "no database {:?}",
+                db_file
+            )));
+        }
+        let connection = open_connection(&db_file, mode)?;
+        connection.execute_batch(&format!("{}{}", KEY_TABLE, MAP_TABLES))?;
+        let mut db = Self::with_connection(db_file, connection);
+        migrate(&db.connection, &db.tables)?;
+        db.refresh_columns()?;
+        Ok(db)
+    }
+
+    /// A handle on a db whose tables exist, with the default settings
+    fn with_connection(db_file: PathBuf, connection: Connection) -> Self {
         Self {
             db_file,
             connection,
             columns: Default::default(),
             current_id: None,
             current_row_iter: None,
+            iter_order: IterOrder::default(),
+            normalizers: vec![],
+            validation: Default::default(),
+            index_policy: IndexPolicy::default(),
+            tables: Tables::default(),
+            maps: HashMap::new(),
+            observer: None,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            statement_cache_capacity: DEFAULT_STATEMENT_CACHE_CAPACITY,
+            text_policy: TextPolicy::default(),
+        }
+    }
+
+    /// How long the connections wait for a lock held by another connection, e.g. a
+    /// [`TableMapDb::read_only_conn`] reading while this one writes, before failing with
+    /// `database is locked`. Used by this connection, the ones opened by
+    /// [`TableMapDb::read_only_conn`] and the readers of the exports, see
+    /// [`ExportOptions::busy_timeout`]. Defaults to 5 seconds
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        if let Err(e) = self.connection.busy_timeout(timeout) {
+            warn!("Failed to set the busy timeout: {}", e);
+        }
+        self
+    }
+
+    /// How many prepared statements each connection keeps for reuse, e.g. to stop the
+    /// statements the crate caches being evicted when running many distinct queries on
+    /// [`TableMapDb::connection`]. Used by this connection, the ones opened by
+    /// [`TableMapDb::read_only_conn`], the readers of the exports and of
+    /// [`shared::SharedTableMapDb`]. Defaults to 16, 0 disables the cache
+    pub fn statement_cache_capacity(mut self, capacity: usize) -> Self {
+        self.statement_cache_capacity = capacity;
+        self.connection
+            .set_prepared_statement_cache_capacity(capacity);
+        self
+    }
+
+    /// The capacity set by [`TableMapDb::statement_cache_capacity`]
+    pub fn get_statement_cache_capacity(&self) -> usize {
+        self.statement_cache_capacity
+    }
+
+    /// How the keys and values read back that are not valid UTF-8 are handled, e.g. ones
+    /// copied in from another db through [`TableMapDb::connection`]. Used by the row
+    /// iterators, the lookups of an item, the exports and the lookups of
+    /// [`shared::SharedTableMapDb`]. Defaults to [`TextPolicy::Strict`], the row iterators
+    /// then yield an error for the rows that are not valid UTF-8, and go on with the next
+    pub fn text_policy(mut self, policy: TextPolicy) -> Self {
+        self.text_policy = policy;
+        self
+    }
+
+    /// The policy set by [`TableMapDb::text_policy`]
+    pub fn get_text_policy(&self) -> TextPolicy {
+        self.text_policy
+    }
+
+    /// Sets the order used when iterating over the rows
+    pub fn set_iter_order(&mut self, order: IterOrder) {
+        self.iter_order = order;
+        self.current_row_iter = None;
+    }
+
+    pub fn iter_order(&self) -> &IterOrder {
+        &self.iter_order
+    }
+
+    /// Returns `limit` rows starting at `offset`, in the configured order.
+    /// An offset past the last item gives an empty Vec
+    pub fn items_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<IndexMap<String, String>>, DataToolErrors> {
+        let ids = self
+            .iter_order
+            .item_ids_page(&self.connection, &self.tables, offset, limit)?;
+        self.rows_with_id(&ids)
+    }
+
+    /// The `limit` items with the highest, or with `desc` unset the lowest, value under
+    /// `key`, compared as numbers if `numeric` is set, with the same `id` as
+    /// [`TableMapDb::items_page`]. Items with the same value are in insertion order, items
+    /// without the key are left out. If the key was inserted more than once for an item,
+    /// the last value wins.
+    ///
+    /// The items are sorted by SQLite, only the ones returned are read
+    pub fn items_sorted_by(
+        &self,
+        key: &str,
+        numeric: bool,
+        desc: bool,
+        limit: usize,
+    ) -> Result<Vec<IndexMap<String, String>>, DataToolErrors> {
+        self.ensure_read_indexes()?;
+        let sort_val = if numeric {
+            "cast(value as real)"
+        } else {
+            "value"
+        };
+        let q = format!(
+            "select item_id from data_columns where id in \
+             (select max(id) from data_columns where key = ?1 group by item_id) \
+             order by {} {}, item_id limit ?2",
+            sort_val,
+            if desc { "desc" } else { "asc" }
+        );
+        let ids = query_ids(&self.connection, &self.sql(&q), params![key, limit as i64])?;
+        self.rows_with_id(&ids)
+    }
+
+    /// The rows of the items, in the order of `ids`, each with the item id under `id`
+    fn rows_with_id(&self, ids: &[i64]) -> Result<Vec<IndexMap<String, String>>, DataToolErrors> {
+        let mut items = read_items(
+            &self.connection,
+            &self.tables,
+            ids,
+            None,
+            None,
+            self.text_policy,
+        )?;
+        Ok(ids
+            .iter()
+            .map(|id| {
+                let mut im = IndexMap::new();
+                im.insert("id".to_string(), id.to_string());
+                if let Some(cols) = items.swap_remove(id) {
+                    im.extend(cols);
+                }
+                im
+            })
+            .collect())
+    }
+
+    /// Iterate over the items stored when it is called, oldest first, each row with the item
+    /// id under `id`. Items and cells stored afterwards, e.g. by another connection while
+    /// iterating, are never read, so the rows are the same however much is inserted meanwhile.
+    ///
+    /// Cells changed in place or deleted afterwards are read as they are then. An item being
+    /// inserted when the iterator is created may be read with only some of its cells, unless
+    /// it is inserted in a transaction, see [`TableMapDb::begin`]
+    pub fn snapshot_rows(&self) -> Result<SnapshotIter<'_>, DataToolErrors> {
+        self.read_indexes_or_warn();
+        Ok(SnapshotIter {
+            db: self,
+            snapshot: self.snapshot_bounds()?,
+            last: 0,
+            rows: VecDeque::new(),
+        })
+    }
+
+    /// Ids of the last item and the last cell stored
+    pub(crate) fn snapshot_bounds(&self) -> rusqlite::Result<Snapshot> {
+        // a single statement, so both are read from the same state of the db
+        let mut stmt = self.connection.prepare_cached(&self.sql(
+            "select (select coalesce(max(id), 0) from item_data), \
+             (select coalesce(max(id), 0) from data_columns)",
+        ))?;
+        stmt.query_row([], |r| {
+            Ok(Snapshot {
+                max_item: r.get(0)?,
+                max_cell: r.get(1)?,
+            })
+        })
+    }
+
+    /// Iterate over all the rows, in the configured order. Fails if the items can not be
+    /// counted, each row being an error of its own if it can not be read
+    pub fn rows(&self) -> Result<Rows<'_>, DataToolErrors> {
+        self.read_indexes_or_warn();
+        Ok(Rows {
+            db: self,
+            cursor: RowCursor::new(self)?,
+        })
+    }
+
+    /// Number of rows the iterator has yet to yield, all the items if iteration has not started
+    pub fn len_remaining(&self) -> Result<usize, DataToolErrors> {
+        match &self.current_row_iter {
+            Some(cursor) => Ok(cursor.remaining),
+            None => count_items(&self.connection, &self.tables).map_err(DataToolErrors::from),
         }
     }
 
-    /// count the total number of items in the `item_data` table
-    pub fn how_many_items(&mut self) -> Result<usize, DataToolErrors> {
+    /// Iterate over the rows that satisfy the predicate, in the configured order. The rows
+    /// that can not be read are errors, the predicate is not called for them
+    pub fn iter_filtered<'a, F>(
+        &'a self,
+        mut f: F,
+    ) -> Result<
+        impl Iterator<Item = Result<IndexMap<String, String>, DataToolErrors>> + 'a,
+        DataToolErrors,
+    >
+    where
+        F: FnMut(&IndexMap<String, String>) -> bool + 'a,
+    {
+        Ok(self.rows()?.filter(move |row| match row {
+            Ok(row) => f(row),
+            Err(_) => true,
+        }))
+    }
+
+    /// Number of items with an id up to `max_item`
+    pub(crate) fn items_up_to(&self, max_item: i64) -> Result<usize, DataToolErrors> {
         let mut stmt = self
             .connection
-            .prepare_cached("select count(item_val) from item_data")
-            .unwrap();
-        stmt.query_row([], |r| Ok(r.get(0)?))
-            .map_err(|e| DataToolErrors::GenericError(e.to_string()))
+            .prepare_cached(&self.sql("select count(*) from item_data where id <= ?1"))?;
+        stmt.query_row([max_item], |r| r.get(0))
+            .map_err(DataToolErrors::from)
+    }
+
+    /// count the total number of items in the `item_data` table, with or without an
+    /// `item_val`
+    pub fn how_many_items(&self) -> Result<usize, DataToolErrors> {
+        count_items(&self.connection, &self.tables).map_err(DataToolErrors::from)
+    }
+
+    /// Approximate number of items, the highest item id, read without counting them, e.g. to
+    /// report the progress of an ingestion every second on a large db. Exact as long as no
+    /// item was deleted, it then counts the deleted ones too, unless they were the last ones
+    /// added. Use [`TableMapDb::how_many_items`] for the exact count
+    pub fn how_many_items_fast(&self) -> Result<usize, DataToolErrors> {
+        max_item_id(&self.connection, &self.tables).map_err(DataToolErrors::from)
+    }
+
+    /// Ids of the items created at or after `epoch_ms`, in unix epoch milliseconds, in
+    /// ascending order, e.g. the ones added since the last export. Items from before the db
+    /// stored a creation time are never listed
+    pub fn items_since(&self, epoch_ms: i64) -> Result<Vec<i64>, DataToolErrors> {
+        let mut stmt = self.connection.prepare_cached(
+            &self.sql("select id from item_data where created_at >= ?1 order by id"),
+        )?;
+        let ids = stmt
+            .query_map([epoch_ms], |r| r.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(DataToolErrors::from);
+        ids
+    }
+
+    /// The highest revision of the items, 0 without items. Each change to the cells of an
+    /// item, or its creation, gives it a revision higher than all the others, to export only
+    /// the items changed since an earlier export, see [`ExportOptions::changed_since`]
+    pub fn max_revision(&self) -> Result<i64, DataToolErrors> {
+        let mut stmt = self
+            .connection
+            .prepare_cached(&self.sql("select coalesce(max(revision), 0) from item_data"))?;
+        stmt.query_row([], |r| r.get(0))
+            .map_err(DataToolErrors::from)
+    }
+
+    /// Ids of the items changed after `revision`, see [`TableMapDb::max_revision`], in
+    /// ascending order
+    pub fn items_changed_since(&self, revision: i64) -> Result<Vec<i64>, DataToolErrors> {
+        let mut stmt = self.connection.prepare_cached(
+            &self.sql("select id from item_data where revision > ?1 order by id"),
+        )?;
+        let ids = stmt
+            .query_map([revision], |r| r.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(DataToolErrors::from);
+        ids
     }
 
     pub fn db_file(&self) -> PathBuf {
         self.db_file.clone()
     }
 
-    pub fn read_only_conn(&self) -> Connection {
-        Connection::open_with_flags(&self.db_file, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap()
+    /// Interrupts the statement running on [`TableMapDb::connection`] when called, from
+    /// any thread, e.g. a `^C` handler stopping a long [`TableMapDb::get_distinct_keys`].
+    /// The call running it then fails with [`DataToolErrors::Cancelled`], the statements
+    /// started afterwards run as usual. The connections of the exports are interrupted by
+    /// their [`ExportOptions::cancel_token`] instead
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.connection.get_interrupt_handle()
+    }
+
+    pub fn read_only_conn(&self) -> Result<Connection, DataToolErrors> {
+        self.read_only_conn_with(OpenFlags::SQLITE_OPEN_READ_ONLY)
+    }
+
+    /// Same as [`TableMapDb::read_only_conn`], opened as `mode` says, e.g. immutable, see
+    /// [`OpenMode`]. The connection is only read-only if the flags say so
+    pub fn read_only_conn_with(
+        &self,
+        mode: impl Into<OpenMode>,
+    ) -> Result<Connection, DataToolErrors> {
+        let conn = open_connection(&self.db_file, mode)?;
+        conn.busy_timeout(self.busy_timeout)?;
+        conn.set_prepared_statement_cache_capacity(self.statement_cache_capacity);
+        Ok(conn)
     }
 
-    pub fn item_ids(&self) -> Vec<i64> {
+    pub fn item_ids(&self) -> Result<Vec<i64>, DataToolErrors> {
         let mut stmt = self
             .connection
-            .prepare_cached("select id from item_data")
-            .unwrap();
-        stmt.query_map([], |r| Ok(r.get(0)?))
-            .unwrap()
-            .map(|v| v.unwrap())
-            .collect()
+            .prepare_cached(&self.sql("select id from item_data"))?;
+        let ids = stmt
+            .query_map([], |r| r.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(DataToolErrors::from);
+        ids
+    }
+
+    /// The `item_val` of every item, in insertion order. Items without one, which are only
+    /// found in dbs written by other tools, are left out
+    pub fn item_vals(&self) -> Result<Vec<String>, DataToolErrors> {
+        let mut stmt = self.connection.prepare_cached(
+            &self.sql("select item_val from item_data where item_val is not null order by id"),
+        )?;
+        let vals = stmt
+            .query_map([], |r| r.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(DataToolErrors::from);
+        vals
     }
 
-    pub fn next_row(&mut self, d: &str) -> rusqlite::Result<()> {
-        if let Err(_) = self
+    /// The id, `item_val` and number of keys of every item, in insertion order, e.g. to find
+    /// the items a scrape only partly filled. Items without any key are included, with 0.
+    /// A key inserted more than once for an item counts once, the `item_val` is empty for
+    /// the items stored without one
+    pub fn cells_per_item(&self) -> Result<Vec<(i64, String, usize)>, DataToolErrors> {
+        self.ensure_read_indexes()?;
+        let mut stmt = self.connection.prepare_cached(&self.sql(
+            "select i.id, coalesce(i.item_val, ''), count(distinct d.key) from item_data i \
+             left join data_columns d on d.item_id = i.id group by i.id order by i.id",
+        ))?;
+        let counts = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(DataToolErrors::from);
+        counts
+    }
+
+    /// Creates the item (or finds it, if it exists) and makes it the current item.
+    /// Returns the item id
+    pub fn next_row(&mut self, d: &str) -> Result<i64, DataToolErrors> {
+        let inserted = self
             .connection
-            .execute("insert into item_data (item_val) values(?1)", [d])
-        {
-            // maybe it exists in the db already, find it
-            let mut stmt = self
+            .prepare_cached(&self.sql(NEW_ITEM))
+            .and_then(|mut stmt| stmt.execute(params![d, now_ms()]));
+        let id = match inserted {
+            Ok(_) => {
+                let id = self.connection.last_insert_rowid();
+                self.notify(|| ChangeEvent::ItemCreated {
+                    id,
+                    item_val: d.to_string(),
+                });
+                id
+            }
+            // it exists in the db already, find it
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+            {
+                self.connection
+                    .prepare_cached(&self.sql("select id from item_data where item_val = ?1"))
+                    .and_then(|mut stmt| stmt.query_row([d], |row| row.get(0)))?
+            }
+            Err(e) => {
+                error!("Failed to get next row: {}", e);
+                return Err(e.into());
+            }
+        };
+        self.current_id = Some(id);
+        Ok(id)
+    }
+
+    /// Creates an item with a generated `item_val`, a random UUID v4, and makes it the
+    /// current item, for the data without a natural key. Returns the item id.
+    ///
+    /// Unlike [`TableMapDb::next_row`], an existing item is never returned, a new value is
+    /// generated in the unlikely case one is taken. The value is stored and exported as any
+    /// other `item_val`
+    pub fn next_row_auto(&mut self) -> Result<i64, DataToolErrors> {
+        loop {
+            let item_val = auto_item_val();
+            let inserted = self
                 .connection
-                .prepare_cached("select id from item_data where item_val = ?1")
-                .unwrap();
-            self.current_id = match stmt.query_row([d], |row| row.get(0)) {
-                Ok(v) => Some(v),
+                .prepare_cached(&self.sql(NEW_ITEM))
+                .and_then(|mut stmt| stmt.execute(params![item_val, now_ms()]));
+            match inserted {
+                Ok(_) => {
+                    let id = self.connection.last_insert_rowid();
+                    self.notify(|| ChangeEvent::ItemCreated { id, item_val });
+                    self.current_id = Some(id);
+                    return Ok(id);
+                }
+                Err(rusqlite::Error::SqliteFailure(e, _))
+                    if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+                {
+                    warn!(
+                        "Generated item_val {:?} is taken, generating another",
+                        item_val
+                    );
+                }
                 Err(e) => {
-                    error!("Failed to get next row");
-                    return Err(e);
+                    error!("Failed to get next row: {}", e);
+                    return Err(e.into());
                 }
-            };
-        } else {
-            self.current_id = Some(self.connection.last_insert_rowid());
+            }
         }
-        Ok(())
     }
 
-    pub fn insert_batched(
-        &mut self,
-        index_map: &IndexMap<String, String>,
-    ) -> Result<(), DataToolErrors> {
-        if self.current_id.is_none() {
-            return Err(DataToolErrors::GenericError("No Item is set".to_string()));
+    /// [`TableMapDb::next_row`] if the record has a key, [`TableMapDb::next_row_auto`]
+    /// otherwise, for the pipelines where only some records have one
+    pub fn next_row_or_auto(&mut self, item_val: Option<&str>) -> Result<i64, DataToolErrors> {
+        match item_val {
+            Some(item_val) => self.next_row(item_val),
+            None => self.next_row_auto(),
         }
-        let mut stmt = match self
+    }
+
+    /// Makes the item the current one, to add more columns to it
+    pub fn set_current_item(&mut self, id: i64) -> Result<(), DataToolErrors> {
+        self.item_val_of(id)?;
+        self.current_id = Some(id);
+        Ok(())
+    }
+
+    /// Same as [`TableMapDb::set_current_item`], with the item's `item_val`
+    pub fn set_current_item_by_val(&mut self, item_val: &str) -> Result<(), DataToolErrors> {
+        let id = self
             .connection
-            .prepare_cached("insert into data_columns (key, value, item_id) values(?1, ?2, ?3)")
-        {
-            Ok(s) => s,
-            Err(e) => {
-                error!("Failed to create stmt: {}", e);
-                return Err(DataToolErrors::GenericError(
-                    "Failed to create statement".to_string(),
-                ));
+            .prepare_cached(&self.sql("select id from item_data where item_val = ?1"))
+            .and_then(|mut stmt| stmt.query_row([item_val], |r| r.get(0)).optional())?;
+        match id {
+            Some(id) => {
+                self.current_id = Some(id);
+                Ok(())
             }
-        };
-        index_map.iter().for_each(|(k, v)| {
-            if let Err(e) = stmt.execute([
-                k.clone(),
-                v.clone(),
-                self.current_id.clone().unwrap().to_string(),
-            ]) {
-                error!("Error occurred: {}", e)
+            None => Err(DataToolErrors::InvalidArgument(format!(
+                "no item {:?}",
+                item_val
+            ))),
+        }
+    }
+
+    /// Changes the `item_val` of the item, e.g. once a URL is known to redirect to the
+    /// canonical one, keeping its id and cells. Fails with [`DataToolErrors::ItemValTaken`]
+    /// if another item has `new_val`, see [`TableMapDb::merge_into`] to merge them instead.
+    ///
+    /// The item's revision is bumped, as its exported rows change
+    pub fn update_item_val(&mut self, id: i64, new_val: &str) -> Result<(), DataToolErrors> {
+        let old_item_val = self.item_val_of(id)?;
+        if old_item_val.as_deref() == Some(new_val) {
+            return Ok(());
+        }
+        let updated = self
+            .connection
+            .prepare_cached(&self.sql("update item_data set item_val = ?1 where id = ?2"))
+            .and_then(|mut stmt| stmt.execute(params![new_val, id]));
+        match updated {
+            Ok(_) => {}
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+            {
+                let other = self
+                    .connection
+                    .prepare_cached(&self.sql("select id from item_data where item_val = ?1"))
+                    .and_then(|mut stmt| stmt.query_row([new_val], |r| r.get(0)))?;
+                return Err(DataToolErrors::ItemValTaken {
+                    item_val: new_val.to_string(),
+                    id: other,
+                });
             }
+            Err(e) => return Err(e.into()),
+        }
+        self.connection
+            .prepare_cached(&self.sql(BUMP_REVISION))?
+            .execute([id])?;
+        self.notify(|| ChangeEvent::ItemValChanged {
+            id,
+            old_item_val,
+            item_val: new_val.to_string(),
         });
         Ok(())
     }
 
-    pub fn insert(&mut self, column: &str, val: &str) -> Result<(), String> {
+    /// The id and `item_val` of the current item, if any
+    pub fn current_item(&self) -> Option<(i64, String)> {
+        let id = self.current_id?;
+        let item_val = self.item_val_of(id).ok()?;
+        Some((id, item_val.unwrap_or_default()))
+    }
+
+    /// Stores the column for the item, whatever the current item is
+    pub fn insert_for(
+        &mut self,
+        item_id: i64,
+        column: &str,
+        val: &str,
+    ) -> Result<(), DataToolErrors> {
+        self.item_val_of(item_id)?;
+        let Some(val) = self.prepare_cell(column, val)? else {
+            return Ok(());
+        };
+        self.store_cells_for(item_id, &[(column, val)])
+    }
+
+    /// The `item_val` of the item, fails if there is no such item
+    fn item_val_of(&self, id: i64) -> Result<Option<String>, DataToolErrors> {
+        let item_val = self
+            .connection
+            .prepare_cached(&self.sql("select item_val from item_data where id = ?1"))
+            .and_then(|mut stmt| stmt.query_row([id], |r| r.get(0)).optional())?;
+        item_val.ok_or(DataToolErrors::ItemNotFound(id))
+    }
+
+    /// Stores the columns for the current item, from any map or list of pairs, e.g. an
+    /// `&IndexMap<String, String>` or a `Vec<(&str, &str)>`. If one of them fails the
+    /// validation, none is stored
+    pub fn insert_batched<I, K, V>(&mut self, columns: I) -> Result<(), DataToolErrors>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
         if self.current_id.is_none() {
-            return Err("No item is set".to_string());
+            return Err(DataToolErrors::NoCurrentItem);
+        }
+        let pairs: Vec<(K, V)> = columns.into_iter().collect();
+        let cells = self.prepare_cells(&pairs)?;
+        self.store_cells(cells)
+    }
+
+    /// The columns to store, normalized and checked
+    fn prepare_cells<'a, K, V>(
+        &self,
+        pairs: &'a [(K, V)],
+    ) -> Result<Vec<(&'a str, Cow<'a, str>)>, DataToolErrors>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let mut cells = Vec::with_capacity(pairs.len());
+        for (k, v) in pairs {
+            if let Some(v) = self.prepare_cell(k.as_ref(), v.as_ref())? {
+                cells.push((k.as_ref(), v));
+            }
+        }
+        Ok(cells)
+    }
+
+    fn store_cells(&mut self, cells: Vec<(&str, Cow<str>)>) -> Result<(), DataToolErrors> {
+        let id = self.current_id.ok_or(DataToolErrors::NoCurrentItem)?;
+        self.store_cells_for(id, &cells)
+    }
+
+    /// Stores the cells of the item, every write of cells goes through it
+    pub(crate) fn store_cells_for(
+        &mut self,
+        item_id: i64,
+        cells: &[(&str, Cow<str>)],
+    ) -> Result<(), DataToolErrors> {
+        insert_cells(&self.connection, &self.tables, item_id, cells)?;
+        for (k, _) in cells {
+            self.add_column(k);
+            self.notify(|| ChangeEvent::CellInserted {
+                item_id,
+                key: k.to_string(),
+            });
         }
-        self.connection
-            .execute(
-                "insert into data_columns (key, value, item_id) values(?1, ?2, ?3)",
-                [column, val, &self.current_id.clone().unwrap().to_string()],
-            )
-            .map_err(|v| v.to_string())?;
         Ok(())
     }
 
-    pub fn get_distinct_keys(
+    /// Stores the column for the current item
+    pub fn insert<K, V>(&mut self, column: K, val: V) -> Result<(), DataToolErrors>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let (column, val) = (column.as_ref(), val.as_ref());
+        let id = self.current_id.ok_or(DataToolErrors::NoCurrentItem)?;
+        let Some(val) = self.prepare_cell(column, val)? else {
+            return Ok(());
+        };
+        self.store_cells_for(id, &[(column, val)])
+    }
+
+    /// Same as [`TableMapDb::insert`], for any value that can be displayed, e.g. a number,
+    /// stored as it is displayed
+    pub fn insert_display<V: fmt::Display>(
         &mut self,
-        mut priority_cols: Vec<String>,
-    ) -> Result<Vec<String>, DataToolErrors> {
+        column: &str,
+        val: V,
+    ) -> Result<(), DataToolErrors> {
+        self.insert(column, val.to_string())
+    }
+
+    /// Creates the item (or finds it, if it exists), makes it the current item,
+    /// and stores all the columns for it. Returns the item id.
+    /// If one of the columns fails the validation, the item is not created
+    pub fn add_row<I, K, V>(&mut self, item_val: &str, columns: I) -> Result<i64, DataToolErrors>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let pairs: Vec<(K, V)> = columns.into_iter().collect();
+        let cells = self.prepare_cells(&pairs)?;
+        let id = self.next_row(item_val)?;
+        self.store_cells(cells)?;
+        Ok(id)
+    }
+
+    /// Value stored under `key` for the item, if any
+    pub fn get_value(&self, item_val: &str, key: &str) -> Result<Option<String>, DataToolErrors> {
+        get_value(
+            &self.connection,
+            &self.tables,
+            item_val,
+            key,
+            self.text_policy,
+        )
+        .map_err(DataToolErrors::from)
+    }
+
+    /// All the stored columns of the item, same as the rows returned by the iterators
+    pub fn get_item(
+        &self,
+        item_val: &str,
+    ) -> Result<Option<IndexMap<String, String>>, DataToolErrors> {
         let mut stmt = self
             .connection
-            .prepare_cached("select distinct key from data_columns")
-            .unwrap();
-        let x: Vec<_> = stmt
-            .query_map([], |row| Ok(ColumnDef(row.get(0)?)))
-            .map_err(|v| DataToolErrors::GenericError(v.to_string()))?
-            .filter_map(|v| {
-                let k = v.unwrap().0;
-                if priority_cols.contains(&k) {
-                    return None;
-                }
-                Some(k)
+            .prepare_cached(&self.sql("select id from item_data where item_val = ?1"))?;
+        match stmt.query_row([item_val], |r| r.get(0)) {
+            Ok(id) => Ok(Some(read_row(
+                &self.connection,
+                &self.tables,
+                id,
+                self.text_policy,
+            )?)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The values of the item under `columns`, in that order, empty for the keys it does not
+    /// have, the same as the cells the CSV exports write for them. If a key was inserted more
+    /// than once for the item, the last value wins
+    pub fn row_as_record(
+        &self,
+        item_id: i64,
+        columns: &[String],
+    ) -> Result<Vec<String>, DataToolErrors> {
+        let mut records = self.rows_as_records(&[item_id], columns)?;
+        Ok(records.remove(0))
+    }
+
+    /// Same as [`TableMapDb::row_as_record`] for many items, read with a single query, in the
+    /// order of `item_ids`. Fails with [`DataToolErrors::ItemNotFound`] if one of them is
+    /// not stored
+    pub fn rows_as_records(
+        &self,
+        item_ids: &[i64],
+        columns: &[String],
+    ) -> Result<Vec<Vec<String>>, DataToolErrors> {
+        let found = existing_ids(&self.connection, &self.tables, item_ids)?;
+        if let Some(id) = item_ids.iter().find(|id| !found.contains(id)) {
+            return Err(DataToolErrors::ItemNotFound(*id));
+        }
+        let items = read_items(
+            &self.connection,
+            &self.tables,
+            item_ids,
+            Some(columns),
+            None,
+            self.text_policy,
+        )?;
+        Ok(item_ids
+            .iter()
+            .map(|id| {
+                let item = items.get(id);
+                columns
+                    .iter()
+                    .map(|c| item.and_then(|im| im.get(c).cloned()).unwrap_or_default())
+                    .collect()
             })
+            .collect())
+    }
+
+    /// Ids of the items having `value` stored under `key`
+    pub fn find_items(&self, key: &str, value: &str) -> Result<Vec<i64>, DataToolErrors> {
+        find_items(&self.connection, &self.tables, key, value).map_err(DataToolErrors::from)
+    }
+
+    /// Keys starting with `prefix`, e.g. the `C/` namespace, in the order they were first
+    /// inserted. The prefix is matched as is, case included
+    pub fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, DataToolErrors> {
+        Ok(self
+            .columns
+            .iter()
+            .filter(|k| k.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    /// Number of items having each key, the most frequent first, keys as frequent in the
+    /// order they were first inserted
+    pub fn key_counts(&self) -> Result<Vec<(String, usize)>, DataToolErrors> {
+        let mut stmt = self.connection.prepare_cached(&self.sql(
+            "select key, count(distinct item_id) from data_columns \
+                 group by key order by 2 desc, min(id)",
+        ))?;
+        let counts = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get::<_, i64>(1)? as usize)))
+            .and_then(|rows| rows.collect())
+            .map_err(DataToolErrors::from);
+        counts
+    }
+
+    /// The `priority_cols` in the given order, each once, then the other stored keys in the
+    /// order they were first inserted. Served from the keys known to the handle, see
+    /// [`TableMapDb::columns`]. Fails with [`DataToolErrors::UnknownColumns`] if some of the
+    /// `priority_cols` are not stored keys
+    pub fn get_distinct_keys(
+        &self,
+        priority_cols: Vec<String>,
+    ) -> Result<Vec<String>, DataToolErrors> {
+        self.get_distinct_keys_by(priority_cols, &KeyOrder::FirstSeen)
+    }
+
+    /// Same as [`TableMapDb::get_distinct_keys`], the other keys being in `order`
+    pub fn get_distinct_keys_by(
+        &self,
+        priority_cols: Vec<String>,
+        order: &KeyOrder,
+    ) -> Result<Vec<String>, DataToolErrors> {
+        let unknown = self.unknown_keys(&priority_cols);
+        if !unknown.is_empty() {
+            return Err(DataToolErrors::UnknownColumns(unknown));
+        }
+        self.order_keys(priority_cols, self.columns(), order)
+    }
+
+    /// The `priority_cols` that are not stored keys, each once
+    pub(crate) fn unknown_keys(&self, priority_cols: &[String]) -> Vec<String> {
+        priority_cols
+            .iter()
+            .filter(|c| !self.columns.contains(*c))
+            .cloned()
+            .collect::<IndexSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// The `priority_cols`, without repeats, then the other `keys` in `order`
+    pub(crate) fn order_keys(
+        &self,
+        priority_cols: Vec<String>,
+        mut keys: Vec<String>,
+        order: &KeyOrder,
+    ) -> Result<Vec<String>, DataToolErrors> {
+        // a repeated priority column keeps its first place
+        let mut priority_cols: Vec<String> = priority_cols
+            .into_iter()
+            .collect::<IndexSet<_>>()
+            .into_iter()
             .collect();
-        priority_cols.extend(x);
+        keys.retain(|k| !priority_cols.contains(k));
+        match order {
+            KeyOrder::FirstSeen => {}
+            KeyOrder::Alphabetical => keys.sort(),
+            KeyOrder::ByFrequencyDesc => {
+                let rank: HashMap<String, usize> = self
+                    .key_counts()?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (k, _))| (k, i))
+                    .collect();
+                keys.sort_by_key(|k| rank.get(k).copied().unwrap_or(usize::MAX));
+            }
+            KeyOrder::Custom(listed) => {
+                // the listed keys first, the others after them, in the order they were
+                // first inserted
+                keys.sort_by_key(|k| listed.iter().position(|l| l == k).unwrap_or(usize::MAX));
+            }
+        }
+        priority_cols.extend(keys);
         Ok(priority_cols)
     }
+
+    /// Every stored key, in the order they were first inserted. Kept up to date by the
+    /// insert paths of the handle, read from the db when it is opened, merged into, or
+    /// deduped. Keys written with SQL through [`TableMapDb::connection`] are only known
+    /// after [`TableMapDb::refresh_columns`]
+    pub fn columns(&self) -> Vec<String> {
+        self.columns.iter().cloned().collect()
+    }
+
+    /// Reads the stored keys from the db again, replacing the ones known to the handle
+    pub fn refresh_columns(&mut self) -> Result<(), DataToolErrors> {
+        let mut stmt = self.connection.prepare_cached(
+            &self.sql("select key from data_columns group by key order by min(id)"),
+        )?;
+        let keys = stmt
+            .query_map([], |row| Ok(ColumnDef(row.get(0)?)))?
+            .map(|c| c.map(|c| c.0))
+            .collect::<rusqlite::Result<IndexSet<_>>>()?;
+        drop(stmt);
+        if keys != self.columns {
+            info!(
+                "{} keys stored, {} were known",
+                keys.len(),
+                self.columns.len()
+            );
+        }
+        self.columns = keys;
+        Ok(())
+    }
+
+    /// Creates the read indexes before iterating, iterating without them is only slower
+    fn read_indexes_or_warn(&self) {
+        if let Err(e) = self.ensure_read_indexes() {
+            warn!("Failed to create the read indexes: {}", e);
+        }
+    }
+
+    /// Notes that `key` is stored
+    pub(crate) fn add_column(&mut self, key: &str) {
+        if !self.columns.contains(key) {
+            self.columns.insert(key.to_string());
+        }
+    }
 }
 
 pub struct KeyValPair {
@@ -220,201 +1722,127 @@ pub struct KeyValPair {
 }
 
 impl Iterator for TableMapDb {
-    type Item = IndexMap<String, String>;
+    type Item = Result<IndexMap<String, String>, DataToolErrors>;
 
+    /// An error if the iteration can not start, the next call trying again, or if a row can
+    /// not be read
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current_row_iter.is_none() {
-            let mut stmt = self
-                .connection
-                .prepare_cached("select id, item_val from item_data order by id desc")
-                .unwrap();
-            let nn: Vec<i64> = stmt
-                .query_map([], |r| Ok(r.get(0)?))
-                .unwrap()
-                .map(|v| v.unwrap())
-                .collect();
-            self.current_row_iter = Some(nn);
-        }
-
-        if let Some(n) = self.current_row_iter.as_mut().unwrap().pop() {
-            let mut inner_stmt = self
-                .connection
-                .prepare_cached("select key, value from data_columns where item_id = ?1")
-                .unwrap();
-            let rows = inner_stmt
-                .query_map([n], |r| {
-                    Ok(KeyValPair {
-                        key: r.get(0).unwrap(),
-                        value: r.get(1).unwrap(),
-                    })
-                })
-                .unwrap();
-            let mut im = IndexMap::new();
-            im.insert("id".to_string(), n.to_string());
-            for row in rows {
-                let r = row.unwrap();
-                im.insert(r.key.clone(), r.value.clone());
-            }
-            return Some(im);
-        }
-        None
-    }
-}
-
-pub async fn dump_csv(
-    mut db: &mut TableMapDb,
-    file_name: &Path,
-    chunk_size: usize,
-    column_order: Vec<String>,
-) -> Result<(), DataToolErrors> {
-    if file_name.exists() {
-        warn!("Deleting file: {:?}", file_name);
-        fs::remove_file(file_name).unwrap();
-    }
-    let mut csv_writer = csv::Writer::from_path(&file_name)?;
-    let columns = db.get_distinct_keys(column_order)?;
-    let all_ids = db.item_ids();
-    let ids_count = all_ids.chunks(chunk_size);
-    // creating def for creating table
-    csv_writer.write_record(&columns).unwrap();
-    // creating def for data insertion
-    let dbf = db.db_file();
-    let nn = ids_count.len();
-    let mut cols = proc_ids(dbf, ids_count, nn, columns.clone());
-    info!("processing done");
-    while let Some(c) = cols.join_next().await {
-        let n = c.unwrap();
-        for row in n.iter() {
-            if let Err(e) = csv_writer.write_record(row) {
-                error!("Failed to store data: {}", e);
+        let cursor = match &mut self.current_row_iter {
+            Some(cursor) => cursor,
+            None => {
+                self.read_indexes_or_warn();
+                match RowCursor::new(self) {
+                    Ok(cursor) => self.current_row_iter.insert(cursor),
+                    Err(e) => return Some(Err(e.into())),
+                }
             }
+        };
+        cursor.next_row(&self.connection)
+    }
+
+    /// The rows of the page read are yielded whatever happens, the items counted when the
+    /// iteration started at most, as items can be deleted between the calls
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.current_row_iter {
+            Some(cursor) => (cursor.ids.len(), Some(cursor.remaining)),
+            None => (0, None),
         }
     }
-    info!("Done!");
-    Ok(())
 }
 
-/// export the data in a CSV file.
-pub async fn dump_db(
-    mut tmd: &mut TableMapDb,
-    file_name: &Path,
-    chunk_size: usize,
-    priority_cols: Vec<String>,
-) -> Result<(), DataToolErrors> {
-    if file_name.exists() {
-        warn!("Deleting file: {:?}", file_name);
-        fs::remove_file(file_name).unwrap();
-    }
-    let db = Connection::open(file_name).unwrap();
-    let mut columns: Vec<_> = tmd.get_distinct_keys(priority_cols).unwrap();
-    let q = format!(
-        "create table products ({})",
-        columns
-            .iter()
-            .map(|v| format!("\"{}\" TEXT", v))
-            .collect::<Vec<_>>()
-            .join(",")
-    );
-    db.execute(&q, []).unwrap();
-    let pos_vals = (0..columns.len())
-        .map(|v| format!("?{}", v + 1))
-        .collect::<Vec<String>>()
-        .join(",");
-    let q = format!(
-        "insert into products ({}) values ({})",
-        columns
-            .iter()
-            .map(|v| format!("\"{}\"", v))
-            .collect::<Vec<_>>()
-            .join(","),
-        pos_vals
-    );
-    let mut stmt = db.prepare_cached(&q).unwrap();
-    let all_ids = tmd.item_ids();
-    let ids_count = all_ids.chunks(chunk_size);
-    let dbf = tmd.db_file();
-    let nn = ids_count.len();
-    let mut cols = proc_ids(dbf, ids_count, nn, columns.clone());
-    while let Some(c) = cols.join_next().await {
-        let n = c.unwrap();
-        for row in n.iter() {
-            if let Err(e) = stmt.execute(params_from_iter(row.iter())) {
-                error!("Failed to store to db: {}", e);
+/// Iterator over the rows of a [`TableMapDb`], see [`TableMapDb::rows`]
+pub struct Rows<'a> {
+    db: &'a TableMapDb,
+    cursor: RowCursor,
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = Result<IndexMap<String, String>, DataToolErrors>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.cursor.next_row(&self.db.connection)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.cursor.remaining, Some(self.cursor.remaining))
+    }
+}
+
+/// Exact, as the db can not be changed while it is borrowed and the items stored by another
+/// connection since the iteration started are not iterated over. Items deleted by another
+/// connection are only accounted for once the last page of ids is read
+impl<'a> ExactSizeIterator for Rows<'a> {}
+
+impl<'a> Rows<'a> {
+    /// Number of rows yet to be yielded, see [`ExactSizeIterator`]
+    pub fn len_remaining(&self) -> usize {
+        self.cursor.remaining
+    }
+}
+
+/// Ids of the last item and the last cell stored at some point, reads bounded by them see the
+/// data as it was then, except for the cells changed in place or deleted since
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Snapshot {
+    pub max_item: i64,
+    pub max_cell: i64,
+}
+
+/// Iterator over the items stored when it was created, see [`TableMapDb::snapshot_rows`]
+pub struct SnapshotIter<'a> {
+    db: &'a TableMapDb,
+    snapshot: Snapshot,
+    /// id of the last item read
+    last: i64,
+    rows: VecDeque<IndexMap<String, String>>,
+}
+
+impl SnapshotIter<'_> {
+    /// Id of the last item stored when the iterator was created, 0 if there were none
+    pub fn max_item_id(&self) -> i64 {
+        self.snapshot.max_item
+    }
+
+    /// Reads the next page of rows, empty when there are none left
+    fn next_page(&mut self) -> Result<(), DataToolErrors> {
+        let db = self.db;
+        let ids = query_ids(
+            &db.connection,
+            &db.sql("select id from item_data where id > ?1 and id <= ?2 order by id limit ?3"),
+            params![self.last, self.snapshot.max_item, ITER_PAGE_SIZE as i64],
+        )?;
+        let mut items = read_items(
+            &db.connection,
+            &db.tables,
+            &ids,
+            None,
+            Some(self.snapshot.max_cell),
+            db.text_policy,
+        )?;
+        for id in ids {
+            let mut im = IndexMap::new();
+            im.insert("id".to_string(), id.to_string());
+            if let Some(cols) = items.swap_remove(&id) {
+                im.extend(cols);
             }
+            self.rows.push_back(im);
+            self.last = id;
         }
+        Ok(())
     }
-    info!("Done!");
-    Ok(())
 }
 
-fn proc_ids(
-    dbf: PathBuf,
-    ids_count: Chunks<i64>,
-    nn: usize,
-    columns: Vec<String>,
-) -> JoinSet<Vec<Vec<String>>> {
-    let mut cols = JoinSet::new();
-    for (ii, ids) in ids_count.enumerate() {
-        trace!("processing ... {} of {}", ii + 1, nn);
-        cols.spawn(read_db_chunked(
-            dbf.clone(),
-            columns.clone(),
-            ids.to_vec(),
-            ii,
-        ));
-    }
-    cols
-}
-
-async fn read_db_chunked(
-    file_name: PathBuf,
-    columns: Vec<String>,
-    ids: Vec<i64>,
-    cc: usize,
-) -> Vec<Vec<String>> {
-    let conn = match Connection::open_with_flags(&file_name, OpenFlags::SQLITE_OPEN_READ_ONLY) {
-        Ok(c) => c,
-        Err(e) => {
-            error!("{}", e);
-            return vec![];
+impl Iterator for SnapshotIter<'_> {
+    type Item = Result<IndexMap<String, String>, DataToolErrors>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rows.is_empty() && self.last < self.snapshot.max_item {
+            if let Err(e) = self.next_page() {
+                // nothing more is read after an error
+                self.last = self.snapshot.max_item;
+                return Some(Err(e));
+            }
         }
-    };
-    let mut res_vec = vec![];
-    let t = Instant::now();
-    let ids_s: Vec<_> = ids.iter().map(|v| v.to_string()).collect();
-    let mut inner_stmt = conn
-        .prepare(&format!(
-            "select item_id, key, value from data_columns where item_id in({}) order by item_id",
-            ids_s.join(",")
-        ))
-        .unwrap();
-    let mut im_dd: IndexMap<i64, IndexMap<String, String>> = IndexMap::new();
-    let _: Vec<_> = inner_stmt
-        .query_map([], |row| {
-            let item_id: i64 = row.get(0)?;
-            let key: String = row.get(1)?;
-            let val: String = row.get(2)?;
-            im_dd
-                .entry(item_id)
-                .and_modify(|mut v| {
-                    v.insert(key.clone(), val.clone());
-                })
-                .or_insert_with(|| {
-                    let mut im = IndexMap::new();
-                    im.insert(key, val);
-                    im
-                });
-            Ok(())
-        })
-        .unwrap()
-        .collect();
-    for (_ii, im) in im_dd.iter() {
-        let prep_cols = columns
-            .iter()
-            .map(|k| im.get(k).cloned().unwrap_or_default())
-            .collect();
-        res_vec.push(prep_cols);
+        self.rows.pop_front().map(Ok)
     }
-    trace!("done processing: {}, {:2}", cc, t.elapsed().as_secs_f32());
-    res_vec
 }
@@ -0,0 +1,211 @@
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use rusqlite::DatabaseName;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{info, warn};
+
+const READ_INDEXES: &str = "
+create index if not exists data_columns_item_id on data_columns (item_id);
+create index if not exists data_columns_key on data_columns (key);
+";
+
+/// How the WAL is checkpointed, see [`TableMapDb::checkpoint`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CheckpointMode {
+    /// copies as much of the WAL as possible without waiting for the readers
+    #[default]
+    Passive,
+    /// waits for the readers, then copies the whole WAL
+    Full,
+    /// same as [`CheckpointMode::Full`], then truncates the WAL file to 0 bytes
+    Truncate,
+}
+
+/// When the indexes the reads need are created, see [`TableMapDb::index_policy`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexPolicy {
+    /// before the first iteration or export, so they do not slow the inserts down
+    #[default]
+    Lazy,
+    /// when the policy is set, the inserts are slower, the first export does not wait
+    Eager,
+    /// only by [`TableMapDb::create_read_indexes`]
+    Manual,
+}
+
+/// Disk space used by the db, see [`TableMapDb::storage_stats`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StorageStats {
+    /// size of the db file, in bytes
+    pub file_size: u64,
+    /// size of the `-wal` file, 0 if there is none
+    pub wal_size: u64,
+    /// size of the `-shm` file, 0 if there is none
+    pub shm_size: u64,
+    /// `page_count` × `page_size`, the size of the db once the WAL is checkpointed
+    pub pages_size: u64,
+    pub page_size: u64,
+    /// pages left unused by deletes, given back by [`TableMapDb::vacuum`]
+    pub freelist_pages: u64,
+    /// the three files, divided by the number of items, `None` without items
+    pub bytes_per_item: Option<f64>,
+}
+
+impl StorageStats {
+    /// Bytes on disk, the db file and its `-wal` and `-shm` files
+    pub fn total_size(&self) -> u64 {
+        self.file_size + self.wal_size + self.shm_size
+    }
+}
+
+impl CheckpointMode {
+    fn pragma(self) -> &'static str {
+        match self {
+            CheckpointMode::Passive => "PRAGMA wal_checkpoint(PASSIVE)",
+            CheckpointMode::Full => "PRAGMA wal_checkpoint(FULL)",
+            CheckpointMode::Truncate => "PRAGMA wal_checkpoint(TRUNCATE)",
+        }
+    }
+}
+
+impl TableMapDb {
+    /// Copies the pages of the `-wal` file into the db file, nothing does it otherwise
+    /// during a long ingestion, and the WAL keeps growing. Logs a warning if a reader kept
+    /// the checkpoint from completing
+    pub fn checkpoint(&self, mode: CheckpointMode) -> Result<(), DataToolErrors> {
+        let t = Instant::now();
+        let (busy, pages, copied): (bool, i64, i64) =
+            self.connection
+                .query_row(mode.pragma(), [], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+        if busy {
+            warn!(
+                "{:?} checkpoint did not complete, {} of {} WAL pages copied",
+                mode, copied, pages
+            );
+        } else {
+            info!("{:?} checkpoint done in {:?}", mode, t.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the db file, giving back the space of the deleted rows, e.g. after
+    /// [`TableMapDb::dedupe_by_key`]. Needs as much free disk space as the db takes, and
+    /// fails in a transaction
+    pub fn vacuum(&mut self) -> Result<(), DataToolErrors> {
+        let t = Instant::now();
+        self.connection.execute_batch("VACUUM")?;
+        info!("Done! {:?} vacuumed in {:?}", self.db_file, t.elapsed());
+        Ok(())
+    }
+
+    /// Disk space used by the db, cheap enough to be polled, e.g. to start exporting before
+    /// the disk fills up
+    pub fn storage_stats(&self) -> Result<StorageStats, DataToolErrors> {
+        let pragma = |name: &str| -> Result<u64, DataToolErrors> {
+            let v: i64 = self
+                .connection
+                .query_row(&format!("PRAGMA {}", name), [], |r| r.get(0))?;
+            Ok(v as u64)
+        };
+        let page_size = pragma("page_size")?;
+        let mut stats = StorageStats {
+            file_size: file_size(&self.db_file)?,
+            wal_size: file_size(&sibling(&self.db_file, "-wal"))?,
+            shm_size: file_size(&sibling(&self.db_file, "-shm"))?,
+            pages_size: pragma("page_count")? * page_size,
+            page_size,
+            freelist_pages: pragma("freelist_count")?,
+            bytes_per_item: None,
+        };
+        let items = self.how_many_items()?;
+        if items > 0 {
+            stats.bytes_per_item = Some(stats.total_size() as f64 / items as f64);
+        }
+        Ok(stats)
+    }
+
+    /// When the indexes the reads need are created, see [`TableMapDb::create_read_indexes`].
+    /// With [`IndexPolicy::Eager`] they are created right away, or before the first read if
+    /// that fails
+    pub fn index_policy(mut self, policy: IndexPolicy) -> Self {
+        self.index_policy = policy;
+        if policy == IndexPolicy::Eager {
+            if let Err(e) = self.create_read_indexes() {
+                warn!(
+                    "Failed to create the read indexes, retrying before reading: {}",
+                    e
+                );
+                self.index_policy = IndexPolicy::Lazy;
+            }
+        }
+        self
+    }
+
+    /// Creates the indexes of `data_columns` on `item_id`, which every read of the rows of
+    /// an item goes through, and on `key`, for the lookups by key, e.g.
+    /// [`TableMapDb::find_items`]. Without them each read scans the whole table. Called by
+    /// the iterators and the exports unless the policy is [`IndexPolicy::Manual`], does
+    /// nothing if they exist
+    pub fn create_read_indexes(&self) -> Result<(), DataToolErrors> {
+        let t = Instant::now();
+        if !self.read_indexes_missing()? {
+            return Ok(());
+        }
+        self.connection.execute_batch(&self.sql(READ_INDEXES))?;
+        info!("read indexes created in {:?}", t.elapsed());
+        Ok(())
+    }
+
+    fn read_indexes_missing(&self) -> Result<bool, DataToolErrors> {
+        let missing = self.connection.query_row(
+            &self.sql(
+                "select count(*) < 2 from sqlite_master where type = 'index' \
+                 and name in ('data_columns_item_id', 'data_columns_key')",
+            ),
+            [],
+            |r| r.get(0),
+        )?;
+        Ok(missing)
+    }
+
+    /// Creates the read indexes, unless the policy is manual or the db is opened read-only,
+    /// see [`TableMapDb::open_existing_with`], the reads then do without the missing ones
+    pub(crate) fn ensure_read_indexes(&self) -> Result<(), DataToolErrors> {
+        if self.connection.is_readonly(DatabaseName::Main)? {
+            if self.index_policy != IndexPolicy::Manual && self.read_indexes_missing()? {
+                warn!("The db is opened read-only, reading it without the read indexes");
+            }
+            return Ok(());
+        }
+        match self.index_policy {
+            IndexPolicy::Manual => Ok(()),
+            _ => self.create_read_indexes(),
+        }
+    }
+
+    /// Runs `PRAGMA optimize`, updating the stats the query planner uses, cheap enough to
+    /// run after every large ingestion
+    pub fn optimize(&self) -> Result<(), DataToolErrors> {
+        self.connection.execute_batch("PRAGMA optimize")?;
+        Ok(())
+    }
+}
+
+/// `db_file` with `suffix` appended, e.g. its `-wal` file
+fn sibling(db_file: &Path, suffix: &str) -> PathBuf {
+    let mut name = db_file.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
+
+/// Size of the file, 0 if it does not exist
+fn file_size(path: &Path) -> Result<u64, DataToolErrors> {
+    match fs::metadata(path) {
+        Ok(m) => Ok(m.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
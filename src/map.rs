@@ -0,0 +1,165 @@
+use crate::errors::DataToolErrors;
+use crate::{migrate, RowCursor, TableMapDb, MAP_TABLES};
+use indexmap::IndexSet;
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::OnceLock;
+
+/// The tables of the map a handle works on. The tables of a named map are the ones of the
+/// default map suffixed by its name, e.g. `item_data_products` and `data_columns_products`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Tables {
+    /// `None` for the default map
+    map: Option<String>,
+}
+
+impl Tables {
+    fn named(name: &str) -> Self {
+        Self {
+            map: Some(name.to_string()),
+        }
+    }
+
+    /// `q`, written for the default map, with the tables of this map. The names of the
+    /// indexes, triggers and full-text index of the tables are suffixed the same way
+    pub(crate) fn sql<'a>(&self, q: &'a str) -> Cow<'a, str> {
+        static TABLE_NAMES: OnceLock<Regex> = OnceLock::new();
+        match &self.map {
+            None => Cow::Borrowed(q),
+            Some(name) => TABLE_NAMES
+                .get_or_init(|| Regex::new(r"\b(item_data|data_columns|data_fts)").unwrap())
+                .replace_all(q, |c: &Captures| format!("{}_{}", &c[1], name)),
+        }
+    }
+}
+
+/// What a handle keeps of a map while working on another one
+#[derive(Debug, Default)]
+pub(crate) struct MapState {
+    tables: Tables,
+    current_id: Option<i64>,
+    columns: IndexSet<String>,
+    current_row_iter: Option<RowCursor>,
+}
+
+/// A named map of a [`TableMapDb`], see [`TableMapDb::named_map`]. Dereferences to the db, every
+/// method then working on the map, e.g. `dump_csv(&mut handle, ..)` exports the map.
+/// The db goes back to the map it was working on when the handle is dropped
+pub struct MapHandle<'a> {
+    db: &'a mut TableMapDb,
+    name: String,
+    /// the state of the map the db was working on
+    outer: Option<MapState>,
+}
+
+impl TableMapDb {
+    /// A named map stored in the same file, e.g. the products, sellers and reviews of a
+    /// scrape, created if it does not exist. Each map has its own items, keys, current item
+    /// and iteration, the items of the default map, the one the db works on otherwise, are
+    /// not in it. Map names are made of ASCII letters, digits and `_`.
+    ///
+    /// Not supported by [`TableMapDb::merge_from`], and [`crate::diff`] compares the
+    /// default maps. Not named `map`, which would be shadowed by [`Iterator::map`]
+    pub fn named_map(&mut self, name: &str) -> Result<MapHandle<'_>, DataToolErrors> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "map name {:?} must be made of ASCII letters, digits and _",
+                name
+            )));
+        }
+        if self.tables.map.as_deref() == Some(name) {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "map {:?} is already in use",
+                name
+            )));
+        }
+        let (state, new) = match self.maps.remove(name) {
+            Some(state) => (state, false),
+            None => {
+                let tables = Tables::named(name);
+                self.connection.execute_batch(&tables.sql(MAP_TABLES))?;
+                migrate(&self.connection, &tables)?;
+                let state = MapState {
+                    tables,
+                    ..Default::default()
+                };
+                (state, true)
+            }
+        };
+        let outer = self.swap_map(state);
+        let mut handle = MapHandle {
+            db: self,
+            name: name.to_string(),
+            outer: Some(outer),
+        };
+        if new {
+            // the map may have been filled by an earlier handle on the file
+            handle.refresh_columns()?;
+        }
+        Ok(handle)
+    }
+
+    /// The named maps stored in the file, see [`TableMapDb::named_map`]
+    pub fn map_names(&self) -> Result<Vec<String>, DataToolErrors> {
+        let mut stmt = self.connection.prepare_cached(
+            "select substr(name, 11) from sqlite_master where type = 'table' \
+             and name like 'item\\_data\\_%' escape '\\' order by name",
+        )?;
+        let names = stmt
+            .query_map([], |r| r.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(DataToolErrors::from);
+        names
+    }
+
+    /// `q`, written for the default map, with the tables of the map the handle works on
+    pub(crate) fn sql<'a>(&self, q: &'a str) -> Cow<'a, str> {
+        self.tables.sql(q)
+    }
+
+    /// Works on the map of `state`, returning the state of the map it was working on
+    fn swap_map(&mut self, mut state: MapState) -> MapState {
+        mem::swap(&mut self.tables, &mut state.tables);
+        mem::swap(&mut self.current_id, &mut state.current_id);
+        mem::swap(&mut self.columns, &mut state.columns);
+        mem::swap(&mut self.current_row_iter, &mut state.current_row_iter);
+        state
+    }
+
+    /// Whether the handle works on the default map
+    pub(crate) fn on_default_map(&self) -> bool {
+        self.tables.map.is_none()
+    }
+}
+
+impl MapHandle<'_> {
+    /// The name of the map
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Deref for MapHandle<'_> {
+    type Target = TableMapDb;
+
+    fn deref(&self) -> &TableMapDb {
+        self.db
+    }
+}
+
+impl DerefMut for MapHandle<'_> {
+    fn deref_mut(&mut self) -> &mut TableMapDb {
+        self.db
+    }
+}
+
+impl Drop for MapHandle<'_> {
+    fn drop(&mut self) {
+        if let Some(outer) = self.outer.take() {
+            let state = self.db.swap_map(outer);
+            self.db.maps.insert(self.name.clone(), state);
+        }
+    }
+}
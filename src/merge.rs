@@ -0,0 +1,524 @@
+use crate::errors::DataToolErrors;
+use crate::{now_ms, ChangeEvent, TableMapDb, Tables, BUMP_REVISION};
+use rusqlite::Connection;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Which value wins when an item of both dbs has the same key, see
+/// [`TableMapDb::merge_from`]. For [`TableMapDb::merge_into`], this db is the item merged
+/// into and the other db the item merged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// The values of this db are kept, the other db's are not copied
+    #[default]
+    PreferSelf,
+    /// The values of this db are replaced by the other db's
+    PreferOther,
+    /// Both values are kept, the other db's being read back as it is the latest. Values
+    /// this db already has for the key are not copied again
+    KeepBoth,
+}
+
+/// What a merge did
+#[derive(Debug, Clone, Default)]
+pub struct MergeSummary {
+    /// items of the other db this db did not have
+    pub items_added: usize,
+    /// items of the other db this db already had
+    pub items_merged: usize,
+    pub cells_copied: usize,
+    pub elapsed: Duration,
+}
+
+impl TableMapDb {
+    /// Copies the items of another table map db file into this one, matching them by
+    /// `item_val`. Items this db does not have are added with all their keys, the keys of the
+    /// items it has are merged following `policy`.
+    ///
+    /// The other file is attached to the connection and the copy is done by SQLite in a
+    /// single transaction, no cell goes through Rust. Items of the other db without an
+    /// `item_val` are not copied
+    pub fn merge_from(
+        &mut self,
+        other_db_file: &Path,
+        policy: MergePolicy,
+    ) -> Result<MergeSummary, DataToolErrors> {
+        let t = Instant::now();
+        if !self.on_default_map() {
+            return Err(DataToolErrors::InvalidArgument(
+                "merge_from is only supported on the default map".to_string(),
+            ));
+        }
+        if !other_db_file.exists() {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "no database {:?}",
+                other_db_file
+            )));
+        }
+        if fs::canonicalize(other_db_file)? == fs::canonicalize(&self.db_file)? {
+            return Err(DataToolErrors::InvalidArgument(
+                "can not merge a db into itself".to_string(),
+            ));
+        }
+        let conn = &self.connection;
+        // the items and cells added by the merge have larger ids, only read if observed
+        let last_ids = match self.observer {
+            Some(_) => Some(last_ids(conn)?),
+            None => None,
+        };
+        conn.execute(
+            "attach database ?1 as other",
+            [other_db_file.to_string_lossy()],
+        )?;
+        let res = merge_attached(conn, other_db_file, policy);
+        // detaching even if the merge failed, so the connection is left as it was
+        let detached = conn.execute("detach database other", []);
+        let mut summary = res?;
+        detached?;
+        // the copied keys are not known
+        self.refresh_columns()?;
+        if let Some((last_item, last_cell)) = last_ids {
+            self.notify_merged(last_item, last_cell)?;
+        }
+        summary.elapsed = t.elapsed();
+        info!("Done! {:?}", summary);
+        Ok(summary)
+    }
+
+    /// Moves the cells of the item `from_id` onto the item `to_id` and deletes `from_id`,
+    /// e.g. once both are known to be the same product, see [`TableMapDb::update_item_val`]
+    /// when only one exists. The keys both items have are merged following `policy`, `to_id`
+    /// being this db. Returns the number of cells moved.
+    ///
+    /// Runs in a single transaction, the cells of `from_id` are deleted along with it. If
+    /// `from_id` was the current item, `to_id` becomes the current item
+    pub fn merge_into(
+        &mut self,
+        from_id: i64,
+        to_id: i64,
+        policy: MergePolicy,
+    ) -> Result<usize, DataToolErrors> {
+        if from_id == to_id {
+            return Err(DataToolErrors::InvalidArgument(format!(
+                "can not merge item {} into itself",
+                from_id
+            )));
+        }
+        self.item_val_of(from_id)?;
+        self.item_val_of(to_id)?;
+        let conn = &self.connection;
+        conn.execute_batch("BEGIN")?;
+        let res = merge_item(conn, &self.tables, from_id, to_id, policy);
+        let end = if res.is_ok() { "COMMIT" } else { "ROLLBACK" };
+        let ended = conn.execute_batch(end).map_err(DataToolErrors::from);
+        let moved = res?;
+        ended?;
+        if self.current_id == Some(from_id) {
+            self.current_id = Some(to_id);
+        }
+        self.notify(|| ChangeEvent::ItemDeleted { id: from_id });
+        info!(
+            "Item {} merged into {}, {} cells moved",
+            from_id, to_id, moved
+        );
+        Ok(moved)
+    }
+
+    /// Hands the items and cells added after `last_item` and `last_cell` to the observer,
+    /// as [`ChangeEvent::ItemCreated`] and [`ChangeEvent::CellInserted`]
+    fn notify_merged(&mut self, last_item: i64, last_cell: i64) -> Result<(), DataToolErrors> {
+        let items = self
+            .connection
+            .prepare("select id, item_val from main.item_data where id > ?1 order by id")?
+            .query_map([last_item], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+        let cells = self
+            .connection
+            .prepare("select item_id, key from main.data_columns where id > ?1 order by id")?
+            .query_map([last_cell], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+        for (id, item_val) in items {
+            self.notify(|| ChangeEvent::ItemCreated { id, item_val });
+        }
+        for (item_id, key) in cells {
+            self.notify(|| ChangeEvent::CellInserted { item_id, key });
+        }
+        Ok(())
+    }
+}
+
+/// Ids of the last item and cell of the default map
+fn last_ids(conn: &Connection) -> rusqlite::Result<(i64, i64)> {
+    conn.query_row(
+        "select (select coalesce(max(id), 0) from main.item_data),
+                (select coalesce(max(id), 0) from main.data_columns)",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )
+}
+
+/// Copies the cells of `from_id` to `to_id` and deletes `from_id`, returning the number of
+/// cells copied
+fn merge_item(
+    conn: &Connection,
+    tables: &Tables,
+    from_id: i64,
+    to_id: i64,
+    policy: MergePolicy,
+) -> rusqlite::Result<usize> {
+    // the cells of `from_id` that `to_id` already has, that are not copied
+    let same_cell = match policy {
+        MergePolicy::PreferSelf => Some("s.key = f.key"),
+        MergePolicy::PreferOther => {
+            conn.execute(
+                &tables.sql(
+                    "delete from data_columns where item_id = ?2 and key in
+                     (select key from data_columns where item_id = ?1)",
+                ),
+                [from_id, to_id],
+            )?;
+            None
+        }
+        MergePolicy::KeepBoth => Some("s.key = f.key and s.value is f.value"),
+    };
+    let filter = same_cell.map_or(String::new(), |same_cell| {
+        format!(
+            " and not exists (
+                 select 1 from data_columns s where s.item_id = ?2 and {})",
+            same_cell
+        )
+    });
+    // copied as new cells, so the cells of `from_id` are the latest ones of `to_id`
+    let moved = conn.execute(
+        &tables.sql(&format!(
+            "insert into data_columns (key, value, item_id)
+             select f.key, f.value, ?2 from data_columns f where f.item_id = ?1{}
+             order by f.id",
+            filter
+        )),
+        [from_id, to_id],
+    )?;
+    conn.execute(
+        &tables.sql("delete from data_columns where item_id = ?1"),
+        [from_id],
+    )?;
+    conn.execute(
+        &tables.sql("delete from item_data where id = ?1"),
+        [from_id],
+    )?;
+    conn.prepare_cached(&tables.sql(BUMP_REVISION))?
+        .execute([to_id])?;
+    Ok(moved)
+}
+
+/// Merges the db attached as `other`, rolling back if anything fails
+fn merge_attached(
+    conn: &Connection,
+    other_db_file: &Path,
+    policy: MergePolicy,
+) -> Result<MergeSummary, DataToolErrors> {
+    let tables: usize = conn.query_row(
+        "select count(*) from other.sqlite_master \
+             where type = 'table' and name in ('item_data', 'data_columns')",
+        [],
+        |r| r.get(0),
+    )?;
+    if tables != 2 {
+        return Err(DataToolErrors::InvalidArgument(format!(
+            "{:?} is not a table map db",
+            other_db_file
+        )));
+    }
+    conn.execute_batch("BEGIN")?;
+    let res = merge_items(conn, policy);
+    let end = if res.is_ok() { "COMMIT" } else { "ROLLBACK" };
+    let ended = conn.execute_batch(end).map_err(DataToolErrors::from);
+    let summary = res?;
+    ended?;
+    Ok(summary)
+}
+
+fn merge_items(conn: &Connection, policy: MergePolicy) -> rusqlite::Result<MergeSummary> {
+    // the id each item of the other db has in this one, once added
+    conn.execute_batch(
+        "create temp table merge_ids
+         (other_id integer primary key, item_val text, self_id integer, existed integer);
+         insert into merge_ids
+             select o.id, o.item_val, m.id, m.id is not null from other.item_data o
+             left join main.item_data m on m.item_val = o.item_val
+             where o.item_val is not null;",
+    )?;
+    let items_merged = conn.query_row(
+        "select count(*) from temp.merge_ids where existed",
+        [],
+        |r| r.get(0),
+    )?;
+    // the items added are created now, whenever they were created in the other db
+    let items_added = conn.execute(
+        "insert into main.item_data (item_val, created_at)
+         select item_val, ?1 from temp.merge_ids where not existed order by other_id",
+        [now_ms()],
+    )?;
+    conn.execute(
+        "update temp.merge_ids
+         set self_id = (select m.id from main.item_data m where m.item_val = merge_ids.item_val)
+         where not existed",
+        [],
+    )?;
+    // the cells of the items of both dbs, indexed, as data_columns is not
+    conn.execute_batch(
+        "create temp table merge_cells as
+             select d.id, d.item_id, d.key, d.value from main.data_columns d
+             where d.item_id in (select self_id from temp.merge_ids where existed);
+         create index temp.merge_cells_item_key on merge_cells (item_id, key);",
+    )?;
+    // the cells of the other db this one already has, that are not copied
+    let same_cell = match policy {
+        MergePolicy::PreferSelf => Some("s.key = od.key"),
+        MergePolicy::PreferOther => {
+            conn.execute(
+                "delete from main.data_columns where id in (
+                     select s.id from other.data_columns od
+                     join temp.merge_ids mi on mi.other_id = od.item_id and mi.existed
+                     join temp.merge_cells s on s.item_id = mi.self_id and s.key = od.key)",
+                [],
+            )?;
+            None
+        }
+        MergePolicy::KeepBoth => Some("s.key = od.key and s.value is od.value"),
+    };
+    let filter = same_cell.map_or(String::new(), |same_cell| {
+        format!(
+            "where not exists (
+                 select 1 from temp.merge_cells s where s.item_id = mi.self_id and {})",
+            same_cell
+        )
+    });
+    let last_cell: i64 = conn.query_row(
+        "select coalesce(max(id), 0) from main.data_columns",
+        [],
+        |r| r.get(0),
+    )?;
+    let cells_copied = conn.execute(
+        &format!(
+            "insert into main.data_columns (key, value, item_id)
+             select od.key, od.value, mi.self_id from other.data_columns od
+             join temp.merge_ids mi on mi.other_id = od.item_id {}
+             order by od.id",
+            filter
+        ),
+        [],
+    )?;
+    // the items added and the ones that got cells are changed, all at the same revision
+    conn.execute(
+        "update main.item_data
+         set revision = (select coalesce(max(revision), 0) + 1 from main.item_data)
+         where id in (select self_id from temp.merge_ids where not existed)
+            or id in (select item_id from main.data_columns where id > ?1)",
+        [last_cell],
+    )?;
+    conn.execute_batch("drop table temp.merge_ids; drop table temp.merge_cells;")?;
+    Ok(MergeSummary {
+        items_added,
+        items_merged,
+        cells_copied,
+        elapsed: Duration::ZERO,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+    use std::sync::{Arc, Mutex};
+
+    /// A new item with the cells, returning its id
+    fn item(db: &mut TableMapDb, item_val: &str, cells: &[(&str, &str)]) -> i64 {
+        let id = db.next_row(item_val).unwrap();
+        db.insert_batched(cells.iter().copied()).unwrap();
+        id
+    }
+
+    /// The events the observer gets from now on
+    fn observe(db: &mut TableMapDb) -> Arc<Mutex<Vec<ChangeEvent>>> {
+        let events = Arc::new(Mutex::new(vec![]));
+        let seen = events.clone();
+        db.set_observer(move |e| seen.lock().unwrap().push(e));
+        events
+    }
+
+    fn cells_of(db: &TableMapDb, id: i64) -> usize {
+        db.connection
+            .query_row(
+                "select count(*) from data_columns where item_id = ?1",
+                [id],
+                |r| r.get(0),
+            )
+            .unwrap()
+    }
+
+    fn value(db: &TableMapDb, item_val: &str, key: &str) -> Option<String> {
+        db.get_value(item_val, key).unwrap()
+    }
+
+    #[test]
+    fn update_item_val_keeps_the_id_and_cells() {
+        let dir = TestDir::new("update_item_val");
+        let mut db = dir.db();
+        let a = item(&mut db, "a", &[("name", "apple"), ("price", "1")]);
+        let b = item(&mut db, "b", &[("price", "2")]);
+        let events = observe(&mut db);
+        db.update_item_val(a, "a2").unwrap();
+        assert_eq!(db.get_item("a").unwrap(), None);
+        assert_eq!(value(&db, "a2", "name").as_deref(), Some("apple"));
+        assert_eq!(db.current_item(), Some((b, "b".to_string())));
+        db.set_current_item_by_val("a2").unwrap();
+        assert_eq!(db.current_item(), Some((a, "a2".to_string())));
+        assert_eq!(cells_of(&db, a), 2);
+        // taken by another item, nothing changes
+        let res = db.update_item_val(a, "b");
+        assert!(
+            matches!(&res, Err(DataToolErrors::ItemValTaken { id, .. }) if *id == b),
+            "{:?}",
+            res
+        );
+        assert_eq!(value(&db, "a2", "price").as_deref(), Some("1"));
+        assert_eq!(value(&db, "b", "price").as_deref(), Some("2"));
+        // renaming to the same item_val does nothing
+        db.update_item_val(a, "a2").unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [ChangeEvent::ItemValChanged {
+                id: a,
+                old_item_val: Some("a".to_string()),
+                item_val: "a2".to_string(),
+            }]
+        );
+        assert!(matches!(
+            db.update_item_val(99, "c"),
+            Err(DataToolErrors::ItemNotFound(99))
+        ));
+    }
+
+    #[test]
+    fn merge_into_moves_the_cells_and_deletes_the_item() {
+        let dir = TestDir::new("merge_into");
+        let mut db = dir.db();
+        let a = item(&mut db, "a", &[("name", "apple"), ("price", "1")]);
+        let b = item(&mut db, "b", &[("price", "2"), ("color", "blue")]);
+        let c = item(&mut db, "c", &[("price", "3")]);
+        let d = item(&mut db, "d", &[("price", "4"), ("name", "date")]);
+        db.set_current_item(a).unwrap();
+        let events = observe(&mut db);
+        assert_eq!(db.merge_into(a, b, MergePolicy::PreferSelf).unwrap(), 1);
+        assert_eq!(db.get_item("a").unwrap(), None);
+        // no cell is left pointing at the deleted item
+        assert_eq!(cells_of(&db, a), 0);
+        assert_eq!(value(&db, "b", "price").as_deref(), Some("2"));
+        assert_eq!(value(&db, "b", "name").as_deref(), Some("apple"));
+        assert_eq!(value(&db, "b", "color").as_deref(), Some("blue"));
+        assert_eq!(db.current_item(), Some((b, "b".to_string())));
+        assert_eq!(db.merge_into(c, d, MergePolicy::PreferOther).unwrap(), 1);
+        assert_eq!(cells_of(&db, c), 0);
+        assert_eq!(cells_of(&db, d), 2);
+        assert_eq!(value(&db, "d", "price").as_deref(), Some("3"));
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                ChangeEvent::ItemDeleted { id: a },
+                ChangeEvent::ItemDeleted { id: c },
+            ]
+        );
+        // the deleted item can not be merged again, and nothing changes
+        assert!(matches!(
+            db.merge_into(a, b, MergePolicy::PreferSelf),
+            Err(DataToolErrors::ItemNotFound(_))
+        ));
+        assert_eq!(cells_of(&db, b), 3);
+    }
+
+    #[test]
+    fn overlapping_items_are_merged_following_the_policy() {
+        let dir = TestDir::new("merge_from_policies");
+        let mut other = TableMapDb::new(dir.path("other.db")).unwrap();
+        item(
+            &mut other,
+            "a",
+            &[("name", "apple"), ("price", "1"), ("color", "green")],
+        );
+        item(&mut other, "x", &[("name", "xigua")]);
+        drop(other);
+        // the value of name, the cells of a, and the cells copied
+        for (policy, name, cells, copied) in [
+            (MergePolicy::PreferSelf, "apricot", 3, 2),
+            (MergePolicy::PreferOther, "apple", 3, 4),
+            // the price is the same, so not copied again
+            (MergePolicy::KeepBoth, "apple", 4, 3),
+        ] {
+            let mut db = TableMapDb::new(dir.path(&format!("{:?}.db", policy))).unwrap();
+            let a = item(&mut db, "a", &[("name", "apricot"), ("price", "1")]);
+            let b = item(&mut db, "b", &[("name", "banana")]);
+            let summary = db.merge_from(&dir.path("other.db"), policy).unwrap();
+            assert_eq!(
+                (summary.items_added, summary.items_merged),
+                (1, 1),
+                "{:?}",
+                policy
+            );
+            assert_eq!(summary.cells_copied, copied, "{:?}", policy);
+            assert_eq!(
+                value(&db, "a", "name").as_deref(),
+                Some(name),
+                "{:?}",
+                policy
+            );
+            assert_eq!(value(&db, "a", "price").as_deref(), Some("1"));
+            assert_eq!(value(&db, "a", "color").as_deref(), Some("green"));
+            assert_eq!(cells_of(&db, a), cells, "{:?}", policy);
+            assert_eq!(value(&db, "x", "name").as_deref(), Some("xigua"));
+            assert_eq!(cells_of(&db, b), 1);
+            assert_eq!(db.columns(), ["name", "price", "color"]);
+        }
+        let mut db = TableMapDb::open_existing(dir.path("other.db")).unwrap();
+        let res = db.merge_from(&dir.path("other.db"), MergePolicy::PreferSelf);
+        assert!(matches!(res, Err(DataToolErrors::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn merged_items_and_cells_are_observed() {
+        let dir = TestDir::new("merge_from_observed");
+        let mut other = TableMapDb::new(dir.path("other.db")).unwrap();
+        item(&mut other, "a", &[("name", "apple"), ("color", "green")]);
+        item(&mut other, "x", &[("name", "xigua")]);
+        drop(other);
+        let mut db = dir.db();
+        let a = item(&mut db, "a", &[("name", "apricot")]);
+        let events = observe(&mut db);
+        let summary = db
+            .merge_from(&dir.path("other.db"), MergePolicy::PreferSelf)
+            .unwrap();
+        assert_eq!((summary.items_added, summary.items_merged), (1, 1));
+        let x = a + 1;
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                ChangeEvent::ItemCreated {
+                    id: x,
+                    item_val: "x".to_string(),
+                },
+                ChangeEvent::CellInserted {
+                    item_id: a,
+                    key: "color".to_string(),
+                },
+                ChangeEvent::CellInserted {
+                    item_id: x,
+                    key: "name".to_string(),
+                },
+            ]
+        );
+        assert_eq!(value(&db, "a", "name").as_deref(), Some("apricot"));
+        assert_eq!(value(&db, "x", "name").as_deref(), Some("xigua"));
+    }
+}
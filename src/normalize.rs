@@ -0,0 +1,79 @@
+use crate::TableMapDb;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+/// Change made to the values before they are stored, see [`TableMapDb::normalizer`]
+#[derive(Clone)]
+pub enum Normalizer {
+    /// remove the leading and trailing whitespace, non-breaking spaces included
+    TrimWhitespace,
+    /// replace each run of whitespace by a single space
+    CollapseInnerWhitespace,
+    /// do not store empty values
+    NullifyEmpty,
+    /// any other change, gets the key and the value, and returns the value to store, or
+    /// `None` to not store it
+    Custom(Arc<NormalizeFn>),
+}
+
+type NormalizeFn = dyn Fn(&str, &str) -> Option<String> + Send + Sync;
+
+impl fmt::Debug for Normalizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Normalizer::TrimWhitespace => f.write_str("TrimWhitespace"),
+            Normalizer::CollapseInnerWhitespace => f.write_str("CollapseInnerWhitespace"),
+            Normalizer::NullifyEmpty => f.write_str("NullifyEmpty"),
+            Normalizer::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl Normalizer {
+    fn apply<'a>(&self, key: &str, value: Cow<'a, str>) -> Option<Cow<'a, str>> {
+        match self {
+            Normalizer::TrimWhitespace => match value {
+                Cow::Borrowed(v) => Some(Cow::Borrowed(v.trim())),
+                Cow::Owned(v) => Some(Cow::Owned(v.trim().to_string())),
+            },
+            Normalizer::CollapseInnerWhitespace => {
+                if !value.chars().any(char::is_whitespace) {
+                    return Some(value);
+                }
+                let mut collapsed = String::with_capacity(value.len());
+                let mut in_space = false;
+                for c in value.chars() {
+                    if !c.is_whitespace() {
+                        collapsed.push(c);
+                    } else if !in_space {
+                        collapsed.push(' ');
+                    }
+                    in_space = c.is_whitespace();
+                }
+                Some(Cow::Owned(collapsed))
+            }
+            Normalizer::NullifyEmpty => (!value.is_empty()).then_some(value),
+            Normalizer::Custom(f) => f(key, &value).map(Cow::Owned),
+        }
+    }
+}
+
+impl TableMapDb {
+    /// Adds a normalizer applied to the values before they are stored, and checked, after the
+    /// ones already added. The values are normalized by [`TableMapDb::insert`],
+    /// [`TableMapDb::insert_batched`], [`TableMapDb::add_row`], the
+    /// [`RowSender`](crate::writer::RowSender) and the imports, not by
+    /// [`TableMapDb::merge_from`], which copies the values as stored in the other db
+    pub fn normalizer(mut self, normalizer: Normalizer) -> Self {
+        self.normalizers.push(normalizer);
+        self
+    }
+
+    /// The value to store under `key`, or `None` if it is not stored
+    pub(crate) fn normalize<'a>(&self, key: &str, value: &'a str) -> Option<Cow<'a, str>> {
+        self.normalizers
+            .iter()
+            .try_fold(Cow::Borrowed(value), |v, n| n.apply(key, v))
+    }
+}
@@ -0,0 +1,61 @@
+use crate::TableMapDb;
+#[cfg(feature = "async")]
+use tokio::sync::mpsc;
+
+/// A change to the stored items, see [`TableMapDb::set_observer`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// a new item, not sent for an item [`TableMapDb::next_row`] found
+    ItemCreated { id: i64, item_val: String },
+    /// a cell stored for the item, the value can be read back with the key
+    CellInserted { item_id: i64, key: String },
+    /// the item and its cells were deleted, e.g. by [`TableMapDb::dedupe_by_key`]
+    ItemDeleted { id: i64 },
+    /// the item was renamed by [`TableMapDb::update_item_val`], its cells are unchanged
+    ItemValChanged {
+        id: i64,
+        old_item_val: Option<String>,
+        item_val: String,
+    },
+}
+
+/// The function changes are handed to
+pub(crate) type Observer = Box<dyn FnMut(ChangeEvent) + Send>;
+
+impl TableMapDb {
+    /// Calls `f` with every change to the items, e.g. to stream the rows to a dashboard while
+    /// they are stored, by the inserts, [`TableMapDb::add_row`], the imports, the merges and
+    /// the [`TableMapDb::spawn_writer`] task. Replaces the observer set before.
+    ///
+    /// `f` is called right after the write, before the call storing the data returns, so a
+    /// slow observer slows the ingestion down, see [`TableMapDb::observe_channel`]. Changes
+    /// of a transaction rolled back afterwards are not taken back. The items and cells
+    /// copied by [`TableMapDb::merge_from`] are handed over once the merge is committed
+    pub fn set_observer(&mut self, f: impl FnMut(ChangeEvent) + Send + 'static) {
+        self.observer = Some(Box::new(f));
+    }
+
+    /// Same as [`TableMapDb::set_observer`], the changes being sent to the returned receiver
+    /// instead, never waiting for it. The changes are kept until received, and dropped once
+    /// the receiver is
+    #[cfg(feature = "async")]
+    pub fn observe_channel(&mut self) -> mpsc::UnboundedReceiver<ChangeEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.set_observer(move |event| {
+            let _ = tx.send(event);
+        });
+        rx
+    }
+
+    /// Removes the observer, see [`TableMapDb::set_observer`]
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    /// Hands the change to the observer, the event is only built if there is one
+    pub(crate) fn notify(&mut self, event: impl FnOnce() -> ChangeEvent) {
+        if let Some(observer) = &mut self.observer {
+            observer(event());
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::errors::DataToolErrors;
+use crate::{
+    count_items, find_items, get_value, max_item_id, open_connection, TableMapDb, Tables,
+    TextPolicy,
+};
+use indexmap::IndexMap;
+use rusqlite::{Connection, OpenFlags};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+/// A [`TableMapDb`] that can be shared between threads, i.e. behind an `Arc`.
+///
+/// Writes go through the wrapped `TableMapDb`, one at a time. Lookups use a read-only
+/// connection per thread, opened on first use, so they do not wait for the writes.
+pub struct SharedTableMapDb {
+    db_file: PathBuf,
+    tables: Tables,
+    busy_timeout: Duration,
+    statement_cache_capacity: usize,
+    text_policy: TextPolicy,
+    writer: Mutex<TableMapDb>,
+    readers: Mutex<HashMap<ThreadId, Connection>>,
+}
+
+impl SharedTableMapDb {
+    pub fn new(db: TableMapDb) -> Self {
+        Self {
+            db_file: db.db_file(),
+            tables: db.tables.clone(),
+            busy_timeout: db.busy_timeout,
+            statement_cache_capacity: db.statement_cache_capacity,
+            text_policy: db.text_policy,
+            writer: Mutex::new(db),
+            readers: Default::default(),
+        }
+    }
+
+    /// Gives back the wrapped `TableMapDb`, e.g. for exporting
+    pub fn into_inner(self) -> TableMapDb {
+        self.writer
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Same as [`TableMapDb::add_row`]
+    pub fn add_row(
+        &self,
+        item_val: &str,
+        columns: &IndexMap<String, String>,
+    ) -> Result<i64, DataToolErrors> {
+        let mut db = self
+            .writer
+            .lock()
+            .map_err(|e| DataToolErrors::GenericError(e.to_string()))?;
+        db.add_row(item_val, columns)
+    }
+
+    /// Same as [`TableMapDb::get_value`]
+    pub fn get_value(&self, item_val: &str, key: &str) -> Result<Option<String>, DataToolErrors> {
+        self.with_reader(|conn| get_value(conn, &self.tables, item_val, key, self.text_policy))
+    }
+
+    /// Same as [`TableMapDb::find_items`]
+    pub fn find_items(&self, key: &str, value: &str) -> Result<Vec<i64>, DataToolErrors> {
+        self.with_reader(|conn| find_items(conn, &self.tables, key, value))
+    }
+
+    /// Same as [`TableMapDb::how_many_items`]
+    pub fn how_many_items(&self) -> Result<usize, DataToolErrors> {
+        self.with_reader(|conn| count_items(conn, &self.tables))
+    }
+
+    /// Same as [`TableMapDb::how_many_items_fast`]
+    pub fn how_many_items_fast(&self) -> Result<usize, DataToolErrors> {
+        self.with_reader(|conn| max_item_id(conn, &self.tables))
+    }
+
+    /// Runs the query on the current thread's read-only connection
+    fn with_reader<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+    ) -> Result<T, DataToolErrors> {
+        let id = thread::current().id();
+        // the connection is taken out of the map while in use, so other threads
+        // are not blocked by the query
+        let conn = self
+            .readers
+            .lock()
+            .map_err(|e| DataToolErrors::GenericError(e.to_string()))?
+            .remove(&id);
+        let conn = match conn {
+            Some(c) => c,
+            None => {
+                let conn = open_connection(&self.db_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+                conn.busy_timeout(self.busy_timeout)?;
+                conn.set_prepared_statement_cache_capacity(self.statement_cache_capacity);
+                conn
+            }
+        };
+        let res = f(&conn).map_err(DataToolErrors::from);
+        if let Ok(mut readers) = self.readers.lock() {
+            readers.insert(id, conn);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+    use std::sync::Arc;
+
+    #[test]
+    fn inserts_from_many_threads() {
+        let dir = TestDir::new("shared");
+        let shared = Arc::new(SharedTableMapDb::new(dir.db()));
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for i in 0..10_000 {
+                        let item_val = format!("{}-{}", t, i);
+                        let columns = IndexMap::from([("n".to_string(), i.to_string())]);
+                        shared.add_row(&item_val, &columns).unwrap();
+                        // lookups run on the thread's own connection meanwhile
+                        if i % 1000 == 0 {
+                            assert_eq!(
+                                shared.get_value(&item_val, "n").unwrap(),
+                                Some(i.to_string())
+                            );
+                        }
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(shared.how_many_items().unwrap(), 80_000);
+        assert_eq!(shared.find_items("n", "9999").unwrap().len(), 8);
+    }
+}
@@ -0,0 +1,332 @@
+use super::*;
+use std::process;
+
+/// A directory of its own for a test, removed once dropped
+pub(crate) struct TestDir(PathBuf);
+
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("table_map_db-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub(crate) fn path(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+
+    /// A new db in the directory
+    pub(crate) fn db(&self) -> TableMapDb {
+        TableMapDb::new(self.path("test.db")).unwrap()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Ids of the rows the iterator yields in `order`
+fn iter_ids(db: &mut TableMapDb, order: IterOrder) -> Vec<i64> {
+    db.set_iter_order(order);
+    db.rows()
+        .unwrap()
+        .map(|r| r.unwrap()["id"].parse().unwrap())
+        .collect()
+}
+
+#[test]
+fn iterates_over_several_pages_in_every_order() {
+    let dir = TestDir::new("pages");
+    let mut db = dir.db();
+    let n = ITER_PAGE_SIZE * 2 + 7;
+    for i in 0..n {
+        // the item_vals are not in insertion order
+        db.add_row(
+            &format!("item{:05}", (i * 7) % n),
+            [("k", (i % 10).to_string())],
+        )
+        .unwrap();
+    }
+    let asc: Vec<i64> = (1..=n as i64).collect();
+    assert_eq!(iter_ids(&mut db, IterOrder::InsertionAsc), asc);
+    let desc: Vec<i64> = asc.iter().rev().copied().collect();
+    assert_eq!(iter_ids(&mut db, IterOrder::InsertionDesc), desc);
+    let mut by_val = asc.clone();
+    by_val.sort_by_key(|id| ((*id as usize - 1) * 7) % n);
+    assert_eq!(iter_ids(&mut db, IterOrder::ByItemVal), by_val);
+    let mut by_key = asc.clone();
+    by_key.sort_by_key(|id| (*id as usize - 1) % 10);
+    let order = IterOrder::ByKeyValue {
+        key: "k".to_string(),
+        numeric: true,
+    };
+    assert_eq!(iter_ids(&mut db, order), by_key);
+}
+
+#[test]
+fn items_without_item_val_are_iterated_by_item_val() {
+    let dir = TestDir::new("null_item_val");
+    let mut db = dir.db();
+    db.add_row("b", [("k", "1")]).unwrap();
+    // more than a page without an item_val, so a page ends on one
+    for _ in 0..ITER_PAGE_SIZE + 3 {
+        db.connection
+            .execute("insert into item_data (item_val) values (null)", [])
+            .unwrap();
+    }
+    db.add_row("a", [("k", "2")]).unwrap();
+    let nulls = 2..=(ITER_PAGE_SIZE as i64 + 4);
+    let last = ITER_PAGE_SIZE as i64 + 5;
+    let expected: Vec<i64> = nulls.chain([last, 1]).collect();
+    assert_eq!(iter_ids(&mut db, IterOrder::ByItemVal), expected);
+}
+
+#[test]
+fn rows_count_down_the_remaining_rows() {
+    let dir = TestDir::new("remaining");
+    let mut db = dir.db();
+    for i in 0..3 {
+        db.add_row(&i.to_string(), [("k", "v")]).unwrap();
+    }
+    assert_eq!(db.len_remaining().unwrap(), 3);
+    let mut rows = db.rows().unwrap();
+    assert_eq!(rows.len_remaining(), 3);
+    rows.next().unwrap().unwrap();
+    assert_eq!(rows.len_remaining(), 2);
+    assert_eq!(rows.size_hint(), (2, Some(2)));
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows.count(), 2);
+    // the consuming iterator yields the page it read at least
+    db.next().unwrap().unwrap();
+    assert_eq!(db.size_hint(), (2, Some(2)));
+    db.next_row("3").unwrap();
+    assert_eq!(db.len_remaining().unwrap(), 2);
+    assert_eq!(db.by_ref().count(), 2);
+}
+
+#[test]
+fn rows_are_counted_exactly_over_several_pages() {
+    let dir = TestDir::new("exact_rows");
+    let mut db = dir.db();
+    let n = ITER_PAGE_SIZE * 2 + 5;
+    for i in 0..n {
+        db.add_row(&format!("{:05}", i), [("k", "v")]).unwrap();
+    }
+    for order in [
+        IterOrder::InsertionAsc,
+        IterOrder::InsertionDesc,
+        IterOrder::ByItemVal,
+    ] {
+        db.set_iter_order(order);
+        let mut rows = db.rows().unwrap();
+        // stored by another statement once the iteration started, not iterated over
+        db.connection
+            .execute("insert into item_data (item_val) values ('new')", [])
+            .unwrap();
+        let mut left = n;
+        while let Some(row) = rows.next() {
+            row.unwrap();
+            left -= 1;
+            assert_eq!(rows.len(), left);
+        }
+        assert_eq!(left, 0);
+        db.connection
+            .execute("delete from item_data where item_val = 'new'", [])
+            .unwrap();
+    }
+    // deleted once the iteration started, accounted for with the last page
+    db.set_iter_order(IterOrder::InsertionAsc);
+    let mut rows = db.rows().unwrap();
+    db.connection
+        .execute("delete from item_data where id > ?1", [n as i64 - 3])
+        .unwrap();
+    assert_eq!(
+        rows.by_ref().take(ITER_PAGE_SIZE * 2).count(),
+        ITER_PAGE_SIZE * 2
+    );
+    assert_eq!(rows.len(), 5);
+    rows.next().unwrap().unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows.count(), 1);
+}
+
+#[test]
+fn new_fails_instead_of_panicking() {
+    let dir = TestDir::new("new_fails");
+    // the parent of the db file is a file
+    fs::write(dir.path("file"), "").unwrap();
+    let res = TableMapDb::new(dir.path("file").join("test.db"));
+    assert!(
+        matches!(res, Err(DataToolErrors::Sqlite { .. })),
+        "{:?}",
+        res.err()
+    );
+}
+
+#[test]
+fn rows_that_can_not_be_read_are_errors() {
+    let dir = TestDir::new("row_errors");
+    let mut db = dir.db();
+    db.add_row("a", [("k", "1")]).unwrap();
+    db.add_row("b", [("k", "2")]).unwrap();
+    db.add_row("c", [("k", "3")]).unwrap();
+    db.connection
+        .execute(
+            "update data_columns set value = x'ff' where item_id = 2",
+            [],
+        )
+        .unwrap();
+    let rows: Vec<_> = db.rows().unwrap().collect();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0].as_ref().unwrap()["k"], "1");
+    assert!(matches!(
+        rows[1],
+        Err(DataToolErrors::InvalidUtf8 { item_id: 2, .. })
+    ));
+    assert_eq!(rows[2].as_ref().unwrap()["k"], "3");
+    // the rows are filtered, the errors kept
+    let filtered: Vec<_> = db.iter_filtered(|r| r["k"] == "3").unwrap().collect();
+    assert_eq!(filtered.len(), 2);
+    assert!(filtered[0].is_err());
+    // and the db itself iterates the same way
+    let ks: Vec<_> = db
+        .by_ref()
+        .map(|r| r.map(|r| r["k"].clone()).map_err(|_| ()))
+        .collect();
+    assert_eq!(ks, [Ok("1".to_string()), Err(()), Ok("3".to_string())]);
+}
+
+#[test]
+fn batched_inserts_store_every_pair() {
+    let dir = TestDir::new("batched");
+    let mut db = dir.db();
+    // full batches and a remainder
+    let n = 1_234;
+    assert!(n % CELLS_PER_INSERT != 0 && n > CELLS_PER_INSERT);
+    db.next_row("a").unwrap();
+    let pairs: Vec<_> = (0..n)
+        .map(|i| (format!("k{}", i), format!("v{}", i)))
+        .collect();
+    db.insert_batched(pairs.clone()).unwrap();
+    for (k, v) in &pairs {
+        assert_eq!(db.get_value("a", k).unwrap().as_ref(), Some(v));
+    }
+    // in the order inserted, after the id
+    let item = db.get_item("a").unwrap().unwrap();
+    let stored: Vec<_> = item.into_iter().filter(|(k, _)| k != "id").collect();
+    assert_eq!(stored, pairs);
+    assert_eq!(db.columns().len(), n);
+}
+
+#[test]
+fn unknown_priority_columns_are_errors() {
+    let dir = TestDir::new("unknown_priority");
+    let mut db = dir.db();
+    db.add_row("a", [("name", "apple")]).unwrap();
+    let res = db.get_distinct_keys(vec!["name".into(), "nope".into(), "nope".into()]);
+    assert!(
+        matches!(&res, Err(DataToolErrors::UnknownColumns(c)) if c == &["nope"]),
+        "{:?}",
+        res
+    );
+    let res = db.get_distinct_keys_by(vec!["gone".into()], &KeyOrder::Alphabetical);
+    assert!(matches!(res, Err(DataToolErrors::UnknownColumns(_))));
+}
+
+#[test]
+fn priority_columns_come_first_each_once_then_the_keys_in_order() {
+    let dir = TestDir::new("priority_order");
+    let mut db = dir.db();
+    db.add_row("a", [("c", "1"), ("a", "1")]).unwrap();
+    db.add_row("b", [("b", "2"), ("d", "2"), ("a", "2")])
+        .unwrap();
+    db.add_row("c", [("d", "3"), ("a", "3"), ("z", "3")])
+        .unwrap();
+    db.add_row("d", [("z", "4")]).unwrap();
+    let priority = || vec!["b".to_string(), "d".to_string(), "b".to_string()];
+    let keys = |order| db.get_distinct_keys_by(priority(), &order).unwrap();
+    let first_seen = ["b", "d", "c", "a", "z"];
+    assert_eq!(db.get_distinct_keys(priority()).unwrap(), first_seen);
+    assert_eq!(keys(KeyOrder::FirstSeen), first_seen);
+    assert_eq!(keys(KeyOrder::Alphabetical), ["b", "d", "a", "c", "z"]);
+    assert_eq!(keys(KeyOrder::ByFrequencyDesc), ["b", "d", "a", "z", "c"]);
+    assert_eq!(
+        keys(KeyOrder::Custom(vec!["z".to_string()])),
+        ["b", "d", "z", "c", "a"]
+    );
+    // without priority columns
+    let all = db.get_distinct_keys(vec![]).unwrap();
+    assert_eq!(all, ["c", "a", "b", "d", "z"]);
+}
+
+#[test]
+fn invalid_utf8_is_read_following_the_text_policy() {
+    let dir = TestDir::new("text_policy");
+    let mut db = dir.db();
+    db.add_row("a", [("k", "ok")]).unwrap();
+    db.add_row("b", [("k", "x")]).unwrap();
+    db.add_row("c", [("k", "fine")]).unwrap();
+    // "ba" and a byte that is not UTF-8
+    db.connection
+        .execute(
+            "update data_columns set value = x'6261ff' where item_id = 2",
+            [],
+        )
+        .unwrap();
+    fn invalid<T>(res: &Result<T, DataToolErrors>) -> bool {
+        matches!(res, Err(DataToolErrors::InvalidUtf8 { item_id: 2, key }) if key == "k")
+    }
+    // exported by range, by id list and in the long shape
+    let exports = [
+        ExportOptions::default(),
+        ExportOptions::default().order(IterOrder::InsertionDesc),
+        ExportOptions::default().shape(ExportShape::Long),
+    ];
+    assert_eq!(db.get_text_policy(), TextPolicy::Strict);
+    let rows: Vec<_> = db.rows().unwrap().collect();
+    assert!(invalid(&rows[1]), "{:?}", rows[1]);
+    assert!(rows[0].is_ok() && rows[2].is_ok());
+    assert!(invalid(&db.get_value("b", "k")));
+    assert!(invalid(&db.get_item("b")));
+    assert_eq!(db.get_value("c", "k").unwrap().as_deref(), Some("fine"));
+    for (i, options) in exports.iter().enumerate() {
+        let out = dir.path(&format!("strict{}.csv", i));
+        let res = dump_csv_sync(&mut db, &out, options.clone().chunk(2), Default::default());
+        assert!(invalid(&res), "{:?}", res.map(|s| s.rows_written));
+        assert!(!out.exists());
+    }
+    let shared = shared::SharedTableMapDb::new(db);
+    assert!(invalid(&shared.get_value("b", "k")));
+    drop(shared);
+
+    let mut db = TableMapDb::open_existing(dir.path("test.db"))
+        .unwrap()
+        .text_policy(TextPolicy::Lossy);
+    let bad = "ba\u{FFFD}";
+    let ks: Vec<_> = db
+        .rows()
+        .unwrap()
+        .map(|r| r.unwrap()["k"].clone())
+        .collect();
+    assert_eq!(ks, ["ok", bad, "fine"]);
+    assert_eq!(db.get_value("b", "k").unwrap().as_deref(), Some(bad));
+    assert_eq!(db.get_item("b").unwrap().unwrap()["k"], bad);
+    let expected = [
+        format!("k\nok\n{}\nfine\n", bad),
+        format!("k\nfine\n{}\nok\n", bad),
+        format!(
+            "item_id,item_val,key,value\n1,a,k,ok\n2,b,k,{}\n3,c,k,fine\n",
+            bad
+        ),
+    ];
+    for (i, (options, expected)) in exports.into_iter().zip(expected).enumerate() {
+        let out = dir.path(&format!("lossy{}.csv", i));
+        dump_csv_sync(&mut db, &out, options.chunk(2), Default::default()).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), expected);
+    }
+    let shared = shared::SharedTableMapDb::new(db);
+    assert_eq!(shared.get_value("b", "k").unwrap().as_deref(), Some(bad));
+}
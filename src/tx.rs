@@ -0,0 +1,163 @@
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use std::fmt;
+use tracing::{error, warn};
+
+/// A transaction on a [`TableMapDb`], see [`TableMapDb::begin`]. Everything done through it
+/// is stored on [`TableMapTx::commit`], and rolled back if it is dropped without committing
+pub struct TableMapTx<'a> {
+    db: &'a mut TableMapDb,
+    /// the current item when the transaction began, set back on rollback as the items
+    /// created since are gone
+    current_id: Option<i64>,
+    /// the number of keys known when the transaction began, the ones added since are
+    /// forgotten on rollback
+    columns: usize,
+    done: bool,
+}
+
+impl TableMapDb {
+    /// Begins a transaction, to store the rows of several code paths all or nothing, or to
+    /// batch many inserts for speed. Fails if a transaction is already open on the
+    /// connection, transactions do not nest
+    pub fn begin(&mut self) -> Result<TableMapTx<'_>, DataToolErrors> {
+        if !self.connection.is_autocommit() {
+            return Err(DataToolErrors::InvalidArgument(
+                "a transaction is already open, transactions do not nest".to_string(),
+            ));
+        }
+        self.connection.execute_batch("BEGIN")?;
+        Ok(TableMapTx {
+            current_id: self.current_id,
+            columns: self.columns.len(),
+            db: self,
+            done: false,
+        })
+    }
+}
+
+impl TableMapTx<'_> {
+    /// Same as [`TableMapDb::next_row`]
+    pub fn next_row(&mut self, d: &str) -> Result<i64, DataToolErrors> {
+        self.db.next_row(d)
+    }
+
+    /// Same as [`TableMapDb::insert`]
+    pub fn insert<K, V>(&mut self, column: K, val: V) -> Result<(), DataToolErrors>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.db.insert(column, val)
+    }
+
+    /// Same as [`TableMapDb::insert_display`]
+    pub fn insert_display<V: fmt::Display>(
+        &mut self,
+        column: &str,
+        val: V,
+    ) -> Result<(), DataToolErrors> {
+        self.db.insert_display(column, val)
+    }
+
+    /// Same as [`TableMapDb::insert_batched`]
+    pub fn insert_batched<I, K, V>(&mut self, columns: I) -> Result<(), DataToolErrors>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.db.insert_batched(columns)
+    }
+
+    /// The db, for any other operation to run in the transaction. The ones running their own
+    /// transaction, e.g. [`TableMapDb::dedupe_by_key`], fail
+    pub fn db(&mut self) -> &mut TableMapDb {
+        self.db
+    }
+
+    /// Stores everything done in the transaction
+    pub fn commit(mut self) -> Result<(), DataToolErrors> {
+        self.done = true;
+        if let Err(e) = self.db.connection.execute_batch("COMMIT") {
+            self.rollback_quietly();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Drops everything done in the transaction, same as dropping it
+    pub fn rollback(mut self) -> Result<(), DataToolErrors> {
+        self.done = true;
+        self.forget();
+        self.db.connection.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+
+    /// Sets the handle back as it was when the transaction began
+    fn forget(&mut self) {
+        self.db.current_id = self.current_id;
+        self.db.columns.truncate(self.columns);
+    }
+
+    fn rollback_quietly(&mut self) {
+        self.forget();
+        if let Err(e) = self.db.connection.execute_batch("ROLLBACK") {
+            error!("Failed to roll back: {}", e);
+        }
+    }
+}
+
+impl Drop for TableMapTx<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            warn!("transaction dropped without committing, rolling back");
+            self.rollback_quietly();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::TestDir;
+
+    #[test]
+    fn dropped_transactions_are_rolled_back() {
+        let dir = TestDir::new("tx_drop");
+        let mut db = dir.db();
+        db.add_row("a", [("name", "apple")]).unwrap();
+        let before = db.current_item();
+        {
+            let mut tx = db.begin().unwrap();
+            tx.next_row("b").unwrap();
+            tx.insert("name", "banana").unwrap();
+            tx.insert("color", "yellow").unwrap();
+            assert!(tx.db().begin().is_err());
+        }
+        assert_eq!(db.item_vals().unwrap(), ["a"]);
+        assert_eq!(db.current_item(), before);
+        // the keys added in the transaction are forgotten, and added again when inserted
+        assert_eq!(db.columns(), ["name"]);
+        db.next_row("c").unwrap();
+        db.insert("color", "red").unwrap();
+        assert_eq!(db.columns(), ["name", "color"]);
+        assert_eq!(db.get_value("c", "color").unwrap().as_deref(), Some("red"));
+    }
+
+    #[test]
+    fn committed_transactions_are_stored() {
+        let dir = TestDir::new("tx_commit");
+        let mut db = dir.db();
+        let mut tx = db.begin().unwrap();
+        tx.next_row("a").unwrap();
+        tx.insert("name", "apple").unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin().unwrap();
+        tx.next_row("b").unwrap();
+        tx.insert("color", "yellow").unwrap();
+        tx.rollback().unwrap();
+        assert_eq!(db.item_vals().unwrap(), ["a"]);
+        assert_eq!(db.columns(), ["name"]);
+        assert_eq!(db.current_item().map(|(_, v)| v).as_deref(), Some("a"));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use regex::Regex;
+use std::borrow::Cow;
+
+/// Checks made on the keys and values before they are stored, see
+/// [`TableMapDb::max_key_len`], [`TableMapDb::max_value_len`] and [`TableMapDb::key_pattern`]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Validation {
+    max_key_len: Option<usize>,
+    /// the maximum length, and whether longer values are truncated instead of rejected
+    max_value_len: Option<(usize, bool)>,
+    key_pattern: Option<Regex>,
+}
+
+impl TableMapDb {
+    /// Rejects the keys longer than this many characters
+    pub fn max_key_len(mut self, max: usize) -> Self {
+        self.validation.max_key_len = Some(max);
+        self
+    }
+
+    /// Rejects the values longer than this many characters, once normalized, or truncates
+    /// them if `truncate`
+    pub fn max_value_len(mut self, max: usize, truncate: bool) -> Self {
+        self.validation.max_value_len = Some((max, truncate));
+        self
+    }
+
+    /// Rejects the keys the pattern does not match, e.g. `^C/` for the keys that must be in
+    /// the `C/` namespace
+    pub fn key_pattern(mut self, pattern: Regex) -> Self {
+        self.validation.key_pattern = Some(pattern);
+        self
+    }
+
+    /// The value to store under `key`, normalized and checked, or `None` if it is not
+    /// stored. The same for every insert path, fails with [`DataToolErrors::ValidationError`]
+    pub(crate) fn prepare_cell<'a>(
+        &self,
+        key: &str,
+        value: &'a str,
+    ) -> Result<Option<Cow<'a, str>>, DataToolErrors> {
+        let invalid = |reason: String| DataToolErrors::ValidationError {
+            key: key.to_string(),
+            reason,
+        };
+        let v = &self.validation;
+        if let Some(max) = v.max_key_len {
+            let len = key.chars().count();
+            if len > max {
+                return Err(invalid(format!(
+                    "key is {} characters long, at most {} are allowed",
+                    len, max
+                )));
+            }
+        }
+        if let Some(pattern) = &v.key_pattern {
+            if !pattern.is_match(key) {
+                return Err(invalid(format!(
+                    "key does not match the pattern {:?}",
+                    pattern.as_str()
+                )));
+            }
+        }
+        let Some(value) = self.normalize(key, value) else {
+            return Ok(None);
+        };
+        let Some((max, truncate)) = v.max_value_len else {
+            return Ok(Some(value));
+        };
+        match value.char_indices().nth(max) {
+            None => Ok(Some(value)),
+            Some((end, _)) if truncate => Ok(Some(match value {
+                Cow::Borrowed(v) => Cow::Borrowed(&v[..end]),
+                Cow::Owned(v) => Cow::Owned(v[..end].to_string()),
+            })),
+            Some(_) => Err(invalid(format!(
+                "value is {} characters long, at most {} are allowed",
+                value.chars().count(),
+                max
+            ))),
+        }
+    }
+}
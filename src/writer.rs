@@ -0,0 +1,235 @@
+use crate::errors::DataToolErrors;
+use crate::TableMapDb;
+use indexmap::IndexMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, trace};
+
+type Row = (String, IndexMap<String, String>);
+
+/// What the writer task did, returned with the db once all the senders are dropped
+#[derive(Debug, Clone, Default)]
+pub struct WriterSummary {
+    pub rows_written: usize,
+    /// the rows that were not stored, by item_val, with why. When a batch transaction
+    /// fails, it is rolled back and every row of the batch is listed.
+    pub failed: Vec<(String, DataToolErrors)>,
+}
+
+/// Settings for [`TableMapDb::spawn_writer_with`]
+#[derive(Debug, Clone)]
+pub struct WriterOptions {
+    /// rows written per transaction
+    pub batch_size: usize,
+    /// a partial batch is written after waiting this long
+    pub flush_interval: Duration,
+    /// rows that can be waiting to be written, before `send` starts waiting
+    pub channel_capacity: usize,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 1000,
+            flush_interval: Duration::from_millis(500),
+            channel_capacity: 10_000,
+        }
+    }
+}
+
+/// The writer task, see [`TableMapDb::spawn_writer`]
+pub type WriterHandle = JoinHandle<Result<(TableMapDb, WriterSummary), DataToolErrors>>;
+
+/// Sends rows to the writer task, see [`TableMapDb::spawn_writer`].
+/// Cloning is cheap, every producer can have its own.
+#[derive(Debug, Clone)]
+pub struct RowSender {
+    tx: mpsc::Sender<Row>,
+}
+
+impl RowSender {
+    /// Queues the row to be stored, same as [`TableMapDb::add_row`].
+    /// Waits if the writer is too far behind.
+    pub async fn send(
+        &self,
+        item_val: impl Into<String>,
+        columns: IndexMap<String, String>,
+    ) -> Result<(), DataToolErrors> {
+        self.tx
+            .send((item_val.into(), columns))
+            .await
+            .map_err(|_| DataToolErrors::GenericError("Writer has stopped".to_string()))
+    }
+
+    /// Same as [`RowSender::send`], for use outside of async code
+    pub fn blocking_send(
+        &self,
+        item_val: impl Into<String>,
+        columns: IndexMap<String, String>,
+    ) -> Result<(), DataToolErrors> {
+        self.tx
+            .blocking_send((item_val.into(), columns))
+            .map_err(|_| DataToolErrors::GenericError("Writer has stopped".to_string()))
+    }
+}
+
+impl TableMapDb {
+    /// Moves the db to a background task that stores the rows received through the returned
+    /// [`RowSender`], in batched transactions.
+    /// Once all the senders are dropped, the remaining rows are written, and the task returns
+    /// the db, e.g. for exporting, with a [`WriterSummary`] listing the rows that failed.
+    /// The task only errors if writing a batch panicked.
+    pub fn spawn_writer(self) -> (RowSender, WriterHandle) {
+        self.spawn_writer_with(WriterOptions::default())
+    }
+
+    /// Same as [`TableMapDb::spawn_writer`], with custom batching
+    pub fn spawn_writer_with(self, options: WriterOptions) -> (RowSender, WriterHandle) {
+        let (tx, rx) = mpsc::channel(options.channel_capacity.max(1));
+        let handle = tokio::spawn(run_writer(self, rx, options));
+        (RowSender { tx }, handle)
+    }
+}
+
+async fn run_writer(
+    mut db: TableMapDb,
+    mut rx: mpsc::Receiver<Row>,
+    options: WriterOptions,
+) -> Result<(TableMapDb, WriterSummary), DataToolErrors> {
+    let batch_size = options.batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut summary = WriterSummary::default();
+    let mut deadline = Instant::now();
+    loop {
+        // no need for a deadline until there is something to write
+        let received = if batch.is_empty() {
+            rx.recv().await
+        } else {
+            match timeout_at(deadline, rx.recv()).await {
+                Ok(r) => r,
+                Err(_) => {
+                    db = write_batch(db, std::mem::take(&mut batch), &mut summary).await?;
+                    continue;
+                }
+            }
+        };
+        match received {
+            Some(row) => {
+                if batch.is_empty() {
+                    deadline = Instant::now() + options.flush_interval;
+                }
+                batch.push(row);
+                if batch.len() >= batch_size {
+                    db = write_batch(db, std::mem::take(&mut batch), &mut summary).await?;
+                }
+            }
+            None => {
+                if !batch.is_empty() {
+                    db = write_batch(db, batch, &mut summary).await?;
+                }
+                return Ok((db, summary));
+            }
+        }
+    }
+}
+
+/// Writes the rows in a single transaction, off the async runtime. The rows that fail are
+/// added to the summary; if the transaction fails, it is rolled back and the whole batch is.
+async fn write_batch(
+    mut db: TableMapDb,
+    batch: Vec<Row>,
+    summary: &mut WriterSummary,
+) -> Result<TableMapDb, DataToolErrors> {
+    let (db, written, failed) = tokio::task::spawn_blocking(move || {
+        trace!("writing {} rows", batch.len());
+        let mut failed = vec![];
+        let res = db.begin().and_then(|mut tx| {
+            for (item_val, columns) in batch.iter() {
+                if let Err(e) = tx.db().add_row(item_val, columns) {
+                    error!("Failed to store {}: {}", item_val, e);
+                    failed.push((item_val.clone(), e));
+                }
+            }
+            tx.commit()
+        });
+        let written = match res {
+            Ok(()) => batch.len() - failed.len(),
+            Err(e) => {
+                error!("Failed to write a batch of {} rows: {}", batch.len(), e);
+                failed = batch
+                    .into_iter()
+                    .map(|(item_val, _)| (item_val, e.clone()))
+                    .collect();
+                0
+            }
+        };
+        (db, written, failed)
+    })
+    .await
+    .map_err(|e| DataToolErrors::GenericError(e.to_string()))?;
+    summary.rows_written += written;
+    summary.failed.extend(failed);
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+
+    fn row(n: usize) -> IndexMap<String, String> {
+        IndexMap::from([("n".to_string(), n.to_string())])
+    }
+
+    #[tokio::test]
+    async fn every_row_sent_is_stored_once_the_senders_are_dropped() {
+        let dir = TestDir::new("writer_every_row");
+        let (tx, handle) = dir.db().spawn_writer_with(WriterOptions {
+            batch_size: 7,
+            ..WriterOptions::default()
+        });
+        let producers: Vec<_> = (0..4)
+            .map(|p| {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    for n in 0..50 {
+                        tx.send(format!("{p}-{n}"), row(n)).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        for p in producers {
+            p.await.unwrap();
+        }
+        let (db, summary) = handle.await.unwrap().unwrap();
+        assert_eq!(summary.rows_written, 200);
+        assert!(summary.failed.is_empty());
+        for p in 0..4 {
+            for n in 0..50 {
+                assert_eq!(
+                    db.get_value(&format!("{p}-{n}"), "n").unwrap(),
+                    Some(n.to_string())
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rows_that_fail_are_listed_and_the_rest_stored() {
+        let dir = TestDir::new("writer_failed_rows");
+        let (tx, handle) = dir.db().max_key_len(3).spawn_writer();
+        tx.send("a", row(1)).await.unwrap();
+        let long = IndexMap::from([("longer".to_string(), "x".to_string())]);
+        tx.send("b", long).await.unwrap();
+        tx.send("c", row(3)).await.unwrap();
+        drop(tx);
+        let (db, summary) = handle.await.unwrap().unwrap();
+        assert_eq!(summary.rows_written, 2);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, "b");
+        assert_eq!(db.get_value("c", "n").unwrap(), Some("3".to_string()));
+    }
+}